@@ -0,0 +1,152 @@
+//! Handle lookups under proxy-style load: `cargo bench --bench handles`.
+//!
+//! Each thread plays one proxied connection, looking up the handles of both
+//! of its ends in turn as it would to pump data between them, while another
+//! thread keeps opening and closing connections. Compares the sharded
+//! `HandleTable` with a single lock over all slots, as the table used to be.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+
+#[allow(dead_code)]
+#[path = "../src/handles.rs"]
+mod handles;
+
+use handles::HandleTable;
+
+/// Stands in for a connection; lookups bump its counter so they are not optimized away.
+#[derive(Default)]
+struct Conn {
+    touched: AtomicU64,
+}
+
+trait Table: Send + Sync + 'static {
+    fn insert(&self, conn: Arc<Conn>) -> i64;
+    fn get(&self, handle: i64) -> Option<Arc<Conn>>;
+    fn remove(&self, handle: i64);
+}
+
+impl Table for HandleTable<Conn> {
+    fn insert(&self, conn: Arc<Conn>) -> i64 {
+        HandleTable::insert(self, conn)
+    }
+
+    fn get(&self, handle: i64) -> Option<Arc<Conn>> {
+        HandleTable::get(self, handle).ok()
+    }
+
+    fn remove(&self, handle: i64) {
+        let _ = HandleTable::remove(self, handle);
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    /// Generation and connection of each slot.
+    slots: Vec<(u32, Option<Arc<Conn>>)>,
+    free: Vec<usize>,
+}
+
+/// Slots and generations behind one lock, as before sharding.
+#[derive(Default)]
+struct SingleLock {
+    slots: RwLock<Slots>,
+}
+
+impl Table for SingleLock {
+    fn insert(&self, conn: Arc<Conn>) -> i64 {
+        let Slots { slots, free } = &mut *self.slots.write();
+        let index = match free.pop() {
+            Some(index) => {
+                slots[index].1 = Some(conn);
+                index
+            }
+            None => {
+                slots.push((1, Some(conn)));
+                slots.len() - 1
+            }
+        };
+        ((slots[index].0 as i64) << 32) | index as i64
+    }
+
+    fn get(&self, handle: i64) -> Option<Arc<Conn>> {
+        match self.slots.read().slots.get(handle as u32 as usize) {
+            Some((generation, conn)) if *generation == (handle >> 32) as u32 => conn.clone(),
+            _ => None,
+        }
+    }
+
+    fn remove(&self, handle: i64) {
+        let Slots { slots, free } = &mut *self.slots.write();
+        let index = handle as u32 as usize;
+        if let Some(slot) = slots.get_mut(index) {
+            if slot.0 == (handle >> 32) as u32 && slot.1.take().is_some() {
+                slot.0 += 1;
+                free.push(index);
+            }
+        }
+    }
+}
+
+/// Lookups per second across all threads.
+fn run(table: Arc<dyn Table>, pairs: usize, duration: Duration) -> f64 {
+    let stop = Arc::new(AtomicBool::new(false));
+    let lookups = Arc::new(AtomicU64::new(0));
+    // Everyone starts counting together, once all threads exist
+    let start = Arc::new(Barrier::new(pairs + 1));
+
+    let mut threads = Vec::new();
+    for _ in 0..pairs {
+        let client = table.insert(Arc::default());
+        let upstream = table.insert(Arc::default());
+        let (table, stop, lookups, start) = (table.clone(), stop.clone(), lookups.clone(), start.clone());
+        threads.push(thread::spawn(move || {
+            start.wait();
+            let mut count = 0u64;
+            while !stop.load(Ordering::Relaxed) {
+                for handle in [client, upstream] {
+                    let conn = table.get(handle).expect("handle stays open");
+                    conn.touched.fetch_add(1, Ordering::Relaxed);
+                }
+                count += 2;
+            }
+            lookups.fetch_add(count, Ordering::Relaxed);
+        }));
+    }
+
+    // Connections coming and going alongside
+    let churn = {
+        let (table, stop) = (table.clone(), stop.clone());
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let handle = table.insert(Arc::default());
+                table.remove(handle);
+                thread::sleep(Duration::from_micros(100));
+            }
+        })
+    };
+
+    start.wait();
+    let start = Instant::now();
+    thread::sleep(duration);
+    stop.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().expect("lookup thread panicked");
+    }
+    churn.join().expect("churn thread panicked");
+    lookups.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let duration = Duration::from_secs(2);
+    println!("{:>6} {:>16} {:>16} {:>8}", "pairs", "single lock/s", "sharded/s", "speedup");
+    for pairs in [1, 16, 64, 128, 256] {
+        let single = run(Arc::new(SingleLock::default()), pairs, duration);
+        let sharded = run(Arc::new(HandleTable::<Conn>::default()), pairs, duration);
+        println!("{:>6} {:>16.0} {:>16.0} {:>7.2}x", pairs, single, sharded, sharded / single);
+    }
+}
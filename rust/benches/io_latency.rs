@@ -0,0 +1,210 @@
+//! Per-call latency of tcpRead and tcpWrite: `cargo bench --bench io_latency`.
+//!
+//! The main thread plays a JNI thread calling tcpRead or tcpWrite over a
+//! loopback TCP connection whose other end streams or discards data. Each
+//! call is timed on two paths:
+//!
+//! - spawn: the call is spawned on the runtime and its result sent back over
+//!   a channel, reading or writing the socket directly, as every call did
+//!   before read-ahead and write-behind.
+//! - buffered: the call is polled in place with `Handle::block_on`, as
+//!   `GlobalState::run` does, and only copies from a `ReadAhead` buffer or
+//!   into a `WriteBehind` queue that a connection's tasks fill or send.
+//!
+//! Prints mean, p50, p99 and the slowest call for each.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Handle;
+
+#[allow(dead_code)]
+#[path = "../src/read_ahead.rs"]
+mod read_ahead;
+#[allow(dead_code)]
+#[path = "../src/write_behind.rs"]
+mod write_behind;
+
+use read_ahead::ReadAhead;
+use write_behind::WriteBehind;
+
+/// The subset of the crate's error type that `read_ahead.rs` and `write_behind.rs` use.
+#[derive(Debug)]
+enum TunnelError {
+    ConnectionFailed(String),
+    WouldBlock,
+}
+
+impl std::fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnelError::ConnectionFailed(message) => write!(f, "Connection failed: {}", message),
+            TunnelError::WouldBlock => f.write_str("Operation would block"),
+        }
+    }
+}
+
+/// Buffer sizes as connections use by default, and a typical Java array.
+const CAPACITY: usize = 64 * 1024;
+const CHUNK: usize = 16 * 1024;
+const CALL_SIZE: usize = 8192;
+
+const WARM_UP_CALLS: usize = 2_000;
+const MEASURED_CALLS: usize = 50_000;
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .expect("runtime");
+    let handle = runtime.handle().clone();
+
+    let spawn_read = {
+        let stream = runtime.block_on(source(&handle));
+        let (reader, _writer) = stream.into_split();
+        let reader = Arc::new(tokio::sync::Mutex::new(reader));
+        measure(|| {
+            let reader = reader.clone();
+            spawn_and_wait(&handle, async move {
+                let mut buf = vec![0u8; CALL_SIZE];
+                reader.lock().await.read(&mut buf).await.expect("read")
+            });
+        })
+    };
+
+    let buffered_read = {
+        let stream = runtime.block_on(source(&handle));
+        let read_ahead = Arc::new(ReadAhead::new(CAPACITY));
+        let stop = Arc::new(AtomicBool::new(false));
+        let filler = {
+            let (read_ahead, stop) = (read_ahead.clone(), stop.clone());
+            handle.spawn(async move {
+                let (mut reader, _writer) = stream.into_split();
+                let mut chunk = vec![0u8; CHUNK];
+                while !stop.load(Ordering::Relaxed) {
+                    read_ahead.wait_for_space().await;
+                    let n = reader.read(&mut chunk).await.expect("read");
+                    read_ahead.push(Ok(&chunk[..n]));
+                }
+            })
+        };
+        let mut buf = vec![0u8; CALL_SIZE];
+        let latencies = measure(|| {
+            handle.block_on(read_ahead.read(&mut buf, false)).expect("read");
+        });
+        stop.store(true, Ordering::Relaxed);
+        filler.abort();
+        latencies
+    };
+
+    let spawn_write = {
+        let stream = runtime.block_on(sink(&handle));
+        let (_reader, writer) = stream.into_split();
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+        measure(|| {
+            let writer = writer.clone();
+            let data = vec![7u8; CALL_SIZE];
+            spawn_and_wait(&handle, async move { writer.lock().await.write_all(&data).await.expect("write") });
+        })
+    };
+
+    let buffered_write = {
+        let stream = runtime.block_on(sink(&handle));
+        let write_behind = Arc::new(WriteBehind::new(CAPACITY));
+        let sender = {
+            let write_behind = write_behind.clone();
+            handle.spawn(async move {
+                let (_reader, mut writer) = stream.into_split();
+                while let Some(chunk) = write_behind.take(CHUNK).await {
+                    let result = writer.write_all(&chunk).await;
+                    write_behind.sent(result.map_err(|e| TunnelError::ConnectionFailed(e.to_string())));
+                }
+            })
+        };
+        write_behind.set_task(sender);
+        let data = vec![7u8; CALL_SIZE];
+        let latencies = measure(|| {
+            handle.block_on(write_behind.push(&data, false)).expect("write");
+        });
+        write_behind.stop();
+        latencies
+    };
+
+    report("read, spawn", spawn_read);
+    report("read, buffered", buffered_read);
+    report("write, spawn", spawn_write);
+    report("write, buffered", buffered_write);
+}
+
+/// Run a future the way `GlobalState::run` did for every call: spawned, with
+/// the result sent back over a channel.
+fn spawn_and_wait<T, F>(handle: &Handle, future: F) -> T
+where
+    F: std::future::Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = std::sync::mpsc::channel();
+    handle.spawn(async move {
+        let _ = tx.send(future.await);
+    });
+    rx.recv().expect("task panicked")
+}
+
+/// A connection whose peer writes as fast as it can.
+async fn source(handle: &Handle) -> TcpStream {
+    let (local, mut remote) = connect(handle).await;
+    handle.spawn(async move {
+        let chunk = vec![7u8; CHUNK];
+        while remote.write_all(&chunk).await.is_ok() {}
+    });
+    local
+}
+
+/// A connection whose peer reads and discards everything.
+async fn sink(handle: &Handle) -> TcpStream {
+    let (local, mut remote) = connect(handle).await;
+    handle.spawn(async move {
+        let mut chunk = vec![0u8; CHUNK];
+        while remote.read(&mut chunk).await.is_ok_and(|n| n > 0) {}
+    });
+    local
+}
+
+async fn connect(handle: &Handle) -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("address");
+    let accept = handle.spawn(async move { listener.accept().await.expect("accept").0 });
+    let local = TcpStream::connect(addr).await.expect("connect");
+    (local, accept.await.expect("accept task"))
+}
+
+fn measure(mut call: impl FnMut()) -> Vec<Duration> {
+    for _ in 0..WARM_UP_CALLS {
+        call();
+    }
+    (0..MEASURED_CALLS)
+        .map(|_| {
+            let start = Instant::now();
+            call();
+            start.elapsed()
+        })
+        .collect()
+}
+
+fn report(name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort_unstable();
+    let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    let percentile = |p: usize| latencies[(latencies.len() * p / 100).min(latencies.len() - 1)];
+    println!(
+        "{:<16} mean {:>9.1?}  p50 {:>9.1?}  p99 {:>9.1?}  max {:>9.1?}",
+        name,
+        mean,
+        percentile(50),
+        percentile(99),
+        latencies[latencies.len() - 1]
+    );
+}
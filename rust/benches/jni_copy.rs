@@ -0,0 +1,129 @@
+//! Copies between Java byte arrays and Rust buffers: `cargo bench --bench jni_copy`.
+//!
+//! Starts a JVM (needs a JDK, found through JAVA_HOME or `java` on PATH) and
+//! times the copies tcpRead and tcpWrite make for an 8 KiB Java array, in
+//! two ways:
+//!
+//! - per-byte: a fresh buffer per call, converted element by element to or
+//!   from a temporary `jbyte` vector, as both calls did before.
+//! - region: region copies straight into or out of a reused buffer viewed
+//!   through `jbytes`, as they do now.
+//!
+//! A counting allocator sees every Rust heap allocation; the JVM allocates
+//! through its own allocator and is not counted. The region copies must not
+//! allocate at all after a warm-up; the bench fails otherwise.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use jni::objects::JByteArray;
+use jni::{InitArgsBuilder, JNIEnv, JavaVM};
+
+#[path = "../src/jbytes.rs"]
+mod jbytes;
+
+use jbytes::{as_jbytes, as_jbytes_mut, WRITE_BUF};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// A typical Java array handed to tcpRead and tcpWrite.
+const ARRAY_LEN: usize = 8192;
+
+const WARM_UP_CALLS: usize = 10_000;
+const MEASURED_CALLS: usize = 200_000;
+
+fn main() {
+    let args = InitArgsBuilder::new().build().expect("JVM arguments");
+    let vm = JavaVM::new(args).expect("JVM");
+    let env = vm.attach_current_thread().expect("attach");
+    let array = env.new_byte_array(ARRAY_LEN as i32).expect("array");
+    // Data as the connection would have read it
+    let data = vec![7u8; ARRAY_LEN];
+
+    let read_per_byte = measure(|| {
+        // The read buffer was allocated per call too
+        let received = data.clone();
+        let bytes: Vec<i8> = received.iter().map(|&b| b as i8).collect();
+        env.set_byte_array_region(&array, 0, &bytes).expect("copy");
+    });
+
+    // Kept on the connection between reads, like `Connection::take_read_buf`
+    let mut scratch = Vec::new();
+    let read_region = measure(|| {
+        let mut buf = std::mem::take(&mut scratch);
+        buf.resize(ARRAY_LEN, 0);
+        buf.copy_from_slice(&data);
+        env.set_byte_array_region(&array, 0, as_jbytes(&buf)).expect("copy");
+        scratch = buf;
+    });
+
+    let write_per_byte = measure(|| {
+        let mut bytes = vec![0i8; ARRAY_LEN];
+        env.get_byte_array_region(&array, 0, &mut bytes).expect("copy");
+        let written: Vec<u8> = bytes.iter().map(|&b| b as u8).collect();
+        std::hint::black_box(written);
+    });
+
+    let write_region = measure(|| write(&env, &array));
+
+    report("read, per-byte", read_per_byte);
+    report("read, region", read_region);
+    report("write, per-byte", write_per_byte);
+    report("write, region", write_region);
+    assert_eq!(read_region.1, 0, "region copies allocated on the read path");
+    assert_eq!(write_region.1, 0, "region copies allocated on the write path");
+}
+
+/// Copy the array out the way tcpWrite does, into the thread's reused buffer.
+fn write(env: &JNIEnv, array: &JByteArray) {
+    let mut bytes = WRITE_BUF.take();
+    bytes.resize(ARRAY_LEN, 0);
+    env.get_byte_array_region(array, 0, as_jbytes_mut(&mut bytes)).expect("copy");
+    std::hint::black_box(&bytes);
+    WRITE_BUF.set(bytes);
+}
+
+/// Time `call`, returning the time per call and the allocations it made.
+fn measure(mut call: impl FnMut()) -> (Duration, u64) {
+    for _ in 0..WARM_UP_CALLS {
+        call();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..MEASURED_CALLS {
+        call();
+    }
+    let elapsed = start.elapsed();
+    (elapsed / MEASURED_CALLS as u32, ALLOCATIONS.load(Ordering::Relaxed) - allocations)
+}
+
+fn report(name: &str, (per_call, allocations): (Duration, u64)) {
+    println!(
+        "{:<16} {:>9.1?}/call  {:>8.2} allocations/call",
+        name,
+        per_call,
+        allocations as f64 / MEASURED_CALLS as f64
+    );
+}
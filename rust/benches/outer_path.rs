@@ -0,0 +1,184 @@
+//! Outer UDP path with and without the relay: `cargo bench --bench outer_path`.
+//!
+//! With batching on, plain UDP goes tunnel → loopback relay → network
+//! instead of tunnel → network, since the tunnel's own socket belongs to
+//! wireguard-netstack and takes a call per datagram. This measures whether
+//! the batched relay makes up for the extra hop and its syscalls. The
+//! tunnel's socket is played by a socket making one call per datagram, the
+//! relay by the same two forwarding loops as `transport::Relay` over
+//! `BatchSocket`s, and the WireGuard server by a batched peer socket.
+//!
+//! Each path is timed both ways: upload, the tunnel sending to the peer as
+//! fast as it can, and download, the peer sending batches as fast as it can,
+//! as a server streaming a resource pack would. Reports what arrived;
+//! loopback drops whatever the receiver cannot keep up with.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+
+#[allow(dead_code)]
+#[path = "../src/udp_batch.rs"]
+mod udp_batch;
+
+use udp_batch::{BatchSocket, RecvBatch, MAX_BATCH};
+
+/// An encrypted data packet at the tunnel's default MTU.
+const DATAGRAM: usize = 1420 + 32;
+
+#[derive(Clone, Copy)]
+enum Path {
+    Direct,
+    Relay,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+/// Both ends of a path, and the relay between them, if any.
+struct Ends {
+    /// Sends and receives one datagram per call, as wireguard-netstack does.
+    tunnel: UdpSocket,
+    peer: BatchSocket,
+    /// Where the tunnel sends: the peer, or the relay.
+    tunnel_to: SocketAddr,
+    /// Where the peer sends: the tunnel, or the relay's outer socket.
+    peer_to: SocketAddr,
+    /// Forwarding loops; aborted when dropped.
+    _relay: JoinSet<()>,
+}
+
+async fn bind() -> UdpSocket {
+    UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.expect("bind")
+}
+
+async fn ends(path: Path) -> Ends {
+    let (tunnel, peer) = (bind().await, bind().await);
+    let tunnel_addr = tunnel.local_addr().expect("address");
+    let peer_addr = peer.local_addr().expect("address");
+    let peer = BatchSocket::new(peer, true);
+    let mut relay = JoinSet::new();
+    let (tunnel_to, peer_to) = match path {
+        Path::Direct => (peer_addr, tunnel_addr),
+        Path::Relay => {
+            let (local, outer) = (bind().await, bind().await);
+            outer.connect(peer_addr).await.expect("connect");
+            let local_addr = local.local_addr().expect("address");
+            let outer_addr = outer.local_addr().expect("address");
+            let local = Arc::new(BatchSocket::new(local, true));
+            let outer = Arc::new(BatchSocket::new(outer, true));
+            relay.spawn(forward(local.clone(), outer.clone(), None));
+            relay.spawn(forward(outer, local, Some(tunnel_addr)));
+            (local_addr, outer_addr)
+        }
+    };
+    Ends { tunnel, peer, tunnel_to, peer_to, _relay: relay }
+}
+
+/// Move whatever has queued up on `from` to `to`, a batch at a time, as the relay does.
+async fn forward(from: Arc<BatchSocket>, to: Arc<BatchSocket>, destination: Option<SocketAddr>) {
+    let mut batch = RecvBatch::new(MAX_BATCH);
+    while from.recv_batch(&mut batch).await.is_ok() {
+        let packets: Vec<&[u8]> = batch.datagrams().map(|(packet, _)| packet).collect();
+        // Dropped under load, like any UDP send
+        let _ = to.send_batch(&packets, destination).await;
+    }
+}
+
+/// Datagrams sent, and datagrams and bytes received, per second.
+async fn run(path: Path, direction: Direction, duration: Duration) -> (f64, f64, f64) {
+    let ends = Arc::new(ends(path).await);
+    let stop = Arc::new(AtomicBool::new(false));
+    let (received, bytes) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+
+    let receiving = {
+        let (ends, stop, received, bytes) = (ends.clone(), stop.clone(), received.clone(), bytes.clone());
+        tokio::spawn(async move {
+            let mut batch = RecvBatch::new(MAX_BATCH);
+            let mut buf = vec![0u8; 65535];
+            while !stop.load(Ordering::Relaxed) {
+                let wait = Duration::from_millis(50);
+                match direction {
+                    Direction::Upload => {
+                        let Ok(Ok(_)) = tokio::time::timeout(wait, ends.peer.recv_batch(&mut batch)).await else {
+                            continue;
+                        };
+                        for (datagram, _) in batch.datagrams() {
+                            received.fetch_add(1, Ordering::Relaxed);
+                            bytes.fetch_add(datagram.len() as u64, Ordering::Relaxed);
+                        }
+                    }
+                    Direction::Download => {
+                        let Ok(Ok((n, _))) = tokio::time::timeout(wait, ends.tunnel.recv_from(&mut buf)).await else {
+                            continue;
+                        };
+                        received.fetch_add(1, Ordering::Relaxed);
+                        bytes.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                }
+            }
+        })
+    };
+
+    let payload = vec![0x55u8; DATAGRAM];
+    let datagrams = vec![&payload[..]; MAX_BATCH];
+    let start = Instant::now();
+    let mut sent = 0u64;
+    while start.elapsed() < duration {
+        match direction {
+            Direction::Upload => {
+                for datagram in &datagrams {
+                    ends.tunnel.send_to(datagram, ends.tunnel_to).await.expect("send");
+                }
+            }
+            Direction::Download => ends.peer.send_batch(&datagrams, Some(ends.peer_to)).await.expect("send"),
+        }
+        sent += datagrams.len() as u64;
+        // Let the receiver and relay run on a single-core machine
+        tokio::task::yield_now().await;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    stop.store(true, Ordering::Relaxed);
+    receiving.await.expect("receiver panicked");
+    (
+        sent as f64 / elapsed,
+        received.load(Ordering::Relaxed) as f64 / elapsed,
+        bytes.load(Ordering::Relaxed) as f64 / elapsed,
+    )
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .expect("runtime");
+    let duration = Duration::from_secs(2);
+    println!("{:>18} {:>12} {:>12} {:>10} {:>6}", "path", "sent/s", "received/s", "Mbit/s", "loss");
+    for direction in [Direction::Upload, Direction::Download] {
+        for path in [Path::Direct, Path::Relay] {
+            let (sent, received, bytes) = runtime.block_on(run(path, direction, duration));
+            let name = match (direction, path) {
+                (Direction::Upload, Path::Direct) => "upload, direct",
+                (Direction::Upload, Path::Relay) => "upload, relay",
+                (Direction::Download, Path::Direct) => "download, direct",
+                (Direction::Download, Path::Relay) => "download, relay",
+            };
+            println!(
+                "{:>18} {:>12.0} {:>12.0} {:>10.0} {:>5.1}%",
+                name,
+                sent,
+                received,
+                bytes * 8.0 / 1e6,
+                100.0 * (1.0 - received / sent)
+            );
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Heap allocations on the steady-state read path: `cargo bench --bench read_alloc`.
+//!
+//! A task on a multi-threaded runtime fills a `ReadAhead` buffer the way a
+//! connection's read-ahead task does, while the main thread plays a JNI
+//! thread in tcpRead: it takes a reused buffer sized to the Java array, moves
+//! it into a future that reads from the `ReadAhead`, runs that through
+//! `runtime::block_on` as `GlobalState::run` does, and puts the buffer back.
+//! After a warm-up, a counting allocator must see no allocations at all, on
+//! any thread; the bench fails otherwise.
+//!
+//! This covers the buffer handling, the bridge onto the runtime and the
+//! read-ahead copy. It does not cover the rest of `Connection::read` (byte
+//! counters, rate limits, the reconnect signal) or the JNI copy into the
+//! Java array, which `jni_copy` checks.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[allow(dead_code)]
+#[path = "../src/read_ahead.rs"]
+mod read_ahead;
+#[allow(dead_code)]
+#[path = "../src/runtime.rs"]
+mod runtime;
+
+use read_ahead::ReadAhead;
+
+/// The subset of the crate's error type that `read_ahead.rs` uses.
+#[derive(Debug)]
+enum TunnelError {
+    ConnectionFailed(String),
+    WouldBlock,
+}
+
+impl std::fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnelError::ConnectionFailed(message) => write!(f, "Connection failed: {}", message),
+            TunnelError::WouldBlock => f.write_str("Operation would block"),
+        }
+    }
+}
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Read-ahead buffer and chunk sizes, as connections use by default.
+const CAPACITY: usize = 64 * 1024;
+const CHUNK: usize = 16 * 1024;
+
+const WARM_UP_READS: usize = 10_000;
+const MEASURED_READS: usize = 200_000;
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .build()
+        .expect("runtime");
+    let handle = runtime.handle().clone();
+    let read_ahead = Arc::new(ReadAhead::new(CAPACITY));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let filler = {
+        let (read_ahead, stop) = (read_ahead.clone(), stop.clone());
+        runtime.spawn(async move {
+            let chunk = vec![7u8; CHUNK];
+            while !stop.load(Ordering::Relaxed) {
+                read_ahead.wait_for_space().await;
+                read_ahead.push(Ok(&chunk));
+            }
+        })
+    };
+
+    // Kept on the connection between reads, like `Connection::take_read_buf`
+    let mut scratch = Vec::new();
+    let mut read = |java_len: usize| {
+        let mut buf = std::mem::take(&mut scratch);
+        buf.resize(java_len, 0);
+        let io_read_ahead = read_ahead.clone();
+        let (result, buf) = runtime::block_on(&handle, async move {
+            let result = io_read_ahead.read(&mut buf, false).await;
+            (result, buf)
+        });
+        scratch = buf;
+        result.expect("read")
+    };
+
+    for _ in 0..WARM_UP_READS {
+        read(8192);
+    }
+
+    let (allocations, bytes) = (ALLOCATIONS.load(Ordering::Relaxed), ALLOCATED_BYTES.load(Ordering::Relaxed));
+    let start = Instant::now();
+    let mut total = 0;
+    for _ in 0..MEASURED_READS {
+        total += read(8192);
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes;
+
+    stop.store(true, Ordering::Relaxed);
+    handle.block_on(read_ahead.read(&mut scratch, false)).expect("read");
+    runtime.block_on(filler).expect("filler panicked");
+
+    println!(
+        "{} reads, {:.1} MiB in {:?} ({:.0} ns/read): {} allocations, {} bytes",
+        MEASURED_READS,
+        total as f64 / (1024.0 * 1024.0),
+        elapsed,
+        elapsed.as_nanos() as f64 / MEASURED_READS as f64,
+        allocations,
+        bytes
+    );
+    assert_eq!(allocations, 0, "the read path allocated at steady state");
+}
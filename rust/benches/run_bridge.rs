@@ -0,0 +1,136 @@
+//! Per-call cost of bridging a JNI thread onto the runtime: `cargo bench --bench run_bridge`.
+//!
+//! Every JNI call runs its future through `GlobalState::run`, which is
+//! `runtime::block_on`. The main thread plays a JNI thread and times two
+//! bridges on the runtime `NativeRuntime` builds, with worker threads and
+//! without:
+//!
+//! - spawn: the future is spawned and its result sent back over a channel,
+//!   as every call did before `block_on`.
+//! - block_on: `runtime::block_on`, which polls the future in place.
+//!
+//! Each is timed with a future that is ready at once, the bridge's own cost,
+//! and with one that waits for a datagram echoed by a runtime task, like a
+//! read waiting for data the runtime receives. Prints mean, p50, p99 and the
+//! slowest call for each.
+
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::runtime::Handle;
+
+#[allow(dead_code)]
+#[path = "../src/runtime.rs"]
+mod runtime;
+
+use runtime::{NativeRuntime, RuntimeConfig};
+
+const WARM_UP_CALLS: usize = 2_000;
+const MEASURED_CALLS: usize = 50_000;
+
+fn main() {
+    for workers in [2, 0] {
+        runtime::configure(RuntimeConfig {
+            worker_threads: workers,
+            max_blocking_threads: None,
+            thread_stack_size: None,
+        });
+        let native = NativeRuntime::build().expect("runtime");
+        let handle = native.handle();
+        let flavor = if workers == 0 { "current-thread" } else { "multi-thread" };
+
+        report(flavor, "ready, spawn", measure(|| spawn_and_wait(&handle, async { 1 })));
+        report(flavor, "ready, block_on", measure(|| runtime::block_on(&handle, async { 1 })));
+
+        let echo = runtime::block_on(&handle, echo_pair());
+        let spawn = {
+            let echo = echo.clone();
+            measure(|| {
+                let echo = echo.clone();
+                spawn_and_wait(&handle, async move { echo.round_trip().await })
+            })
+        };
+        report(flavor, "echo, spawn", spawn);
+        let block_on = measure(|| {
+            let echo = echo.clone();
+            runtime::block_on(&handle, async move { echo.round_trip().await })
+        });
+        report(flavor, "echo, block_on", block_on);
+
+        drop(echo);
+        native.shutdown(Duration::from_secs(1));
+    }
+}
+
+/// Run a future the way `GlobalState::run` did for every call: spawned, with
+/// the result sent back over a channel.
+fn spawn_and_wait<T, F>(handle: &Handle, future: F) -> T
+where
+    F: std::future::Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = std::sync::mpsc::channel();
+    handle.spawn(async move {
+        let _ = tx.send(future.await);
+    });
+    rx.recv().expect("task panicked")
+}
+
+/// A socket whose datagrams a runtime task sends straight back.
+struct Echo {
+    socket: UdpSocket,
+}
+
+impl Echo {
+    async fn round_trip(&self) -> usize {
+        self.socket.send(&[7; 64]).await.expect("send");
+        let mut buf = [0u8; 64];
+        self.socket.recv(&mut buf).await.expect("receive")
+    }
+}
+
+async fn echo_pair() -> Arc<Echo> {
+    let (socket, peer) = (bind().await, bind().await);
+    socket.connect(peer.local_addr().expect("address")).await.expect("connect");
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        while let Ok((n, from)) = peer.recv_from(&mut buf).await {
+            let _ = peer.send_to(&buf[..n], from).await;
+        }
+    });
+    Arc::new(Echo { socket })
+}
+
+async fn bind() -> UdpSocket {
+    UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.expect("bind")
+}
+
+fn measure<T>(mut call: impl FnMut() -> T) -> Vec<Duration> {
+    for _ in 0..WARM_UP_CALLS {
+        std::hint::black_box(call());
+    }
+    (0..MEASURED_CALLS)
+        .map(|_| {
+            let start = Instant::now();
+            std::hint::black_box(call());
+            start.elapsed()
+        })
+        .collect()
+}
+
+fn report(flavor: &str, name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort_unstable();
+    let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    let percentile = |p: usize| latencies[(latencies.len() * p / 100).min(latencies.len() - 1)];
+    println!(
+        "{:<14} {:<16} mean {:>9.1?}  p50 {:>9.1?}  p99 {:>9.1?}  max {:>9.1?}",
+        flavor,
+        name,
+        mean,
+        percentile(50),
+        percentile(99),
+        latencies[latencies.len() - 1]
+    );
+}
@@ -0,0 +1,115 @@
+//! Outer UDP throughput over loopback: `cargo bench --bench udp_batch`.
+//!
+//! A sender pushes WireGuard-sized datagrams at a receiver as fast as it can,
+//! once with a call per datagram, once with `sendmmsg`/`recvmmsg` alone and
+//! once with segmentation and receive offload on top, as `BatchSocket` does
+//! with batching on. Reports what the receiver got; loopback drops whatever
+//! the receiver cannot keep up with, so loss shows which side is the limit.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+
+#[allow(dead_code)]
+#[path = "../src/udp_batch.rs"]
+mod udp_batch;
+
+use udp_batch::{BatchSocket, RecvBatch, MAX_BATCH};
+
+/// An encrypted data packet at the tunnel's default MTU.
+const DATAGRAM: usize = 1420 + 32;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Single,
+    Batched,
+    Offload,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Single => "one per call",
+            Mode::Batched => "mmsg",
+            Mode::Offload => "mmsg + GSO/GRO",
+        }
+    }
+
+    fn socket(self, socket: UdpSocket) -> BatchSocket {
+        match self {
+            Mode::Single => BatchSocket::new(socket, false),
+            Mode::Batched => BatchSocket::with_offload(socket, true, false),
+            Mode::Offload => BatchSocket::new(socket, true),
+        }
+    }
+}
+
+/// Datagrams sent, and datagrams and bytes received, per second.
+async fn run(mode: Mode, duration: Duration) -> (f64, f64, f64) {
+    let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.expect("bind");
+    let to: SocketAddr = receiver.local_addr().expect("address");
+    let receiver = mode.socket(receiver);
+    let sender = mode.socket(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.expect("bind"));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (received, bytes) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+
+    let receiving = {
+        let (stop, received, bytes) = (stop.clone(), received.clone(), bytes.clone());
+        tokio::spawn(async move {
+            let mut batch = RecvBatch::new(MAX_BATCH);
+            while !stop.load(Ordering::Relaxed) {
+                let Ok(Ok(_)) = tokio::time::timeout(Duration::from_millis(50), receiver.recv_batch(&mut batch)).await
+                else {
+                    continue;
+                };
+                for (datagram, _) in batch.datagrams() {
+                    received.fetch_add(1, Ordering::Relaxed);
+                    bytes.fetch_add(datagram.len() as u64, Ordering::Relaxed);
+                }
+            }
+        })
+    };
+
+    let payload = vec![0x55u8; DATAGRAM];
+    let datagrams = vec![&payload[..]; MAX_BATCH];
+    let start = Instant::now();
+    let mut sent = 0u64;
+    while start.elapsed() < duration {
+        sender.send_batch(&datagrams, Some(to)).await.expect("send");
+        sent += datagrams.len() as u64;
+        // Let the receiver run on a single-core machine
+        tokio::task::yield_now().await;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    stop.store(true, Ordering::Relaxed);
+    receiving.await.expect("receiver panicked");
+    (
+        sent as f64 / elapsed,
+        received.load(Ordering::Relaxed) as f64 / elapsed,
+        bytes.load(Ordering::Relaxed) as f64 / elapsed,
+    )
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .expect("runtime");
+    let duration = Duration::from_secs(2);
+    println!("{:>16} {:>12} {:>12} {:>10} {:>6}", "mode", "sent/s", "received/s", "Mbit/s", "loss");
+    for mode in [Mode::Single, Mode::Batched, Mode::Offload] {
+        let (sent, received, bytes) = runtime.block_on(run(mode, duration));
+        println!(
+            "{:>16} {:>12.0} {:>12.0} {:>10.0} {:>5.1}%",
+            mode.name(),
+            sent,
+            received,
+            bytes * 8.0 / 1e6,
+            100.0 * (1.0 - received / sent)
+        );
+    }
+}
@@ -0,0 +1,115 @@
+/*
+ * C API of the WireGuard tunnel library, built with the `c-api` feature.
+ *
+ * Calls block until done and may be made from any thread. Failures return a
+ * negative code: a negated WGT_TUNNEL_ERROR_* value, or WGT_ERROR_INVALID_ARGUMENT
+ * or WGT_ERROR_PANIC. wgt_last_error then describes the failure.
+ *
+ * The JNI entry points share the same tunnel and connections, so handles from
+ * one can be used with the other in the same process.
+ */
+
+#ifndef WIREGUARD_TUNNEL_H
+#define WIREGUARD_TUNNEL_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Tunnel states */
+#define WGT_TUNNEL_STOPPED 0
+#define WGT_TUNNEL_STARTING 1
+#define WGT_TUNNEL_READY 2
+#define WGT_TUNNEL_FAILED 3
+#define WGT_TUNNEL_PAUSED 4
+
+/* Tunnel error codes, the same as Native.TUNNEL_ERROR_*; functions return them negated */
+#define WGT_TUNNEL_ERROR_NOT_INITIALIZED 1
+#define WGT_TUNNEL_ERROR_ALREADY_RUNNING 2
+#define WGT_TUNNEL_ERROR_NOT_READY 3
+#define WGT_TUNNEL_ERROR_WARP_REGISTRATION 4
+#define WGT_TUNNEL_ERROR_WARP_API 5
+#define WGT_TUNNEL_ERROR_CREDENTIAL_PERSISTENCE 6
+#define WGT_TUNNEL_ERROR_CREDENTIALS_LOCKED 7
+#define WGT_TUNNEL_ERROR_DNS 8
+#define WGT_TUNNEL_ERROR_CONNECTION_FAILED 9
+#define WGT_TUNNEL_ERROR_INVALID_HANDLE 10
+#define WGT_TUNNEL_ERROR_STALE_HANDLE 11
+#define WGT_TUNNEL_ERROR_IO 12
+#define WGT_TUNNEL_ERROR_TIMEOUT 13
+#define WGT_TUNNEL_ERROR_WOULD_BLOCK 14
+#define WGT_TUNNEL_ERROR_TOO_MANY_CONNECTIONS 15
+#define WGT_TUNNEL_ERROR_RECONNECTING 16
+#define WGT_TUNNEL_ERROR_RESTART_REQUIRED 17
+#define WGT_TUNNEL_ERROR_POLICY_DENIED 18
+
+/* An argument was null, not UTF-8 or otherwise invalid */
+#define WGT_ERROR_INVALID_ARGUMENT (-98)
+/* The call panicked; the tunnel state may be inconsistent */
+#define WGT_ERROR_PANIC (-99)
+
+/* Initialize logging and the runtime. Later calls are no-ops. Returns 0. */
+int32_t wgt_init(void);
+
+/* Encrypt persisted credentials with a passphrase, or stop encrypting if NULL. */
+int32_t wgt_set_credential_passphrase(const char *passphrase);
+
+/* Start the WARP tunnel, registering a device on first use.
+ * license_key may be NULL. Returns WGT_TUNNEL_READY. */
+int32_t wgt_tunnel_start_warp(const char *cred_path, const char *license_key);
+
+/* Start a tunnel from WireGuard profile text. Returns WGT_TUNNEL_READY. */
+int32_t wgt_tunnel_import_profile(const char *text);
+
+/* Returns one of the WGT_TUNNEL_* states. */
+int32_t wgt_tunnel_state(void);
+
+/* Shut the tunnel down. */
+int32_t wgt_tunnel_stop(void);
+
+/* Connect to host:port via the tunnel; timeout_ms 0 waits indefinitely.
+ * Returns a connection handle (>0). */
+int64_t wgt_tcp_connect(const char *host, uint16_t port, int64_t timeout_ms);
+
+/* Read up to len bytes. Returns the bytes read, 0 at end of stream, or
+ * -WGT_TUNNEL_ERROR_WOULD_BLOCK if non-blocking and nothing is buffered. */
+int64_t wgt_tcp_read(int64_t handle, uint8_t *buf, size_t len);
+
+/* Write up to len bytes. Returns the bytes written, 0 if non-blocking and
+ * the send buffer is full. */
+int64_t wgt_tcp_write(int64_t handle, const uint8_t *data, size_t len);
+
+/* Close a connection. */
+int32_t wgt_tcp_close(int64_t handle);
+
+/*
+ * Read/write path for java.lang.foreign downcalls, exported with or without
+ * the c-api feature (see Native.FEATURE_FFM). The segment is read or written
+ * in place. Returns as wgt_tcp_read and wgt_tcp_write, except that the
+ * message of a failure is kept on the connection for Native.tcpLastErrorInfo
+ * rather than for wgt_last_error.
+ */
+
+/* A segment for wgt_ffm_tcp_writev: 16 bytes on 64-bit platforms */
+typedef struct {
+    const uint8_t *address;
+    int64_t length;
+} wgt_segment;
+
+int64_t wgt_ffm_tcp_read(int64_t handle, uint8_t *address, int64_t length);
+int64_t wgt_ffm_tcp_write(int64_t handle, const uint8_t *address, int64_t length);
+/* Write count segments in order, as one write */
+int64_t wgt_ffm_tcp_writev(int64_t handle, const wgt_segment *segments, int32_t count);
+
+/* Copy the message of the calling thread's last failure into buf, NUL-terminated
+ * and truncated to len bytes. Returns the full message length, like snprintf. */
+size_t wgt_last_error(char *buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WIREGUARD_TUNNEL_H */
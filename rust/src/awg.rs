@@ -0,0 +1,223 @@
+//! AmneziaWG-style obfuscation of WireGuard packets.
+//!
+//! DPI boxes spot WireGuard by its fixed message types and handshake sizes.
+//! AmneziaWG hides them by sending `Jc` junk datagrams of `Jmin`..=`Jmax`
+//! random bytes before each handshake initiation, prefixing handshake
+//! messages with `S1`/`S2` bytes of random padding, and replacing the four
+//! message type values with `H1`-`H4`.
+//!
+//! Standard WireGuard servers, WARP included, drop the junk datagrams, so
+//! `Jc`/`Jmin`/`Jmax` work on their own. Padding and custom types need an
+//! AmneziaWG server configured with the same values.
+
+use std::io;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+
+use crate::transport::{BoxFuture, Transport};
+
+pub const HANDSHAKE_INIT: u32 = 1;
+pub const HANDSHAKE_RESPONSE: u32 = 2;
+const COOKIE_REPLY: u32 = 3;
+const TRANSPORT_DATA: u32 = 4;
+
+const HANDSHAKE_INIT_LEN: usize = 148;
+const HANDSHAKE_RESPONSE_LEN: usize = 92;
+const COOKIE_REPLY_LEN: usize = 64;
+/// Header, counter and tag of an empty data message (a keepalive).
+const TRANSPORT_DATA_MIN_LEN: usize = 32;
+
+/// Limits from AmneziaWG, keeping obfuscated datagrams within 1280 bytes.
+const MAX_JUNK_COUNT: u32 = 128;
+const MAX_JUNK_LEN: u32 = 1280;
+const MAX_INIT_PADDING: u32 = 1280 - HANDSHAKE_INIT_LEN as u32;
+const MAX_RESPONSE_PADDING: u32 = 1280 - HANDSHAKE_RESPONSE_LEN as u32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Obfuscation {
+    junk_count: u32,
+    junk_min: u32,
+    junk_max: u32,
+    init_padding: usize,
+    response_padding: usize,
+    /// Values sent in place of message types 1 to 4.
+    types: [u32; 4],
+}
+
+impl Default for Obfuscation {
+    fn default() -> Self {
+        Self {
+            junk_count: 0,
+            junk_min: 0,
+            junk_max: 0,
+            init_padding: 0,
+            response_padding: 0,
+            types: [HANDSHAKE_INIT, HANDSHAKE_RESPONSE, COOKIE_REPLY, TRANSPORT_DATA],
+        }
+    }
+}
+
+impl Obfuscation {
+    /// Parse AmneziaWG `[Interface]` keys, e.g. `Jc = 4, Jmin = 40, Jmax = 70`,
+    /// separated by commas, semicolons or newlines. Missing keys keep
+    /// standard WireGuard behaviour.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut params = Self::default();
+        for pair in spec.split([',', ';', '\n']).map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key = value: {}", pair))?;
+            let (key, value) = (key.trim(), value.trim());
+            let value: u32 = value.parse().map_err(|_| format!("Invalid value for {}: {}", key, value))?;
+            match key.to_ascii_lowercase().as_str() {
+                "jc" => params.junk_count = value,
+                "jmin" => params.junk_min = value,
+                "jmax" => params.junk_max = value,
+                "s1" => params.init_padding = value as usize,
+                "s2" => params.response_padding = value as usize,
+                "h1" => params.types[0] = value,
+                "h2" => params.types[1] = value,
+                "h3" => params.types[2] = value,
+                "h4" => params.types[3] = value,
+                _ => return Err(format!("Unknown obfuscation parameter: {}", key)),
+            }
+        }
+        params.validate()?;
+        Ok(params)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.junk_count > MAX_JUNK_COUNT {
+            return Err(format!("Jc must be at most {}", MAX_JUNK_COUNT));
+        }
+        if self.junk_min > self.junk_max || self.junk_max > MAX_JUNK_LEN {
+            return Err(format!("Jmin must not exceed Jmax, and Jmax must be at most {}", MAX_JUNK_LEN));
+        }
+        if self.init_padding > MAX_INIT_PADDING as usize || self.response_padding > MAX_RESPONSE_PADDING as usize {
+            return Err(format!(
+                "S1 must be at most {} and S2 at most {}",
+                MAX_INIT_PADDING, MAX_RESPONSE_PADDING
+            ));
+        }
+        // Otherwise a padded initiation and response have the same size
+        if self.init_padding + HANDSHAKE_INIT_LEN == self.response_padding + HANDSHAKE_RESPONSE_LEN {
+            return Err("S1 + 56 must differ from S2".into());
+        }
+        let types = self.types;
+        if (0..4).any(|i| types[i + 1..].contains(&types[i])) {
+            return Err("H1-H4 must all differ".into());
+        }
+        Ok(())
+    }
+
+    /// Whether packets go out exactly as standard WireGuard sends them.
+    pub fn is_noop(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Rewrite an outgoing WireGuard message.
+    fn obfuscate(&self, packet: &[u8]) -> Vec<u8> {
+        let Some(kind) = message_type(packet) else {
+            return packet.to_vec();
+        };
+        let padding = match kind {
+            HANDSHAKE_INIT => self.init_padding,
+            HANDSHAKE_RESPONSE => self.response_padding,
+            _ => 0,
+        };
+        let mut out = random_bytes(padding);
+        out.extend_from_slice(&self.types[(kind - 1) as usize].to_le_bytes());
+        out.extend_from_slice(&packet[4..]);
+        out
+    }
+
+    /// Restore an incoming datagram to a WireGuard message, or `None` for junk.
+    fn deobfuscate(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let header_at = |offset: usize| -> Option<u32> {
+            let bytes = datagram.get(offset..offset + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().ok()?))
+        };
+        let (offset, kind) = if datagram.len() == self.init_padding + HANDSHAKE_INIT_LEN
+            && header_at(self.init_padding) == Some(self.types[0])
+        {
+            (self.init_padding, HANDSHAKE_INIT)
+        } else if datagram.len() == self.response_padding + HANDSHAKE_RESPONSE_LEN
+            && header_at(self.response_padding) == Some(self.types[1])
+        {
+            (self.response_padding, HANDSHAKE_RESPONSE)
+        } else if datagram.len() == COOKIE_REPLY_LEN && header_at(0) == Some(self.types[2]) {
+            (0, COOKIE_REPLY)
+        } else if datagram.len() >= TRANSPORT_DATA_MIN_LEN && header_at(0) == Some(self.types[3]) {
+            (0, TRANSPORT_DATA)
+        } else {
+            return None;
+        };
+        let mut message = kind.to_le_bytes().to_vec();
+        message.extend_from_slice(&datagram[offset + 4..]);
+        Some(message)
+    }
+
+    fn junk(&self) -> Vec<Vec<u8>> {
+        let span = self.junk_max - self.junk_min + 1;
+        (0..self.junk_count)
+            .map(|_| random_bytes((self.junk_min + OsRng.next_u32() % span) as usize))
+            .collect()
+    }
+}
+
+/// The message type of a WireGuard message: a type byte and three reserved zeros.
+/// The WireGuard message type of `packet`, if it has a known one.
+pub fn message_type(packet: &[u8]) -> Option<u32> {
+    let kind = u32::from_le_bytes(packet.get(..4)?.try_into().ok()?);
+    (HANDSHAKE_INIT..=TRANSPORT_DATA).contains(&kind).then_some(kind)
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// A transport applying `Obfuscation` to everything it carries.
+pub struct Obfuscated {
+    inner: Box<dyn Transport>,
+    params: Obfuscation,
+}
+
+impl Obfuscated {
+    pub fn new(inner: Box<dyn Transport>, params: Obfuscation) -> Self {
+        Self { inner, params }
+    }
+}
+
+impl Transport for Obfuscated {
+    fn send<'a>(&'a self, packet: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            if message_type(packet) == Some(HANDSHAKE_INIT) {
+                for junk in self.params.junk() {
+                    self.inner.send(&junk).await?;
+                }
+            }
+            self.inner.send(&self.params.obfuscate(packet)).await
+        })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            let mut datagram = vec![0u8; buf.len()];
+            loop {
+                let n = self.inner.recv(&mut datagram).await?;
+                if let Some(message) = self.params.deobfuscate(&datagram[..n]) {
+                    let len = message.len().min(buf.len());
+                    buf[..len].copy_from_slice(&message[..len]);
+                    return Ok(len);
+                }
+            }
+        })
+    }
+
+    fn set_dscp(&self, dscp: Option<u8>) -> io::Result<()> {
+        self.inner.set_dscp(dscp)
+    }
+}
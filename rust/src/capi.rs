@@ -0,0 +1,243 @@
+//! Plain C API for consumers outside the JVM.
+//!
+//! Built with the `c-api` feature and declared in `include/wireguard_tunnel.h`.
+//! The functions share the global state behind the JNI entry points, so a
+//! process may use both. Calls block like their JNI counterparts. Failures
+//! return the negated `TunnelError::code`, or one of the codes in `ffm`, and
+//! leave a message for `wgt_last_error` on the calling thread.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr};
+
+use crate::credential_crypto::CredentialKey;
+use crate::ffm::{self, Failure};
+use crate::{
+    close_tcp, connect_profile, connect_tcp, connect_warp, global, install_tunnel, logging, panic_guard, profile,
+    shutdown_tunnel, tunnel_state, ActiveTunnel, CredentialFile, CredentialSecret, RegistrationOptions, TunnelError,
+    TunnelState,
+};
+
+thread_local! {
+    /// Message of the last failed call on this thread.
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Run an entry point body, turning an error or panic into its code.
+fn call<T: From<i32>>(f: impl FnOnce() -> Result<T, Failure>) -> T {
+    let failure = match panic_guard::run(f) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(failure)) => failure,
+        Err(report) => Failure::panic(report),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = failure.message);
+    T::from(failure.code)
+}
+
+/// Read a NUL-terminated UTF-8 argument; `None` if `ptr` is null.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a NUL-terminated string.
+unsafe fn optional_str(ptr: *const c_char, name: &str) -> Result<Option<String>, Failure> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map(|s| Some(s.to_owned()))
+        .map_err(|_| Failure::invalid(format!("{} is not valid UTF-8", name)))
+}
+
+/// # Safety
+///
+/// As for `optional_str`.
+unsafe fn required_str(ptr: *const c_char, name: &str) -> Result<String, Failure> {
+    optional_str(ptr, name)?.ok_or_else(|| Failure::invalid(format!("{} is null", name)))
+}
+
+/// Install the tunnel `result` holds, like `activate_tunnel` does for Java.
+fn activate(result: Result<ActiveTunnel, TunnelError>, failover: bool) -> Result<i32, Failure> {
+    match result {
+        Ok(active_tunnel) => {
+            install_tunnel(active_tunnel, failover);
+            Ok(TunnelState::Ready as i32)
+        }
+        Err(e) => {
+            log::error!("Failed to start tunnel: {}", e);
+            let message = format!("Failed to start tunnel: {}", e);
+            global().record_tunnel_error(&e, message.clone());
+            Err(Failure::tunnel(&e, message))
+        }
+    }
+}
+
+/// Initialize logging and the runtime. Later calls are no-ops.
+#[no_mangle]
+pub extern "C" fn wgt_init() -> i32 {
+    call(|| {
+        logging::init();
+        let _ = global();
+        log::info!("WireGuard Tunnel C API initialized");
+        Ok(0)
+    })
+}
+
+/// Encrypt persisted credentials with a passphrase, or stop if it is null.
+///
+/// # Safety
+///
+/// `passphrase` must be null or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_set_credential_passphrase(passphrase: *const c_char) -> i32 {
+    call(|| {
+        let passphrase = optional_str(passphrase, "passphrase")?.filter(|p| !p.is_empty());
+        *global().credential_secret.write() = passphrase.map(CredentialSecret::Passphrase);
+        Ok(0)
+    })
+}
+
+/// Start the WARP tunnel, registering a device on first use.
+///
+/// # Safety
+///
+/// `cred_path` must point to a NUL-terminated string; `license_key` may also be null.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_tunnel_start_warp(cred_path: *const c_char, license_key: *const c_char) -> i32 {
+    call(|| {
+        let cred_path = required_str(cred_path, "cred_path")?;
+        let license_key = optional_str(license_key, "license_key")?.filter(|k| !k.is_empty());
+        if global().tunnel.read().is_some() {
+            log::warn!("Tunnel already running");
+            return Ok(TunnelState::Ready as i32);
+        }
+
+        let key = match global().credential_secret.read().as_ref() {
+            None => None,
+            Some(CredentialSecret::Passphrase(p)) => Some(CredentialKey::Passphrase(p.clone())),
+            Some(CredentialSecret::Provider(_)) => {
+                return Err(Failure::invalid("The credential key provider is only available from Java"))
+            }
+        };
+        let cred_file = CredentialFile { path: cred_path, key };
+        let options = RegistrationOptions {
+            license_key,
+            ..RegistrationOptions::default()
+        };
+        activate(connect_warp(cred_file, options, None), true)
+    })
+}
+
+/// Start a tunnel from WireGuard profile text.
+///
+/// # Safety
+///
+/// `text` must point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_tunnel_import_profile(text: *const c_char) -> i32 {
+    call(|| {
+        let text = required_str(text, "text")?;
+        if global().tunnel.read().is_some() {
+            log::warn!("Tunnel already running");
+            return Ok(TunnelState::Ready as i32);
+        }
+
+        let profile = profile::parse(&text).map_err(|errors| {
+            Failure::invalid(format!(
+                "Invalid WireGuard config: {}",
+                errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+            ))
+        })?;
+        activate(connect_profile(profile, None), false)
+    })
+}
+
+/// Current tunnel state (0=Stopped, 1=Starting, 2=Ready, 3=Failed, 4=Paused).
+#[no_mangle]
+pub extern "C" fn wgt_tunnel_state() -> i32 {
+    call(|| Ok(tunnel_state() as i32))
+}
+
+/// Shut the tunnel down.
+#[no_mangle]
+pub extern "C" fn wgt_tunnel_stop() -> i32 {
+    call(|| {
+        shutdown_tunnel();
+        Ok(0)
+    })
+}
+
+/// Connect to `host:port` via the tunnel with the configured connect policy.
+///
+/// Returns a connection handle (>0).
+///
+/// # Safety
+///
+/// `host` must point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_tcp_connect(host: *const c_char, port: u16, timeout_ms: i64) -> i64 {
+    call(|| {
+        let host = required_str(host, "host")?;
+        let policy = global().options.read().connect_policy;
+        connect_tcp(host, port, timeout_ms, policy)
+            .map_err(|e| Failure::tunnel(&e, format!("Connection failed: {}", e)))
+    })
+}
+
+/// Read up to `len` bytes into `buf`.
+///
+/// Returns the bytes read, 0 at end of stream, or -14 (would block) if the
+/// connection is non-blocking and nothing is buffered.
+///
+/// # Safety
+///
+/// `buf` must be valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_tcp_read(handle: i64, buf: *mut u8, len: usize) -> i64 {
+    call(|| {
+        let len = i64::try_from(len).map_err(|_| Failure::invalid("len is too large"))?;
+        ffm::read(handle, ffm::segment_mut(buf, len)?).map(|n| n as i64)
+    })
+}
+
+/// Write up to `len` bytes from `data`.
+///
+/// Returns the bytes written, or 0 if the connection is non-blocking and the
+/// send buffer is full.
+///
+/// # Safety
+///
+/// `data` must be valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_tcp_write(handle: i64, data: *const u8, len: usize) -> i64 {
+    call(|| {
+        let len = i64::try_from(len).map_err(|_| Failure::invalid("len is too large"))?;
+        ffm::write(handle, ffm::segment(data, len)?).map(|n| n as i64)
+    })
+}
+
+/// Close a connection, lingering as configured.
+#[no_mangle]
+pub extern "C" fn wgt_tcp_close(handle: i64) -> i32 {
+    call(|| close_tcp(handle).map(|()| 0).map_err(|e| Failure::tunnel(&e, e.to_string())))
+}
+
+/// Copy the message of the last failed call on this thread into `buf`.
+///
+/// Like `snprintf`, writes at most `len` bytes including the terminating NUL
+/// and returns the message length, so a larger buffer can be retried.
+///
+/// # Safety
+///
+/// `buf` must be null with `len` 0, or valid for writes of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_last_error(buf: *mut c_char, len: usize) -> usize {
+    LAST_ERROR.with(|last| {
+        let message = last.borrow();
+        if !buf.is_null() && len > 0 {
+            let n = message.len().min(len - 1);
+            std::ptr::copy_nonoverlapping(message.as_ptr().cast::<c_char>(), buf, n);
+            *buf.add(n) = 0;
+        }
+        message.len()
+    })
+}
@@ -0,0 +1,143 @@
+//! pcapng capture of decrypted tunnel traffic.
+//!
+//! Packets are raw IP (LINKTYPE_RAW), marked inbound in each block's flags.
+//! Outbound packets go from the netstack straight to WireGuard and cannot be
+//! captured yet, see UPSTREAM.md.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_RAW: u16 = 101;
+const OPTION_EPB_FLAGS: u16 = 2;
+const OPTION_END: u16 = 0;
+const EPB_FLAGS_INBOUND: u32 = 1;
+
+struct Writer {
+    out: BufWriter<File>,
+    written: u64,
+    /// Stop once the file would grow past this many bytes; 0 for no limit.
+    max_bytes: u64,
+}
+
+impl Writer {
+    fn create(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        let mut writer = Self {
+            out: BufWriter::new(File::create(path)?),
+            written: 0,
+            max_bytes,
+        };
+
+        let mut shb = Vec::with_capacity(16);
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes()); // major version
+        shb.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        shb.extend_from_slice(&(-1i64).to_le_bytes()); // section length unknown
+        writer.write_block(BLOCK_SECTION_HEADER, &shb)?;
+
+        let mut idb = Vec::with_capacity(8);
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        idb.extend_from_slice(&0u32.to_le_bytes()); // no snap length
+        writer.write_block(BLOCK_INTERFACE_DESCRIPTION, &idb)?;
+
+        writer.out.flush()?;
+        Ok(writer)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let padded = body.len().next_multiple_of(4);
+        let total = (padded + 12) as u32;
+        self.out.write_all(&block_type.to_le_bytes())?;
+        self.out.write_all(&total.to_le_bytes())?;
+        self.out.write_all(body)?;
+        self.out.write_all(&[0u8; 3][..padded - body.len()])?;
+        self.out.write_all(&total.to_le_bytes())?;
+        self.written += total as u64;
+        Ok(())
+    }
+
+    /// Append a packet, returning false once the size limit is reached.
+    fn write_packet(&mut self, packet: &[u8]) -> io::Result<bool> {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        let mut epb = Vec::with_capacity(packet.len() + 36);
+        epb.extend_from_slice(&0u32.to_le_bytes()); // interface id
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+        epb.extend_from_slice(packet);
+        epb.resize(epb.len().next_multiple_of(4), 0);
+        epb.extend_from_slice(&OPTION_EPB_FLAGS.to_le_bytes());
+        epb.extend_from_slice(&4u16.to_le_bytes());
+        epb.extend_from_slice(&EPB_FLAGS_INBOUND.to_le_bytes());
+        epb.extend_from_slice(&OPTION_END.to_le_bytes());
+        epb.extend_from_slice(&0u16.to_le_bytes());
+
+        if self.max_bytes > 0 && self.written + epb.len() as u64 + 12 > self.max_bytes {
+            return Ok(false);
+        }
+        self.write_block(BLOCK_ENHANCED_PACKET, &epb)?;
+        Ok(true)
+    }
+}
+
+/// Shared handle to the active capture, if any.
+#[derive(Clone, Default)]
+pub struct PacketCapture {
+    writer: Arc<Mutex<Option<Writer>>>,
+}
+
+impl PacketCapture {
+    /// Start capturing to `path`, replacing any capture in progress.
+    pub fn start(&self, path: &Path, max_bytes: u64) -> io::Result<()> {
+        let writer = Writer::create(path, max_bytes)?;
+        if let Some(mut old) = self.writer.lock().replace(writer) {
+            let _ = old.out.flush();
+        }
+        Ok(())
+    }
+
+    /// Stop capturing, returning false if no capture was running.
+    pub fn stop(&self) -> io::Result<bool> {
+        match self.writer.lock().take() {
+            Some(mut writer) => writer.out.flush().map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Record an inbound packet if a capture is running.
+    ///
+    /// The capture ends by itself when it hits its size limit or a write fails.
+    pub fn record_inbound(&self, packet: &[u8]) {
+        let mut guard = self.writer.lock();
+        let Some(writer) = guard.as_mut() else {
+            return;
+        };
+
+        match writer.write_packet(packet) {
+            Ok(true) => {}
+            Ok(false) => {
+                log::info!("Packet capture reached its size limit, stopping");
+                let _ = writer.out.flush();
+                *guard = None;
+            }
+            Err(e) => {
+                log::warn!("Packet capture write failed, stopping: {}", e);
+                *guard = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Cache of the last WireGuard config fetched from the WARP API.
+//!
+//! Starting from a cached config skips a network round trip on startup and
+//! keeps the tunnel starting while the API is briefly unreachable. The cache
+//! sits next to the credentials file, or in the directory set with
+//! `setCacheDirectory`, and never holds the private key, which comes from the
+//! credentials when the config is rebuilt.
+
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use warp_wireguard_gen::WarpCredentials;
+use wireguard_netstack::WireGuardConfig;
+
+use crate::TunnelError;
+
+/// How long a cached config is used without a successful refresh.
+pub const CONFIG_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Serialize, Deserialize)]
+struct CachedConfig {
+    /// Device the config was fetched for.
+    device_id: String,
+    /// Unix time of the fetch, in seconds.
+    fetched_at: u64,
+    peer_public_key: String,
+    peer_endpoint: SocketAddr,
+    tunnel_ip: Ipv4Addr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preshared_key: Option<String>,
+}
+
+/// A config loaded from the cache.
+pub struct Cached {
+    pub config: WireGuardConfig,
+    pub age: Duration,
+}
+
+/// Cache file belonging to the credentials at `cred_path`, in `cache_dir` if set.
+pub fn path_for(cred_path: &str, cache_dir: Option<&Path>) -> PathBuf {
+    let path = PathBuf::from(format!("{}.config", cred_path));
+    match (cache_dir, path.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => path,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn decode_key(key: &str) -> Option<[u8; 32]> {
+    STANDARD.decode(key).ok()?.try_into().ok()
+}
+
+/// Load the cached config for `credentials`, whatever its age.
+///
+/// Returns `None` if there is none, it is unreadable, or it belongs to another device.
+pub fn load(path: &Path, credentials: &WarpCredentials) -> Option<Cached> {
+    let content = fs::read_to_string(path).ok()?;
+    let cached: CachedConfig = match serde_json::from_str(&content) {
+        Ok(cached) => cached,
+        Err(e) => {
+            log::warn!("Ignoring unreadable WireGuard config cache {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    if cached.device_id != credentials.device_id {
+        return None;
+    }
+
+    let preshared_key = match &cached.preshared_key {
+        Some(key) => Some(decode_key(key)?),
+        None => None,
+    };
+    Some(Cached {
+        config: WireGuardConfig {
+            private_key: credentials.private_key,
+            peer_public_key: decode_key(&cached.peer_public_key)?,
+            peer_endpoint: cached.peer_endpoint,
+            tunnel_ip: cached.tunnel_ip,
+            preshared_key,
+            keepalive_seconds: None,
+            mtu: None,
+        },
+        age: Duration::from_secs(now().saturating_sub(cached.fetched_at)),
+    })
+}
+
+/// Store `config`, just fetched for `credentials`.
+pub fn save(path: &Path, credentials: &WarpCredentials, config: &WireGuardConfig) -> Result<(), TunnelError> {
+    let cached = CachedConfig {
+        device_id: credentials.device_id.clone(),
+        fetched_at: now(),
+        peer_public_key: STANDARD.encode(config.peer_public_key),
+        peer_endpoint: config.peer_endpoint,
+        tunnel_ip: config.tunnel_ip,
+        preshared_key: config.preshared_key.map(|key| STANDARD.encode(key)),
+    };
+    let content = serde_json::to_string_pretty(&cached)
+        .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to serialize config: {}", e)))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to create cache dir: {}", e)))?;
+    }
+    fs::write(path, content)
+        .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to write config cache: {}", e)))
+}
+
+/// Remove the cache, e.g. along with the credentials it belongs to.
+pub fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log::warn!("Failed to remove WireGuard config cache {}: {}", path.display(), e);
+        }
+    }
+}
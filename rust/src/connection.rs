@@ -0,0 +1,1079 @@
+//! Connections behind a JNI handle.
+//!
+//! A handle is normally a TCP socket on the tunnel netstack, but policies
+//! such as direct-connect fallback can also put a plain OS socket behind
+//! one, and `tlsConnect` a TLS session over a netstack socket. All are
+//! driven through the same read/write/close calls.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest, ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use smoltcp::socket::tcp::State as TcpState;
+use tokio::sync::{Mutex, Notify};
+use tokio_rustls::client::TlsStream;
+use wireguard_netstack::TcpConnection;
+
+use crate::ratelimit::RateLimit;
+use crate::read_ahead::ReadAhead;
+use crate::stream::TunnelStream;
+use crate::write_behind::WriteBehind;
+use crate::TunnelError;
+
+/// Coalesced writes are sent early once this many bytes are buffered, a
+/// little under one TCP segment at the default tunnel MTU.
+const COALESCE_LIMIT: usize = 1200;
+
+/// Largest single transport read made to fill the read-ahead buffer.
+const READ_AHEAD_CHUNK: usize = 16 * 1024;
+
+/// Largest single transport write made from the write-behind queue.
+const WRITE_BEHIND_CHUNK: usize = 16 * 1024;
+
+/// Readiness bits, matching `java.nio.channels.SelectionKey`.
+pub const READY_READ: i32 = 1;
+pub const READY_WRITE: i32 = 4;
+
+/// How often `wait_writable` checks a connection without write-behind.
+const WRITABLE_CHECK_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How often `drain` checks whether the peer has acknowledged our data.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A plain TCP connection outside the tunnel.
+///
+/// The halves are locked separately so a blocked read does not hold up writes.
+pub struct DirectConnection {
+    reader: Mutex<OwnedReadHalf>,
+    writer: Mutex<OwnedWriteHalf>,
+    /// Second handle to the socket, for setting options while a read is blocked.
+    socket: Option<socket2::Socket>,
+}
+
+/// A TLS session over a tunnel TCP connection.
+///
+/// Split like `DirectConnection`, so a blocked read does not hold up writes.
+pub struct TlsConnection {
+    /// The underlying netstack socket, for its state.
+    conn: Arc<TcpConnection>,
+    reader: Mutex<TlsReader>,
+    writer: Mutex<WriteHalf<TlsStream<TunnelStream>>>,
+    /// ALPN protocol the server picked.
+    alpn: Option<Vec<u8>>,
+}
+
+struct TlsReader {
+    stream: ReadHalf<TlsStream<TunnelStream>>,
+    /// Plaintext read by a readiness check and not yet returned; empty at EOF.
+    peeked: Option<Vec<u8>>,
+}
+
+enum Transport {
+    Tunnel(TcpConnection),
+    Direct(DirectConnection),
+    Tls(TlsConnection),
+}
+
+/// How the peer ended a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum PeerClose {
+    /// Orderly close; the peer sent FIN.
+    Fin = 0,
+    /// The peer reset the connection.
+    Reset = 1,
+}
+
+/// Why the last read or write on a connection failed or hit EOF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ErrorCode {
+    None = 0,
+    /// The peer closed the connection in an orderly way.
+    Eof = 1,
+    /// The peer reset the connection.
+    Reset = 2,
+    Timeout = 3,
+    /// The tunnel the connection runs through is stopped or paused.
+    TunnelDown = 4,
+    /// The send buffer stayed full until the write timed out.
+    BufferFull = 5,
+    Other = 6,
+    /// A read was cut short because the tunnel is re-handshaking, e.g. after
+    /// the machine slept. The connection may still work, so retry.
+    Reconnecting = 7,
+}
+
+impl ErrorCode {
+    fn from_io(e: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => Self::Reset,
+            ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => Self::Eof,
+            ErrorKind::TimedOut => Self::Timeout,
+            ErrorKind::WouldBlock => Self::BufferFull,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Byte counters for one connection.
+pub struct ConnectionStats {
+    pub opened: Instant,
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    /// Milliseconds after `opened` of the last completed read or write.
+    last_active_ms: AtomicU64,
+}
+
+impl ConnectionStats {
+    fn new() -> Self {
+        Self {
+            opened: Instant::now(),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            last_active_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let ms = self.opened.elapsed().as_millis() as u64;
+        self.last_active_ms.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    /// Time since the last completed read or write, or since opening.
+    pub fn idle_time(&self) -> Duration {
+        let last = Duration::from_millis(self.last_active_ms.load(Ordering::Relaxed));
+        self.opened.elapsed().saturating_sub(last)
+    }
+}
+
+/// Kernel TCP statistics of a direct connection, from TCP_INFO.
+pub struct KernelTcpInfo {
+    /// Smoothed round-trip time and its variance.
+    pub rtt: Duration,
+    pub rtt_var: Duration,
+    /// Times the oldest unacknowledged segment was retransmitted.
+    pub retransmits: u32,
+    pub total_retransmits: u32,
+    /// Congestion window, in segments.
+    pub cwnd: u32,
+    pub mss: u32,
+}
+
+/// When each phase of opening a connection happened, for diagnosing slow
+/// connects. Phases that did not happen, e.g. DNS for an IP literal, are `None`.
+#[derive(Clone, Copy)]
+pub struct ConnectTrace {
+    pub started: Instant,
+    pub dns_start: Option<Instant>,
+    pub dns_end: Option<Instant>,
+    /// When the connect was started; the netstack sends the SYN right away.
+    pub syn_sent: Option<Instant>,
+    /// When the handshake completed, i.e. the SYN-ACK arrived.
+    pub established: Option<Instant>,
+    /// A pre-warmed spare was used, so there was no handshake.
+    pub pooled: bool,
+}
+
+impl ConnectTrace {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            dns_start: None,
+            dns_end: None,
+            syn_sent: None,
+            established: None,
+            pooled: false,
+        }
+    }
+}
+
+pub struct Connection {
+    transport: Transport,
+    remote: SocketAddr,
+    /// Local address, when known; the netstack does not report the port it picks.
+    local: Option<SocketAddr>,
+    stats: ConnectionStats,
+    limit: RateLimit,
+    /// Limit shared with other connections, e.g. the global tunnel cap.
+    shared_limit: Option<Arc<RateLimit>>,
+    /// Whether small writes are held back in `pending`; shared so coalescing
+    /// can be switched for all connections at once.
+    coalesce: Option<Arc<AtomicBool>>,
+    /// Opts this connection out of coalescing, like TCP_NODELAY.
+    no_delay: AtomicBool,
+    /// Reads and writes fail with `WouldBlock` instead of waiting.
+    non_blocking: AtomicBool,
+    /// Writes alone accept what fits instead of waiting; reads still wait.
+    partial_writes: AtomicBool,
+    /// Log each read and write on this connection, see `is_traced`.
+    trace: AtomicBool,
+    /// Data read ahead of Java by a background task, when enabled.
+    read_ahead: Option<ReadAhead>,
+    /// Writes queued for a background task to send, when enabled.
+    write_behind: Option<WriteBehind>,
+    /// Buffer for reads that cannot go straight into Java's array, reused so
+    /// steady traffic does not allocate.
+    read_buf: parking_lot::Mutex<Vec<u8>>,
+    /// Coalesced writes not yet sent. Also serializes writes with flushes.
+    pending: Mutex<Vec<u8>>,
+    /// Message of a failed send of `pending`, reported by every later write,
+    /// flush and close since the bytes were already accepted.
+    pending_failed: parking_lot::Mutex<Option<String>>,
+    /// Set once we have sent FIN, so a later close is not taken for a reset.
+    shut_down: AtomicBool,
+    /// Close seen by a direct read, waiting to be reported.
+    peer_closed: parking_lot::Mutex<Option<PeerClose>>,
+    /// Set once the peer's close has been reported.
+    close_reported: AtomicBool,
+    /// `ErrorCode` of the last failed read or write.
+    last_error: AtomicI32,
+    /// Message of the last failure reported to Java, cleared with a new code.
+    last_error_message: parking_lot::Mutex<Option<String>>,
+    connect_trace: Option<ConnectTrace>,
+    /// When the first data arrived from the peer.
+    first_byte: std::sync::OnceLock<Instant>,
+    /// Signalled when the tunnel reconnects, failing reads waiting on it.
+    reconnect: Option<Arc<Notify>>,
+}
+
+impl Connection {
+    pub fn tunnel(conn: TcpConnection, remote: SocketAddr) -> Self {
+        Self::from_transport(Transport::Tunnel(conn), remote, None)
+    }
+
+    /// Wrap a TLS session established over `conn`.
+    pub fn tls(conn: Arc<TcpConnection>, stream: TlsStream<TunnelStream>, remote: SocketAddr) -> Self {
+        let alpn = stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+        let (reader, writer) = tokio::io::split(stream);
+        let tls = TlsConnection {
+            conn,
+            reader: Mutex::new(TlsReader { stream: reader, peeked: None }),
+            writer: Mutex::new(writer),
+            alpn,
+        };
+        Self::from_transport(Transport::Tls(tls), remote, None)
+    }
+
+    fn from_transport(transport: Transport, remote: SocketAddr, local: Option<SocketAddr>) -> Self {
+        Self {
+            transport,
+            remote,
+            local,
+            stats: ConnectionStats::new(),
+            limit: RateLimit::default(),
+            shared_limit: None,
+            coalesce: None,
+            no_delay: AtomicBool::new(false),
+            non_blocking: AtomicBool::new(false),
+            partial_writes: AtomicBool::new(false),
+            trace: AtomicBool::new(false),
+            read_ahead: None,
+            write_behind: None,
+            read_buf: parking_lot::Mutex::new(Vec::new()),
+            pending: Mutex::new(Vec::new()),
+            pending_failed: parking_lot::Mutex::new(None),
+            shut_down: AtomicBool::new(false),
+            peer_closed: parking_lot::Mutex::new(None),
+            close_reported: AtomicBool::new(false),
+            last_error: AtomicI32::new(ErrorCode::None as i32),
+            last_error_message: parking_lot::Mutex::new(None),
+            connect_trace: None,
+            first_byte: std::sync::OnceLock::new(),
+            reconnect: None,
+        }
+    }
+
+    pub fn set_connect_trace(&mut self, trace: ConnectTrace) {
+        self.connect_trace = Some(trace);
+    }
+
+    /// How the connection was opened, and when its first data arrived.
+    pub fn connect_trace(&self) -> Option<(ConnectTrace, Option<Instant>)> {
+        self.connect_trace.map(|trace| (trace, self.first_byte.get().copied()))
+    }
+
+    /// Also apply `limit`, which may be shared with other connections.
+    pub fn set_shared_limit(&mut self, limit: Arc<RateLimit>) {
+        self.shared_limit = Some(limit);
+    }
+
+    /// Fail reads pending when `signal` is notified with `Reconnecting`.
+    pub fn set_reconnect_signal(&mut self, signal: Arc<Notify>) {
+        self.reconnect = Some(signal);
+    }
+
+    /// Hold back small writes while `enabled` is set, until the next flush.
+    pub fn set_coalescing(&mut self, enabled: Arc<AtomicBool>) {
+        self.coalesce = Some(enabled);
+    }
+
+    /// Send every write immediately, even while coalescing is enabled.
+    pub fn set_no_delay(&self, no_delay: bool) {
+        self.no_delay.store(no_delay, Ordering::Relaxed);
+    }
+
+    pub fn set_non_blocking(&self, non_blocking: bool) {
+        self.non_blocking.store(non_blocking, Ordering::Relaxed);
+    }
+
+    fn is_non_blocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Relaxed)
+    }
+
+    /// Let writes accept only what fits, like a non-blocking write, while
+    /// reads keep waiting for data.
+    pub fn set_partial_writes(&self, partial: bool) {
+        self.partial_writes.store(partial, Ordering::Relaxed);
+    }
+
+    pub fn set_trace(&self, trace: bool) {
+        self.trace.store(trace, Ordering::Relaxed);
+    }
+
+    /// Whether reads and writes on this connection are logged.
+    ///
+    /// Only traced connections log per call, and only at debug level, so the
+    /// hot path costs an untraced connection one load rather than a pass
+    /// through the log filter, whatever the filter lets through.
+    pub fn is_traced(&self) -> bool {
+        self.trace.load(Ordering::Relaxed) && log::log_enabled!(log::Level::Debug)
+    }
+
+    fn writes_non_blocking(&self) -> bool {
+        self.is_non_blocking() || self.partial_writes.load(Ordering::Relaxed)
+    }
+
+    /// Buffer up to `capacity` bytes read ahead of Java, once started.
+    pub fn set_read_ahead(&mut self, capacity: usize) {
+        self.read_ahead = Some(ReadAhead::new(capacity));
+    }
+
+    /// Start filling the read-ahead buffer, if there is one.
+    ///
+    /// The task keeps the connection alive until `stop_read_ahead` or the end
+    /// of the stream.
+    pub fn start_read_ahead(self: &Arc<Self>, runtime: &tokio::runtime::Handle) {
+        let Some(read_ahead) = &self.read_ahead else {
+            return;
+        };
+        let conn = self.clone();
+        read_ahead.set_task(runtime.spawn(async move { conn.fill_read_ahead().await }));
+    }
+
+    pub fn stop_read_ahead(&self) {
+        if let Some(read_ahead) = &self.read_ahead {
+            read_ahead.stop();
+        }
+    }
+
+    /// Queue up to `capacity` bytes of writes for a background task, once started.
+    pub fn set_write_behind(&mut self, capacity: usize) {
+        self.write_behind = Some(WriteBehind::new(capacity));
+    }
+
+    /// Start sending queued writes, if writes are queued.
+    ///
+    /// The task keeps the connection alive until `shutdown` has it send what
+    /// is left and exit.
+    pub fn start_write_behind(self: &Arc<Self>, runtime: &tokio::runtime::Handle) {
+        let Some(write_behind) = &self.write_behind else {
+            return;
+        };
+        let conn = self.clone();
+        write_behind.set_task(runtime.spawn(async move { conn.send_write_behind().await }));
+    }
+
+    async fn send_write_behind(&self) {
+        let Some(write_behind) = &self.write_behind else {
+            return;
+        };
+        while let Some(chunk) = write_behind.take(WRITE_BEHIND_CHUNK).await {
+            if !write_behind.sent(self.write_now(&chunk, false).await.map(|_| ())) {
+                break;
+            }
+        }
+        if write_behind.finish() {
+            write_behind.set_closed(self.close_sending().await);
+        }
+    }
+
+    async fn fill_read_ahead(&self) {
+        let Some(read_ahead) = &self.read_ahead else {
+            return;
+        };
+        let mut chunk = vec![0u8; READ_AHEAD_CHUNK];
+        loop {
+            read_ahead.wait_for_space().await;
+            let result = self.read_transport(&mut chunk, false).await;
+            if !read_ahead.push(result.map(|n| &chunk[..n])) {
+                return;
+            }
+        }
+    }
+
+    /// Which of `READY_READ` and `READY_WRITE` would not block right now.
+    ///
+    /// EOF and errors count as ready, so the next call reports them. A direct
+    /// connection with a read or write in progress is not ready that way.
+    pub async fn readiness(&self) -> i32 {
+        let (mut readable, mut writable) = match &self.transport {
+            Transport::Tunnel(conn) => {
+                let ns = &conn.netstack;
+                (
+                    ns.can_recv(conn.handle) || !ns.may_recv(conn.handle),
+                    ns.can_send(conn.handle) || !ns.may_send(conn.handle),
+                )
+            }
+            Transport::Direct(conn) => {
+                let readable = match conn.reader.try_lock() {
+                    Ok(reader) => poll_ready(reader.ready(Interest::READABLE)).await,
+                    Err(_) => false,
+                };
+                let writable = match conn.writer.try_lock() {
+                    Ok(writer) => poll_ready(writer.ready(Interest::WRITABLE)).await,
+                    Err(_) => false,
+                };
+                (readable, writable)
+            }
+            Transport::Tls(tls) => {
+                // Records already received may hold plaintext, so try a read
+                let readable = match tls.reader.try_lock() {
+                    Ok(mut reader) => reader.peeked.is_some() || peek_tls(&mut reader).await,
+                    Err(_) => false,
+                };
+                let ns = &tls.conn.netstack;
+                let handle = tls.conn.handle;
+                let writable = tls.writer.try_lock().is_ok() && (ns.can_send(handle) || !ns.may_send(handle));
+                (readable, writable)
+            }
+        };
+        if let Some(read_ahead) = &self.read_ahead {
+            readable = read_ahead.is_readable();
+        }
+        if let Some(write_behind) = &self.write_behind {
+            writable = write_behind.is_writable();
+        }
+        let mut ready = 0;
+        if readable {
+            ready |= READY_READ;
+        }
+        if writable {
+            ready |= READY_WRITE;
+        }
+        ready
+    }
+
+    /// Wait until a write would accept some data without waiting.
+    ///
+    /// Queued writes wake this as soon as they are sent; the netstack has no
+    /// wakeups of its own, so otherwise readiness is rechecked periodically.
+    pub async fn wait_writable(&self) {
+        loop {
+            let drained = self.write_behind.as_ref().map(WriteBehind::drained);
+            if self.readiness().await & READY_WRITE != 0 {
+                return;
+            }
+            match drained {
+                Some(drained) => drained.await,
+                None => tokio::time::sleep(WRITABLE_CHECK_INTERVAL).await,
+            }
+        }
+    }
+
+    fn coalescing(&self) -> bool {
+        !self.no_delay.load(Ordering::Relaxed) && self.coalesce.as_ref().is_some_and(|c| c.load(Ordering::Relaxed))
+    }
+
+    pub fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    /// Open a direct connection outside the tunnel.
+    pub async fn connect_direct(addr: SocketAddr, timeout_ms: i64) -> Result<Self, TunnelError> {
+        let connect = TcpStream::connect(addr);
+        let stream = if timeout_ms > 0 {
+            tokio::time::timeout(Duration::from_millis(timeout_ms as u64), connect)
+                .await
+                .map_err(|_| TunnelError::Timeout)?
+        } else {
+            connect.await
+        }
+        .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+
+        let _ = stream.set_nodelay(true);
+        let local = stream.local_addr().ok();
+        let socket = socket2::SockRef::from(&stream).try_clone().ok();
+        let (reader, writer) = stream.into_split();
+        Ok(Self::from_transport(
+            Transport::Direct(DirectConnection {
+                reader: Mutex::new(reader),
+                writer: Mutex::new(writer),
+                socket,
+            }),
+            addr,
+            local,
+        ))
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Local address; `None` for tunnel connections, whose port is not known.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local
+    }
+
+    pub fn is_tunneled(&self) -> bool {
+        matches!(self.transport, Transport::Tunnel(_) | Transport::Tls(_))
+    }
+
+    /// ALPN protocol negotiated by a TLS connection, if any.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match &self.transport {
+            Transport::Tls(tls) => tls.alpn.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether the connection can still be used in both directions.
+    ///
+    /// Direct sockets report no state without I/O, so they are assumed open.
+    pub fn is_open(&self) -> bool {
+        match &self.transport {
+            Transport::Tunnel(conn) => conn.netstack.may_send(conn.handle) && conn.netstack.may_recv(conn.handle),
+            Transport::Tls(tls) => {
+                let conn = &tls.conn;
+                conn.netstack.may_send(conn.handle) && conn.netstack.may_recv(conn.handle)
+            }
+            Transport::Direct(_) => true,
+        }
+    }
+
+    /// Kernel TCP statistics of a direct connection (Linux only).
+    ///
+    /// smoltcp keeps its RTT estimate and congestion state private, and the
+    /// netstack does not expose its sockets anyway, so tunnel connections
+    /// return `None`; see UPSTREAM.md.
+    #[cfg(target_os = "linux")]
+    pub fn kernel_tcp_info(&self) -> Option<KernelTcpInfo> {
+        use std::os::fd::AsRawFd;
+
+        let Transport::Direct(conn) = &self.transport else {
+            return None;
+        };
+        let socket = conn.socket.as_ref()?;
+        // SAFETY: tcp_info is plain data, so all zeroes is a valid value
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        // SAFETY: `info` is valid for `len` bytes, which the kernel does not exceed
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                (&mut info as *mut libc::tcp_info).cast(),
+                &mut len,
+            )
+        };
+        if result != 0 {
+            return None;
+        }
+        Some(KernelTcpInfo {
+            rtt: Duration::from_micros(info.tcpi_rtt.into()),
+            rtt_var: Duration::from_micros(info.tcpi_rttvar.into()),
+            retransmits: info.tcpi_retransmits.into(),
+            total_retransmits: info.tcpi_total_retrans,
+            cwnd: info.tcpi_snd_cwnd,
+            mss: info.tcpi_snd_mss,
+        })
+    }
+
+    /// Kernel TCP statistics; only available on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub fn kernel_tcp_info(&self) -> Option<KernelTcpInfo> {
+        None
+    }
+
+    /// Enable TCP keepalive probes after `idle` without traffic, repeated
+    /// every `interval`, or disable them with `None`.
+    ///
+    /// Returns false for tunnel connections, whose netstack sockets do not
+    /// expose the option.
+    pub fn set_keepalive(&self, idle: Option<Duration>, interval: Duration) -> Result<bool, TunnelError> {
+        let socket = match &self.transport {
+            Transport::Tunnel(_) | Transport::Tls(_) => return Ok(false),
+            Transport::Direct(conn) => conn.socket.as_ref(),
+        };
+        let socket = socket.ok_or_else(|| TunnelError::ConnectionFailed("Socket options unavailable".into()))?;
+        match idle {
+            Some(idle) => {
+                let params = socket2::TcpKeepalive::new().with_time(idle).with_interval(interval);
+                socket.set_tcp_keepalive(&params)?;
+            }
+            None => socket.set_keepalive(false)?,
+        }
+        Ok(true)
+    }
+
+    /// TCP state of a tunnel connection; `None` for direct ones.
+    pub fn socket_state(&self) -> Option<TcpState> {
+        match &self.transport {
+            Transport::Tunnel(conn) => Some(conn.netstack.socket_state(conn.handle)),
+            Transport::Tls(tls) => Some(tls.conn.netstack.socket_state(tls.conn.handle)),
+            Transport::Direct(_) => None,
+        }
+    }
+
+    /// How the peer closed the connection, reported only once.
+    pub fn take_peer_close(&self) -> Option<PeerClose> {
+        let close = match self.socket_state() {
+            Some(TcpState::CloseWait | TcpState::LastAck | TcpState::Closing | TcpState::TimeWait) => {
+                Some(PeerClose::Fin)
+            }
+            Some(TcpState::Closed) if self.reset_by_peer() => Some(PeerClose::Reset),
+            Some(TcpState::Closed) => Some(PeerClose::Fin),
+            Some(_) => None,
+            None => *self.peer_closed.lock(),
+        }?;
+        (!self.close_reported.swap(true, Ordering::Relaxed)).then_some(close)
+    }
+
+    /// Whether a tunnel connection was reset by the peer.
+    fn reset_by_peer(&self) -> bool {
+        // Reaching CLOSED without having sent FIN means the peer reset it
+        self.socket_state() == Some(TcpState::Closed) && !self.shut_down.load(Ordering::Relaxed)
+    }
+
+    /// `ErrorCode` of the last failed read or write, as an int for Java.
+    pub fn last_error(&self) -> i32 {
+        self.last_error.load(Ordering::Relaxed)
+    }
+
+    pub fn set_last_error(&self, code: ErrorCode) {
+        self.last_error.store(code as i32, Ordering::Relaxed);
+        *self.last_error_message.lock() = None;
+    }
+
+    /// Message of the last failure reported to Java, if any since the code was set.
+    pub fn last_error_message(&self) -> Option<String> {
+        self.last_error_message.lock().clone()
+    }
+
+    pub fn set_last_error_message(&self, message: String) {
+        *self.last_error_message.lock() = Some(message);
+    }
+
+    /// Record a netstack error and convert it.
+    fn netstack_error(&self, e: wireguard_netstack::Error) -> TunnelError {
+        use wireguard_netstack::Error;
+        let code = match e {
+            Error::ReadTimeout => ErrorCode::Timeout,
+            // The netstack waits for buffer space until it gives up
+            Error::WriteTimeout => ErrorCode::BufferFull,
+            Error::ConnectionClosed if self.reset_by_peer() => ErrorCode::Reset,
+            Error::ConnectionClosed => ErrorCode::Eof,
+            _ => ErrorCode::Other,
+        };
+        self.set_last_error(code);
+        TunnelError::ConnectionFailed(e.to_string())
+    }
+
+    /// Record an OS socket error and convert it.
+    fn io_error(&self, e: std::io::Error) -> TunnelError {
+        self.set_last_error(ErrorCode::from_io(&e));
+        e.into()
+    }
+
+    fn record_peer_close(&self, close: PeerClose) {
+        self.peer_closed.lock().get_or_insert(close);
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Take the connection's read buffer, resized to `len`.
+    ///
+    /// Give it back with `put_read_buf`; a read made meanwhile gets a new one.
+    pub fn take_read_buf(&self, len: usize) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut *self.read_buf.lock());
+        buf.resize(len, 0);
+        buf
+    }
+
+    pub fn put_read_buf(&self, buf: Vec<u8>) {
+        *self.read_buf.lock() = buf;
+    }
+
+    /// Read into `buf`, returning 0 on EOF.
+    ///
+    /// A non-blocking connection with nothing to read fails with `WouldBlock`,
+    /// and a read pending when the tunnel reconnects fails with `Reconnecting`.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, TunnelError> {
+        let read = async {
+            match &self.read_ahead {
+                Some(read_ahead) => read_ahead.read(buf, self.is_non_blocking()).await,
+                None => self.read_transport(buf, self.is_non_blocking()).await,
+            }
+        };
+        // Both reads are cancel-safe, so nothing is lost when one is cut short
+        let n = match &self.reconnect {
+            Some(reconnect) => tokio::select! {
+                n = read => n?,
+                _ = reconnect.notified() => {
+                    self.set_last_error(ErrorCode::Reconnecting);
+                    return Err(TunnelError::Reconnecting);
+                }
+            },
+            None => read.await?,
+        };
+        self.stats.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+        self.stats.touch();
+
+        // Charged after the fact, so the delay holds back the next read
+        self.limit.on_read(n).await;
+        if let Some(shared) = &self.shared_limit {
+            shared.on_read(n).await;
+        }
+        Ok(n)
+    }
+
+    async fn read_transport(&self, buf: &mut [u8], non_blocking: bool) -> Result<usize, TunnelError> {
+        let n = match &self.transport {
+            Transport::Tunnel(conn)
+                if non_blocking && !conn.netstack.can_recv(conn.handle) && conn.netstack.may_recv(conn.handle) =>
+            {
+                return Err(TunnelError::WouldBlock);
+            }
+            Transport::Tunnel(conn) => match conn.read(buf).await {
+                // The netstack reports a reset as EOF too
+                Ok(0) if self.reset_by_peer() => {
+                    self.set_last_error(ErrorCode::Reset);
+                    0
+                }
+                Ok(0) => {
+                    self.set_last_error(ErrorCode::Eof);
+                    0
+                }
+                Ok(n) => n,
+                Err(e) => return Err(self.netstack_error(e)),
+            },
+            Transport::Direct(conn) => match read_direct(conn, buf, non_blocking).await {
+                Ok(0) => {
+                    self.record_peer_close(PeerClose::Fin);
+                    self.set_last_error(ErrorCode::Eof);
+                    0
+                }
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Err(TunnelError::WouldBlock),
+                Err(e) => {
+                    if e.kind() == std::io::ErrorKind::ConnectionReset {
+                        self.record_peer_close(PeerClose::Reset);
+                    }
+                    return Err(self.io_error(e));
+                }
+            },
+            Transport::Tls(tls) => match read_tls(tls, buf, non_blocking).await {
+                Ok(0) => {
+                    self.set_last_error(if self.reset_by_peer() { ErrorCode::Reset } else { ErrorCode::Eof });
+                    0
+                }
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Err(TunnelError::WouldBlock),
+                Err(e) => return Err(self.io_error(e)),
+            },
+        };
+        if n > 0 {
+            self.first_byte.get_or_init(Instant::now);
+        }
+        Ok(n)
+    }
+
+    /// Write all of `data`, returning the number of bytes accepted.
+    ///
+    /// While coalescing, small writes are only buffered and go out with the
+    /// next flush or once enough has accumulated. A non-blocking connection
+    /// accepts only what fits in the send buffer, failing with `WouldBlock`
+    /// if that is nothing; held-back writes are still sent in full first.
+    ///
+    /// With write-behind, the data is only queued, and the same applies to the
+    /// room left in the queue instead of the send buffer. A failed send is
+    /// reported by the next write, flush or close.
+    pub async fn write(&self, data: &[u8]) -> Result<usize, TunnelError> {
+        match &self.write_behind {
+            // After shutdown the transport reports the failure itself
+            Some(write_behind) if !self.shut_down.load(Ordering::Relaxed) => {
+                self.stats.touch();
+                write_behind.push(data, self.writes_non_blocking()).await
+            }
+            _ => self.write_now(data, self.writes_non_blocking()).await,
+        }
+    }
+
+    async fn write_now(&self, data: &[u8], non_blocking: bool) -> Result<usize, TunnelError> {
+        self.stats.touch();
+        let mut pending = self.pending.lock().await;
+        if let Some(e) = self.pending_failure() {
+            return Err(e);
+        }
+        if self.coalescing() {
+            pending.extend_from_slice(data);
+            if pending.len() >= COALESCE_LIMIT {
+                self.send_pending(&mut pending).await?;
+            }
+            return Ok(data.len());
+        }
+
+        // Coalescing may have just been switched off
+        self.send_pending(&mut pending).await?;
+        self.send(data, non_blocking).await
+    }
+
+    fn pending_failure(&self) -> Option<TunnelError> {
+        self.pending_failed.lock().as_ref().map(|message| TunnelError::ConnectionFailed(message.clone()))
+    }
+
+    /// Send coalesced writes, keeping them if that fails.
+    ///
+    /// The failure sticks, so nothing written later goes out ahead of them.
+    async fn send_pending(&self, pending: &mut Vec<u8>) -> Result<(), TunnelError> {
+        if let Some(e) = self.pending_failure() {
+            return Err(e);
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+        match self.send(pending, false).await {
+            Ok(_) => {
+                pending.clear();
+                Ok(())
+            }
+            Err(e) => {
+                *self.pending_failed.lock() = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    async fn send(&self, data: &[u8], non_blocking: bool) -> Result<usize, TunnelError> {
+        self.limit.on_write(data.len()).await;
+        if let Some(shared) = &self.shared_limit {
+            shared.on_write(data.len()).await;
+        }
+
+        let n = match &self.transport {
+            Transport::Tunnel(conn) if non_blocking => {
+                let ns = &conn.netstack;
+                if !ns.may_send(conn.handle) {
+                    return Err(self.netstack_error(wireguard_netstack::Error::ConnectionClosed));
+                }
+                let n = ns.send(conn.handle, data).map_err(|e| self.netstack_error(e))?;
+                if n == 0 {
+                    return Err(TunnelError::WouldBlock);
+                }
+                ns.poll();
+                n
+            }
+            Transport::Direct(conn) if non_blocking => match conn.writer.lock().await.try_write(data) {
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Err(TunnelError::WouldBlock),
+                Err(e) => return Err(self.io_error(e)),
+            },
+            // TcpConnection::write polls the netstack itself once the data is
+            // queued; whatever the window holds back is sent by the tunnel's
+            // driver task as ACKs arrive
+            Transport::Tunnel(conn) => conn.write(data).await.map_err(|e| self.netstack_error(e))?,
+            Transport::Direct(conn) => {
+                conn.writer
+                    .lock()
+                    .await
+                    .write_all(data)
+                    .await
+                    .map_err(|e| self.io_error(e))?;
+                data.len()
+            }
+            Transport::Tls(tls) if non_blocking => {
+                let mut writer = tls.writer.lock().await;
+                match tokio::time::timeout(Duration::ZERO, writer.write(data)).await {
+                    Ok(Ok(n)) => n,
+                    Ok(Err(e)) => return Err(self.io_error(e)),
+                    Err(_) => return Err(TunnelError::WouldBlock),
+                }
+            }
+            Transport::Tls(tls) => {
+                let mut writer = tls.writer.lock().await;
+                writer.write_all(data).await.map_err(|e| self.io_error(e))?;
+                // Push the encrypted records out rather than leave them in rustls
+                writer.flush().await.map_err(|e| self.io_error(e))?;
+                data.len()
+            }
+        };
+        self.stats.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    /// Whether coalesced writes are waiting to be sent.
+    ///
+    /// A connection busy writing reports false, since that write sends them,
+    /// as does one whose coalesced writes failed to send.
+    pub fn has_pending(&self) -> bool {
+        self.pending_failed.lock().is_none() && self.pending.try_lock().is_ok_and(|p| !p.is_empty())
+    }
+
+    /// Send any queued or coalesced writes immediately.
+    pub async fn flush(&self) -> Result<(), TunnelError> {
+        if let Some(write_behind) = &self.write_behind {
+            write_behind.wait_empty().await?;
+        }
+        self.send_pending(&mut *self.pending.lock().await).await?;
+        match &self.transport {
+            // Nothing is buffered on our side; the netstack sends queued data
+            // as soon as the peer's window allows
+            Transport::Tunnel(_) => Ok(()),
+            Transport::Direct(conn) => conn.writer.lock().await.flush().await.map_err(|e| self.io_error(e)),
+            Transport::Tls(tls) => tls.writer.lock().await.flush().await.map_err(|e| self.io_error(e)),
+        }
+    }
+
+    /// Wait up to `timeout` for everything sent before `shutdown` to be
+    /// acknowledged, returning false if some was still in flight.
+    ///
+    /// Only tunnel connections wait; the OS keeps sending for direct ones.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        if !self.is_tunneled() {
+            return true;
+        }
+        // smoltcp leaves FinWait1, Closing and LastAck once our FIN, and so
+        // all data before it, has been acknowledged
+        let drained = || {
+            !matches!(
+                self.socket_state(),
+                Some(TcpState::Established | TcpState::FinWait1 | TcpState::CloseWait | TcpState::Closing | TcpState::LastAck)
+            )
+        };
+        tokio::time::timeout(timeout, async {
+            while !drained() {
+                tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Close the sending side once queued writes are sent; reads continue
+    /// until the peer closes.
+    ///
+    /// Waits up to `timeout` for the write-behind queue; whatever is still
+    /// queued then goes out in the background, followed by FIN. Fails if a
+    /// queued write could not be sent, since the caller was told it was.
+    pub async fn shutdown(&self, timeout: Duration) -> Result<(), TunnelError> {
+        self.shut_down.store(true, Ordering::Relaxed);
+        if let Some(write_behind) = &self.write_behind {
+            if write_behind.close() {
+                return match tokio::time::timeout(timeout, write_behind.wait_closed()).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        log::debug!("Writes still queued after {:?}, sending them in the background", timeout);
+                        Ok(())
+                    }
+                };
+            }
+            if let Some(e) = write_behind.failed() {
+                self.close_sending().await?;
+                return Err(e);
+            }
+        }
+        self.close_sending().await
+    }
+
+    /// Stop sending queued writes, dropping them, for a connection whose
+    /// transport is going away.
+    pub fn stop_write_behind(&self) {
+        if let Some(write_behind) = &self.write_behind {
+            write_behind.stop();
+        }
+    }
+
+    /// Send coalesced writes, then FIN.
+    async fn close_sending(&self) -> Result<(), TunnelError> {
+        let sent = self.send_pending(&mut *self.pending.lock().await).await;
+        match &self.transport {
+            Transport::Tunnel(conn) => conn.shutdown(),
+            Transport::Direct(conn) => {
+                let _ = conn.writer.lock().await.shutdown().await;
+            }
+            // Sends close_notify, then FIN
+            Transport::Tls(tls) => {
+                let _ = tls.writer.lock().await.shutdown().await;
+            }
+        }
+        sent
+    }
+}
+
+/// Whether a readiness future completes without waiting; errors count as ready.
+async fn poll_ready<T>(ready: impl std::future::Future<Output = std::io::Result<T>>) -> bool {
+    tokio::time::timeout(Duration::ZERO, ready).await.is_ok()
+}
+
+async fn read_direct(conn: &DirectConnection, buf: &mut [u8], non_blocking: bool) -> std::io::Result<usize> {
+    let mut reader = conn.reader.lock().await;
+    if non_blocking {
+        reader.try_read(buf)
+    } else {
+        reader.read(buf).await
+    }
+}
+
+/// Read plaintext from a TLS connection, returning anything peeked first.
+async fn read_tls(tls: &TlsConnection, buf: &mut [u8], non_blocking: bool) -> std::io::Result<usize> {
+    let mut reader = tls.reader.lock().await;
+    if let Some(mut peeked) = reader.peeked.take() {
+        let n = peeked.len().min(buf.len());
+        buf[..n].copy_from_slice(&peeked[..n]);
+        if n < peeked.len() {
+            peeked.drain(..n);
+            reader.peeked = Some(peeked);
+        }
+        return Ok(n);
+    }
+    let result = if non_blocking {
+        match tokio::time::timeout(Duration::ZERO, reader.stream.read(buf)).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::ErrorKind::WouldBlock.into()),
+        }
+    } else {
+        reader.stream.read(buf).await
+    };
+    match result {
+        // Many servers close without close_notify; treat it as a plain EOF
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(0),
+        result => result,
+    }
+}
+
+/// Whether a TLS read would not block, keeping any plaintext it returns.
+async fn peek_tls(reader: &mut TlsReader) -> bool {
+    let mut buf = vec![0u8; READ_AHEAD_CHUNK];
+    match tokio::time::timeout(Duration::ZERO, reader.stream.read(&mut buf)).await {
+        Ok(Ok(n)) => {
+            buf.truncate(n);
+            reader.peeked = Some(buf);
+            true
+        }
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            reader.peeked = Some(Vec::new());
+            true
+        }
+        // The error comes back on the next read
+        Ok(Err(_)) => true,
+        Err(_) => false,
+    }
+}
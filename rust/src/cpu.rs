@@ -0,0 +1,36 @@
+//! Which vector extensions this CPU has, for `nativeFeatures` and bug reports.
+//!
+//! The ChaCha20-Poly1305 implementations pick a vector path at runtime, so
+//! one binary per platform covers every CPU: AVX2 where x86 CPUs have it,
+//! SSE2 otherwise, and NEON on aarch64, where it is part of the base
+//! architecture (RustCrypto's ChaCha20 only uses it when built with
+//! `--cfg chacha20_force_neon`, see `.cargo/config.toml`). This reports what
+//! the CPU supports, detected the same way; whether the AEAD gotatun is
+//! built with takes that path cannot be seen from here.
+
+use once_cell::sync::Lazy;
+
+static SIMD_LEVEL: Lazy<&'static str> = Lazy::new(detect);
+
+fn detect() -> &'static str {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if std::arch::is_x86_feature_detected!("avx2") {
+            return "avx2";
+        }
+        if std::arch::is_x86_feature_detected!("sse2") {
+            return "sse2";
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        return "neon";
+    }
+    "none"
+}
+
+/// The widest vector extension this CPU supports, detected once: `avx2`,
+/// `sse2`, `neon` or `none`.
+pub fn simd_level() -> &'static str {
+    *SIMD_LEVEL
+}
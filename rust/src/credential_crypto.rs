@@ -0,0 +1,124 @@
+//! Optional encryption of the persisted WARP credentials.
+//!
+//! An encrypted credential file is a small JSON envelope around the
+//! ChaCha20-Poly1305 ciphertext of the plaintext credentials JSON. The key is
+//! either derived from a passphrase (Argon2id) or supplied directly by a
+//! Java-side keystore callback.
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+
+use crate::TunnelError;
+
+const ENVELOPE_VERSION: u32 = 1;
+const KDF_ARGON2ID: &str = "argon2id";
+const KDF_RAW: &str = "raw";
+const SALT_LEN: usize = 16;
+
+/// Key material used to encrypt the credential file.
+pub enum CredentialKey {
+    /// User passphrase, stretched with Argon2id and a per-file salt.
+    Passphrase(String),
+    /// 32-byte key from the Java keystore callback, used as-is.
+    Raw([u8; 32]),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    kdf: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn locked(msg: impl Into<String>) -> TunnelError {
+    TunnelError::CredentialsLocked(msg.into())
+}
+
+fn derive_key(key: &CredentialKey, salt: &[u8]) -> Result<[u8; 32], TunnelError> {
+    match key {
+        CredentialKey::Raw(raw) => Ok(*raw),
+        CredentialKey::Passphrase(passphrase) => {
+            let mut out = [0u8; 32];
+            Argon2::default()
+                .hash_password_into(passphrase.as_bytes(), salt, &mut out)
+                .map_err(|e| TunnelError::CredentialPersistence(format!("Key derivation failed: {}", e)))?;
+            Ok(out)
+        }
+    }
+}
+
+/// Check whether file content is an encrypted envelope rather than plaintext credentials.
+pub fn is_encrypted(content: &str) -> bool {
+    serde_json::from_str::<Envelope>(content).is_ok()
+}
+
+/// Encrypt plaintext credentials JSON into an envelope.
+pub fn encrypt(key: &CredentialKey, plaintext: &[u8]) -> Result<String, TunnelError> {
+    let (kdf, salt) = match key {
+        CredentialKey::Passphrase(_) => {
+            let mut salt = [0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            (KDF_ARGON2ID, Some(salt))
+        }
+        CredentialKey::Raw(_) => (KDF_RAW, None),
+    };
+
+    let derived = derive_key(key, salt.as_ref().map_or(&[][..], |s| &s[..]))?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&derived));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| TunnelError::CredentialPersistence("Encryption failed".into()))?;
+
+    let envelope = Envelope {
+        version: ENVELOPE_VERSION,
+        kdf: kdf.to_string(),
+        salt: salt.map(|s| STANDARD.encode(s)),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    };
+    serde_json::to_string_pretty(&envelope)
+        .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to serialize: {}", e)))
+}
+
+/// Decrypt an envelope back into plaintext credentials JSON.
+pub fn decrypt(key: Option<&CredentialKey>, content: &str) -> Result<Vec<u8>, TunnelError> {
+    let envelope: Envelope = serde_json::from_str(content)
+        .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to parse: {}", e)))?;
+
+    if envelope.version != ENVELOPE_VERSION {
+        return Err(locked(format!("unsupported envelope version {}", envelope.version)));
+    }
+
+    let key = key.ok_or_else(|| locked("no passphrase or key provider is set"))?;
+    match (envelope.kdf.as_str(), key) {
+        (KDF_ARGON2ID, CredentialKey::Passphrase(_)) | (KDF_RAW, CredentialKey::Raw(_)) => {}
+        (KDF_ARGON2ID, _) => return Err(locked("file was encrypted with a passphrase")),
+        (KDF_RAW, _) => return Err(locked("file was encrypted with a key provider")),
+        (kdf, _) => return Err(locked(format!("unknown key derivation {}", kdf))),
+    }
+
+    let decode = |field: &str, value: &str| {
+        STANDARD
+            .decode(value)
+            .map_err(|e| TunnelError::CredentialPersistence(format!("Invalid {}: {}", field, e)))
+    };
+    let salt = envelope.salt.as_deref().map(|s| decode("salt", s)).transpose()?;
+    let nonce = decode("nonce", &envelope.nonce)?;
+    let ciphertext = decode("ciphertext", &envelope.ciphertext)?;
+    if nonce.len() != 12 {
+        return Err(TunnelError::CredentialPersistence("Invalid nonce length".into()));
+    }
+
+    let derived = derive_key(key, salt.as_deref().unwrap_or_default())?;
+    ChaCha20Poly1305::new(Key::from_slice(&derived))
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| locked("wrong passphrase or key"))
+}
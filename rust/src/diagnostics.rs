@@ -0,0 +1,184 @@
+//! Diagnostics bundle for bug reports.
+//!
+//! `exportDiagnostics` gathers recent logs, the tunnel config without keys,
+//! stats and connect traces into one zip. Entries are stored uncompressed:
+//! the bundle is small, and deflate would need another dependency.
+
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::metrics::{ConnectTimeline, ConnectionInfo};
+use crate::transport::TransportConfig;
+
+const LOCAL_HEADER: u32 = 0x0403_4B50;
+const CENTRAL_HEADER: u32 = 0x0201_4B50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4B50;
+/// Version 2.0, the minimum for stored entries in directories.
+const VERSION: u16 = 20;
+/// General purpose flag bit 11: names are UTF-8.
+const FLAG_UTF8: u16 = 0x0800;
+const METHOD_STORED: u16 = 0;
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data
+        .iter()
+        .fold(!0u32, |crc, b| CRC_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+/// MS-DOS time and date of `at`, in UTC; zip has no time zone field.
+fn dos_time(at: SystemTime) -> (u16, u16) {
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    // DOS dates start in 1980
+    let year = year.clamp(1980, 2107) - 1980;
+
+    let time = ((secs / 3600) << 11) | ((secs % 3600 / 60) << 5) | (secs % 60 / 2);
+    let date = (year << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+/// Build a zip of `entries`, each a file name and its contents.
+fn zip(entries: &[(String, Vec<u8>)]) -> io::Result<Vec<u8>> {
+    let too_large = || io::Error::other("Diagnostics bundle is too large for a zip file");
+    let (time, date) = dos_time(SystemTime::now());
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for (name, data) in entries {
+        let offset = u32::try_from(out.len()).map_err(|_| too_large())?;
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let crc = crc32(data);
+        // Fields shared by the local and central headers, from "version needed" to "extra field length"
+        let mut common = Vec::with_capacity(26);
+        common.extend_from_slice(&VERSION.to_le_bytes());
+        common.extend_from_slice(&FLAG_UTF8.to_le_bytes());
+        common.extend_from_slice(&METHOD_STORED.to_le_bytes());
+        common.extend_from_slice(&time.to_le_bytes());
+        common.extend_from_slice(&date.to_le_bytes());
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&size.to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+
+        out.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        out.extend_from_slice(&common);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+
+        central.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        central.extend_from_slice(&VERSION.to_le_bytes());
+        central.extend_from_slice(&common);
+        // Comment length, disk number, internal and external attributes
+        central.extend_from_slice(&[0u8; 10]);
+        central.extend_from_slice(&offset.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+    }
+
+    let directory_offset = u32::try_from(out.len()).map_err(|_| too_large())?;
+    let directory_size = central.len() as u32;
+    let count = u16::try_from(entries.len()).map_err(|_| too_large())?;
+    out.extend_from_slice(&central);
+    out.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    // This disk and the disk the directory starts on
+    out.extend_from_slice(&[0u8; 4]);
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(&directory_size.to_le_bytes());
+    out.extend_from_slice(&directory_offset.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    Ok(out)
+}
+
+/// Write a zip of `entries` to `path`, replacing any file there.
+pub fn write_zip(path: &Path, entries: &[(String, Vec<u8>)]) -> io::Result<()> {
+    std::fs::write(path, zip(entries)?)
+}
+
+#[derive(Serialize)]
+pub struct Environment {
+    pub library_version: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+    /// Widest vector extension this CPU has.
+    pub cpu_simd: &'static str,
+    pub cpus: usize,
+    /// Unix time the bundle was made.
+    pub generated_at: u64,
+    pub tunnel_state: &'static str,
+    /// `None` without a tunnel.
+    pub account_type: Option<String>,
+    pub endpoint: Option<String>,
+}
+
+/// Tunnel options with credentials left out.
+#[derive(Serialize)]
+pub struct Options {
+    pub mtu: u16,
+    pub keepalive_seconds: Option<u16>,
+    pub connect_policy: String,
+    pub endpoint_override: Option<String>,
+    pub transport: String,
+    pub obfuscation: Option<String>,
+    pub socket: String,
+    /// Whether a preshared key is set; the key itself is not included.
+    pub preshared_key: bool,
+    pub dns_servers: Option<Vec<String>>,
+}
+
+/// An open connection and, if it was opened by tcpConnect, how.
+#[derive(Serialize)]
+pub struct Connection {
+    #[serde(flatten)]
+    pub info: ConnectionInfo,
+    pub connect_trace: Option<ConnectTimeline>,
+}
+
+/// Bytes per second, oldest first, from the throughput history.
+#[derive(Serialize)]
+pub struct Throughput {
+    pub rx_bytes: Vec<u64>,
+    pub tx_bytes: Vec<u64>,
+}
+
+/// Describe a transport as its spec string, with SOCKS credentials replaced.
+pub fn redacted_transport(transport: &TransportConfig) -> String {
+    match transport {
+        TransportConfig::Udp => "udp".into(),
+        TransportConfig::Socks5 { proxy, auth } => {
+            let auth = if auth.is_some() { "<redacted>@" } else { "" };
+            format!("socks5://{}{}", auth, proxy)
+        }
+        TransportConfig::WebSocket { tls, host, port, path } => {
+            format!("{}://{}:{}{}", if *tls { "wss" } else { "ws" }, host, port, path)
+        }
+    }
+}
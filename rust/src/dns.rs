@@ -0,0 +1,613 @@
+//! DNS lookups through the tunnel.
+//!
+//! wireguard-netstack's `DohResolver` only answers A queries. This sends raw
+//! DNS messages over the netstack so any record type resolves without
+//! leaving the tunnel. By default they go to Cloudflare's DoH endpoint,
+//! which works on networks that block port 53. Other servers can be DoH or
+//! plain DNS over TCP, since the netstack has no UDP.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use wireguard_netstack::{NetStack, TcpConnection};
+
+use crate::https;
+use crate::TunnelError;
+
+/// Default DoH server queried through the tunnel.
+const DOH_HOSTNAME: &str = "cloudflare-dns.com";
+const DOH_IPS: [Ipv4Addr; 2] = [Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(1, 0, 0, 1)];
+
+/// Upper bound on a DoH HTTP response.
+const MAX_RESPONSE: usize = 64 * 1024;
+
+/// How long one server gets to answer before the next is tried.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+const TYPE_SOA: u16 = 6;
+
+/// Bounds applied to record TTLs when caching answers.
+const MIN_CACHE_TTL: Duration = Duration::from_secs(30);
+const MAX_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// Bounds for caching names or types that do not exist (RFC 2308), and the
+/// TTL used when the server sends no SOA record to take it from.
+const MIN_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(300);
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+/// Cached answers kept before expired ones are swept out.
+const MAX_CACHE_ENTRIES: usize = 1024;
+
+/// Service label prepended to a domain for Minecraft SRV lookups.
+const MINECRAFT_SRV_PREFIX: &str = "_minecraft._tcp.";
+
+/// A decoded SRV record.
+#[derive(Clone, Debug)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// A decoded answer record.
+#[derive(Clone, Debug)]
+pub enum Record {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Txt(String),
+    Srv(SrvRecord),
+}
+
+impl Record {
+    fn record_type(&self) -> u16 {
+        match self {
+            Record::A(_) => TYPE_A,
+            Record::Aaaa(_) => TYPE_AAAA,
+            Record::Cname(_) => TYPE_CNAME,
+            Record::Txt(_) => TYPE_TXT,
+            Record::Srv(_) => TYPE_SRV,
+        }
+    }
+}
+
+/// Presentation format, as returned to Java.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Record::A(ip) => write!(f, "{}", ip),
+            Record::Aaaa(ip) => write!(f, "{}", ip),
+            Record::Cname(name) => write!(f, "{}", name),
+            Record::Txt(text) => write!(f, "{}", text),
+            Record::Srv(srv) => write!(f, "{} {} {} {}", srv.priority, srv.weight, srv.port, srv.target),
+        }
+    }
+}
+
+/// A DNS server queried through the tunnel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Server {
+    /// DNS-over-HTTPS at `addr`, with `hostname` for TLS and the Host header.
+    Https { addr: Ipv4Addr, hostname: String },
+    /// Plain DNS over TCP port 53 (RFC 7766).
+    Tcp(Ipv4Addr),
+}
+
+impl Server {
+    /// Parse "ip" for DNS over TCP, or "ip#hostname" for DoH.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (addr, hostname) = match spec.trim().split_once('#') {
+            Some((addr, hostname)) => (addr.trim(), Some(hostname.trim())),
+            None => (spec.trim(), None),
+        };
+        let addr: Ipv4Addr = addr
+            .parse()
+            .map_err(|_| format!("DNS server must be an IPv4 address: {}", addr))?;
+        match hostname {
+            Some("") => Err(format!("Missing DoH hostname after '#': {}", spec)),
+            Some(hostname) => Ok(Server::Https {
+                addr,
+                hostname: hostname.to_string(),
+            }),
+            None => Ok(Server::Tcp(addr)),
+        }
+    }
+
+    /// Parse a comma-separated list of servers.
+    pub fn parse_list(spec: &str) -> Result<Vec<Self>, String> {
+        let servers = spec
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(Self::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if servers.is_empty() {
+            return Err("No DNS servers given".into());
+        }
+        Ok(servers)
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Server::Https { addr, hostname } => write!(f, "{}#{}", addr, hostname),
+            Server::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// Cloudflare's DoH endpoint, used unless other servers are configured.
+pub fn default_servers() -> Vec<Server> {
+    DOH_IPS
+        .into_iter()
+        .map(|addr| Server::Https {
+            addr,
+            hostname: DOH_HOSTNAME.to_string(),
+        })
+        .collect()
+}
+
+struct CacheEntry {
+    records: Vec<Record>,
+    expires_at: Instant,
+}
+
+fn dns_error(msg: impl Into<String>) -> TunnelError {
+    TunnelError::Dns(msg.into())
+}
+
+/// Convert a hostname to the ASCII form DNS uses, punycode-encoding
+/// internationalized labels (IDNA, UTS #46), e.g. `сервер.рф` to
+/// `xn--b1afb6bcb.xn--p1ai`.
+pub fn to_ascii(host: &str) -> Result<String, TunnelError> {
+    idna::domain_to_ascii_cow(host.as_bytes(), idna::AsciiDenyList::URL)
+        .map(|name| name.into_owned())
+        .map_err(|_| dns_error(format!("Invalid hostname: {}", host)))
+}
+
+fn build_query(name: &str, qtype: u16) -> Result<Vec<u8>, TunnelError> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    // ID 0 as recommended for DoH (RFC 8484), RD=1, one question
+    query.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(dns_error(format!("Invalid domain name: {}", name)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes()); // QCLASS IN
+    Ok(query)
+}
+
+/// Read a (possibly compressed) name starting at `pos`.
+///
+/// Returns the dotted name and the position just past it in the original record.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), TunnelError> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bound pointer chasing so a malicious loop cannot spin forever
+    for _ in 0..128 {
+        let len = *msg.get(pos).ok_or_else(|| dns_error("Truncated name"))? as usize;
+        if len & 0xC0 == 0xC0 {
+            let lo = *msg.get(pos + 1).ok_or_else(|| dns_error("Truncated name"))? as usize;
+            end.get_or_insert(pos + 2);
+            pos = ((len & 0x3F) << 8) | lo;
+            continue;
+        }
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        let label = msg
+            .get(pos + 1..pos + 1 + len)
+            .ok_or_else(|| dns_error("Truncated name"))?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    Err(dns_error("Name compression loop"))
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16, TunnelError> {
+    msg.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| dns_error("Truncated record"))
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Result<u32, TunnelError> {
+    Ok(((read_u16(msg, pos)? as u32) << 16) | read_u16(msg, pos + 2)? as u32)
+}
+
+/// How long a negative answer may be cached: the smaller of the SOA
+/// record's TTL and its MINIMUM field, from the authority section.
+fn negative_ttl(msg: &[u8], mut pos: usize, nscount: u16) -> Result<Duration, TunnelError> {
+    for _ in 0..nscount {
+        pos = read_name(msg, pos)?.1;
+        let rtype = read_u16(msg, pos)?;
+        let record_ttl = read_u32(msg, pos + 4)?;
+        let rdata_start = pos + 10;
+        if rtype == TYPE_SOA {
+            // MNAME and RNAME, then serial, refresh, retry, expire and minimum
+            let rname = read_name(msg, rdata_start)?.1;
+            let minimum = read_u32(msg, read_name(msg, rname)?.1 + 16)?;
+            let ttl = Duration::from_secs(record_ttl.min(minimum) as u64);
+            return Ok(ttl.clamp(MIN_NEGATIVE_TTL, MAX_NEGATIVE_TTL));
+        }
+        pos = rdata_start + read_u16(msg, pos + 8)? as usize;
+    }
+    Ok(DEFAULT_NEGATIVE_TTL)
+}
+
+/// Parse a DNS response into its answer records and how long to cache them.
+///
+/// Answers are cached for their smallest TTL; empty answers and names that
+/// do not exist for the negative TTL the server gives.
+fn parse_response(msg: &[u8]) -> Result<(Vec<Record>, Duration), TunnelError> {
+    if msg.len() < 12 {
+        return Err(dns_error("Response too short"));
+    }
+    let rcode = msg[3] & 0x0F;
+    // 3 is NXDOMAIN, whose answer section is empty
+    if rcode != 0 && rcode != 3 {
+        return Err(dns_error(format!("Server returned RCODE {}", rcode)));
+    }
+
+    let qdcount = read_u16(msg, 4)?;
+    let ancount = read_u16(msg, 6)?;
+    let nscount = read_u16(msg, 8)?;
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+
+    let mut records = Vec::new();
+    let mut ttl = MAX_CACHE_TTL;
+    for _ in 0..ancount {
+        pos = read_name(msg, pos)?.1;
+        let rtype = read_u16(msg, pos)?;
+        let record_ttl = read_u32(msg, pos + 4)? as u64;
+        let rdlength = read_u16(msg, pos + 8)? as usize;
+        let rdata_start = pos + 10;
+        let rdata = msg
+            .get(rdata_start..rdata_start + rdlength)
+            .ok_or_else(|| dns_error("Truncated record data"))?;
+
+        let record = match rtype {
+            TYPE_A if rdlength == 4 => Some(Record::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))),
+            TYPE_AAAA if rdlength == 16 => {
+                let octets: [u8; 16] = rdata.try_into().expect("length checked");
+                Some(Record::Aaaa(Ipv6Addr::from(octets)))
+            }
+            TYPE_CNAME => Some(Record::Cname(read_name(msg, rdata_start)?.0)),
+            TYPE_TXT => {
+                // One or more length-prefixed character strings, concatenated
+                let mut text = Vec::with_capacity(rdlength);
+                let mut i = 0;
+                while i < rdata.len() {
+                    let len = rdata[i] as usize;
+                    let chunk = rdata
+                        .get(i + 1..i + 1 + len)
+                        .ok_or_else(|| dns_error("Truncated TXT record"))?;
+                    text.extend_from_slice(chunk);
+                    i += 1 + len;
+                }
+                Some(Record::Txt(String::from_utf8_lossy(&text).into_owned()))
+            }
+            TYPE_SRV if rdlength >= 7 => Some(Record::Srv(SrvRecord {
+                priority: read_u16(msg, rdata_start)?,
+                weight: read_u16(msg, rdata_start + 2)?,
+                port: read_u16(msg, rdata_start + 4)?,
+                target: read_name(msg, rdata_start + 6)?.0,
+            })),
+            _ => None,
+        };
+        if let Some(record) = record {
+            ttl = ttl.min(Duration::from_secs(record_ttl));
+            records.push(record);
+        }
+        pos = rdata_start + rdlength;
+    }
+
+    if records.is_empty() {
+        return Ok((records, negative_ttl(msg, pos, nscount)?));
+    }
+    Ok((records, ttl.clamp(MIN_CACHE_TTL, MAX_CACHE_TTL)))
+}
+
+async fn query_server(netstack: Arc<NetStack>, server: &Server, query: &[u8]) -> Result<Vec<u8>, TunnelError> {
+    let exchange = async {
+        match server {
+            Server::Https { addr, hostname } => query_https(netstack, *addr, hostname, query).await,
+            Server::Tcp(addr) => query_tcp(netstack, *addr, query).await,
+        }
+    };
+    tokio::time::timeout(QUERY_TIMEOUT, exchange)
+        .await
+        .map_err(|_| dns_error(format!("{} did not answer in time", server)))?
+}
+
+async fn query_https(
+    netstack: Arc<NetStack>,
+    server: Ipv4Addr,
+    hostname: &str,
+    query: &[u8],
+) -> Result<Vec<u8>, TunnelError> {
+    let mut request = format!(
+        "POST /dns-query HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: application/dns-message\r\n\
+         Accept: application/dns-message\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n",
+        hostname,
+        query.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(query);
+
+    let addr = SocketAddr::new(IpAddr::V4(server), 443);
+    let (status, body) = https::request(netstack, addr, hostname, &request, MAX_RESPONSE).await?;
+    if status != 200 {
+        return Err(dns_error(format!("DoH server returned HTTP {}", status)));
+    }
+    Ok(body)
+}
+
+/// Send a query over TCP, where each message is prefixed with its length.
+async fn query_tcp(netstack: Arc<NetStack>, server: Ipv4Addr, query: &[u8]) -> Result<Vec<u8>, TunnelError> {
+    let addr = SocketAddr::new(IpAddr::V4(server), 53);
+    let conn = TcpConnection::connect(netstack, addr)
+        .await
+        .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    conn.write_all(&message)
+        .await
+        .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(len) = response.get(..2).map(|b: &[u8]| u16::from_be_bytes([b[0], b[1]]) as usize) {
+            if response.len() >= 2 + len {
+                return Ok(response[2..2 + len].to_vec());
+            }
+        }
+        let n = conn
+            .read(&mut buf)
+            .await
+            .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+        if n == 0 {
+            return Err(dns_error("Server closed the connection before answering"));
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// Resolver bound to a running tunnel, with a TTL-based answer cache.
+///
+/// Each tunnel has its own, so servers and cached answers never leak
+/// between tunnels.
+pub struct Resolver {
+    netstack: Arc<NetStack>,
+    /// Tried in order until one answers.
+    servers: Mutex<Vec<Server>>,
+    cache: Mutex<HashMap<(String, u16), CacheEntry>>,
+}
+
+impl Resolver {
+    pub fn new(netstack: Arc<NetStack>, servers: Vec<Server>) -> Self {
+        Self {
+            netstack,
+            servers: Mutex::new(servers),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn netstack(&self) -> Arc<NetStack> {
+        self.netstack.clone()
+    }
+
+    pub fn servers(&self) -> Vec<Server> {
+        self.servers.lock().clone()
+    }
+
+    /// Switch to other servers, dropping answers cached from the old ones.
+    pub fn set_servers(&self, servers: Vec<Server>) {
+        *self.servers.lock() = servers;
+        self.flush();
+    }
+
+    /// Drop all cached answers, returning how many there were.
+    pub fn flush(&self) -> usize {
+        let mut cache = self.cache.lock();
+        let count = cache.len();
+        cache.clear();
+        count
+    }
+
+    /// Look up records of type `qtype` for `name` through the tunnel.
+    ///
+    /// Internationalized names are punycode-encoded first.
+    /// Only records of the requested type are returned; CNAMEs followed by the
+    /// server are dropped unless CNAME itself was asked for. A name that does
+    /// not exist yields an empty list.
+    pub async fn query(&self, name: &str, qtype: u16) -> Result<Vec<Record>, TunnelError> {
+        let name = &to_ascii(name)?;
+        let key = (name.trim_end_matches('.').to_ascii_lowercase(), qtype);
+        {
+            let mut cache = self.cache.lock();
+            match cache.get(&key) {
+                Some(entry) if entry.expires_at > Instant::now() => {
+                    log::debug!("DNS cache hit for {} (type {})", name, qtype);
+                    return Ok(entry.records.clone());
+                }
+                Some(_) => {
+                    cache.remove(&key);
+                }
+                None => {}
+            }
+        }
+
+        let query = build_query(name, qtype)?;
+        log::debug!("Resolving {} (type {}) through the tunnel", name, qtype);
+
+        let mut last_error = None;
+        let servers = self.servers();
+        for server in &servers {
+            match query_server(self.netstack.clone(), server, &query).await {
+                Ok(response) => {
+                    let (mut records, ttl) = parse_response(&response)?;
+                    records.retain(|r| r.record_type() == qtype);
+                    let mut cache = self.cache.lock();
+                    if cache.len() >= MAX_CACHE_ENTRIES {
+                        let now = Instant::now();
+                        cache.retain(|_, entry| entry.expires_at > now);
+                        if cache.len() >= MAX_CACHE_ENTRIES {
+                            cache.clear();
+                        }
+                    }
+                    cache.insert(
+                        key,
+                        CacheEntry {
+                            records: records.clone(),
+                            expires_at: Instant::now() + ttl,
+                        },
+                    );
+                    return Ok(records);
+                }
+                Err(e) => {
+                    log::warn!("DNS query to {} failed: {}", server, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| dns_error("No DNS servers configured")))
+    }
+
+    /// Resolve a hostname or IP literal to an address.
+    ///
+    /// IPv6 literals may be bracketed. A records are preferred because the
+    /// netstack only routes IPv4; AAAA is consulted only for names without
+    /// any, so callers can tell "IPv6-only" apart from "does not exist".
+    pub async fn resolve_host(&self, host: &str) -> Result<IpAddr, TunnelError> {
+        Ok(self.resolve_host_all(host).await?[0])
+    }
+
+    /// Resolve a hostname or IP literal to all of its addresses, in the
+    /// order the server gave them, preferring A records like `resolve_host`.
+    ///
+    /// Never returns an empty list.
+    pub async fn resolve_host_all(&self, host: &str) -> Result<Vec<IpAddr>, TunnelError> {
+        let literal = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            return Ok(vec![ip.to_canonical()]);
+        }
+
+        for qtype in [TYPE_A, TYPE_AAAA] {
+            let found: Vec<IpAddr> = self
+                .query(host, qtype)
+                .await?
+                .into_iter()
+                .filter_map(|r| match r {
+                    Record::A(ip) => Some(IpAddr::V4(ip)),
+                    Record::Aaaa(ip) => Some(IpAddr::V6(ip)),
+                    _ => None,
+                })
+                .collect();
+            if !found.is_empty() {
+                return Ok(found);
+            }
+        }
+        Err(dns_error(format!("No A or AAAA records for {}", host)))
+    }
+
+    /// Look up the Minecraft SRV record for a domain.
+    ///
+    /// Picks the lowest priority record, preferring higher weight within it.
+    /// Returns `None` for IP literals and domains without a record.
+    pub async fn resolve_minecraft_srv(&self, domain: &str) -> Result<Option<SrvRecord>, TunnelError> {
+        if domain.parse::<IpAddr>().is_ok() || domain.starts_with('[') {
+            return Ok(None);
+        }
+        let records = self.query(&format!("{}{}", MINECRAFT_SRV_PREFIX, domain), TYPE_SRV).await?;
+        Ok(records
+            .into_iter()
+            .filter_map(|r| match r {
+                // A target of "." means the service is explicitly unavailable
+                Record::Srv(srv) if !srv.target.is_empty() => Some(srv),
+                _ => None,
+            })
+            .min_by_key(|srv| (srv.priority, std::cmp::Reverse(srv.weight))))
+    }
+}
+
+/// Resolve a hostname with the system resolver, outside the tunnel.
+///
+/// Only for connections that are direct anyway.
+pub async fn resolve_system(host: &str, port: u16) -> Result<IpAddr, TunnelError> {
+    Ok(resolve_system_all(host, port).await?[0])
+}
+
+/// Resolve a hostname to all of its addresses with the system resolver.
+///
+/// Never returns an empty list.
+pub async fn resolve_system_all(host: &str, port: u16) -> Result<Vec<IpAddr>, TunnelError> {
+    let addrs: Vec<IpAddr> = tokio::net::lookup_host((to_ascii(host)?.as_str(), port))
+        .await
+        .map_err(|e| dns_error(format!("System lookup of {} failed: {}", host, e)))?
+        .map(|addr| addr.ip())
+        .collect();
+    if addrs.is_empty() {
+        return Err(dns_error(format!("No addresses for {}", host)));
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internationalized_names_become_punycode() {
+        assert_eq!(to_ascii("сервер.рф").unwrap(), "xn--b1afb6bcb.xn--p1ai");
+        assert_eq!(to_ascii("bücher.example").unwrap(), "xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn ascii_names_are_unchanged() {
+        assert_eq!(to_ascii("play.example.com").unwrap(), "play.example.com");
+        assert_eq!(to_ascii("mc-01.example.net").unwrap(), "mc-01.example.net");
+    }
+
+    #[test]
+    fn ascii_names_are_lowercased() {
+        assert_eq!(to_ascii("Play.Example.COM").unwrap(), "play.example.com");
+    }
+
+    #[test]
+    fn invalid_labels_are_rejected() {
+        for host in ["bad host.example", "xn--a.example", "a..b/c", "under_score?.example"] {
+            assert!(matches!(to_ascii(host), Err(TunnelError::Dns(_))), "{} accepted", host);
+        }
+    }
+}
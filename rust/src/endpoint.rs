@@ -0,0 +1,180 @@
+//! WARP endpoint selection and failover.
+//!
+//! Networks that block WireGuard's usual port often let one of the other
+//! ports WARP listens on through. The endpoint from the WARP config is tried
+//! first, then the same address on the other ports. While the tunnel runs,
+//! a watchdog probes it when nothing has arrived for a while and moves to
+//! the next candidate once the active endpoint stops answering. With
+//! connections preserved, the running tunnel moves there; otherwise a new
+//! tunnel is built, closing tunneled connections.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use wireguard_netstack::{NetStack, TcpConnection, WireGuardConfig};
+
+use crate::capture::PacketCapture;
+use crate::transport::OuterConfig;
+use crate::tunnel::{HandshakeStats, Tunnel};
+use crate::TunnelError;
+
+/// UDP ports WARP endpoints accept WireGuard on.
+const WARP_PORTS: [u16; 4] = [2408, 500, 1701, 4500];
+
+/// Handshake timeout per candidate, shorter than a single endpoint's so a
+/// blocked port does not hold up the rest.
+const CANDIDATE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the watchdog checks the active endpoint.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Failed probes in a row before failing over.
+pub const MAX_FAILED_PROBES: u32 = 2;
+
+/// Probe target through the tunnel; Cloudflare's resolver answers on port 443.
+const PROBE_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 443);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An endpoint pinned by the user in place of the one from the WARP config.
+#[derive(Clone, Debug)]
+pub struct EndpointOverride {
+    host: String,
+    /// `None` keeps the port from the WARP config.
+    port: Option<u16>,
+}
+
+impl EndpointOverride {
+    /// Parse `ip`, `ip:port`, `host` or `host:port`.
+    pub fn parse(endpoint: &str) -> Result<Self, String> {
+        let endpoint = endpoint.trim();
+        if let Ok(addr) = endpoint.parse::<SocketAddr>() {
+            return Ok(Self { host: addr.ip().to_string(), port: Some(addr.port()) });
+        }
+        if endpoint.parse::<IpAddr>().is_ok() {
+            return Ok(Self { host: endpoint.to_string(), port: None });
+        }
+        let (host, port) = match endpoint.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port.parse().map_err(|_| format!("Invalid endpoint port: {}", port))?;
+                (host, Some(port))
+            }
+            None => (endpoint, None),
+        };
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '[' || c == ']') {
+            return Err(format!("Invalid endpoint: {}", endpoint));
+        }
+        Ok(Self { host: host.to_string(), port })
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Resolve to an address, outside the tunnel, using `default_port` if none was given.
+    pub async fn resolve(&self, default_port: u16) -> Result<SocketAddr, TunnelError> {
+        let port = self.port.unwrap_or(default_port);
+        let ip = match self.host.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => crate::dns::resolve_system(&self.host, port).await?,
+        };
+        if ip.is_ipv6() {
+            // The WireGuard UDP socket is bound to an IPv4 address
+            return Err(TunnelError::ConnectionFailed(format!("IPv6 endpoint {} is not supported", ip)));
+        }
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+/// Endpoints to try for `primary`: itself, then its address on the other WARP ports.
+pub fn candidates(primary: SocketAddr) -> Vec<SocketAddr> {
+    let mut candidates = vec![primary];
+    for port in WARP_PORTS {
+        let addr = SocketAddr::new(primary.ip(), port);
+        if !candidates.contains(&addr) {
+            candidates.push(addr);
+        }
+    }
+    candidates
+}
+
+/// Connect to the first candidate that completes a handshake, trying them in
+/// order from `start` and wrapping around.
+///
+/// Returns the tunnel and the index of the candidate it uses.
+pub async fn connect_first(
+    config: &WireGuardConfig,
+    candidates: &[SocketAddr],
+    start: usize,
+    outer: &OuterConfig,
+    capture: &PacketCapture,
+    handshakes: &Arc<HandshakeStats>,
+) -> Result<(Tunnel, usize), TunnelError> {
+    let timeout = if candidates.len() > 1 {
+        CANDIDATE_HANDSHAKE_TIMEOUT
+    } else {
+        crate::tunnel::HANDSHAKE_TIMEOUT
+    };
+
+    let mut last_error = None;
+    for offset in 0..candidates.len() {
+        let index = (start + offset) % candidates.len();
+        let endpoint = candidates[index];
+        let mut config = config.clone();
+        config.peer_endpoint = endpoint;
+        log::info!("Trying WARP endpoint {}", endpoint);
+        match Tunnel::connect(config, outer, capture.clone(), handshakes.clone(), timeout).await {
+            Ok(tunnel) => return Ok((tunnel, index)),
+            Err(e) => {
+                log::warn!("WARP endpoint {} failed: {}", endpoint, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| TunnelError::ConnectionFailed("No WARP endpoints to try".into())))
+}
+
+/// Move `tunnel` to the first candidate that completes a handshake, trying
+/// them in order from `start` and wrapping around, keeping its connections.
+///
+/// Returns the index of the candidate it moved to, or `None` if the tunnel
+/// cannot move and has to be rebuilt with `connect_first`.
+pub async fn move_first(
+    tunnel: &Tunnel,
+    candidates: &[SocketAddr],
+    start: usize,
+    outer: &OuterConfig,
+) -> Result<Option<usize>, TunnelError> {
+    let timeout = if candidates.len() > 1 {
+        CANDIDATE_HANDSHAKE_TIMEOUT
+    } else {
+        crate::tunnel::HANDSHAKE_TIMEOUT
+    };
+
+    let mut last_error = None;
+    for offset in 0..candidates.len() {
+        let index = (start + offset) % candidates.len();
+        let endpoint = candidates[index];
+        log::info!("Moving to WARP endpoint {}", endpoint);
+        match tunnel.move_to(endpoint, outer, timeout).await {
+            Ok(true) => return Ok(Some(index)),
+            Ok(false) => return Ok(None),
+            Err(e) => {
+                log::warn!("WARP endpoint {} failed: {}", endpoint, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| TunnelError::ConnectionFailed("No WARP endpoints to try".into())))
+}
+
+/// Whether a TCP handshake through the tunnel completes.
+pub async fn probe(netstack: Arc<NetStack>) -> bool {
+    match tokio::time::timeout(PROBE_TIMEOUT, TcpConnection::connect(netstack, PROBE_ADDR)).await {
+        Ok(Ok(conn)) => {
+            conn.shutdown();
+            true
+        }
+        _ => false,
+    }
+}
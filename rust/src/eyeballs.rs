@@ -0,0 +1,78 @@
+//! Connection racing across a host's addresses (Happy Eyeballs, RFC 8305).
+//!
+//! Servers behind several A records are often partly unreachable through
+//! WARP. Rather than failing on the first address, attempts start one after
+//! another, `ATTEMPT_DELAY` apart or as soon as the previous one fails, and
+//! the first connection established wins. The others are cancelled.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::task::JoinSet;
+
+use crate::TunnelError;
+
+/// Connection Attempt Delay recommended by RFC 8305 section 8.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Alternate address families, starting with the family of the first
+/// address (RFC 8305 section 4).
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+    let mut out = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => return out,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Race `connect` across `addrs`, returning the first connection established.
+///
+/// With a single address this is just `connect`. Otherwise the error of the
+/// last attempt to fail is returned if none succeeds.
+pub async fn race<T, F, Fut>(addrs: &[SocketAddr], mut connect: F) -> Result<T, TunnelError>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, TunnelError>> + Send + 'static,
+    T: Send + 'static,
+{
+    if let [addr] = addrs {
+        return connect(*addr).await;
+    }
+
+    let mut pending = addrs.iter().copied().peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = pending.next() {
+            let attempt = connect(addr);
+            attempts.spawn(async move { (addr, attempt.await) });
+        } else if attempts.is_empty() {
+            return Err(last_error
+                .unwrap_or_else(|| TunnelError::ConnectionFailed("No addresses to connect to".into())));
+        }
+
+        let more = pending.peek().is_some();
+        tokio::select! {
+            Some(joined) = attempts.join_next() => match joined {
+                Ok((_, Ok(conn))) => return Ok(conn),
+                Ok((addr, Err(e))) => {
+                    log::debug!("Connection attempt to {} failed: {}", addr, e);
+                    last_error = Some(e);
+                }
+                Err(e) => last_error = Some(TunnelError::ConnectionFailed(e.to_string())),
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if more => {}
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Entry points for `java.lang.foreign` (Panama) downcalls.
+//!
+//! The read/write path without JNI: each function takes a connection handle
+//! and the address and length of a memory segment. Reads and writes use the
+//! segment in place, so nothing is pinned or copied. Handles are the ones
+//! `tcpConnect` returns. Failures return a negated `TUNNEL_ERROR_*` code, or
+//! one of the codes below, and I/O errors leave their message for
+//! `tcpLastErrorInfo`.
+//!
+//! The functions never call back into Java. On a non-blocking connection they
+//! can be linked with `Linker.Option.critical(true)`, which on Java 22 and later
+//! also accepts heap segments; a blocking call must not be critical, as it
+//! would hold up garbage collection while it waits.
+
+use std::sync::Arc;
+
+use crate::connection::Connection;
+use crate::{global, panic_guard, record_tunnel_down, TunnelError};
+
+/// An argument was null, negative or otherwise invalid.
+pub const ERROR_INVALID_ARGUMENT: i32 = -98;
+/// The call panicked; the tunnel state may be inconsistent.
+pub const ERROR_PANIC: i32 = -99;
+
+/// A failed call: the code it returns and what went wrong.
+pub struct Failure {
+    pub code: i32,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Failure {
+            code: ERROR_INVALID_ARGUMENT,
+            message: message.into(),
+        }
+    }
+
+    pub fn tunnel(err: &TunnelError, message: String) -> Self {
+        Failure {
+            code: -err.code(),
+            message,
+        }
+    }
+
+    pub fn panic(report: String) -> Self {
+        Failure {
+            code: ERROR_PANIC,
+            message: report,
+        }
+    }
+}
+
+/// A segment to write from, laid out as `{ address, length }`: 16 bytes on 64-bit platforms.
+#[repr(C)]
+pub struct Segment {
+    address: *const u8,
+    length: i64,
+}
+
+/// View `length` bytes at `address` as a slice.
+///
+/// # Safety
+///
+/// `address` must be valid for reads of `length` bytes for `'a`.
+pub unsafe fn segment<'a>(address: *const u8, length: i64) -> Result<&'a [u8], Failure> {
+    match length {
+        0 => Ok(&[]),
+        n if n < 0 => Err(Failure::invalid(format!("Negative segment length: {}", n))),
+        _ if address.is_null() => Err(Failure::invalid("Segment address is null")),
+        n => Ok(std::slice::from_raw_parts(address, n as usize)),
+    }
+}
+
+/// Like `segment`, for a segment to write into.
+///
+/// # Safety
+///
+/// `address` must be valid for writes of `length` bytes for `'a`, and not
+/// otherwise accessed meanwhile.
+pub unsafe fn segment_mut<'a>(address: *mut u8, length: i64) -> Result<&'a mut [u8], Failure> {
+    match length {
+        0 => Ok(&mut []),
+        n if n < 0 => Err(Failure::invalid(format!("Negative segment length: {}", n))),
+        _ if address.is_null() => Err(Failure::invalid("Segment address is null")),
+        n => Ok(std::slice::from_raw_parts_mut(address, n as usize)),
+    }
+}
+
+fn connection(handle: i64) -> Result<Arc<Connection>, Failure> {
+    global().connections.get(handle).map_err(|e| Failure::tunnel(&e, e.to_string()))
+}
+
+/// Record a failed read or write, like `throw_io_error` does for Java.
+fn io_error(conn: &Connection, op: &str, err: &TunnelError) -> Failure {
+    record_tunnel_down(conn);
+    let message = format!("{} error: {}", op, err);
+    conn.set_last_error_message(message.clone());
+    Failure::tunnel(err, message)
+}
+
+/// Read into `buf` in place, returning 0 on EOF.
+///
+/// A non-blocking connection with nothing to read fails with `WouldBlock`.
+pub fn read(handle: i64, buf: &mut [u8]) -> Result<usize, Failure> {
+    let conn = connection(handle)?;
+    let result = match global().run_borrowed(conn.read(buf)) {
+        Some(result) => result,
+        None => {
+            let io_conn = conn.clone();
+            let mut owned = conn.take_read_buf(buf.len());
+            let (result, owned) = global().run(async move {
+                let result = io_conn.read(&mut owned).await;
+                (result, owned)
+            });
+            if let Ok(n) = result {
+                buf[..n].copy_from_slice(&owned[..n]);
+            }
+            conn.put_read_buf(owned);
+            result
+        }
+    };
+    result.map_err(|e| match e {
+        TunnelError::WouldBlock => Failure::tunnel(&e, e.to_string()),
+        e => io_error(&conn, "Read", &e),
+    })
+}
+
+/// Write from `data` in place, returning 0 if the connection is non-blocking
+/// and the send buffer is full.
+pub fn write(handle: i64, data: &[u8]) -> Result<usize, Failure> {
+    let conn = connection(handle)?;
+    let result = match global().run_borrowed(conn.write(data)) {
+        Some(result) => result,
+        None => {
+            let io_conn = conn.clone();
+            let owned = data.to_vec();
+            global().run(async move { io_conn.write(&owned).await })
+        }
+    };
+    match result {
+        Ok(n) => Ok(n),
+        Err(TunnelError::WouldBlock) => Ok(0),
+        Err(e) => Err(io_error(&conn, "Write", &e)),
+    }
+}
+
+/// Run an entry point body, turning an error or panic into its code.
+fn call(f: impl FnOnce() -> Result<usize, Failure>) -> i64 {
+    let failure = match panic_guard::run(f) {
+        Ok(Ok(n)) => return n as i64,
+        Ok(Err(failure)) => failure,
+        Err(report) => Failure::panic(report),
+    };
+    // Would-block is routine on a non-blocking connection's hot path
+    if failure.code != -TunnelError::WouldBlock.code() {
+        log::debug!("FFM call failed: {}", failure.message);
+    }
+    failure.code as i64
+}
+
+/// Read up to `length` bytes into the segment at `address`.
+///
+/// Returns the bytes read, 0 at end of stream, or a negative code; -14 (would
+/// block) if the connection is non-blocking and nothing is buffered.
+///
+/// # Safety
+///
+/// `address` must be valid for writes of `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_ffm_tcp_read(handle: i64, address: *mut u8, length: i64) -> i64 {
+    call(|| read(handle, segment_mut(address, length)?))
+}
+
+/// Write up to `length` bytes from the segment at `address`.
+///
+/// Returns the bytes written, 0 if the connection is non-blocking and the
+/// send buffer is full, or a negative code.
+///
+/// # Safety
+///
+/// `address` must be valid for reads of `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_ffm_tcp_write(handle: i64, address: *const u8, length: i64) -> i64 {
+    call(|| write(handle, segment(address, length)?))
+}
+
+/// Write `count` segments in order, as one write.
+///
+/// Returns as `wgt_ffm_tcp_write`.
+///
+/// # Safety
+///
+/// `segments` must point to `count` segments, each valid for reads of its length.
+#[no_mangle]
+pub unsafe extern "C" fn wgt_ffm_tcp_writev(handle: i64, segments: *const Segment, count: i32) -> i64 {
+    call(|| {
+        let segments = match count {
+            0 => &[][..],
+            n if n < 0 => return Err(Failure::invalid(format!("Negative segment count: {}", n))),
+            _ if segments.is_null() => return Err(Failure::invalid("Segment array is null")),
+            n => std::slice::from_raw_parts(segments, n as usize),
+        };
+        let mut data = Vec::new();
+        for s in segments {
+            data.extend_from_slice(segment(s.address, s.length)?);
+        }
+        write(handle, &data)
+    })
+}
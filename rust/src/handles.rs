@@ -0,0 +1,224 @@
+//! Table of the handles Java holds for open connections.
+//!
+//! Handles carry the slot index in their low 32 bits and the slot's
+//! generation above it. The generation changes whenever the slot is freed,
+//! so a handle kept after close never reaches the slot's next connection.
+//!
+//! Every read and write looks its handle up, so the table is split into
+//! shards, each behind its own lock, and a slot index picks its shard. Each
+//! thread also remembers its last few lookups, enough for both ends of a
+//! proxied connection, and reuses them without locking until something is
+//! removed from their shard.
+//!
+//! Kept free of other crate items so `benches/handles.rs` can include it.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use parking_lot::RwLock;
+
+/// Shards per table; a power of two comfortably above typical core counts.
+const SHARDS: usize = 32;
+
+/// Generations stay within 31 bits so handles are always positive.
+const MAX_GENERATION: u32 = i32::MAX as u32;
+
+/// Lookups each thread remembers.
+const REMEMBERED: usize = 4;
+
+/// Why a handle was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleError {
+    /// Never issued by this table.
+    Invalid,
+    /// Issued, but its value has since been removed.
+    Stale,
+}
+
+/// A reusable place for one value.
+struct Slot<T> {
+    generation: u32,
+    value: Option<Arc<T>>,
+}
+
+struct Slots<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+}
+
+struct Shard<T> {
+    slots: RwLock<Slots<T>>,
+    /// Bumped by every removal, invalidating lookups threads remember.
+    removals: AtomicU64,
+}
+
+/// A lookup a thread remembers.
+struct Remembered {
+    table: usize,
+    handle: i64,
+    removals: u64,
+    value: Weak<dyn Any + Send + Sync>,
+}
+
+#[derive(Default)]
+struct RecentLookups {
+    entries: [Option<Remembered>; REMEMBERED],
+    /// Entry the next lookup replaces, round-robin.
+    next: usize,
+}
+
+thread_local! {
+    static RECENT_LOOKUPS: RefCell<RecentLookups> = RefCell::new(RecentLookups::default());
+}
+
+/// Tells tables apart in `RECENT_LOOKUPS`, even one created where a dropped one was.
+static NEXT_TABLE_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct HandleTable<T> {
+    id: usize,
+    shards: Box<[Shard<T>]>,
+    /// Shard the next insert goes to, round-robin.
+    next_shard: AtomicUsize,
+    len: AtomicUsize,
+}
+
+impl<T> Default for HandleTable<T> {
+    fn default() -> Self {
+        Self {
+            id: NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed),
+            shards: (0..SHARDS)
+                .map(|_| Shard {
+                    slots: RwLock::new(Slots {
+                        slots: Vec::new(),
+                        free: Vec::new(),
+                    }),
+                    removals: AtomicU64::new(0),
+                })
+                .collect(),
+            next_shard: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Shard and index within it of the slot `handle` names.
+fn locate(handle: i64) -> (usize, usize, u32) {
+    let index = handle as u32 as usize;
+    (index % SHARDS, index / SHARDS, (handle >> 32) as u32)
+}
+
+fn handle(shard: usize, local: usize, generation: u32) -> i64 {
+    ((generation as i64) << 32) | (local * SHARDS + shard) as i64
+}
+
+impl<T: Send + Sync + 'static> HandleTable<T> {
+    /// Store `value`, returning its new handle.
+    pub fn insert(&self, value: Arc<T>) -> i64 {
+        let shard = self.next_shard.fetch_add(1, Ordering::Relaxed) % SHARDS;
+        let mut slots = self.shards[shard].slots.write();
+        let local = match slots.free.pop() {
+            Some(local) => {
+                slots.slots[local as usize].value = Some(value);
+                local as usize
+            }
+            None => {
+                slots.slots.push(Slot {
+                    generation: 1,
+                    value: Some(value),
+                });
+                slots.slots.len() - 1
+            }
+        };
+        self.len.fetch_add(1, Ordering::Relaxed);
+        handle(shard, local, slots.slots[local].generation)
+    }
+
+    /// Check `handle` against its slot, returning the slot's index.
+    fn check(slots: &Slots<T>, handle: i64) -> Result<usize, HandleError> {
+        let (_, local, generation) = locate(handle);
+        let slot = match slots.slots.get(local) {
+            Some(slot) if generation != 0 && generation <= slot.generation => slot,
+            _ => return Err(HandleError::Invalid),
+        };
+        if slot.generation != generation || slot.value.is_none() {
+            return Err(HandleError::Stale);
+        }
+        Ok(local)
+    }
+
+    pub fn get(&self, handle: i64) -> Result<Arc<T>, HandleError> {
+        let (shard_index, _, _) = locate(handle);
+        let shard = &self.shards[shard_index];
+        let removals = shard.removals.load(Ordering::Acquire);
+        let remembered = RECENT_LOOKUPS.with(|recent| {
+            recent
+                .borrow()
+                .entries
+                .iter()
+                .flatten()
+                .find(|entry| entry.table == self.id && entry.handle == handle && entry.removals == removals)
+                .and_then(|entry| entry.value.upgrade())
+        });
+        if let Some(value) = remembered.and_then(|value| value.downcast::<T>().ok()) {
+            return Ok(value);
+        }
+
+        let slots = shard.slots.read();
+        let local = Self::check(&slots, handle)?;
+        let value = slots.slots[local].value.clone().expect("check only passes occupied slots");
+        // Read under the lock, so no removal can slip in between
+        let removals = shard.removals.load(Ordering::Acquire);
+        drop(slots);
+        let any: Arc<dyn Any + Send + Sync> = value.clone();
+        RECENT_LOOKUPS.with(|recent| {
+            let mut recent = recent.borrow_mut();
+            let next = recent.next;
+            recent.entries[next] = Some(Remembered {
+                table: self.id,
+                handle,
+                removals,
+                value: Arc::downgrade(&any),
+            });
+            recent.next = (next + 1) % REMEMBERED;
+        });
+        Ok(value)
+    }
+
+    pub fn remove(&self, handle: i64) -> Result<Arc<T>, HandleError> {
+        let (shard_index, _, _) = locate(handle);
+        let shard = &self.shards[shard_index];
+        let mut slots = shard.slots.write();
+        let local = Self::check(&slots, handle)?;
+        let slot = &mut slots.slots[local];
+        let value = slot.value.take().expect("check only passes occupied slots");
+        // A slot whose generations ran out is retired rather than reused
+        if slot.generation < MAX_GENERATION {
+            slot.generation += 1;
+            slots.free.push(local as u32);
+        }
+        shard.removals.fetch_add(1, Ordering::Release);
+        drop(slots);
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Ok(value)
+    }
+
+    /// Number of values stored.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Every handle and its value, in slot order.
+    pub fn snapshot(&self) -> Vec<(i64, Arc<T>)> {
+        let mut entries = Vec::with_capacity(self.len());
+        for (shard_index, shard) in self.shards.iter().enumerate() {
+            let slots = shard.slots.read();
+            entries.extend(slots.slots.iter().enumerate().filter_map(|(local, slot)| {
+                Some((handle(shard_index, local, slot.generation), slot.value.clone()?))
+            }));
+        }
+        entries.sort_unstable_by_key(|(handle, _)| *handle as u32);
+        entries
+    }
+}
@@ -0,0 +1,55 @@
+//! Per-second throughput samples for live bandwidth graphs.
+//!
+//! Samples are payload bytes read and written by tunneled connections, as in
+//! the per-route metrics; outbound tunnel packets cannot be observed (see
+//! UPSTREAM.md), so counting payload both ways keeps the two series comparable.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+/// How often a sample is taken.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples kept: ten minutes.
+pub const CAPACITY: usize = 600;
+
+#[derive(Default)]
+pub struct ThroughputHistory {
+    /// Bytes [received, sent] per interval, oldest first.
+    samples: Mutex<VecDeque<[u64; 2]>>,
+    /// Totals at the last sample, to take the next one's difference from.
+    last_totals: Mutex<Option<[u64; 2]>>,
+}
+
+impl ThroughputHistory {
+    /// Record a sample from running totals.
+    ///
+    /// The first call only sets the baseline. Totals that went down, e.g.
+    /// while a closing connection moves between counters, count as no traffic.
+    pub fn record(&self, totals: [u64; 2]) {
+        let Some(last) = self.last_totals.lock().replace(totals) else {
+            return;
+        };
+        let sample = [totals[0].saturating_sub(last[0]), totals[1].saturating_sub(last[1])];
+        let mut samples = self.samples.lock();
+        if samples.len() == CAPACITY {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Bytes received and sent in each of the last `seconds` intervals,
+    /// oldest first; fewer if the history is shorter.
+    pub fn recent(&self, seconds: usize) -> (Vec<u64>, Vec<u64>) {
+        let samples = self.samples.lock();
+        let skip = samples.len().saturating_sub(seconds);
+        samples.iter().skip(skip).map(|[rx, tx]| (*rx, *tx)).unzip()
+    }
+
+    pub fn clear(&self) {
+        self.samples.lock().clear();
+        *self.last_totals.lock() = None;
+    }
+}
@@ -0,0 +1,308 @@
+//! Minimal HTTP/1.1 client over the tunnel.
+//!
+//! Enough for one-shot requests, both to known Cloudflare endpoints (DoH,
+//! trace) and for `httpRequest`: a fresh netstack connection per request,
+//! `Connection: close`, and the response read to EOF. Redirects are not
+//! followed.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use wireguard_netstack::{NetStack, TcpConnection};
+
+use crate::stream::TunnelStream;
+use crate::TunnelError;
+
+fn http_error(msg: impl Into<String>) -> TunnelError {
+    TunnelError::ConnectionFailed(msg.into())
+}
+
+pub fn tls_connector() -> Result<TlsConnector, TunnelError> {
+    tls_connector_with_alpn(Vec::new())
+}
+
+/// A connector offering `alpn` protocols, in order of preference.
+pub fn tls_connector_with_alpn(alpn: Vec<Vec<u8>>) -> Result<TlsConnector, TunnelError> {
+    let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| http_error(format!("TLS config: {}", e)))?
+        .with_root_certificates(Arc::new(rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        }))
+        .with_no_client_auth();
+    config.alpn_protocols = alpn;
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// A parsed HTTP response.
+pub struct Response {
+    pub status: u16,
+    /// Header names and values in the order received.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Split an HTTP/1.1 response into status code, headers and body.
+///
+/// Chunked bodies are decoded. `bodyless` marks a response to HEAD, which
+/// has no body whatever its headers say.
+fn parse_http(response: &[u8], bodyless: bool) -> Result<Response, TunnelError> {
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| http_error("Malformed HTTP response"))?;
+    let head = std::str::from_utf8(&response[..header_end]).map_err(|_| http_error("Malformed HTTP headers"))?;
+    let mut lines = head.split("\r\n");
+    let status: u16 = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| http_error("Malformed HTTP status line"))?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let header = |wanted: &str| {
+        headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.as_str())
+    };
+
+    let mut body = &response[header_end + 4..];
+    let body = if bodyless || status / 100 == 1 || status == 204 || status == 304 {
+        Vec::new()
+    } else if header("transfer-encoding").is_some_and(|te| te.to_ascii_lowercase().contains("chunked")) {
+        decode_chunked(body)?
+    } else {
+        if let Some(len) = header("content-length") {
+            let len: usize = len.parse().map_err(|_| http_error("Malformed Content-Length"))?;
+            body = body.get(..len).ok_or_else(|| http_error("Truncated HTTP body"))?;
+        }
+        body.to_vec()
+    };
+    Ok(Response { status, headers, body })
+}
+
+/// Decode a chunked body, ignoring chunk extensions and trailers.
+fn decode_chunked(mut data: &[u8]) -> Result<Vec<u8>, TunnelError> {
+    let truncated = || http_error("Truncated chunked HTTP body");
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n").ok_or_else(truncated)?;
+        let size = std::str::from_utf8(&data[..line_end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .ok_or_else(|| http_error("Malformed HTTP chunk size"))?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        body.extend_from_slice(data.get(..size).ok_or_else(truncated)?);
+        data = data.get(size + 2..).ok_or_else(truncated)?;
+    }
+}
+
+/// The parts of an `http` or `https` URL needed to send a request.
+pub struct Url {
+    pub https: bool,
+    /// Hostname or IP address, without brackets.
+    pub host: String,
+    pub port: u16,
+    /// Path and query, starting with `/`.
+    pub target: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid URL: {}", url);
+        let (scheme, rest) = url.trim().split_once("://").ok_or_else(invalid)?;
+        let https = match scheme.to_ascii_lowercase().as_str() {
+            "https" => true,
+            "http" => false,
+            _ => return Err(format!("Unsupported URL scheme: {}", scheme)),
+        };
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        if authority.contains('@') {
+            return Err("User info in URLs is not supported".into());
+        }
+        let default_port = if https { 443 } else { 80 };
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(v6) => {
+                let (host, port) = v6.split_once(']').ok_or_else(invalid)?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let host = match host.parse::<IpAddr>() {
+            Ok(_) => host.to_string(),
+            Err(_) => crate::dns::to_ascii(host).map_err(|e| e.to_string())?,
+        };
+        Ok(Self {
+            https,
+            host,
+            port,
+            target,
+        })
+    }
+
+    /// The `Host` header value.
+    fn host_header(&self) -> String {
+        let default_port = if self.https { 443 } else { 80 };
+        let host = match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+            _ => self.host.clone(),
+        };
+        if self.port == default_port {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+/// Build a `Connection: close` request with `headers` and `body`.
+///
+/// `Host` is filled in unless `headers` sets it. Headers managing the
+/// connection or body framing are rejected, since those are set here.
+pub fn build_request(
+    method: &str,
+    url: &Url,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Result<Vec<u8>, String> {
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err(format!("Invalid HTTP method: {}", method));
+    }
+    let mut head = format!("{} {} HTTP/1.1\r\n", method.to_ascii_uppercase(), url.target);
+    if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("host")) {
+        head.push_str(&format!("Host: {}\r\n", url.host_header()));
+    }
+    for (name, value) in headers {
+        let valid_name = !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+        if !valid_name || value.contains(['\r', '\n']) {
+            return Err(format!("Invalid HTTP header: {}", name));
+        }
+        if ["connection", "content-length", "transfer-encoding"].contains(&name.to_ascii_lowercase().as_str()) {
+            return Err(format!("The {} header is set automatically", name));
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() || ["POST", "PUT", "PATCH"].contains(&method.to_ascii_uppercase().as_str()) {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+
+    let mut request = head.into_bytes();
+    request.extend_from_slice(body);
+    Ok(request)
+}
+
+/// Start TLS to `hostname` over an open tunnel connection.
+pub async fn tls_connect(conn: TcpConnection, hostname: &str) -> Result<TlsStream<TunnelStream>, TunnelError> {
+    tls_handshake(Arc::new(conn), hostname, Vec::new()).await
+}
+
+/// Start TLS over a shared tunnel connection, sending `server_name` as SNI
+/// and verifying the certificate against it.
+///
+/// `server_name` may also be an IP address, which is then verified but not sent.
+pub async fn tls_handshake(
+    conn: Arc<TcpConnection>,
+    server_name: &str,
+    alpn: Vec<Vec<u8>>,
+) -> Result<TlsStream<TunnelStream>, TunnelError> {
+    let name = ServerName::try_from(server_name.to_string())
+        .map_err(|_| http_error(format!("Invalid hostname: {}", server_name)))?;
+    tls_connector_with_alpn(alpn)?
+        .connect(name, TunnelStream::new(conn))
+        .await
+        .map_err(|e| http_error(format!("TLS handshake failed: {}", e)))
+}
+
+/// Send `request` (head and body) to `hostname` at `addr` over TLS through the
+/// tunnel, returning the status code and body.
+///
+/// The request must ask for `Connection: close`. Responses larger than
+/// `max_response` bytes are rejected.
+pub async fn request(
+    netstack: Arc<NetStack>,
+    addr: SocketAddr,
+    hostname: &str,
+    request: &[u8],
+    max_response: usize,
+) -> Result<(u16, Vec<u8>), TunnelError> {
+    let conn = TcpConnection::connect(netstack, addr)
+        .await
+        .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+    let mut tls = tls_connect(conn, hostname).await?;
+
+    tls.write_all(request).await?;
+    tls.flush().await?;
+    read_response(&mut tls, max_response).await
+}
+
+/// Read a `Connection: close` response to EOF.
+pub async fn read_response(
+    tls: &mut TlsStream<TunnelStream>,
+    max_response: usize,
+) -> Result<(u16, Vec<u8>), TunnelError> {
+    let response = read_full_response(tls, max_response, false).await?;
+    Ok((response.status, response.body))
+}
+
+/// Send `request` on `stream` and read the `Connection: close` response to EOF.
+pub async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &[u8],
+    max_response: usize,
+    bodyless: bool,
+) -> Result<Response, TunnelError> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+    read_full_response(stream, max_response, bodyless).await
+}
+
+async fn read_full_response<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_response: usize,
+    bodyless: bool,
+) -> Result<Response, TunnelError> {
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => {
+                response.extend_from_slice(&buf[..n]);
+                if response.len() > max_response {
+                    return Err(http_error("HTTP response too large"));
+                }
+            }
+            // Servers commonly close without close_notify after Connection: close
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    parse_http(&response, bodyless)
+}
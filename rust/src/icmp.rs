@@ -0,0 +1,169 @@
+//! ICMP echo (ping) through the tunnel.
+//!
+//! The netstack only has TCP sockets, so echo requests are built here as raw
+//! IPv4 packets and handed straight to WireGuard. The tunnel's receive loop
+//! offers every inbound packet to `Pinger::accept` before the netstack sees
+//! it, which picks out the replies to our requests.
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{Icmpv4Message, Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Packet, Ipv4Repr};
+use tokio::sync::oneshot;
+
+/// Payload of each echo request, as sent by the usual ping tools.
+const PAYLOAD: [u8; 32] = *b"abcdefghijklmnopqrstuvwabcdefghi";
+
+const HOP_LIMIT: u8 = 64;
+
+/// Sends echo requests and matches their replies.
+pub struct Pinger {
+    /// Echo identifier of our requests; replies carrying another are not ours.
+    ident: u16,
+    next_seq: AtomicU16,
+    /// Requests waiting for a reply, by destination and sequence number.
+    waiting: Mutex<HashMap<(Ipv4Addr, u16), oneshot::Sender<()>>>,
+}
+
+/// An echo request waiting for its reply; dropping it stops waiting.
+pub struct Echo<'a> {
+    pinger: &'a Pinger,
+    key: (Ipv4Addr, u16),
+    /// The IPv4 packet to send.
+    pub packet: BytesMut,
+    pub reply: oneshot::Receiver<()>,
+}
+
+impl Drop for Echo<'_> {
+    fn drop(&mut self) {
+        self.pinger.waiting.lock().remove(&self.key);
+    }
+}
+
+impl Pinger {
+    pub fn new() -> Self {
+        Self {
+            ident: std::process::id() as u16,
+            next_seq: AtomicU16::new(0),
+            waiting: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Build an echo request from `source` to `destination`.
+    pub fn request(&self, source: Ipv4Addr, destination: Ipv4Addr) -> Echo<'_> {
+        let seq_no = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let icmp = Icmpv4Repr::EchoRequest { ident: self.ident, seq_no, data: &PAYLOAD };
+        let ip = Ipv4Repr {
+            src_addr: source,
+            dst_addr: destination,
+            next_header: IpProtocol::Icmp,
+            payload_len: icmp.buffer_len(),
+            hop_limit: HOP_LIMIT,
+        };
+        let mut packet = BytesMut::zeroed(ip.buffer_len() + icmp.buffer_len());
+        let checksums = ChecksumCapabilities::default();
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet[..]);
+        ip.emit(&mut ip_packet, &checksums);
+        icmp.emit(&mut Icmpv4Packet::new_unchecked(ip_packet.payload_mut()), &checksums);
+
+        let key = (destination, seq_no);
+        let (tx, reply) = oneshot::channel();
+        self.waiting.lock().insert(key, tx);
+        Echo { pinger: self, key, packet, reply }
+    }
+
+    /// Complete the request `packet` replies to, returning false if it is not
+    /// a reply to one of ours.
+    pub fn accept(&self, packet: &[u8]) -> bool {
+        let Ok(ip) = Ipv4Packet::new_checked(packet) else {
+            return false;
+        };
+        if ip.next_header() != IpProtocol::Icmp {
+            return false;
+        }
+        let Ok(icmp) = Icmpv4Packet::new_checked(ip.payload()) else {
+            return false;
+        };
+        if icmp.msg_type() != Icmpv4Message::EchoReply || icmp.echo_ident() != self.ident {
+            return false;
+        }
+        if let Some(tx) = self.waiting.lock().remove(&(ip.src_addr(), icmp.echo_seq_no())) {
+            let _ = tx.send(());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Turn an echo request into the reply its destination would send.
+    fn reply_to(request: &[u8]) -> Vec<u8> {
+        let ip = Ipv4Packet::new_checked(request).unwrap();
+        let icmp = Icmpv4Packet::new_checked(ip.payload()).unwrap();
+        let reply = Icmpv4Repr::EchoReply { ident: icmp.echo_ident(), seq_no: icmp.echo_seq_no(), data: icmp.data() };
+        let repr = Ipv4Repr {
+            src_addr: ip.dst_addr(),
+            dst_addr: ip.src_addr(),
+            next_header: IpProtocol::Icmp,
+            payload_len: reply.buffer_len(),
+            hop_limit: HOP_LIMIT,
+        };
+        let mut packet = vec![0; repr.buffer_len() + reply.buffer_len()];
+        let checksums = ChecksumCapabilities::default();
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet[..]);
+        repr.emit(&mut ip_packet, &checksums);
+        reply.emit(&mut Icmpv4Packet::new_unchecked(ip_packet.payload_mut()), &checksums);
+        packet
+    }
+
+    #[test]
+    fn request_is_valid_echo() {
+        let pinger = Pinger::new();
+        let echo = pinger.request(Ipv4Addr::new(172, 16, 0, 2), Ipv4Addr::new(1, 1, 1, 1));
+        let ip = Ipv4Packet::new_checked(&echo.packet[..]).unwrap();
+        assert!(ip.verify_checksum());
+        assert_eq!(ip.dst_addr(), Ipv4Addr::new(1, 1, 1, 1));
+        let icmp = Icmpv4Packet::new_checked(ip.payload()).unwrap();
+        assert!(icmp.verify_checksum());
+        assert_eq!(icmp.msg_type(), Icmpv4Message::EchoRequest);
+    }
+
+    #[test]
+    fn reply_completes_its_request() {
+        let pinger = Pinger::new();
+        let mut first = pinger.request(Ipv4Addr::new(172, 16, 0, 2), Ipv4Addr::new(1, 1, 1, 1));
+        let mut second = pinger.request(Ipv4Addr::new(172, 16, 0, 2), Ipv4Addr::new(1, 1, 1, 1));
+        assert!(pinger.accept(&reply_to(&second.packet)));
+        assert!(second.reply.try_recv().is_ok());
+        assert!(first.reply.try_recv().is_err());
+    }
+
+    #[test]
+    fn other_packets_pass_through() {
+        let pinger = Pinger::new();
+        let echo = pinger.request(Ipv4Addr::new(172, 16, 0, 2), Ipv4Addr::new(1, 1, 1, 1));
+        // Our own request, as if looped back, is not a reply
+        assert!(!pinger.accept(&echo.packet));
+        assert!(!pinger.accept(&[0x45, 0, 0]));
+        let mut tcp = reply_to(&echo.packet);
+        Ipv4Packet::new_unchecked(&mut tcp[..]).set_next_header(IpProtocol::Tcp);
+        assert!(!pinger.accept(&tcp));
+    }
+
+    #[test]
+    fn dropped_request_stops_waiting() {
+        let pinger = Pinger::new();
+        let echo = pinger.request(Ipv4Addr::new(172, 16, 0, 2), Ipv4Addr::new(1, 1, 1, 1));
+        let reply = reply_to(&echo.packet);
+        drop(echo);
+        assert!(pinger.waiting.lock().is_empty());
+        // Still ours, just no longer awaited
+        assert!(pinger.accept(&reply));
+    }
+}
@@ -0,0 +1,68 @@
+//! Java callbacks for reads and writes that complete in the background.
+//!
+//! The byte array of an async read is held by a global reference until the
+//! read finishes, then filled in on the completing thread just before
+//! `onComplete` is called.
+
+use jni::objects::{GlobalRef, JByteArray, JValue};
+use jni::JavaVM;
+
+pub const COMPLETE_METHOD_SIG: &str = "(JI)V";
+pub const ERROR_METHOD_SIG: &str = "(JILjava/lang/String;)V";
+
+/// Java object implementing `IoCallback`.
+pub struct IoCallback {
+    vm: JavaVM,
+    callback: GlobalRef,
+}
+
+impl IoCallback {
+    pub fn new(vm: JavaVM, callback: GlobalRef) -> Self {
+        Self { vm, callback }
+    }
+
+    /// Copy `data` into the start of `buffer`, then call `onComplete` with its length.
+    pub fn complete_read(&self, handle: i64, buffer: &GlobalRef, data: &[u8]) -> jni::errors::Result<()> {
+        if !data.is_empty() {
+            let env = self.vm.attach_current_thread_as_daemon()?;
+            let array = <&JByteArray>::from(buffer.as_obj());
+            env.set_byte_array_region(array, 0, crate::jbytes::as_jbytes(data))?;
+        }
+        self.complete(handle, data.len() as i32)
+    }
+
+    /// Call `onComplete` with the number of bytes transferred, 0 meaning EOF for reads.
+    pub fn complete(&self, handle: i64, result: i32) -> jni::errors::Result<()> {
+        let mut env = self.vm.attach_current_thread_as_daemon()?;
+        let result = env
+            .call_method(
+                &self.callback,
+                "onComplete",
+                COMPLETE_METHOD_SIG,
+                &[JValue::Long(handle), JValue::Int(result)],
+            )
+            .map(|_| ());
+        if result.is_err() {
+            let _ = env.exception_clear();
+        }
+        result
+    }
+
+    /// Call `onError` with a `TCP_ERROR_*` code and message.
+    pub fn fail(&self, handle: i64, code: i32, message: &str) -> jni::errors::Result<()> {
+        let mut env = self.vm.attach_current_thread_as_daemon()?;
+        let message = env.new_string(message)?;
+        let result = env
+            .call_method(
+                &self.callback,
+                "onError",
+                ERROR_METHOD_SIG,
+                &[JValue::Long(handle), JValue::Int(code), JValue::Object(&message)],
+            )
+            .map(|_| ());
+        if result.is_err() {
+            let _ = env.exception_clear();
+        }
+        result
+    }
+}
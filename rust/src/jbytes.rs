@@ -0,0 +1,23 @@
+//! Copies between Java byte arrays and Rust buffers.
+//!
+//! JNI region copies take `jbyte` (i8) slices. Viewing a `u8` buffer as one
+//! lets them copy straight into and out of it, with no per-byte conversion
+//! or temporary array, and the buffers themselves are reused between calls.
+
+/// View bytes as the `jbyte` slice used by JNI array region copies.
+pub fn as_jbytes(bytes: &[u8]) -> &[i8] {
+    // SAFETY: u8 and i8 have the same size and alignment
+    unsafe { std::slice::from_raw_parts(bytes.as_ptr().cast(), bytes.len()) }
+}
+
+pub fn as_jbytes_mut(bytes: &mut [u8]) -> &mut [i8] {
+    // SAFETY: u8 and i8 have the same size and alignment, and any bit pattern is valid for both
+    unsafe { std::slice::from_raw_parts_mut(bytes.as_mut_ptr().cast(), bytes.len()) }
+}
+
+thread_local! {
+    /// Buffer reused by tcpWrite on each JNI thread, so steady traffic does
+    /// not allocate. Moved into the I/O future and back. Reads use the
+    /// connection's own buffer instead.
+    pub static WRITE_BUF: std::cell::Cell<Vec<u8>> = const { std::cell::Cell::new(Vec::new()) };
+}
@@ -3,22 +3,81 @@
 //! Exposes WireGuard tunnel functionality to Java via JNI.
 //! Uses wireguard-netstack for userspace WireGuard with embedded TCP/IP stack.
 
-use jni::objects::{JByteArray, JClass, JString};
-use jni::sys::{jint, jlong, jstring};
+use jni::objects::{GlobalRef, JByteArray, JByteBuffer, JClass, JIntArray, JLongArray, JObject, JObjectArray, JString};
+use jni::sys::{jboolean, jint, jlong, jlongArray, jobject, jobjectArray, jstring, JNI_FALSE, JNI_TRUE};
 use jni::JNIEnv;
-use once_cell::sync::OnceCell;
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicI64, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
 use thiserror::Error;
-use tokio::runtime::{Handle, Runtime};
-use warp_wireguard_gen::{get_config, register, RegistrationOptions, WarpCredentials};
-use wireguard_netstack::{ManagedTunnel, NetStack, TcpConnection, WireGuardConfig};
+use tokio::runtime::Handle;
+use warp_wireguard_gen::{
+    get_config, register, update_license, RegistrationOptions, TeamsEnrollment, WarpCredentials,
+};
+use wireguard_netstack::{NetStack, TcpConnection, WireGuardConfig};
+
+mod awg;
+#[cfg(feature = "c-api")]
+mod capi;
+mod capture;
+mod config_cache;
+mod connection;
+mod cpu;
+mod credential_crypto;
+mod diagnostics;
+mod dns;
+mod endpoint;
+mod eyeballs;
+mod ffm;
+mod handles;
+mod history;
+mod https;
+mod icmp;
+mod io_callback;
+mod jbytes;
+mod listener;
+#[cfg(target_os = "android")]
+mod logcat;
+mod logging;
+mod metrics;
+mod minecraft;
+mod onload;
+mod panic_guard;
+mod pool;
+mod policy;
+mod profile;
+mod proxy_protocol;
+mod ratelimit;
+mod read_ahead;
+mod ring;
+mod routing;
+mod runtime;
+mod selftest;
+mod stream;
+mod stun;
+mod suspend;
+mod trace;
+mod transport;
+mod tunnel;
+mod udp;
+mod udp_batch;
+mod udp_forward;
+mod warp_account;
+mod warp_retry;
+mod websocket;
+mod write_behind;
+
+use connection::{ConnectTrace, Connection, ErrorCode};
+use jbytes::{as_jbytes, as_jbytes_mut, WRITE_BUF};
+use smoltcp::socket::tcp::State as TcpState;
+use credential_crypto::CredentialKey;
+use warp_account::AccountType;
 
 // ============================================================================
 // Error types
@@ -34,31 +93,104 @@ pub enum TunnelError {
     NotReady,
     #[error("WARP registration failed: {0}")]
     WarpRegistration(String),
+    #[error("WARP API request failed: {0}")]
+    WarpApi(String),
     #[error("Credential persistence failed: {0}")]
     CredentialPersistence(String),
+    #[error("Credentials are encrypted: {0}")]
+    CredentialsLocked(String),
+    #[error("DNS resolution failed: {0}")]
+    Dns(String),
     #[error("Connection failed: {0}")]
     ConnectionFailed(String),
     #[error("Invalid handle: {0}")]
     InvalidHandle(i64),
+    #[error("Stale handle: {0} was already closed")]
+    StaleHandle(i64),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("Timeout")]
     Timeout,
+    #[error("Operation would block")]
+    WouldBlock,
+    #[error("Connection limit reached: {0} connections open")]
+    TooManyConnections(usize),
+    #[error("Tunnel is reconnecting, retry the operation")]
+    Reconnecting,
+    #[error("{0} cannot change without restarting the tunnel")]
+    RestartRequired(&'static str),
+    #[error("Connection to {0} is not allowed by the connection policy")]
+    PolicyDenied(String),
+}
+
+impl TunnelError {
+    /// Stable code for Java, one of the TUNNEL_ERROR_* constants.
+    fn code(&self) -> i32 {
+        match self {
+            TunnelError::NotInitialized => 1,
+            TunnelError::AlreadyRunning => 2,
+            TunnelError::NotReady => 3,
+            TunnelError::WarpRegistration(_) => 4,
+            TunnelError::WarpApi(_) => 5,
+            TunnelError::CredentialPersistence(_) => 6,
+            TunnelError::CredentialsLocked(_) => 7,
+            TunnelError::Dns(_) => 8,
+            TunnelError::ConnectionFailed(_) => 9,
+            TunnelError::InvalidHandle(_) => 10,
+            TunnelError::StaleHandle(_) => 11,
+            TunnelError::Io(_) => 12,
+            TunnelError::Timeout => 13,
+            TunnelError::WouldBlock => 14,
+            TunnelError::TooManyConnections(_) => 15,
+            TunnelError::Reconnecting => 16,
+            TunnelError::RestartRequired(_) => 17,
+            TunnelError::PolicyDenied(_) => 18,
+        }
+    }
+}
+
+/// The most recent failure to start, reconnect or reconfigure the tunnel.
+struct LastTunnelError {
+    code: i32,
+    message: String,
+    at: Instant,
 }
 
 // ============================================================================
 // WARP Credentials persistence
 // ============================================================================
 
-fn load_credentials(cred_path: &str) -> Result<WarpCredentials, TunnelError> {
-    let content = fs::read_to_string(cred_path)
+/// Location of the credentials file plus the optional key used to encrypt it.
+struct CredentialFile {
+    path: String,
+    key: Option<CredentialKey>,
+}
+
+fn load_credentials(cred_file: &CredentialFile) -> Result<WarpCredentials, TunnelError> {
+    let content = fs::read_to_string(&cred_file.path)
         .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to read: {}", e)))?;
-    serde_json::from_str(&content)
-        .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to parse: {}", e)))
+
+    let encrypted = credential_crypto::is_encrypted(&content);
+    let plaintext = if encrypted {
+        credential_crypto::decrypt(cred_file.key.as_ref(), &content)?
+    } else {
+        content.into_bytes()
+    };
+
+    let credentials = serde_json::from_slice(&plaintext)
+        .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to parse: {}", e)))?;
+
+    // Migrate plaintext credentials once a key is configured
+    if !encrypted && cred_file.key.is_some() {
+        log::info!("Encrypting existing plaintext WARP credentials");
+        save_credentials(cred_file, &credentials)?;
+    }
+
+    Ok(credentials)
 }
 
-fn save_credentials(cred_path: &str, credentials: &WarpCredentials) -> Result<(), TunnelError> {
-    let path = PathBuf::from(cred_path);
+fn save_credentials(cred_file: &CredentialFile, credentials: &WarpCredentials) -> Result<(), TunnelError> {
+    let path = PathBuf::from(&cred_file.path);
     
     // Ensure parent directory exists
     if let Some(parent) = path.parent() {
@@ -68,10 +200,14 @@ fn save_credentials(cred_path: &str, credentials: &WarpCredentials) -> Result<()
 
     let content = serde_json::to_string_pretty(credentials)
         .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to serialize: {}", e)))?;
+    let content = match &cred_file.key {
+        Some(key) => credential_crypto::encrypt(key, content.as_bytes())?,
+        None => content,
+    };
     fs::write(&path, content)
         .map_err(|e| TunnelError::CredentialPersistence(format!("Failed to write: {}", e)))?;
 
-    log::info!("WARP credentials saved to {}", cred_path);
+    log::info!("WARP credentials saved to {}", cred_file.path);
     Ok(())
 }
 
@@ -81,25 +217,130 @@ fn save_credentials(cred_path: &str, credentials: &WarpCredentials) -> Result<()
 /// and other proxies that may reject connections with unusually small MSS.
 const WIREGUARD_MTU: u16 = 1420;
 
-async fn load_or_register_warp(cred_path: &str) -> Result<(WireGuardConfig, WarpCredentials), TunnelError> {
+/// Accepted range for a configured MTU: the IPv4 minimum up to a full Ethernet frame.
+const MIN_MTU: u16 = 576;
+const MAX_MTU: u16 = 1500;
+
+/// Persistent keepalive interval in seconds, matching the official WARP client.
+const DEFAULT_KEEPALIVE_SECONDS: u16 = 25;
+
+/// Bind a WARP+ license key to the device if it is not already applied.
+///
+/// A rejected key is not fatal: the tunnel still comes up on the existing account.
+async fn apply_license_key(cred_file: &CredentialFile, credentials: &mut WarpCredentials, license_key: &str) {
+    if credentials.is_teams {
+        log::warn!("Ignoring WARP+ license key for a Zero Trust enrollment");
+        return;
+    }
+    if credentials.license_key == license_key {
+        return;
+    }
+
+    log::info!("Applying WARP+ license key...");
+    match update_license(credentials, license_key).await {
+        Ok(()) => {
+            credentials.license_key = license_key.to_string();
+            if let Err(e) = save_credentials(cred_file, credentials) {
+                log::warn!("Failed to persist license key: {}", e);
+            }
+        }
+        Err(e) => log::warn!("Failed to apply WARP+ license key: {}", e),
+    }
+}
+
+/// Fetch the device's WireGuard config, retrying transient API failures.
+async fn fetch_config(credentials: &WarpCredentials) -> Result<WireGuardConfig, warp_wireguard_gen::Error> {
+    warp_retry::retrying("WARP config fetch", warp_retry::classify, || get_config(credentials)).await
+}
+
+/// Fetch a fresh config for `credentials` in the background and cache it.
+///
+/// The running tunnel keeps its config; the refreshed one is used from the next start.
+fn refresh_config_cache(credentials: WarpCredentials, cache_path: PathBuf) {
+    global().handle.spawn(async move {
+        match fetch_config(&credentials).await {
+            Ok(config) => match config_cache::save(&cache_path, &credentials, &config) {
+                Ok(()) => log::debug!("Refreshed cached WireGuard config"),
+                Err(e) => log::warn!("Failed to cache WireGuard config: {}", e),
+            },
+            Err(e) => log::warn!("Failed to refresh cached WireGuard config: {}", e),
+        }
+    });
+}
+
+/// Load persisted credentials or register a new device.
+///
+/// With existing credentials, a cached config younger than
+/// `config_cache::CONFIG_TTL` is used right away and refreshed in the
+/// background; an older one is only used if fetching a fresh one fails.
+///
+/// `options.teams` selects a Zero Trust enrollment; stored credentials from the
+/// other kind of enrollment are an error, never replaced.
+async fn load_or_register_warp(
+    cred_file: &CredentialFile,
+    options: RegistrationOptions,
+) -> Result<(WireGuardConfig, WarpCredentials), TunnelError> {
+    let cred_path = cred_file.path.as_str();
     let path = PathBuf::from(cred_path);
+    let cache_path = config_cache::path_for(cred_path, global().cache_dir.read().as_deref());
+    let wants_teams = options.teams.is_some();
 
     // Try to load existing credentials
     if path.exists() {
-        match load_credentials(cred_path) {
-            Ok(credentials) => {
+        match load_credentials(cred_file) {
+            // Never overwrite credentials we merely failed to decrypt
+            Err(e @ TunnelError::CredentialsLocked(_)) => return Err(e),
+            // Registering here would overwrite the other enrollment's key
+            Ok(credentials) if credentials.is_teams != wants_teams => {
+                let kind = |teams: bool| if teams { "Zero Trust" } else { "consumer WARP" };
+                return Err(TunnelError::CredentialPersistence(format!(
+                    "{} holds a {} enrollment, use another credentials path for {}",
+                    cred_path,
+                    kind(credentials.is_teams),
+                    kind(wants_teams)
+                )));
+            }
+            Ok(mut credentials) => {
                 log::info!("Loaded existing WARP credentials from {}", cred_path);
-                // Get fresh config using existing credentials
-                match get_config(&credentials).await {
-                    Ok(mut config) => {
-                        // Set proper MTU for compatibility with proxied servers
-                        config.mtu = Some(WIREGUARD_MTU);
-                        log::info!("Using MTU {} for WireGuard tunnel", WIREGUARD_MTU);
-                        return Ok((config, credentials));
-                    }
-                    Err(e) => {
-                        log::warn!("Failed to get config with existing credentials: {}, re-registering", e);
+                if let Some(key) = &options.license_key {
+                    apply_license_key(cred_file, &mut credentials, key).await;
+                }
+                match config_cache::load(&cache_path, &credentials) {
+                    Some(cached) if cached.age < config_cache::CONFIG_TTL => {
+                        log::info!(
+                            "Using cached WireGuard config ({}s old), refreshing in the background",
+                            cached.age.as_secs()
+                        );
+                        refresh_config_cache(credentials.clone(), cache_path);
+                        return Ok((cached.config, credentials));
                     }
+                    // Get fresh config using existing credentials
+                    cached => match fetch_config(&credentials).await {
+                        Ok(config) => {
+                            if let Err(e) = config_cache::save(&cache_path, &credentials, &config) {
+                                log::warn!("Failed to cache WireGuard config: {}", e);
+                            }
+                            return Ok((config, credentials));
+                        }
+                        Err(e) => match cached {
+                            Some(cached) => {
+                                log::warn!(
+                                    "Failed to get config with existing credentials: {}, using cached config ({}s old)",
+                                    e,
+                                    cached.age.as_secs()
+                                );
+                                return Ok((cached.config, credentials));
+                            }
+                            // Only a rejection means the device is gone; registering
+                            // another on an outage would pile up devices on the account
+                            None if !matches!(warp_retry::classify(&e), warp_retry::Retry::No) => {
+                                return Err(TunnelError::WarpApi(format!("Failed to get config: {}", e)));
+                            }
+                            None => {
+                                log::warn!("Failed to get config with existing credentials: {}, re-registering", e);
+                            }
+                        },
+                    },
                 }
             }
             Err(e) => {
@@ -108,18 +349,29 @@ async fn load_or_register_warp(cred_path: &str) -> Result<(WireGuardConfig, Warp
         }
     }
 
-    // Register new WARP device
+    // Register new WARP device; the license key is applied separately so a
+    // rejected key does not prevent registration
     log::info!("Registering new WARP device...");
-    let (mut config, credentials) = register(RegistrationOptions::default())
-        .await
-        .map_err(|e| TunnelError::WarpRegistration(e.to_string()))?;
-
-    // Set proper MTU for compatibility with proxied servers
-    config.mtu = Some(WIREGUARD_MTU);
-    log::info!("Using MTU {} for WireGuard tunnel", WIREGUARD_MTU);
+    let license_key = options.license_key.clone();
+    let options = RegistrationOptions {
+        license_key: None,
+        ..options
+    };
+    let (config, mut credentials) = warp_retry::retrying("WARP registration", warp_retry::classify, || {
+        register(options.clone())
+    })
+    .await
+    .map_err(|e| TunnelError::WarpRegistration(e.to_string()))?;
 
     // Persist credentials
-    save_credentials(cred_path, &credentials)?;
+    save_credentials(cred_file, &credentials)?;
+    if let Err(e) = config_cache::save(&cache_path, &credentials, &config) {
+        log::warn!("Failed to cache WireGuard config: {}", e);
+    }
+
+    if let Some(key) = &license_key {
+        apply_license_key(cred_file, &mut credentials, key).await;
+    }
 
     log::info!("WARP device registered successfully");
     Ok((config, credentials))
@@ -129,31 +381,178 @@ async fn load_or_register_warp(cred_path: &str) -> Result<(WireGuardConfig, Warp
 // TCP Connection Handle Management
 // ============================================================================
 
+/// Map a rejected handle to the error Java sees.
+fn handle_error(handle: i64, err: handles::HandleError) -> TunnelError {
+    match err {
+        handles::HandleError::Invalid => TunnelError::InvalidHandle(handle),
+        handles::HandleError::Stale => TunnelError::StaleHandle(handle),
+    }
+}
+
+/// Payload totals of closed connections to one destination.
+#[derive(Default, Clone, Copy)]
+struct DestinationTotals {
+    connections: u64,
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+/// Read-ahead buffer for new connections until `setReadAhead` changes it.
+const DEFAULT_READ_AHEAD: usize = 64 * 1024;
+
+/// Write-behind queue for new connections until `setWriteBehind` changes it;
+/// off, since a write then returns before the data is sent.
+const DEFAULT_WRITE_BEHIND: usize = 0;
+
 struct ConnectionManager {
-    connections: RwLock<HashMap<i64, Arc<TcpConnection>>>,
-    next_handle: AtomicI64,
+    connections: handles::HandleTable<Connection>,
+    /// Payload bytes [read, written] of closed connections, for metrics.
+    closed_tunnel: [AtomicU64; 2],
+    closed_direct: [AtomicU64; 2],
+    /// Closed connection totals by destination IP and whether it was tunneled.
+    closed_by_destination: parking_lot::Mutex<HashMap<(IpAddr, bool), DestinationTotals>>,
+    /// Bandwidth cap shared by all tunneled connections.
+    tunnel_limit: Arc<ratelimit::RateLimit>,
+    /// Opt-in spares for repeated connects to the same server.
+    pool: pool::ConnectionPool,
+    /// Whether connections hold back small writes, shared with each of them.
+    coalesce: Arc<AtomicBool>,
+    /// Background task sending coalesced writes.
+    flusher: parking_lot::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    /// Background task reporting closes by the peer to the Java listener.
+    close_watch: parking_lot::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    /// How long tcpClose waits for sent data to be acknowledged, in ms.
+    linger_ms: AtomicU64,
+    /// Wakes a tcpSelect in progress; `wakeup_pending` also makes the next
+    /// one return at once, like Selector#wakeup.
+    select_wake: tokio::sync::Notify,
+    wakeup_pending: AtomicBool,
+    /// Read-ahead buffer size for new connections in bytes; 0 disables it.
+    read_ahead: AtomicUsize,
+    /// Write-behind queue size for new connections in bytes; 0 disables it.
+    write_behind: AtomicUsize,
+    /// Most connections open at once; 0 for no limit.
+    max_connections: AtomicUsize,
+    /// Background task closing connections idle for too long.
+    idle_reaper: parking_lot::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    /// Shared-memory rings attached with tcpAttachRings, by handle.
+    rings: parking_lot::Mutex<HashMap<i64, Arc<ring::Rings>>>,
+    /// Fails reads on tunneled connections that wait on a stale session.
+    reconnect: Arc<tokio::sync::Notify>,
+    /// Runs the read-ahead, write-behind and ring tasks.
+    runtime: Handle,
 }
 
 impl ConnectionManager {
-    fn new() -> Self {
+    fn new(runtime: Handle) -> Self {
         Self {
-            connections: RwLock::new(HashMap::new()),
-            next_handle: AtomicI64::new(1),
+            connections: handles::HandleTable::default(),
+            closed_tunnel: Default::default(),
+            closed_direct: Default::default(),
+            closed_by_destination: parking_lot::Mutex::new(HashMap::new()),
+            tunnel_limit: Arc::default(),
+            pool: pool::ConnectionPool::default(),
+            coalesce: Arc::default(),
+            flusher: parking_lot::Mutex::new(None),
+            close_watch: parking_lot::Mutex::new(None),
+            linger_ms: AtomicU64::new(0),
+            select_wake: tokio::sync::Notify::new(),
+            wakeup_pending: AtomicBool::new(false),
+            read_ahead: AtomicUsize::new(DEFAULT_READ_AHEAD),
+            write_behind: AtomicUsize::new(DEFAULT_WRITE_BEHIND),
+            max_connections: AtomicUsize::new(0),
+            idle_reaper: parking_lot::Mutex::new(None),
+            rings: parking_lot::Mutex::new(HashMap::new()),
+            reconnect: Arc::default(),
+            runtime,
+        }
+    }
+
+    fn closed_totals(&self, tunneled: bool) -> &[AtomicU64; 2] {
+        if tunneled {
+            &self.closed_tunnel
+        } else {
+            &self.closed_direct
+        }
+    }
+
+    /// Payload bytes [read, written] over one route, open and closed
+    /// connections together.
+    fn route_totals(&self, tunneled: bool) -> [u64; 2] {
+        let closed = self.closed_totals(tunneled);
+        let mut totals = [closed[0].load(Ordering::Relaxed), closed[1].load(Ordering::Relaxed)];
+        for (_, conn) in self.snapshot() {
+            if conn.is_tunneled() == tunneled {
+                totals[0] += conn.stats().bytes_read.load(Ordering::Relaxed);
+                totals[1] += conn.stats().bytes_written.load(Ordering::Relaxed);
+            }
+        }
+        totals
+    }
+
+    fn insert(&self, mut conn: Connection) -> i64 {
+        if conn.is_tunneled() {
+            conn.set_shared_limit(self.tunnel_limit.clone());
+            conn.set_reconnect_signal(self.reconnect.clone());
+        }
+        conn.set_coalescing(self.coalesce.clone());
+        let read_ahead = self.read_ahead.load(Ordering::Relaxed);
+        if read_ahead > 0 {
+            conn.set_read_ahead(read_ahead);
+        }
+        let write_behind = self.write_behind.load(Ordering::Relaxed);
+        if write_behind > 0 {
+            conn.set_write_behind(write_behind);
         }
+        let conn = Arc::new(conn);
+        conn.start_read_ahead(&self.runtime);
+        conn.start_write_behind(&self.runtime);
+        self.connections.insert(conn)
     }
 
-    fn insert(&self, conn: TcpConnection) -> i64 {
-        let handle = self.next_handle.fetch_add(1, Ordering::SeqCst);
-        self.connections.write().insert(handle, Arc::new(conn));
-        handle
+    fn get(&self, handle: i64) -> Result<Arc<Connection>, TunnelError> {
+        self.connections.get(handle).map_err(|e| handle_error(handle, e))
     }
 
-    fn get(&self, handle: i64) -> Option<Arc<TcpConnection>> {
-        self.connections.read().get(&handle).cloned()
+    fn remove(&self, handle: i64) -> Result<Arc<Connection>, TunnelError> {
+        let conn = self.connections.remove(handle).map_err(|e| handle_error(handle, e))?;
+        conn.stop_read_ahead();
+        if let Some(rings) = self.rings.lock().remove(&handle) {
+            rings.stop();
+        }
+
+        let (rx_bytes, tx_bytes) = (
+            conn.stats().bytes_read.load(Ordering::Relaxed),
+            conn.stats().bytes_written.load(Ordering::Relaxed),
+        );
+        let totals = self.closed_totals(conn.is_tunneled());
+        totals[0].fetch_add(rx_bytes, Ordering::Relaxed);
+        totals[1].fetch_add(tx_bytes, Ordering::Relaxed);
+        let destination = (conn.remote_addr().ip().to_canonical(), conn.is_tunneled());
+        let mut by_destination = self.closed_by_destination.lock();
+        let totals = by_destination.entry(destination).or_default();
+        totals.connections += 1;
+        totals.rx_bytes += rx_bytes;
+        totals.tx_bytes += tx_bytes;
+        drop(by_destination);
+        Ok(conn)
+    }
+
+    /// Fail if opening another connection would exceed the limit.
+    fn check_capacity(&self) -> Result<(), TunnelError> {
+        let max = self.max_connections.load(Ordering::Relaxed);
+        if max == 0 {
+            return Ok(());
+        }
+        let open = self.connections.len();
+        if open >= max {
+            return Err(TunnelError::TooManyConnections(open));
+        }
+        Ok(())
     }
 
-    fn remove(&self, handle: i64) -> Option<Arc<TcpConnection>> {
-        self.connections.write().remove(&handle)
+    fn snapshot(&self) -> Vec<(i64, Arc<Connection>)> {
+        self.connections.snapshot()
     }
 }
 
@@ -168,73 +567,217 @@ pub enum TunnelState {
     Starting = 1,
     Ready = 2,
     Failed = 3,
+    Paused = 4,
 }
 
 struct ActiveTunnel {
-    #[allow(dead_code)]
-    tunnel: ManagedTunnel,
-    netstack: Arc<NetStack>,
+    tunnel: Arc<tunnel::Tunnel>,
+    /// DNS resolver bound to the tunnel's netstack.
+    resolver: Arc<dns::Resolver>,
+    account_type: AccountType,
+    /// Device ID assigned by WARP at registration; `None` for imported profiles.
+    device_id: Option<String>,
+    /// The device's WireGuard public key, base64, if the API returned it.
+    public_key: Option<String>,
+    /// Interface addresses, IPv4 first.
+    addresses: Vec<String>,
+    /// Config the tunnel was built from, for reconnecting to another endpoint.
+    config: WireGuardConfig,
+    /// Endpoint candidates, and the one in use.
+    endpoints: Vec<SocketAddr>,
+    endpoint_index: usize,
+    /// Outer transport and obfuscation the tunnel was started with.
+    outer: transport::OuterConfig,
+    /// MTU passed to the start call, which reloads keep over the profile's.
+    mtu: Option<u16>,
+    /// Private and public key from `rotateKeys`, used once `confirmKeyRotation` is called.
+    rotated_key: Option<([u8; 32], [u8; 32])>,
+}
+
+/// How `reloadTunnelConfig` applied a profile.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(i32)]
+pub enum ReloadResult {
+    /// Nothing the tunnel uses changed.
+    Unchanged = 0,
+    /// The tunnel was rebuilt, closing tunneled connections.
+    Reconnected = 1,
+    /// Only the DNS servers changed, so nothing was reconnected.
+    DnsUpdated = 2,
+    /// Only the endpoint changed, and the tunnel moved there keeping its connections.
+    Moved = 3,
+}
+
+/// What `tcpConnect` does when the tunnel is not available.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(i32)]
+pub enum ConnectPolicy {
+    /// Fail the connection rather than expose the real IP.
+    KillSwitch = 0,
+    /// Connect directly, outside the tunnel.
+    FallbackDirect = 1,
+}
+
+impl ConnectPolicy {
+    fn from_jint(value: jint) -> Option<Self> {
+        match value {
+            0 => Some(ConnectPolicy::KillSwitch),
+            1 => Some(ConnectPolicy::FallbackDirect),
+            _ => None,
+        }
+    }
 }
 
 // ============================================================================
 // Global State
 // ============================================================================
 
+/// Source of the credential encryption key, configured from Java.
+enum CredentialSecret {
+    Passphrase(String),
+    /// Java object implementing `CredentialKeyProvider`.
+    Provider(GlobalRef),
+}
+
+/// Tunnel settings configured from Java.
+///
+/// MTU and keepalive are applied on the next start; the rest are read per connection.
+#[derive(Clone)]
+struct TunnelOptions {
+    mtu: u16,
+    /// Persistent keepalive interval; `None` disables it.
+    keepalive_seconds: Option<u16>,
+    connect_policy: ConnectPolicy,
+    /// Endpoint used instead of the one from the WARP config.
+    endpoint_override: Option<endpoint::EndpointOverride>,
+    /// How encrypted packets reach the endpoint.
+    outer: transport::OuterConfig,
+    /// Preshared key mixed into handshakes, for servers that require one.
+    preshared_key: Option<[u8; 32]>,
+    /// Servers for hostname lookups; `None` uses the profile's or Cloudflare's.
+    dns_servers: Option<Vec<dns::Server>>,
+}
+
+impl Default for TunnelOptions {
+    fn default() -> Self {
+        Self {
+            mtu: WIREGUARD_MTU,
+            keepalive_seconds: Some(DEFAULT_KEEPALIVE_SECONDS),
+            connect_policy: ConnectPolicy::KillSwitch,
+            endpoint_override: None,
+            outer: transport::OuterConfig::default(),
+            preshared_key: None,
+            dns_servers: None,
+        }
+    }
+}
+
 struct GlobalState {
-    #[allow(dead_code)]
-    runtime: Runtime,
+    /// Taken out by `shutdownNative` to shut the runtime down.
+    runtime: parking_lot::Mutex<Option<runtime::NativeRuntime>>,
     handle: Handle,
     tunnel: RwLock<Option<ActiveTunnel>>,
     connections: ConnectionManager,
+    credential_secret: RwLock<Option<CredentialSecret>>,
+    /// Directory for the WARP config cache; next to the credentials if unset.
+    cache_dir: RwLock<Option<PathBuf>>,
+    options: RwLock<TunnelOptions>,
+    router: RwLock<routing::Router>,
+    /// Destinations new connections may go to.
+    policy: RwLock<policy::Policy>,
+    /// Destinations that get a PROXY protocol header, and what it carries.
+    proxy_protocol: RwLock<proxy_protocol::Rules>,
+    /// WebSockets opened with `wsConnect`, all carried by the tunnel.
+    websockets: websocket::Registry,
+    /// UDP port forwards from `udpForwardStart`.
+    udp_forwards: udp_forward::Registry,
+    capture: capture::PacketCapture,
+    /// Fails the tunnel over to another endpoint when the active one dies.
+    endpoint_watchdog: parking_lot::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    /// Re-handshakes when the machine wakes from sleep.
+    sleep_watchdog: parking_lot::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    /// Handshakes of every tunnel since initJNI.
+    handshakes: Arc<tunnel::HandshakeStats>,
+    /// Forwards handshake events to the Java tunnel listener.
+    tunnel_listener: parking_lot::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    /// Per-second tunnel throughput while a tunnel runs, and the task sampling it.
+    throughput: history::ThroughputHistory,
+    throughput_sampler: parking_lot::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    /// Kept across tunnel restarts, for `lastTunnelError`.
+    last_tunnel_error: parking_lot::Mutex<Option<LastTunnelError>>,
 }
 
 impl GlobalState {
     fn new() -> Self {
-        let runtime = tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(4)
-            .enable_all()
-            .build()
-            .expect("Failed to create Tokio runtime");
-
-        let handle = runtime.handle().clone();
+        let runtime = runtime::NativeRuntime::build().expect("Failed to create Tokio runtime");
+        let handle = runtime.handle();
 
         Self {
-            runtime,
-            handle,
+            runtime: parking_lot::Mutex::new(Some(runtime)),
+            handle: handle.clone(),
             tunnel: RwLock::new(None),
-            connections: ConnectionManager::new(),
+            connections: ConnectionManager::new(handle),
+            credential_secret: RwLock::new(None),
+            cache_dir: RwLock::new(None),
+            options: RwLock::new(TunnelOptions::default()),
+            router: RwLock::new(routing::Router::default()),
+            policy: RwLock::new(policy::Policy::default()),
+            proxy_protocol: RwLock::new(proxy_protocol::Rules::default()),
+            websockets: websocket::Registry::default(),
+            udp_forwards: udp_forward::Registry::default(),
+            capture: capture::PacketCapture::default(),
+            endpoint_watchdog: parking_lot::Mutex::new(None),
+            sleep_watchdog: parking_lot::Mutex::new(None),
+            handshakes: Arc::default(),
+            tunnel_listener: parking_lot::Mutex::new(None),
+            throughput: history::ThroughputHistory::default(),
+            throughput_sampler: parking_lot::Mutex::new(None),
+            last_tunnel_error: parking_lot::Mutex::new(None),
         }
     }
 
-    fn netstack(&self) -> Result<Arc<NetStack>, TunnelError> {
-        self.tunnel
-            .read()
-            .as_ref()
-            .map(|t| t.netstack.clone())
-            .ok_or(TunnelError::NotInitialized)
+    /// Remember a tunnel failure for `lastTunnelError`; `message` is what Java was told.
+    fn record_tunnel_error(&self, err: &TunnelError, message: String) {
+        *self.last_tunnel_error.lock() = Some(LastTunnelError {
+            code: err.code(),
+            message,
+            at: Instant::now(),
+        });
+    }
+
+    fn resolver(&self) -> Result<Arc<dns::Resolver>, TunnelError> {
+        match self.tunnel.read().as_ref() {
+            Some(t) if t.tunnel.is_paused() => Err(TunnelError::NotReady),
+            Some(t) => Ok(t.resolver.clone()),
+            None => Err(TunnelError::NotInitialized),
+        }
     }
 
-    /// Run an async block on the runtime, safe to call from any thread.
-    /// This spawns the future on the runtime and blocks until completion.
+    /// Run an async block on the runtime, safe to call from any thread; see
+    /// `runtime::block_on`.
     fn run<F, T>(&self, future: F) -> T
     where
         F: std::future::Future<Output = T> + Send + 'static,
         T: Send + 'static,
     {
-        // Spawn the future on the runtime and block on the result
-        let (tx, rx) = std::sync::mpsc::channel();
-        self.handle.spawn(async move {
-            let result = future.await;
-            let _ = tx.send(result);
-        });
-        rx.recv().expect("Runtime task panicked")
+        runtime::block_on(&self.handle, future)
+    }
+
+    /// Run a future that borrows from the caller, or return `None` where it
+    /// would have to be copied; see `runtime::block_on_borrowed`.
+    fn run_borrowed<F: std::future::Future>(&self, future: F) -> Option<F::Output> {
+        runtime::block_on_borrowed(&self.handle, future)
     }
 }
 
-static GLOBAL: OnceCell<GlobalState> = OnceCell::new();
+/// Global state, created on first use and dropped by `shutdownNative`.
+static GLOBAL: RwLock<Option<Arc<GlobalState>>> = RwLock::new(None);
 
-fn global() -> &'static GlobalState {
-    GLOBAL.get_or_init(GlobalState::new)
+fn global() -> Arc<GlobalState> {
+    if let Some(state) = GLOBAL.read().as_ref() {
+        return state.clone();
+    }
+    GLOBAL.write().get_or_insert_with(|| Arc::new(GlobalState::new())).clone()
 }
 
 // ============================================================================
@@ -245,12 +788,167 @@ fn throw_exception(env: &mut JNIEnv, msg: &str) {
     let _ = env.throw_new("java/lang/RuntimeException", msg);
 }
 
+/// Throw a read, write or flush failure.
+///
+/// Failures on tunneled connections while the tunnel is down are recorded
+/// as such for `tcpLastError`, whatever the netstack reported.
+fn throw_io_error(env: &mut JNIEnv, conn: &Connection, op: &str, err: &TunnelError) {
+    record_tunnel_down(conn);
+    let message = format!("{} error: {}", op, err);
+    throw_exception(env, &message);
+    conn.set_last_error_message(message);
+}
+
+/// Record a failure on a tunneled connection while the tunnel is down as such.
+fn record_tunnel_down(conn: &Connection) {
+    if conn.is_tunneled() && global().resolver().is_err() {
+        conn.set_last_error(ErrorCode::TunnelDown);
+    }
+}
+
+/// Report a failed async read or write to its callback.
+fn fail_async(callback: &io_callback::IoCallback, handle: i64, conn: &Connection, op: &str, err: &TunnelError) {
+    record_tunnel_down(conn);
+    let message = format!("{} error: {}", op, err);
+    conn.set_last_error_message(message.clone());
+    if let Err(e) = callback.fail(handle, conn.last_error(), &message) {
+        log::warn!("Failed to call IoCallback.onError, handle={}: {}", handle, e);
+    }
+}
+
+fn new_io_callback(env: &mut JNIEnv, callback: &JObject) -> jni::errors::Result<io_callback::IoCallback> {
+    let vm = env.get_java_vm()?;
+    Ok(io_callback::IoCallback::new(vm, env.new_global_ref(callback)?))
+}
+
+/// Throw an `InvalidConfigException` carrying `errors` as JSON.
+fn throw_config_errors(env: &mut JNIEnv, errors: &[profile::ConfigError]) {
+    let message = format!(
+        "Invalid WireGuard config: {}",
+        errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
+    );
+    let json = serde_json::to_string(errors).unwrap_or_default();
+    let thrown = (|| -> jni::errors::Result<()> {
+        let message = env.new_string(&message)?;
+        let json = env.new_string(json)?;
+        let exception = env.new_object(
+            "codes/dreaming/wireguard/jni/InvalidConfigException",
+            "(Ljava/lang/String;Ljava/lang/String;)V",
+            &[(&message).into(), (&json).into()],
+        )?;
+        env.throw(jni::objects::JThrowable::from(exception))
+    })();
+    if thrown.is_err() {
+        throw_exception(env, &message);
+    }
+}
+
+/// Throw a connection handle lookup error, using a dedicated exception for stale handles.
+fn throw_handle_error(env: &mut JNIEnv, err: &TunnelError) {
+    match err {
+        TunnelError::StaleHandle(_) => {
+            let _ = env.throw_new("codes/dreaming/wireguard/jni/StaleHandleException", err.to_string());
+        }
+        _ => throw_exception(env, &err.to_string()),
+    }
+}
+
 fn get_string(env: &mut JNIEnv, s: &JString) -> Result<String, String> {
     env.get_string(s)
         .map(|s| s.into())
         .map_err(|e| format!("Failed to get string: {}", e))
 }
 
+/// Build a Java `String[]` from `values`.
+fn new_string_array<T: ToString>(env: &mut JNIEnv, values: &[T]) -> Result<jobjectArray, String> {
+    let array = env
+        .new_object_array(values.len() as i32, "java/lang/String", JObject::null())
+        .map_err(|e| format!("Failed to create array: {}", e))?;
+    for (i, value) in values.iter().enumerate() {
+        let value = env
+            .new_string(value.to_string())
+            .map_err(|e| format!("Failed to create string: {}", e))?;
+        env.set_object_array_element(&array, i as i32, value)
+            .map_err(|e| format!("Failed to fill array: {}", e))?;
+    }
+    Ok(array.into_raw())
+}
+
+/// Build a Java `long[][]` from `rows`.
+fn new_long_arrays(env: &mut JNIEnv, rows: &[Vec<u64>]) -> Result<jobjectArray, String> {
+    let array = env
+        .new_object_array(rows.len() as i32, "[J", JObject::null())
+        .map_err(|e| format!("Failed to create array: {}", e))?;
+    for (i, row) in rows.iter().enumerate() {
+        let values: Vec<i64> = row.iter().map(|&value| value as i64).collect();
+        let row = env
+            .new_long_array(values.len() as i32)
+            .map_err(|e| format!("Failed to create array: {}", e))?;
+        env.set_long_array_region(&row, 0, &values)
+            .map_err(|e| format!("Failed to fill array: {}", e))?;
+        env.set_object_array_element(&array, i as i32, row)
+            .map_err(|e| format!("Failed to fill array: {}", e))?;
+    }
+    Ok(array.into_raw())
+}
+
+/// Resolve the configured credential secret into key material.
+///
+/// Calls into the Java key provider, so this must run on a JNI thread.
+fn resolve_credential_key(env: &mut JNIEnv) -> Result<Option<CredentialKey>, String> {
+    let provider = match global().credential_secret.read().as_ref() {
+        None => return Ok(None),
+        Some(CredentialSecret::Passphrase(p)) => return Ok(Some(CredentialKey::Passphrase(p.clone()))),
+        Some(CredentialSecret::Provider(provider)) => provider.clone(),
+    };
+
+    let value = env
+        .call_method(&provider, "getCredentialKey", "()[B", &[])
+        .and_then(|v| v.l())
+        .map_err(|e| {
+            let _ = env.exception_clear();
+            format!("Credential key provider failed: {}", e)
+        })?;
+    if value.is_null() {
+        return Err("Credential key provider returned null".into());
+    }
+
+    let bytes = env
+        .convert_byte_array(JByteArray::from(value))
+        .map_err(|e| format!("Failed to read credential key: {}", e))?;
+    let key: [u8; 32] = bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| format!("Credential key must be 32 bytes, got {}", b.len()))?;
+    Ok(Some(CredentialKey::Raw(key)))
+}
+
+/// Like `get_string`, but maps a null or empty Java string to `None`.
+fn get_optional_string(env: &mut JNIEnv, s: &JString) -> Result<Option<String>, String> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    get_string(env, s).map(|s| Some(s).filter(|s| !s.is_empty()))
+}
+
+/// Read a Java `String[]`, mapping a null array to an empty list.
+fn get_string_array(env: &mut JNIEnv, array: &JObjectArray) -> Result<Vec<String>, String> {
+    if array.is_null() {
+        return Ok(Vec::new());
+    }
+    let len = env.get_array_length(array).map_err(|e| format!("Failed to read array: {}", e))?;
+    let mut values = Vec::with_capacity(len as usize);
+    for i in 0..len {
+        let element = env
+            .get_object_array_element(array, i)
+            .map_err(|e| format!("Failed to read array: {}", e))?;
+        if element.is_null() {
+            return Err(format!("Element {} is null", i));
+        }
+        values.push(get_string(env, &JString::from(element))?);
+    }
+    Ok(values)
+}
+
 // ============================================================================
 // JNI Functions - Initialization
 // ============================================================================
@@ -258,405 +956,5656 @@ fn get_string(env: &mut JNIEnv, s: &JString) -> Result<String, String> {
 /// Initialize JNI - stores the JavaVM reference for later use.
 #[no_mangle]
 pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_initJNI(
-    env: JNIEnv,
+    mut env: JNIEnv,
     _class: JClass,
 ) {
-    // Initialize env_logger for Rust logging (respects RUST_LOG env var)
-    // Default to "info" level if RUST_LOG is not set
-    let _ = env_logger::Builder::from_env(
-        env_logger::Env::default().default_filter_or("info")
-    ).try_init();
+    panic_guard::catch(&mut env, (), |env| {
+        // Initialize logging (respects RUST_LOG env var, default "info").
+        // Output goes to stderr until a Java logger is registered.
+        logging::init();
     
-    let _ = env.get_java_vm().expect("Failed to get JavaVM");
-    // Initialize global state (creates runtime)
-    let _ = global();
+        let _ = env.get_java_vm().expect("Failed to get JavaVM");
+        // Initialize global state (creates runtime)
+        let _ = global();
     
-    log::info!("WireGuard Tunnel JNI initialized");
+        log::info!("WireGuard Tunnel JNI initialized");
+    })
+}
+
+/// Configure the Tokio runtime before it is built.
+/// 
+/// Must be called before `initJNI`, which builds the runtime.
+/// 
+/// @param workerThreads Worker threads, or 0 for a single-threaded low-footprint runtime
+/// @param blockingThreads Maximum blocking pool threads, or 0 for the Tokio default (512)
+/// @param stackSizeKb Stack size of runtime threads in KiB, or 0 for the Tokio default (2048)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_configureRuntime(
+    mut env: JNIEnv,
+    _class: JClass,
+    worker_threads: jint,
+    blocking_threads: jint,
+    stack_size_kb: jint,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        if GLOBAL.read().is_some() {
+            throw_exception(env, "Runtime already started; configureRuntime must be called before initJNI");
+            return;
+        }
+        if worker_threads < 0 || blocking_threads < 0 || stack_size_kb < 0 {
+            throw_exception(env, "Runtime settings must not be negative");
+            return;
+        }
+
+        runtime::configure(runtime::RuntimeConfig {
+            worker_threads: worker_threads as usize,
+            max_blocking_threads: (blocking_threads > 0).then_some(blocking_threads as usize),
+            thread_stack_size: (stack_size_kb > 0).then_some(stack_size_kb as usize * 1024),
+        });
+    })
+}
+
+/// Forward native log records to a Java logger instead of stderr.
+/// 
+/// @param logger Object implementing `NativeLogger`, or null to log to stderr again
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_registerLogger<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    logger: JObject<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        if logger.is_null() {
+            logging::set_java_logger(None);
+            return;
+        }
+
+        let logger = env
+            .get_java_vm()
+            .and_then(|vm| Ok(logging::JavaLogger::new(vm, env.new_global_ref(&logger)?)));
+        match logger {
+            Ok(logger) => logging::set_java_logger(Some(logger)),
+            Err(e) => throw_exception(env, &format!("Failed to store logger: {}", e)),
+        }
+    })
+}
+
+/// Change the native log filter without restarting.
+/// 
+/// @param targetFilter Filter in `RUST_LOG` syntax (e.g. "info,wireguard_netstack=trace"),
+///                     or null/empty to restore the filter from `RUST_LOG`
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setLogLevel<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    target_filter: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let filter = match get_optional_string(env, &target_filter) {
+            Ok(filter) => filter,
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+
+        logging::set_filter(filter.as_deref());
+        log::info!("Log filter set to {}", filter.as_deref().unwrap_or("default"));
+    })
 }
 
 /// Simple ping function to verify native library is loaded correctly.
 #[no_mangle]
 pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_ping<'local>(
-    env: JNIEnv<'local>,
+    mut env: JNIEnv<'local>,
     _class: JClass<'local>,
 ) -> jstring {
-    let output = env
-        .new_string("wireguard_tunnel_jni OK")
-        .expect("Failed to create Java string");
-    output.into_raw()
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let output = env
+            .new_string("wireguard_tunnel_jni OK")
+            .expect("Failed to create Java string");
+        output.into_raw()
+    })
 }
 
 /// Get the version of the native library.
 #[no_mangle]
 pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_version<'local>(
-    env: JNIEnv<'local>,
+    mut env: JNIEnv<'local>,
     _class: JClass<'local>,
 ) -> jstring {
-    let version = env!("CARGO_PKG_VERSION");
-    let output = env
-        .new_string(version)
-        .expect("Failed to create Java string");
-    output.into_raw()
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let version = env!("CARGO_PKG_VERSION");
+        let output = env
+            .new_string(version)
+            .expect("Failed to create Java string");
+        output.into_raw()
+    })
 }
 
-// ============================================================================
-// JNI Functions - Tunnel Lifecycle
-// ============================================================================
+// Capabilities reported by `nativeFeatures`, one bit each. A bit keeps its
+// meaning once assigned, so Java can test for the ones it knows about.
+const FEATURE_ASYNC_IO: jlong = 1 << 0;
+const FEATURE_SELECT: jlong = 1 << 1;
+const FEATURE_SHARED_RINGS: jlong = 1 << 2;
+const FEATURE_TLS: jlong = 1 << 3;
+const FEATURE_SOCKS5_TRANSPORT: jlong = 1 << 4;
+const FEATURE_WEBSOCKET_TRANSPORT: jlong = 1 << 5;
+const FEATURE_OBFUSCATION: jlong = 1 << 6;
+const FEATURE_WIREGUARD_PROFILES: jlong = 1 << 7;
+const FEATURE_ZERO_TRUST: jlong = 1 << 8;
+const FEATURE_HTTP: jlong = 1 << 9;
+const FEATURE_WEBSOCKET: jlong = 1 << 10;
+const FEATURE_PACKET_CAPTURE: jlong = 1 << 11;
+const FEATURE_PROXY_PROTOCOL: jlong = 1 << 12;
+const FEATURE_DIAGNOSTICS: jlong = 1 << 13;
+const FEATURE_SOCKET_MARK: jlong = 1 << 14;
+const FEATURE_KERNEL_TCP_INFO: jlong = 1 << 15;
+const FEATURE_DSCP_IPV6: jlong = 1 << 16;
+const FEATURE_FFM: jlong = 1 << 17;
+const FEATURE_WRITE_BEHIND: jlong = 1 << 18;
+const FEATURE_PARTIAL_WRITES: jlong = 1 << 19;
+const FEATURE_IO_TRACE: jlong = 1 << 20;
+const FEATURE_UDP_BATCHING: jlong = 1 << 21;
+const FEATURE_CPU_AVX2: jlong = 1 << 22;
+const FEATURE_CPU_NEON: jlong = 1 << 23;
+const FEATURE_PRESERVE_CONNECTIONS: jlong = 1 << 24;
+const FEATURE_ICMP_PING: jlong = 1 << 25;
+const FEATURE_UDP_FORWARD: jlong = 1 << 26;
 
-/// Start the WARP tunnel.
+/// Get the capabilities of this build of the native library.
 /// 
-/// @param credPath Path to store/load WARP credentials JSON
-/// @return tunnel state (0=Stopped, 1=Starting, 2=Ready, 3=Failed)
+/// Platform-specific bits (socket marks and interface binding, kernel TCP
+/// statistics, DSCP on IPv6 sockets, UDP batching) are only set where they
+/// work, and the CPU bits say which vector extensions this CPU has, detected
+/// at runtime. They describe the CPU, not the AEAD the tunnel ends up using.
+/// 
+/// @return Bitmask of FEATURE_* constants
 #[no_mangle]
-pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_startWarpTunnel<'local>(
-    mut env: JNIEnv<'local>,
-    _class: JClass<'local>,
-    cred_path: JString<'local>,
-) -> jint {
-    let cred_path = match get_string(&mut env, &cred_path) {
-        Ok(s) => s,
-        Err(e) => {
-            throw_exception(&mut env, &e);
-            return TunnelState::Failed as jint;
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_nativeFeatures(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    panic_guard::catch(&mut env, 0, |_| {
+        let mut features = FEATURE_ASYNC_IO
+            | FEATURE_SELECT
+            | FEATURE_SHARED_RINGS
+            | FEATURE_TLS
+            | FEATURE_SOCKS5_TRANSPORT
+            | FEATURE_WEBSOCKET_TRANSPORT
+            | FEATURE_OBFUSCATION
+            | FEATURE_WIREGUARD_PROFILES
+            | FEATURE_ZERO_TRUST
+            | FEATURE_HTTP
+            | FEATURE_WEBSOCKET
+            | FEATURE_PACKET_CAPTURE
+            | FEATURE_PROXY_PROTOCOL
+            | FEATURE_DIAGNOSTICS
+            | FEATURE_FFM
+            | FEATURE_WRITE_BEHIND
+            | FEATURE_PARTIAL_WRITES
+            | FEATURE_IO_TRACE
+            | FEATURE_PRESERVE_CONNECTIONS
+            | FEATURE_ICMP_PING
+            | FEATURE_UDP_FORWARD;
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            features |= FEATURE_SOCKET_MARK;
         }
-    };
-
-    // Check if already running
-    {
-        let tunnel_guard = global().tunnel.read();
-        if tunnel_guard.is_some() {
-            log::warn!("Tunnel already running");
-            return TunnelState::Ready as jint;
+        if cfg!(target_os = "linux") {
+            features |= FEATURE_KERNEL_TCP_INFO | FEATURE_UDP_BATCHING;
         }
-    }
-
-    log::info!("Starting WARP tunnel with credentials from: {}", cred_path);
-
-    let result = global().run(async move {
-        // Load or register WARP credentials
-        let (config, _credentials) = load_or_register_warp(&cred_path).await?;
-        
-        // Connect the managed tunnel
-        log::info!("Connecting to WireGuard tunnel...");
-        let tunnel = ManagedTunnel::connect(config)
-            .await
-            .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
-
-        let netstack = tunnel.netstack();
-        
-        Ok::<_, TunnelError>(ActiveTunnel { tunnel, netstack })
-    });
-
-    match result {
-        Ok(active_tunnel) => {
-            *global().tunnel.write() = Some(active_tunnel);
-            log::info!("WARP tunnel started successfully");
-            TunnelState::Ready as jint
+        if cfg!(any(target_os = "linux", target_os = "android", target_os = "macos")) {
+            features |= FEATURE_DSCP_IPV6;
         }
-        Err(e) => {
-            log::error!("Failed to start tunnel: {}", e);
-            throw_exception(&mut env, &format!("Failed to start tunnel: {}", e));
-            TunnelState::Failed as jint
+        match cpu::simd_level() {
+            "avx2" => features |= FEATURE_CPU_AVX2,
+            "neon" => features |= FEATURE_CPU_NEON,
+            _ => {}
         }
-    }
+        features
+    })
 }
 
-/// Get the current tunnel state.
-/// 
-/// @return 0=Stopped, 1=Starting, 2=Ready, 3=Failed
-#[no_mangle]
-pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tunnelState(
-    _env: JNIEnv,
-    _class: JClass,
-) -> jint {
-    let tunnel_guard = global().tunnel.read();
-    match tunnel_guard.as_ref() {
-        Some(_) => TunnelState::Ready as jint,
-        None => TunnelState::Stopped as jint,
-    }
-}
+/// How long blocking tasks get to finish when the runtime is shut down.
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
-/// Shutdown the tunnel.
+/// Release all native resources.
+/// 
+/// Shuts down the tunnel, closes every connection, stops the Tokio runtime
+/// and drops global state, so `initJNI` can be called again (e.g. after a
+/// hot reload). Must not be called while other native calls are in progress.
 #[no_mangle]
-pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_shutdownTunnel(
-    _env: JNIEnv,
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_shutdownNative(
+    mut env: JNIEnv,
     _class: JClass,
 ) {
-    log::info!("Shutting down WARP tunnel");
-
-    // Close all connections (ensure shutdown happens on Tokio runtime)
-    let handles: Vec<i64> = global()
-        .connections
-        .connections
-        .read()
-        .keys()
-        .copied()
-        .collect();
-
-    let mut to_close = Vec::with_capacity(handles.len());
-    for handle in handles {
-        if let Some(conn) = global().connections.remove(handle) {
-            to_close.push(conn);
+    panic_guard::catch(&mut env, (), |_| {
+        if GLOBAL.read().is_none() {
+            return;
         }
-    }
+        log::info!("Shutting down native library");
 
-    if !to_close.is_empty() {
-        global().run(async move {
-            for conn in to_close {
-                conn.shutdown();
-            }
-        });
-    }
+        shutdown_tunnel();
+        close_connections(|_| true);
+        // Aborts their tasks, which release the sockets on the runtime
+        drop(global().udp_forwards.drain());
+        if let Err(e) = global().capture.stop() {
+            log::warn!("Failed to finish packet capture: {}", e);
+        }
 
-    // Remove tunnel (ManagedTunnel handles cleanup in Drop)
-    let tunnel = global().tunnel.write().take();
-    if let Some(active) = tunnel {
-        global().run(async move {
-            active.tunnel.shutdown().await;
-        });
-    }
+        let Some(state) = GLOBAL.write().take() else {
+            return;
+        };
+        let runtime = state.runtime.lock().take();
+        drop(state);
+        if let Some(runtime) = runtime {
+            runtime.shutdown(RUNTIME_SHUTDOWN_TIMEOUT);
+        }
 
-    log::info!("WARP tunnel shut down");
+        // Release the Java logger's global reference
+        logging::set_java_logger(None);
+        log::info!("Native library shut down");
+    })
 }
 
 // ============================================================================
-// JNI Functions - TCP Operations
+// JNI Functions - Credential Storage
 // ============================================================================
 
-/// Connect to a remote host via the tunnel.
+/// Encrypt persisted credentials with a passphrase.
 /// 
-/// @param host Hostname or IP address
-/// @param port Port number
-/// @param timeoutMs Connection timeout in milliseconds (0 = no timeout)
+/// Takes effect on the next tunnel start; existing plaintext credentials are
+/// encrypted when they are next loaded.
+/// 
+/// @param passphrase Passphrase, or null to stop encrypting
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setCredentialPassphrase<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    passphrase: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let passphrase = match get_optional_string(env, &passphrase) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+
+        *global().credential_secret.write() = passphrase.map(CredentialSecret::Passphrase);
+    })
+}
+
+/// Encrypt persisted credentials with a key supplied by Java (e.g. an OS keystore).
+/// 
+/// @param provider Object implementing `CredentialKeyProvider`, or null to stop encrypting
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setCredentialKeyProvider<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    provider: JObject<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        if provider.is_null() {
+            *global().credential_secret.write() = None;
+            return;
+        }
+
+        match env.new_global_ref(&provider) {
+            Ok(provider) => *global().credential_secret.write() = Some(CredentialSecret::Provider(provider)),
+            Err(e) => throw_exception(env, &format!("Failed to store key provider: {}", e)),
+        }
+    })
+}
+
+/// Keep the WARP config cache in a directory of its own.
+/// 
+/// By default it sits next to the credentials file. On Android that is
+/// usually shared storage, while the app's cache directory is private and
+/// may be cleared by the system, which only costs a refetch.
+/// 
+/// @param dir Directory, created if missing, or null to keep the cache next to the credentials
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setCacheDirectory<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    dir: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        match get_optional_string(env, &dir) {
+            Ok(dir) => *global().cache_dir.write() = dir.map(PathBuf::from),
+            Err(e) => throw_exception(env, &e),
+        }
+    })
+}
+
+// ============================================================================
+// JNI Functions - Tunnel Options
+// ============================================================================
+
+/// Set the MTU of the tunnel interface.
+/// 
+/// Takes effect on the next tunnel start, unless the start call passes its
+/// own MTU. Lower values help on PPPoE links and ISPs that drop large UDP
+/// packets.
+/// 
+/// @param mtu MTU in bytes (576-1500), or 0 for the default of 1420
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setMtu(
+    mut env: JNIEnv,
+    _class: JClass,
+    mtu: jint,
+) {
+    panic_guard::catch(&mut env, (), |env| match get_mtu(mtu) {
+        Ok(mtu) => global().options.write().mtu = mtu.unwrap_or(WIREGUARD_MTU),
+        Err(e) => throw_exception(env, &e),
+    })
+}
+
+/// Check an MTU passed from Java, where 0 means none was chosen.
+fn get_mtu(mtu: jint) -> Result<Option<u16>, String> {
+    match mtu {
+        0 => Ok(None),
+        m if (MIN_MTU as jint..=MAX_MTU as jint).contains(&m) => Ok(Some(m as u16)),
+        m => Err(format!("MTU {} out of range {}-{}", m, MIN_MTU, MAX_MTU)),
+    }
+}
+
+/// Set the WireGuard persistent keepalive interval.
+/// 
+/// Takes effect on the next tunnel start. Keepalives hold NAT mappings open
+/// on mobile hotspots and similar; disabling them saves battery.
+/// 
+/// @param seconds Interval in seconds (1-65535), or 0 to disable
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setPersistentKeepalive(
+    mut env: JNIEnv,
+    _class: JClass,
+    seconds: jint,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let keepalive = match u16::try_from(seconds) {
+            Ok(0) => None,
+            Ok(s) => Some(s),
+            Err(_) => {
+                throw_exception(env, &format!("Keepalive interval {} out of range 0-65535", seconds));
+                return;
+            }
+        };
+        global().options.write().keepalive_seconds = keepalive;
+    })
+}
+
+/// Set what `tcpConnect` does when the tunnel is down.
+/// 
+/// Takes effect immediately.
+/// 
+/// @param policy 0=Kill switch (fail), 1=Fallback to a direct connection
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setConnectPolicy(
+    mut env: JNIEnv,
+    _class: JClass,
+    policy: jint,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        match ConnectPolicy::from_jint(policy) {
+            Some(policy) => global().options.write().connect_policy = policy,
+            None => throw_exception(env, &format!("Invalid connect policy: {}", policy)),
+        }
+    })
+}
+
+/// Pin the WARP endpoint instead of using the one from the WARP config.
+/// 
+/// Takes effect on the next tunnel start. A pinned endpoint is the only one
+/// tried, with no failover to other ports. Hostnames are resolved outside
+/// the tunnel when it starts.
+/// 
+/// @param endpoint "ip", "ip:port", "host" or "host:port" (the port defaults to
+///                 the config's), or null to use the WARP config's endpoint
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setEndpointOverride<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    endpoint: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let endpoint = match get_optional_string(env, &endpoint) {
+            Ok(endpoint) => endpoint.filter(|e| !e.trim().is_empty()),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        let endpoint = match endpoint.as_deref().map(endpoint::EndpointOverride::parse).transpose() {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        global().options.write().endpoint_override = endpoint;
+    })
+}
+
+/// Set the preshared key mixed into WireGuard handshakes.
+/// 
+/// Takes effect on the next tunnel start. For self-hosted servers that
+/// require one; WARP does not use a preshared key, so leave it unset there.
+/// An imported profile's own PresharedKey takes precedence.
+/// 
+/// @param key Base64 key, as printed by `wg genpsk`, or null for none
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setPresharedKey<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    key: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let key = match get_optional_string(env, &key) {
+            Ok(key) => key.filter(|k| !k.trim().is_empty()),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        let key = match key.as_deref().map(profile::decode_key) {
+            None => None,
+            Some(Some(key)) => Some(key),
+            Some(None) => {
+                throw_exception(env, "Preshared key must be a base64-encoded 32-byte key");
+                return;
+            }
+        };
+        global().options.write().preshared_key = key;
+    })
+}
+
+/// Choose the DNS servers used for hostname lookups through the tunnel.
+/// 
+/// Takes effect on the next tunnel start; each tunnel keeps its own servers
+/// and cache. Queries never leave the tunnel. Without servers, WARP tunnels
+/// use Cloudflare's DoH endpoint and imported profiles their own DNS
+/// servers, falling back to Cloudflare's.
+/// 
+/// @param servers Comma-separated list of "ip" for DNS over TCP or
+///                "ip#hostname" for DoH, or null for the default
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setDnsServers<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    servers: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let servers = match get_optional_string(env, &servers) {
+            Ok(servers) => servers.filter(|s| !s.trim().is_empty()),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        let servers = match servers.as_deref().map(dns::Server::parse_list).transpose() {
+            Ok(servers) => servers,
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        global().options.write().dns_servers = servers;
+    })
+}
+
+/// Choose how encrypted WireGuard packets reach the endpoint.
+/// 
+/// Takes effect on the next tunnel start. For networks that block UDP to
+/// Cloudflare, packets can go through a SOCKS5 proxy's UDP relay, or over a
+/// WebSocket to a bridge that forwards each message as a datagram to the
+/// WireGuard server.
+/// 
+/// @param spec "udp" (the default), "socks5://[user:pass@]host:port",
+///             "ws://host[:port]/path" or "wss://host[:port]/path", or null for UDP
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setOuterTransport<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    spec: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let spec = match get_optional_string(env, &spec) {
+            Ok(spec) => spec.unwrap_or_default(),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        match transport::TransportConfig::parse(&spec) {
+            Ok(transport) => global().options.write().outer.transport = transport,
+            Err(e) => throw_exception(env, &e),
+        }
+    })
+}
+
+/// Set AmneziaWG-style obfuscation parameters for the WireGuard packets.
+/// 
+/// Takes effect on the next tunnel start. Junk packets (Jc, Jmin, Jmax) are
+/// ignored by standard WireGuard servers, WARP included, and already defeat
+/// some DPI. Padding (S1, S2) and custom message types (H1-H4) need an
+/// AmneziaWG server with the same values.
+/// 
+/// @param params AmneziaWG keys, e.g. "Jc=4, Jmin=40, Jmax=70", separated by
+///               commas, semicolons or newlines; null or empty disables obfuscation
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setObfuscation<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    params: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let params = match get_optional_string(env, &params) {
+            Ok(params) => params.unwrap_or_default(),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        match awg::Obfuscation::parse(&params) {
+            Ok(params) => global().options.write().outer.obfuscation = Some(params).filter(|p| !p.is_noop()),
+            Err(e) => throw_exception(env, &e),
+        }
+    })
+}
+
+/// Control where the outer WireGuard sockets send from.
+/// 
+/// Takes effect on the next tunnel start. Binding to the physical interface
+/// or marking packets for a policy route keeps the tunnel's own traffic out
+/// of a system-level VPN, avoiding routing loops, and lets host firewalls
+/// match it. Applies to every socket the outer transport opens.
+/// 
+/// @param bindAddress Local IP address to bind to, or null for any
+/// @param interfaceName Network interface to send through, or null for any (Linux and Android)
+/// @param mark Firewall mark (SO_MARK), or 0 for none (Linux and Android, needs CAP_NET_ADMIN)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setOuterSocketOptions<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    bind_address: JString<'local>,
+    interface_name: JString<'local>,
+    mark: jint,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let bind_address = match get_optional_string(env, &bind_address) {
+            Ok(bind) => bind.filter(|s| !s.trim().is_empty()),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        let interface = match get_optional_string(env, &interface_name) {
+            Ok(interface) => interface.map(|s| s.trim().to_string()).filter(|s| !s.is_empty()),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        let bind_address = match bind_address.map(|s| s.trim().parse::<IpAddr>()).transpose() {
+            Ok(ip) => ip,
+            Err(e) => {
+                throw_exception(env, &format!("Invalid bind address: {}", e));
+                return;
+            }
+        };
+        let state = global();
+        let socket = &mut state.options.write().outer.socket;
+        *socket = transport::SocketOptions {
+            bind_address,
+            interface,
+            mark: (mark != 0).then_some(mark as u32),
+            dscp: socket.dscp,
+        };
+    })
+}
+
+/// Set the DSCP codepoint on outgoing WireGuard packets.
+/// 
+/// Routers running SQM (cake, fq_codel) can prioritize marked packets,
+/// e.g. EF (46) or CS4 (32) for game traffic. Applies to the next tunnel
+/// start and to the running tunnel: in place when its packets already go
+/// through the relay over UDP, otherwise by reconnecting, which closes
+/// tunneled connections. Marks may be cleared by the ISP.
+/// 
+/// @param dscp Codepoint from 1 to 63, or 0 for none
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setOuterDscp(
+    mut env: JNIEnv,
+    _class: JClass,
+    dscp: jint,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let dscp = match u8::try_from(dscp) {
+            Ok(0) => None,
+            Ok(dscp @ 1..=63) => Some(dscp),
+            _ => {
+                throw_exception(env, &format!("Invalid DSCP codepoint: {}", dscp));
+                return;
+            }
+        };
+        global().options.write().outer.socket.dscp = dscp;
+        if let Err(e) = global().run(async move { apply_dscp(&global(), dscp).await }) {
+            let message = format!("Failed to apply DSCP: {}", e);
+            throw_exception(env, &message);
+            global().record_tunnel_error(&e, message);
+        }
+    })
+}
+
+/// Batch outer UDP sends and receives (Linux).
+/// 
+/// Takes effect on the next tunnel start. Datagrams leave in sendmmsg
+/// batches, with runs of equal size handed to the kernel as one segmented
+/// send, and arrive through recvmmsg with receive offload where the kernel
+/// supports it, cutting per-packet syscalls on bulk transfers. Plain UDP
+/// then goes through the loopback relay like other outer transports, which
+/// adds a hop the tunnel still crosses one datagram per call. That costs
+/// more than batching saves unless the network side is the bottleneck, so
+/// measure with benches/outer_path.rs before turning it on. Off by default.
+/// 
+/// @param enabled Whether to batch
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setOuterBatching(
+    mut env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let enabled = enabled != 0;
+        if enabled && !cfg!(target_os = "linux") {
+            throw_exception(env, "Outer UDP batching needs Linux");
+            return;
+        }
+        global().options.write().outer.batching = enabled;
+    })
+}
+
+/// Keep tunneled connections open when the tunnel changes endpoint.
+/// 
+/// Takes effect on the next tunnel start. Endpoint failover, and reloads
+/// that only change the endpoint, then move the running tunnel's WireGuard
+/// session to the new endpoint instead of rebuilding the tunnel, so
+/// connections stall for the handshake rather than closing. Plain UDP goes
+/// through the loopback relay to make this possible. Key, address, MTU and
+/// keepalive changes still rebuild the tunnel. Off by default.
+/// 
+/// @param enabled Whether to preserve connections
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setPreserveConnections(
+    mut env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        global().options.write().outer.preserve_connections = enabled != 0;
+    })
+}
+
+/// Limit how many connections may be open at once.
+/// 
+/// Protects the netstack's socket set from callers that leak handles.
+/// Connects beyond the limit fail; open connections are not affected.
+/// Takes effect immediately.
+/// 
+/// @param max Most open connections, or 0 for no limit
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setMaxConnections(
+    mut env: JNIEnv,
+    _class: JClass,
+    max: jint,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        let max = max.max(0) as usize;
+        log::info!("Setting connection limit to {}", max);
+        global().connections.max_connections.store(max, Ordering::Relaxed);
+    })
+}
+
+/// Record tunnel throughput for `throughputHistory` until the tunnel stops.
+async fn sample_throughput(state: Weak<GlobalState>) {
+    let mut ticker = tokio::time::interval(history::SAMPLE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        state.throughput.record(state.connections.route_totals(true));
+    }
+}
+
+/// Close connections with no read or write completed for `timeout`, until
+/// the global state is dropped.
+async fn reap_idle(state: Weak<GlobalState>, timeout: Duration) {
+    let mut ticker = tokio::time::interval((timeout / 4).clamp(Duration::from_millis(100), Duration::from_secs(5)));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        for (handle, conn) in state.connections.snapshot() {
+            if conn.stats().idle_time() < timeout {
+                continue;
+            }
+            if let Ok(conn) = state.connections.remove(handle) {
+                log::warn!("Closing connection to {} idle for {:?}, handle={}", conn.remote_addr(), timeout, handle);
+                tokio::spawn(async move {
+                    if let Err(e) = conn.shutdown(Duration::ZERO).await {
+                        log::debug!("Idle connection failed while closing, handle={}: {}", handle, e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Close connections that go unused for too long.
+/// 
+/// A connection is idle while no read or write on it completes; a read
+/// waiting for data that never comes does not count as use. Takes effect
+/// immediately.
+/// 
+/// @param seconds Idle time before a connection is closed, or 0 to never close idle connections
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setIdleTimeout(
+    mut env: JNIEnv,
+    _class: JClass,
+    seconds: jint,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        let state = global();
+        log::info!("Setting connection idle timeout to {} s", seconds.max(0));
+        let reaper = (seconds > 0).then(|| {
+            let timeout = Duration::from_secs(seconds as u64);
+            state.handle.spawn(reap_idle(Arc::downgrade(&state), timeout))
+        });
+        let old = std::mem::replace(&mut *state.connections.idle_reaper.lock(), reaper);
+        if let Some(old) = old {
+            old.abort();
+        }
+    })
+}
+
+// ============================================================================
+// JNI Functions - Tunnel Lifecycle
+// ============================================================================
+
+/// Probe the active endpoint while the tunnel is quiet, and fail over to
+/// the next candidate once it stops answering, until the global state is dropped.
+async fn watch_endpoint(state: Weak<GlobalState>) {
+    let mut failed_probes = 0;
+    loop {
+        tokio::time::sleep(endpoint::CHECK_INTERVAL).await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let tunnel = match state.tunnel.read().as_ref() {
+            Some(active) if !active.tunnel.is_paused() => active.tunnel.clone(),
+            _ => {
+                failed_probes = 0;
+                continue;
+            }
+        };
+
+        let quiet = tunnel
+            .rx_counters()
+            .last_packet()
+            .is_none_or(|at| at.elapsed() >= endpoint::CHECK_INTERVAL);
+        if !quiet || endpoint::probe(tunnel.netstack()).await {
+            failed_probes = 0;
+            continue;
+        }
+        failed_probes += 1;
+        log::warn!("WARP endpoint {} did not answer a probe ({} in a row)", tunnel.endpoint(), failed_probes);
+        if failed_probes < endpoint::MAX_FAILED_PROBES {
+            continue;
+        }
+
+        failed_probes = 0;
+        if let Err(e) = fail_over(&state).await {
+            log::error!("WARP endpoint failover failed: {}", e);
+            state.record_tunnel_error(&e, format!("WARP endpoint failover failed: {}", e));
+        }
+    }
+}
+
+/// Recover the tunnel whenever the machine wakes from sleep.
+async fn watch_sleep(state: Weak<GlobalState>) {
+    let mut detector = suspend::SleepDetector::new();
+    loop {
+        tokio::time::sleep(suspend::CHECK_INTERVAL).await;
+        let Some(slept) = detector.check() else {
+            continue;
+        };
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        log::info!("Woke up after about {}s asleep", slept.as_secs());
+        if let Err(e) = recover_from_sleep(&state).await {
+            log::error!("Failed to re-handshake after sleep: {}", e);
+            state.record_tunnel_error(&e, format!("Failed to re-handshake after sleep: {}", e));
+        }
+    }
+}
+
+/// Start a new handshake after sleep, failing reads still waiting on the
+/// old session with `ErrorCode::Reconnecting` so they can be retried
+/// instead of hanging until they time out.
+///
+/// Returns false if no tunnel is running or it is paused.
+async fn recover_from_sleep(state: &GlobalState) -> Result<bool, TunnelError> {
+    let (tunnel, outer) = match state.tunnel.read().as_ref() {
+        Some(active) if !active.tunnel.is_paused() => (active.tunnel.clone(), active.outer.clone()),
+        _ => return Ok(false),
+    };
+    state.connections.reconnect.notify_waiters();
+    tunnel.rehandshake(&outer).await
+}
+
+/// Reconnect the tunnel through the next endpoint candidate.
+///
+/// With connections preserved the running tunnel moves there. Otherwise
+/// tunneled connections and spares ran on the old tunnel's netstack, so
+/// they are closed.
+async fn fail_over(state: &GlobalState) -> Result<(), TunnelError> {
+    let Some((tunnel, config, endpoints, index, outer)) = state.tunnel.read().as_ref().map(|active| {
+        (
+            active.tunnel.clone(),
+            active.config.clone(),
+            active.endpoints.clone(),
+            active.endpoint_index,
+            active.outer.clone(),
+        )
+    }) else {
+        return Ok(());
+    };
+
+    if outer.preserve_connections {
+        if let Some(index) = endpoint::move_first(&tunnel, &endpoints, index + 1, &outer).await? {
+            log::warn!("Tunnel moved to {}, keeping connections", endpoints[index]);
+            if let Some(active) = state.tunnel.write().as_mut() {
+                active.endpoint_index = index;
+            }
+            return Ok(());
+        }
+    }
+
+    let (tunnel, index) =
+        endpoint::connect_first(&config, &endpoints, index + 1, &outer, &state.capture, &state.handshakes).await?;
+    replace_tunnel(state, tunnel, |active| active.endpoint_index = index).await;
+    Ok(())
+}
+
+/// Swap `tunnel` in for the running one, after `update` adjusts the rest of its state.
+///
+/// Tunneled connections and spares ran on the old tunnel's netstack, so
+/// they are closed, dropping writes still queued for them.
+async fn replace_tunnel(state: &GlobalState, tunnel: tunnel::Tunnel, update: impl FnOnce(&mut ActiveTunnel)) {
+    let tunnel = Arc::new(tunnel);
+    let old = state.tunnel.write().as_mut().map(|active| {
+        active.resolver = Arc::new(dns::Resolver::new(tunnel.netstack(), active.resolver.servers()));
+        update(active);
+        std::mem::replace(&mut active.tunnel, tunnel.clone())
+    });
+    let Some(old) = old else {
+        // Shut down while we were connecting
+        tunnel.shutdown().await;
+        return;
+    };
+    log::warn!("Tunnel moved from {} to {}", old.endpoint(), tunnel.endpoint());
+
+    for (handle, conn) in state.connections.snapshot() {
+        if !conn.is_tunneled() {
+            continue;
+        }
+        if let Ok(conn) = state.connections.remove(handle) {
+            let _ = conn.shutdown(Duration::ZERO).await;
+            conn.stop_write_behind();
+        }
+    }
+    drop(state.connections.pool.clear());
+    old.shutdown().await;
+}
+
+/// DNS servers for a tunnel: the configured ones, else the profile's, else Cloudflare's DoH.
+fn dns_servers(options: &TunnelOptions, profile_dns: &[Ipv4Addr]) -> Vec<dns::Server> {
+    match &options.dns_servers {
+        Some(servers) => servers.clone(),
+        None if !profile_dns.is_empty() => profile_dns.iter().map(|&ip| dns::Server::Tcp(ip)).collect(),
+        None => dns::default_servers(),
+    }
+}
+
+/// Apply `profile` to the running tunnel.
+///
+/// Keys and the tunnel address are the tunnel's identity, so changing them
+/// needs a restart. DNS servers are swapped on the running resolver.
+/// Everything else upstream fixes when the tunnel is created, so any other
+/// change rebuilds it.
+async fn reload_tunnel(state: &GlobalState, profile: profile::Profile) -> Result<ReloadResult, TunnelError> {
+    let Some((current, endpoint, outer, resolver, mtu)) = state.tunnel.read().as_ref().map(|active| {
+        (
+            active.config.clone(),
+            active.endpoints[active.endpoint_index],
+            active.outer.clone(),
+            active.resolver.clone(),
+            active.mtu,
+        )
+    }) else {
+        return Err(TunnelError::NotReady);
+    };
+    if profile.private_key != current.private_key {
+        return Err(TunnelError::RestartRequired("PrivateKey"));
+    }
+    if profile.peer_public_key != current.peer_public_key {
+        return Err(TunnelError::RestartRequired("PublicKey"));
+    }
+    if profile.tunnel_ip() != current.tunnel_ip {
+        return Err(TunnelError::RestartRequired("Address"));
+    }
+
+    let options = state.options.read().clone();
+    let target = match &options.endpoint_override {
+        Some(endpoint) => endpoint,
+        None => &profile.endpoint,
+    };
+    let addr = target.resolve(profile.endpoint.port().unwrap_or_default()).await?;
+    let mut config = profile.config(addr);
+    config.mtu = mtu.or(config.mtu).or(Some(options.mtu));
+    config.keepalive_seconds = config.keepalive_seconds.or(options.keepalive_seconds);
+    config.preshared_key = config.preshared_key.or(options.preshared_key);
+    if config.preshared_key != current.preshared_key {
+        return Err(TunnelError::RestartRequired("PresharedKey"));
+    }
+    let servers = dns_servers(&options, &profile.dns);
+    let dns_changed = servers != resolver.servers();
+    let endpoint_only = config.mtu == current.mtu && config.keepalive_seconds == current.keepalive_seconds;
+    if addr == endpoint && endpoint_only {
+        if !dns_changed {
+            return Ok(ReloadResult::Unchanged);
+        }
+        log::info!("Reloading tunnel DNS servers");
+        resolver.set_servers(servers);
+        return Ok(ReloadResult::DnsUpdated);
+    }
+
+    log::info!(
+        "Reloading tunnel: endpoint {}, MTU {:?}, keepalive {:?}s",
+        addr,
+        config.mtu,
+        config.keepalive_seconds
+    );
+    let endpoints = vec![addr];
+    if outer.preserve_connections && endpoint_only {
+        let tunnel = state.tunnel.read().as_ref().map(|active| active.tunnel.clone());
+        if let Some(tunnel) = tunnel {
+            if endpoint::move_first(&tunnel, &endpoints, 0, &outer).await?.is_some() {
+                if let Some(active) = state.tunnel.write().as_mut() {
+                    active.resolver.set_servers(servers);
+                    active.config = config;
+                    active.endpoints = endpoints;
+                    active.endpoint_index = 0;
+                }
+                return Ok(ReloadResult::Moved);
+            }
+        }
+    }
+    let (tunnel, _) = endpoint::connect_first(&config, &endpoints, 0, &outer, &state.capture, &state.handshakes).await?;
+    replace_tunnel(state, tunnel, |active| {
+        active.resolver.set_servers(servers);
+        active.config = config;
+        active.endpoints = endpoints;
+        active.endpoint_index = 0;
+    })
+    .await;
+    Ok(ReloadResult::Reconnected)
+}
+
+/// Mark the running tunnel's outgoing packets with `dscp`.
+///
+/// A tunnel sending directly, or through a transport that cannot change
+/// its sockets in place, is rebuilt with the mark.
+async fn apply_dscp(state: &GlobalState, dscp: Option<u8>) -> Result<(), TunnelError> {
+    let Some((tunnel, config, endpoints, index, mut outer)) = state.tunnel.read().as_ref().map(|active| {
+        (
+            active.tunnel.clone(),
+            active.config.clone(),
+            active.endpoints.clone(),
+            active.endpoint_index,
+            active.outer.clone(),
+        )
+    }) else {
+        return Ok(());
+    };
+    if outer.socket.dscp == dscp {
+        return Ok(());
+    }
+    outer.socket.dscp = dscp;
+    match tunnel.set_dscp(dscp) {
+        Ok(()) => {
+            if let Some(active) = state.tunnel.write().as_mut() {
+                active.outer = outer;
+            }
+        }
+        Err(e) => {
+            log::info!("Reconnecting to apply DSCP {:?}: {}", dscp, e);
+            let (tunnel, index) =
+                endpoint::connect_first(&config, &endpoints, index, &outer, &state.capture, &state.handshakes).await?;
+            replace_tunnel(state, tunnel, |active| {
+                active.outer = outer;
+                active.endpoint_index = index;
+            })
+            .await;
+        }
+    }
+    Ok(())
+}
+
+/// Reconnect the tunnel with the key generated by `rotateKeys`.
+///
+/// The old tunnel keeps running if the handshake fails, e.g. because the
+/// server does not know the new public key yet.
+async fn confirm_rotation(state: &GlobalState) -> Result<(), TunnelError> {
+    let Some((mut config, endpoints, index, outer, key)) = state.tunnel.read().as_ref().map(|active| {
+        (
+            active.config.clone(),
+            active.endpoints.clone(),
+            active.endpoint_index,
+            active.outer.clone(),
+            active.rotated_key,
+        )
+    }) else {
+        return Err(TunnelError::NotReady);
+    };
+    let Some((private_key, public_key)) = key else {
+        return Err(TunnelError::ConnectionFailed("No key rotation in progress".into()));
+    };
+
+    config.private_key = private_key;
+    let (tunnel, index) = endpoint::connect_first(
+        &config,
+        &endpoints,
+        index,
+        &outer,
+        &state.capture,
+        &state.handshakes,
+    )
+    .await?;
+    replace_tunnel(state, tunnel, |active| {
+        active.public_key = Some(profile::encode_key(&public_key));
+        active.config = config;
+        active.endpoint_index = index;
+        active.rotated_key = None;
+    })
+    .await;
+    log::info!("Tunnel key rotated");
+    Ok(())
+}
+
+/// Start a WARP tunnel with the given registration options.
+///
+/// Shared by the consumer and Zero Trust entry points. `mtu` overrides the
+/// tunnel options' MTU for this tunnel.
+fn start_warp(env: &mut JNIEnv, cred_path: String, options: RegistrationOptions, mtu: Option<u16>) -> jint {
+    // Check if already running
+    {
+        let state = global();
+        let tunnel_guard = state.tunnel.read();
+        if tunnel_guard.is_some() {
+            log::warn!("Tunnel already running");
+            return TunnelState::Ready as jint;
+        }
+    }
+
+    let key = match resolve_credential_key(env) {
+        Ok(key) => key,
+        Err(e) => {
+            throw_exception(env, &e);
+            return TunnelState::Failed as jint;
+        }
+    };
+    let cred_file = CredentialFile { path: cred_path, key };
+    activate_tunnel(env, connect_warp(cred_file, options, mtu), true)
+}
+
+/// Register or load the WARP device and connect a tunnel, without installing it.
+///
+/// `mtu` overrides the tunnel options' MTU.
+fn connect_warp(
+    cred_file: CredentialFile,
+    options: RegistrationOptions,
+    mtu: Option<u16>,
+) -> Result<ActiveTunnel, TunnelError> {
+    let tunnel_options = global().options.read().clone();
+
+    log::info!("Starting WARP tunnel with credentials from: {}", cred_file.path);
+
+    global().run(async move {
+        // Load or register WARP credentials
+        let (mut config, credentials) = load_or_register_warp(&cred_file, options).await?;
+
+        let tunnel_mtu = mtu.unwrap_or(tunnel_options.mtu);
+        config.mtu = Some(tunnel_mtu);
+        config.keepalive_seconds = tunnel_options.keepalive_seconds;
+        config.preshared_key = tunnel_options.preshared_key.or(config.preshared_key);
+        log::info!(
+            "Using MTU {} and keepalive {:?}s for WireGuard tunnel",
+            tunnel_mtu,
+            tunnel_options.keepalive_seconds
+        );
+
+        let device = match warp_account::device_info(&credentials).await {
+            Ok(device) => device,
+            Err(e) => {
+                log::warn!("Failed to query WARP device info: {}", e);
+                warp_account::DeviceInfo {
+                    account_type: if credentials.is_teams { AccountType::Team } else { AccountType::Unknown },
+                    public_key: None,
+                    addresses: vec![config.tunnel_ip.to_string()],
+                }
+            }
+        };
+        let account_type = device.account_type;
+        log::info!("WARP account type: {:?}", account_type);
+        
+        // Connect the tunnel
+        log::info!("Connecting to WireGuard tunnel...");
+        let endpoints = match &tunnel_options.endpoint_override {
+            Some(endpoint) => {
+                let addr = endpoint.resolve(config.peer_endpoint.port()).await?;
+                log::info!("Using WARP endpoint override {} instead of {}", addr, config.peer_endpoint);
+                vec![addr]
+            }
+            None if tunnel_options.outer.transport.reaches_endpoint() => endpoint::candidates(config.peer_endpoint),
+            None => vec![config.peer_endpoint],
+        };
+        let servers = dns_servers(&tunnel_options, &[]);
+        let outer = tunnel_options.outer;
+        let (tunnel, endpoint_index) =
+            endpoint::connect_first(&config, &endpoints, 0, &outer, &global().capture, &global().handshakes).await?;
+        let tunnel = Arc::new(tunnel);
+
+        let resolver = Arc::new(dns::Resolver::new(tunnel.netstack(), servers));
+        
+        Ok::<_, TunnelError>(ActiveTunnel {
+            tunnel,
+            resolver,
+            account_type,
+            device_id: Some(credentials.device_id),
+            public_key: device.public_key,
+            addresses: device.addresses,
+            config,
+            endpoints,
+            endpoint_index,
+            outer,
+            mtu,
+            rotated_key: None,
+        })
+    })
+}
+
+/// Install a newly connected tunnel and start its watchdogs.
+/// 
+/// Endpoint failover is only used for WARP, whose probe target is always
+/// reachable through the tunnel.
+fn install_tunnel(active_tunnel: ActiveTunnel, failover: bool) {
+    *global().tunnel.write() = Some(active_tunnel);
+    let state = global();
+    if failover {
+        let watchdog = state.handle.spawn(watch_endpoint(Arc::downgrade(&state)));
+        let old = state.endpoint_watchdog.lock().replace(watchdog);
+        if let Some(old) = old {
+            old.abort();
+        }
+    }
+    let watchdog = state.handle.spawn(watch_sleep(Arc::downgrade(&state)));
+    let old = state.sleep_watchdog.lock().replace(watchdog);
+    if let Some(old) = old {
+        old.abort();
+    }
+    state.throughput.clear();
+    let sampler = state.handle.spawn(sample_throughput(Arc::downgrade(&state)));
+    let old = state.throughput_sampler.lock().replace(sampler);
+    if let Some(old) = old {
+        old.abort();
+    }
+    log::info!("Tunnel started successfully");
+}
+
+/// Install the tunnel `result` holds, or throw its error.
+fn activate_tunnel(env: &mut JNIEnv, result: Result<ActiveTunnel, TunnelError>, failover: bool) -> jint {
+    match result {
+        Ok(active_tunnel) => {
+            install_tunnel(active_tunnel, failover);
+            TunnelState::Ready as jint
+        }
+        Err(e) => {
+            log::error!("Failed to start tunnel: {}", e);
+            let message = format!("Failed to start tunnel: {}", e);
+            throw_exception(env, &message);
+            global().record_tunnel_error(&e, message);
+            TunnelState::Failed as jint
+        }
+    }
+}
+
+/// Start the WARP tunnel.
+/// 
+/// @param credPath Path to store/load WARP credentials JSON
+/// @param licenseKey Optional WARP+ license key to apply (null or empty for none)
+/// @param mtu Tunnel MTU in bytes (576-1500), or 0 for the one set with `setMtu`
+/// @return tunnel state (0=Stopped, 1=Starting, 2=Ready, 3=Failed)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_startWarpTunnel<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    cred_path: JString<'local>,
+    license_key: JString<'local>,
+    mtu: jint,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        let cred_path = match get_string(env, &cred_path) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return TunnelState::Failed as jint;
+            }
+        };
+
+        let license_key = match get_optional_string(env, &license_key) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return TunnelState::Failed as jint;
+            }
+        };
+
+        let mtu = match get_mtu(mtu) {
+            Ok(mtu) => mtu,
+            Err(e) => {
+                throw_exception(env, &e);
+                return TunnelState::Failed as jint;
+            }
+        };
+
+        let options = RegistrationOptions {
+            license_key,
+            ..RegistrationOptions::default()
+        };
+        start_warp(env, cred_path, options, mtu)
+    })
+}
+
+/// Start a WARP tunnel enrolled in a Cloudflare Zero Trust organization.
+/// 
+/// The auth token is only needed to register the device; later starts reuse
+/// the persisted credentials.
+/// 
+/// @param orgName Zero Trust team name (as in `<team>.cloudflareaccess.com`)
+/// @param authToken JWT obtained from `https://<team>.cloudflareaccess.com/warp`
+/// @param credPath Path to store/load WARP credentials JSON
+/// @param mtu Tunnel MTU in bytes (576-1500), or 0 for the one set with `setMtu`
+/// @return tunnel state (0=Stopped, 1=Starting, 2=Ready, 3=Failed)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_startWarpTeamsTunnel<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    org_name: JString<'local>,
+    auth_token: JString<'local>,
+    cred_path: JString<'local>,
+    mtu: jint,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        let args = get_string(env, &org_name).and_then(|org| {
+            let token = get_string(env, &auth_token)?;
+            let path = get_string(env, &cred_path)?;
+            Ok((org, token, path, get_mtu(mtu)?))
+        });
+        let (org_name, auth_token, cred_path, mtu) = match args {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return TunnelState::Failed as jint;
+            }
+        };
+
+        log::info!("Using Zero Trust organization {}", org_name);
+
+        let options = RegistrationOptions {
+            teams: Some(TeamsEnrollment {
+                jwt_token: auth_token,
+                device_name: None,
+                serial_number: None,
+            }),
+            ..RegistrationOptions::default()
+        };
+        start_warp(env, cred_path, options, mtu)
+    })
+}
+
+/// Connect a tunnel from a parsed WireGuard profile, without installing it.
+///
+/// `mtu` overrides both the profile's MTU and the tunnel options'.
+fn connect_profile(profile: profile::Profile, mtu: Option<u16>) -> Result<ActiveTunnel, TunnelError> {
+    let tunnel_options = global().options.read().clone();
+
+    global().run(async move {
+        let endpoint = match &tunnel_options.endpoint_override {
+            Some(endpoint) => endpoint,
+            None => &profile.endpoint,
+        };
+        let addr = endpoint.resolve(profile.endpoint.port().unwrap_or_default()).await?;
+        let mut config = profile.config(addr);
+        config.mtu = mtu.or(config.mtu).or(Some(tunnel_options.mtu));
+        config.keepalive_seconds = config.keepalive_seconds.or(tunnel_options.keepalive_seconds);
+        config.preshared_key = config.preshared_key.or(tunnel_options.preshared_key);
+        log::info!(
+            "Connecting to WireGuard endpoint {} from profile, MTU {:?}, keepalive {:?}s",
+            addr,
+            config.mtu,
+            config.keepalive_seconds
+        );
+
+        let endpoints = vec![addr];
+        let servers = dns_servers(&tunnel_options, &profile.dns);
+        let outer = tunnel_options.outer;
+        let (tunnel, endpoint_index) =
+            endpoint::connect_first(&config, &endpoints, 0, &outer, &global().capture, &global().handshakes).await?;
+        let tunnel = Arc::new(tunnel);
+        let resolver = Arc::new(dns::Resolver::new(tunnel.netstack(), servers));
+
+        let mut addresses: Vec<IpAddr> = profile.addresses.iter().map(|net| net.addr()).collect();
+        addresses.sort_by_key(IpAddr::is_ipv6);
+        Ok::<_, TunnelError>(ActiveTunnel {
+            tunnel,
+            resolver,
+            account_type: AccountType::Unknown,
+            device_id: None,
+            public_key: None,
+            addresses: addresses.iter().map(IpAddr::to_string).collect(),
+            config,
+            endpoints,
+            endpoint_index,
+            outer,
+            mtu,
+            rotated_key: None,
+        })
+    })
+}
+
+/// Start a tunnel from a WireGuard profile in wg-quick `.conf` format.
+/// 
+/// Accepts profiles exported by providers such as Mullvad and Proton, and
+/// those generated by wgcf. MTU and PersistentKeepalive fall back to the
+/// tunnel options when the profile leaves them out. Hostnames resolve
+/// through the profile's IPv4 DNS servers, over TCP, unless `setDnsServers`
+/// chose others.
+/// 
+/// @param text Profile contents
+/// @param mtu Tunnel MTU in bytes (576-1500), overriding the profile's, or 0 to keep it
+/// @return tunnel state (0=Stopped, 1=Starting, 2=Ready, 3=Failed)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_importWireGuardProfile<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    text: JString<'local>,
+    mtu: jint,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        if global().tunnel.read().is_some() {
+            log::warn!("Tunnel already running");
+            return TunnelState::Ready as jint;
+        }
+
+        let (text, mtu) = match get_string(env, &text).and_then(|text| Ok((text, get_mtu(mtu)?))) {
+            Ok(args) => args,
+            Err(e) => {
+                throw_exception(env, &e);
+                return TunnelState::Failed as jint;
+            }
+        };
+        let profile = match profile::parse(&text) {
+            Ok(profile) => profile,
+            Err(errors) => {
+                throw_config_errors(env, &errors);
+                return TunnelState::Failed as jint;
+            }
+        };
+        activate_tunnel(env, connect_profile(profile, mtu), false)
+    })
+}
+
+/// Apply a changed WireGuard profile to the running tunnel.
+/// 
+/// The endpoint, MTU, PersistentKeepalive and DNS servers can change.
+/// Upstream fixes the first three when the tunnel is created, so changing
+/// any of them rebuilds the tunnel and closes tunneled connections, except
+/// that with connections preserved an endpoint change alone moves the
+/// running tunnel. DNS changes alone leave everything open. The keys and
+/// the IPv4 address cannot change without restarting the tunnel. An MTU
+/// passed to `importWireGuardProfile` still overrides the profile's.
+/// 
+/// @param text Profile contents
+/// @return reload result (0=Unchanged, 1=Reconnected, 2=DnsUpdated, 3=Moved), or -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_reloadTunnelConfig<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    text: JString<'local>,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        let text = match get_string(env, &text) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return -1;
+            }
+        };
+        let profile = match profile::parse(&text) {
+            Ok(profile) => profile,
+            Err(errors) => {
+                throw_config_errors(env, &errors);
+                return -1;
+            }
+        };
+        match global().run(async { reload_tunnel(&global(), profile).await }) {
+            Ok(result) => result as jint,
+            Err(e) => {
+                let message = format!("Failed to reload tunnel: {}", e);
+                throw_exception(env, &message);
+                global().record_tunnel_error(&e, message);
+                -1
+            }
+        }
+    })
+}
+
+/// Generate a new key pair for the running tunnel.
+/// 
+/// The tunnel keeps using its current key until `confirmKeyRotation` is
+/// called, so the new public key can first be added to the server. Calling
+/// this again replaces the pending key. Only tunnels started from a profile
+/// can rotate; WARP keys are registered with Cloudflare.
+/// 
+/// @return Base64 public key to add to the server
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_rotateKeys<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let (private_key, public_key) = warp_wireguard_gen::generate_keypair();
+        let rotated = match global().tunnel.write().as_mut() {
+            Some(active) if active.device_id.is_none() => {
+                active.rotated_key = Some((private_key, public_key));
+                Ok(())
+            }
+            Some(_) => Err("WARP tunnels cannot rotate keys"),
+            None => Err("Tunnel not running"),
+        };
+        if let Err(e) = rotated {
+            throw_exception(env, e);
+            return std::ptr::null_mut();
+        }
+        match env.new_string(profile::encode_key(&public_key)) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Switch the running tunnel to the key from `rotateKeys`.
+/// 
+/// Upstream cannot change the key of a running tunnel, so this reconnects
+/// and closes tunneled connections. If the handshake with the new key fails
+/// the old tunnel keeps running and the new key stays pending.
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_confirmKeyRotation(
+    mut env: JNIEnv,
+    _class: JClass,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        if let Err(e) = global().run(async { confirm_rotation(&global()).await }) {
+            let message = format!("Failed to rotate keys: {}", e);
+            throw_exception(env, &message);
+            global().record_tunnel_error(&e, message);
+        }
+    })
+}
+
+/// Check a WireGuard profile in wg-quick `.conf` format without starting anything.
+/// 
+/// Returns a JSON array with one object per problem: `severity` ("error"
+/// or "warning"), `line` (1-based, or 0 for a missing field), `section`,
+/// `field` and `message`. The profile can be imported if there are no
+/// errors.
+/// 
+/// @param text Profile contents
+/// @return JSON array of problems, empty if there are none
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_validateConfig<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    text: JString<'local>,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let text = match get_string(env, &text) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+        let json = serde_json::to_string(&profile::validate(&text)).unwrap_or_default();
+        match env.new_string(json) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Get the current tunnel state.
+/// 
+/// @return 0=Stopped, 1=Starting, 2=Ready, 3=Failed, 4=Paused
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tunnelState(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |_| tunnel_state() as jint)
+}
+
+fn tunnel_state() -> TunnelState {
+    match global().tunnel.read().as_ref() {
+        Some(active) if active.tunnel.is_paused() => TunnelState::Paused,
+        Some(_) => TunnelState::Ready,
+        None => TunnelState::Stopped,
+    }
+}
+
+/// Get the most recent failure to start, reconnect or reconfigure the tunnel.
+/// 
+/// Covers start, reload, resume, re-handshake, DSCP and key rotation calls
+/// that threw, and endpoint failover and sleep recovery in the background.
+/// Kept until the next failure, even across restarts.
+/// 
+/// @return JSON object with `code` (one of the TUNNEL_ERROR_* constants),
+///         `message` and `age_seconds`, or null if nothing failed since initJNI
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_lastTunnelError<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let error = match global().last_tunnel_error.lock().as_ref() {
+            Some(error) => metrics::LastError {
+                code: error.code,
+                message: Some(error.message.clone()),
+                age_seconds: Some(error.at.elapsed().as_secs_f64()),
+            },
+            None => return std::ptr::null_mut(),
+        };
+        let json = serde_json::to_string(&error).unwrap_or_else(|_| "{}".into());
+
+        match env.new_string(json) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Pause the tunnel.
+/// 
+/// Stops the WireGuard and netstack loops but keeps the netstack and
+/// connection handles. New connections are refused and I/O on existing ones
+/// stalls until `resumeTunnel`.
+/// 
+/// @return tunnel state after pausing (4=Paused), or 0=Stopped if no tunnel is running
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_pauseTunnel(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |_| {
+        let tunnel = match global().tunnel.read().as_ref() {
+            Some(active) => active.tunnel.clone(),
+            None => return TunnelState::Stopped as jint,
+        };
+
+        global().run(async move {
+            tunnel.pause().await;
+        });
+        TunnelState::Paused as jint
+    })
+}
+
+/// Resume a paused tunnel.
+/// 
+/// @return tunnel state after resuming (2=Ready), 0=Stopped if no tunnel is running, or 3=Failed
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_resumeTunnel(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        let tunnel = match global().tunnel.read().as_ref() {
+            Some(active) => active.tunnel.clone(),
+            None => return TunnelState::Stopped as jint,
+        };
+
+        match global().run(async move { tunnel.resume().await }) {
+            Ok(_) => TunnelState::Ready as jint,
+            Err(e) => {
+                let message = format!("Failed to resume tunnel: {}", e);
+                throw_exception(env, &message);
+                global().record_tunnel_error(&e, message);
+                TunnelState::Failed as jint
+            }
+        }
+    })
+}
+
+/// Tell the tunnel that the local network changed (Wi-Fi, Ethernet, VPN).
+/// 
+/// Starts a new handshake immediately instead of waiting for the keepalive
+/// to notice. A tunnel sending through the loopback relay, as any outer
+/// option off its default makes it, reopens its outer sockets first; a
+/// direct one relies on the peer roaming to its new address. Open connections are kept;
+/// they stall until the handshake completes over the new network. If the
+/// endpoint stays unreachable, the endpoint watchdog fails over as usual.
+/// 
+/// @return true if a handshake was started, false if no tunnel is running or it is paused
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_notifyNetworkChanged(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    panic_guard::catch(&mut env, JNI_FALSE, |env| {
+        let (tunnel, outer) = match global().tunnel.read().as_ref() {
+            Some(active) => (active.tunnel.clone(), active.outer.clone()),
+            None => return JNI_FALSE,
+        };
+
+        match global().run(async move { tunnel.rehandshake(&outer).await }) {
+            Ok(true) => {
+                log::info!("Network changed, re-handshaking");
+                JNI_TRUE
+            }
+            Ok(false) => JNI_FALSE,
+            Err(e) => {
+                let message = format!("Failed to re-handshake: {}", e);
+                throw_exception(env, &message);
+                global().record_tunnel_error(&e, message);
+                JNI_FALSE
+            }
+        }
+    })
+}
+
+/// Tell the tunnel that the machine woke from sleep.
+/// 
+/// Sleep is also detected on its own from clock jumps, within a few seconds
+/// of waking; this hook skips that delay. Starts a new handshake and fails
+/// reads pending on tunneled connections with TCP_ERROR_RECONNECTING, so
+/// they can be retried instead of hanging until they time out.
+/// 
+/// @return true if a handshake was started, false if no tunnel is running or it is paused
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_notifyResumed(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    panic_guard::catch(&mut env, JNI_FALSE, |env| {
+        match global().run(async { recover_from_sleep(&global()).await }) {
+            Ok(true) => {
+                log::info!("Resumed from sleep, re-handshaking");
+                JNI_TRUE
+            }
+            Ok(false) => JNI_FALSE,
+            Err(e) => {
+                let message = format!("Failed to re-handshake: {}", e);
+                throw_exception(env, &message);
+                global().record_tunnel_error(&e, message);
+                JNI_FALSE
+            }
+        }
+    })
+}
+
+/// Delete the WARP device registration and its credentials file.
+/// 
+/// The tunnel must be shut down first, since the device stops working once deleted.
+/// 
+/// @param credPath Path of the WARP credentials JSON
+/// @return 0 on success, -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_deleteWarpDevice<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    cred_path: JString<'local>,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        let cred_path = match get_string(env, &cred_path) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return -1;
+            }
+        };
+
+        if global().tunnel.read().is_some() {
+            throw_exception(env, &format!("Cannot delete device: {}", TunnelError::AlreadyRunning));
+            return -1;
+        }
+
+        let key = match resolve_credential_key(env) {
+            Ok(key) => key,
+            Err(e) => {
+                throw_exception(env, &e);
+                return -1;
+            }
+        };
+        let cred_file = CredentialFile { path: cred_path, key };
+
+        let result = global().run(async move {
+            let credentials = load_credentials(&cred_file)?;
+            warp_account::delete_device(&credentials).await?;
+            fs::remove_file(&cred_file.path)?;
+            config_cache::remove(&config_cache::path_for(&cred_file.path, global().cache_dir.read().as_deref()));
+            log::info!("Removed WARP credentials at {}", cred_file.path);
+            Ok::<_, TunnelError>(())
+        });
+
+        match result {
+            Ok(()) => 0,
+            Err(e) => {
+                throw_exception(env, &format!("Failed to delete WARP device: {}", e));
+                -1
+            }
+        }
+    })
+}
+
+/// Get the account type of the active WARP tunnel.
+/// 
+/// @return -1=Unknown (or no tunnel), 0=Free, 1=Plus, 2=Team
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_warpAccountType(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |_| {
+        let state = global();
+        let tunnel_guard = state.tunnel.read();
+        match tunnel_guard.as_ref() {
+            Some(active) => active.account_type as jint,
+            None => AccountType::Unknown as jint,
+        }
+    })
+}
+
+/// Get the interface addresses WARP assigned to the running tunnel.
+/// 
+/// @return Addresses as strings, IPv4 first, or null if no tunnel is running
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tunnelAddresses<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jobjectArray {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let addresses = match global().tunnel.read().as_ref() {
+            Some(active) => active.addresses.clone(),
+            None => return std::ptr::null_mut(),
+        };
+        match new_string_array(env, &addresses) {
+            Ok(array) => array,
+            Err(e) => {
+                throw_exception(env, &e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Get the WireGuard public key of the running tunnel's device.
+/// 
+/// @return Base64 public key, or null if no tunnel is running or WARP did not report it
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tunnelPublicKey<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let key = match global().tunnel.read().as_ref().and_then(|active| active.public_key.clone()) {
+            Some(key) => key,
+            None => return std::ptr::null_mut(),
+        };
+        match env.new_string(key) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Export the running tunnel's config as a wg-quick profile.
+/// 
+/// Lets the WARP identity be used from other WireGuard clients, e.g. to
+/// check whether a problem is specific to this tunnel. The endpoint is the
+/// one currently in use; the outer transport and obfuscation are not
+/// included.
+/// 
+/// @param includePrivateKey Whether to include the private and preshared keys
+/// @return Profile text, or null if no tunnel is running
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_exportConfig<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    include_private_key: jboolean,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let text = match global().tunnel.read().as_ref() {
+            Some(active) => profile::export(
+                &active.config,
+                active.endpoints[active.endpoint_index],
+                &active.addresses,
+                include_private_key != 0,
+            ),
+            None => return std::ptr::null_mut(),
+        };
+        match env.new_string(text) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Get the WARP device ID of the running tunnel.
+/// 
+/// @return Device ID, or null if no tunnel is running or it was started from a profile
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_warpDeviceId<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let device_id = match global().tunnel.read().as_ref().and_then(|active| active.device_id.clone()) {
+            Some(device_id) => device_id,
+            None => return std::ptr::null_mut(),
+        };
+        match env.new_string(device_id) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Close and remove connections matching `filter`, on the Tokio runtime.
+///
+/// Writes still queued for them are dropped.
+fn close_connections(filter: impl Fn(&Connection) -> bool) {
+    let handles: Vec<i64> = global()
+        .connections
+        .snapshot()
+        .into_iter()
+        .filter(|(_, conn)| filter(conn))
+        .map(|(handle, _)| handle)
+        .collect();
+
+    let mut to_close = Vec::with_capacity(handles.len());
+    for handle in handles {
+        if let Ok(conn) = global().connections.remove(handle) {
+            to_close.push(conn);
+        }
+    }
+
+    if !to_close.is_empty() {
+        global().run(async move {
+            for conn in to_close {
+                let _ = conn.shutdown(Duration::ZERO).await;
+                conn.stop_write_behind();
+            }
+        });
+    }
+}
+
+/// Shutdown the tunnel.
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_shutdownTunnel(
+    mut env: JNIEnv,
+    _class: JClass,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        shutdown_tunnel();
+    })
+}
+
+fn shutdown_tunnel() {
+    log::info!("Shutting down WARP tunnel");
+
+    if let Some(watchdog) = global().endpoint_watchdog.lock().take() {
+        watchdog.abort();
+    }
+    if let Some(watchdog) = global().sleep_watchdog.lock().take() {
+        watchdog.abort();
+    }
+    if let Some(sampler) = global().throughput_sampler.lock().take() {
+        sampler.abort();
+    }
+
+    // Close all tunneled connections (ensure shutdown happens on Tokio runtime).
+    // Direct connections opened by the fallback policy do not depend on the tunnel.
+    close_connections(Connection::is_tunneled);
+    let websockets = global().websockets.drain();
+    if !websockets.is_empty() {
+        global().run(async move { drop(websockets) });
+    }
+
+    // Spares are dropped on the runtime, since closing a tunnel socket polls the netstack
+    let spares = global().connections.pool.clear();
+    if !spares.is_empty() {
+        global().run(async move { drop(spares) });
+    }
+
+    // Remove tunnel (its background tasks are also aborted on drop)
+    let tunnel = global().tunnel.write().take();
+    if let Some(active) = tunnel {
+        global().run(async move {
+            active.tunnel.shutdown().await;
+        });
+    }
+
+    log::info!("WARP tunnel shut down");
+}
+
+// ============================================================================
+// JNI Functions - TCP Operations
+// ============================================================================
+
+/// Open a TCP connection to an address through the tunnel.
+///
+/// IPv6 addresses are rejected, since the netstack only routes IPv4.
+/// A `timeout_ms` of 0 or less leaves only the netstack's own connect timeout.
+async fn connect_tunnel_addr(
+    netstack: Arc<NetStack>,
+    ip: IpAddr,
+    port: u16,
+    timeout_ms: i64,
+) -> Result<TcpConnection, TunnelError> {
+    if ip.is_ipv6() {
+        // Checked here rather than left to the netstack, which would leak the socket it allocated
+        return Err(TunnelError::ConnectionFailed(format!(
+            "IPv6 destination {} is not supported by the tunnel yet",
+            ip
+        )));
+    }
+
+    let connect = TcpConnection::connect(netstack, SocketAddr::from((ip, port)));
+    let conn = if timeout_ms > 0 {
+        tokio::time::timeout(Duration::from_millis(timeout_ms as u64), connect)
+            .await
+            .map_err(|_| TunnelError::Timeout)?
+    } else {
+        connect.await
+    };
+
+    conn.map_err(|e| TunnelError::ConnectionFailed(e.to_string()))
+}
+
+/// Open a TCP connection through the tunnel.
+///
+/// Hostnames are resolved with DNS-over-HTTPS through the tunnel.
+async fn connect_via_tunnel(
+    resolver: Arc<dns::Resolver>,
+    host: String,
+    port: u16,
+    timeout_ms: i64,
+) -> Result<TcpConnection, TunnelError> {
+    let ip = resolver.resolve_host(&host).await?;
+    connect_tunnel_addr(resolver.netstack(), ip, port, timeout_ms).await
+}
+
+/// Resolve the destination addresses for a new connection.
+///
+/// Hostnames a domain rule routes directly are resolved with the system
+/// resolver, like any other direct connection. Others are resolved through
+/// the tunnel when it is up. While it is down they are only resolved with
+/// the system resolver under the fallback policy, so the kill switch does not
+/// leak lookups. The tunnel resolver lookup is returned alongside so the
+/// caller sees the same tunnel state. Addresses the connection policy denies
+/// are left out.
+async fn resolve_destination(
+    host: &str,
+    port: u16,
+    policy: ConnectPolicy,
+) -> Result<(Vec<SocketAddr>, Result<Arc<dns::Resolver>, TunnelError>), TunnelError> {
+    let resolver = global().resolver();
+    let direct = global().router.read().domain_route(host) == Some(routing::Route::Direct);
+    let ips = match &resolver {
+        _ if direct => dns::resolve_system_all(host, port).await?,
+        Ok(resolver) => resolver.resolve_host_all(host).await?,
+        Err(_) => match host.parse::<IpAddr>() {
+            Ok(ip) => vec![ip],
+            Err(_) if policy == ConnectPolicy::FallbackDirect => dns::resolve_system_all(host, port).await?,
+            Err(_) => {
+                return Err(TunnelError::ConnectionFailed(format!(
+                    "Tunnel not available to resolve {}",
+                    host
+                )))
+            }
+        },
+    };
+    let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
+    Ok((eyeballs::interleave(allowed_addrs(host, addrs)?), resolver))
+}
+
+/// Keep the addresses of `host` the connection policy allows, failing if there are none.
+fn allowed_addrs(host: &str, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, TunnelError> {
+    let state = global();
+    let policy = state.policy.read();
+    let allowed: Vec<SocketAddr> = addrs.iter().copied().filter(|&addr| policy.allows(host, addr)).collect();
+    if allowed.is_empty() {
+        log::warn!("Connection to {} ({}) denied by the connection policy", host, addrs[0]);
+        return Err(TunnelError::PolicyDenied(format!("{} ({})", host, addrs[0])));
+    }
+    Ok(allowed)
+}
+
+/// Race direct connections across `addrs`.
+async fn connect_direct_any(addrs: &[SocketAddr], timeout_ms: i64) -> Result<Connection, TunnelError> {
+    eyeballs::race(addrs, |addr| Connection::connect_direct(addr, timeout_ms)).await
+}
+
+/// Connect to one of a destination's addresses, routed by the split-tunnel
+/// rules and the connect policy.
+///
+/// Domain rules are matched against `host`, and CIDR routes against the
+/// first address.
+async fn connect_resolved(
+    host: &str,
+    addrs: &[SocketAddr],
+    resolver: Result<Arc<dns::Resolver>, TunnelError>,
+    timeout_ms: i64,
+    policy: ConnectPolicy,
+) -> Result<Connection, TunnelError> {
+    let addr = addrs[0];
+    let route = global().router.read().route(host, addr.ip());
+    if route == routing::Route::Direct {
+        log::info!("Connecting to {} ({}) directly (routing rule)", host, addr);
+        return connect_direct_any(addrs, timeout_ms).await;
+    }
+
+    match resolver {
+        Ok(resolver) => {
+            log::info!("Connecting to {} ({}) via WireGuard tunnel", host, addr);
+            connect_tunnel_any(resolver.netstack(), addrs, timeout_ms).await
+        }
+        Err(e) if policy == ConnectPolicy::FallbackDirect => {
+            log::warn!("Tunnel not available ({}), connecting to {} ({}) directly", e, host, addr);
+            connect_direct_any(addrs, timeout_ms).await
+        }
+        Err(e) => Err(TunnelError::ConnectionFailed(format!("Tunnel not available: {}", e))),
+    }
+}
+
+/// Race tunnel connections across `addrs`, skipping IPv6 ones the netstack cannot route.
+async fn connect_tunnel_any(
+    netstack: Arc<NetStack>,
+    addrs: &[SocketAddr],
+    timeout_ms: i64,
+) -> Result<Connection, TunnelError> {
+    let routable: Vec<SocketAddr> = addrs.iter().copied().filter(SocketAddr::is_ipv4).collect();
+    // With nothing routable, fail on the first address for its error message
+    let addrs = if routable.is_empty() { &addrs[..1] } else { &routable[..] };
+    eyeballs::race(addrs, |addr| {
+        let netstack = netstack.clone();
+        async move {
+            connect_tunnel_addr(netstack, addr.ip(), addr.port(), timeout_ms)
+                .await
+                .map(|conn| Connection::tunnel(conn, addr))
+        }
+    })
+    .await
+}
+
+/// Open a connection to `host:port`, using a pre-warmed spare when one is available.
+async fn open_connection(
+    host: String,
+    port: u16,
+    timeout_ms: i64,
+    policy: ConnectPolicy,
+) -> Result<Connection, TunnelError> {
+    let mut trace = ConnectTrace::new();
+    if host.parse::<IpAddr>().is_err() {
+        trace.dns_start = Some(Instant::now());
+    }
+    let (addrs, resolver) = resolve_destination(&host, port, policy).await?;
+    if trace.dns_start.is_some() {
+        trace.dns_end = Some(Instant::now());
+    }
+
+    if let Some(mut conn) = addrs.iter().find_map(|&addr| global().connections.pool.take(addr)) {
+        log::info!("Using pre-warmed connection to {} ({})", host, conn.remote_addr());
+        trace.pooled = true;
+        conn.set_connect_trace(trace);
+        return Ok(conn);
+    }
+
+    trace.syn_sent = Some(Instant::now());
+    let mut conn = connect_resolved(&host, &addrs, resolver, timeout_ms, policy).await?;
+    trace.established = Some(Instant::now());
+    conn.set_connect_trace(trace);
+    Ok(conn)
+}
+
+/// Send a PROXY protocol header on a new connection to `host` if a rule asks for one.
+async fn send_proxy_header(host: &str, conn: &Connection) -> Result<(), TunnelError> {
+    let state = global();
+    let Some(source) = state.proxy_protocol.read().source_for(host, conn.remote_addr().ip()) else {
+        return Ok(());
+    };
+    let src = match source {
+        proxy_protocol::Source::Fixed(addr) => addr,
+        proxy_protocol::Source::Connection => conn.local_addr().unwrap_or_else(|| {
+            let tunnel = state.tunnel.read();
+            let ip = tunnel.as_ref().map_or(Ipv4Addr::UNSPECIFIED, |t| t.tunnel.tunnel_ip());
+            SocketAddr::from((ip, 0))
+        }),
+    };
+    log::debug!("Sending PROXY header for {} to {}", src, conn.remote_addr());
+    let mut header = &proxy_protocol::header(src, conn.remote_addr())[..];
+    while !header.is_empty() {
+        let n = conn.write(header).await?;
+        header = &header[n..];
+    }
+    conn.flush().await
+}
+
+/// Open a connection for `tcpConnect`, applying routing and the connect policy.
+fn tcp_connect(env: &mut JNIEnv, host: &JString, port: jint, timeout_ms: jlong, policy: ConnectPolicy) -> jlong {
+    let host = match get_string(env, host) {
+        Ok(s) => s,
+        Err(e) => {
+            throw_exception(env, &e);
+            return -1;
+        }
+    };
+
+    match connect_tcp(host, port as u16, timeout_ms, policy) {
+        Ok(handle) => handle,
+        Err(e) => {
+            throw_exception(env, &format!("Connection failed: {}", e));
+            -1
+        }
+    }
+}
+
+/// Open a connection to `host:port` and register it, returning its handle.
+fn connect_tcp(host: String, port: u16, timeout_ms: i64, policy: ConnectPolicy) -> Result<i64, TunnelError> {
+    global().connections.check_capacity()?;
+
+    let conn = global().run(async move {
+        let conn = open_connection(host.clone(), port, timeout_ms, policy).await?;
+        send_proxy_header(&host, &conn).await?;
+        Ok::<_, TunnelError>(conn)
+    })?;
+    let handle = global().connections.insert(conn);
+    log::debug!("TCP connection established, handle={}", handle);
+    Ok(handle)
+}
+
+/// Connect to a remote host via the tunnel.
+/// 
+/// When the host has several addresses, attempts start 250 ms apart and the
+/// first to connect wins. If the tunnel is down, the configured connect
+/// policy decides whether this fails or connects directly.
+/// 
+/// @param host Hostname or IP address
+/// @param port Port number
+/// @param timeoutMs Connection timeout in milliseconds (0 = no timeout)
+/// @return Connection handle (>0) on success, -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpConnect<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    host: JString<'local>,
+    port: jint,
+    timeout_ms: jlong,
+) -> jlong {
+    panic_guard::catch(&mut env, -1, |env| {
+        let policy = global().options.read().connect_policy;
+        tcp_connect(env, &host, port, timeout_ms, policy)
+    })
+}
+
+/// Connect to a remote host via the tunnel with a per-connection policy.
+/// 
+/// @param host Hostname or IP address
+/// @param port Port number
+/// @param timeoutMs Connection timeout in milliseconds (0 = no timeout)
+/// @param policy -1=configured default, 0=Kill switch, 1=Fallback to direct
+/// @return Connection handle (>0) on success, -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpConnectWithPolicy<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    host: JString<'local>,
+    port: jint,
+    timeout_ms: jlong,
+    policy: jint,
+) -> jlong {
+    panic_guard::catch(&mut env, -1, |env| {
+        let policy = match policy {
+            -1 => global().options.read().connect_policy,
+            p => match ConnectPolicy::from_jint(p) {
+                Some(p) => p,
+                None => {
+                    throw_exception(env, &format!("Invalid connect policy: {}", p));
+                    return -1;
+                }
+            },
+        };
+        tcp_connect(env, &host, port, timeout_ms, policy)
+    })
+}
+
+/// Connect to a Minecraft server via the tunnel, honouring its SRV record.
+/// 
+/// Looks up `_minecraft._tcp.<host>` through the tunnel like the vanilla
+/// client does and connects to the record's target. Falls back to `host:port`
+/// when there is no record. Routing rules and the connect policy then apply
+/// as for `tcpConnect`.
+/// 
+/// @param host Hostname or IP address
+/// @param port Port number used when there is no SRV record
+/// @param timeoutMs Connection timeout in milliseconds (0 = no timeout)
+/// @return Connection handle (>0) on success, -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpConnectSrv<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    host: JString<'local>,
+    port: jint,
+    timeout_ms: jlong,
+) -> jlong {
+    panic_guard::catch(&mut env, -1, |env| {
+        let host = match get_string(env, &host) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return -1;
+            }
+        };
+
+        let port = port as u16;
+        let (host, port) = match global().resolver() {
+            Ok(resolver) => global().run(async move {
+                match resolver.resolve_minecraft_srv(&host).await {
+                    Ok(Some(srv)) => {
+                        log::info!("SRV record for {} points to {}:{}", host, srv.target, srv.port);
+                        (srv.target, srv.port)
+                    }
+                    Ok(None) => (host, port),
+                    Err(e) => {
+                        log::warn!("SRV lookup for {} failed, using the A/AAAA record: {}", host, e);
+                        (host, port)
+                    }
+                }
+            }),
+            // Without the tunnel the connect policy decides, as for tcpConnect
+            Err(e) => {
+                log::debug!("Skipping SRV lookup for {}: {}", host, e);
+                (host, port)
+            }
+        };
+
+        let policy = global().options.read().connect_policy;
+        match connect_tcp(host, port, timeout_ms, policy) {
+            Ok(handle) => handle,
+            Err(e) => {
+                throw_exception(env, &format!("Connection failed: {}", e));
+                -1
+            }
+        }
+    })
+}
+
+/// Limit on connecting and completing the handshake in `tlsConnect`.
+const TLS_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Open a netstack connection to `host:port` for the native TLS and HTTP
+/// clients, ignoring routing rules but not the connection policy.
+async fn connect_tunnel_host(
+    resolver: &dns::Resolver,
+    host: &str,
+    port: u16,
+) -> Result<(TcpConnection, SocketAddr), TunnelError> {
+    let addrs: Vec<SocketAddr> = resolver
+        .resolve_host_all(host)
+        .await?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect();
+    let addrs = eyeballs::interleave(allowed_addrs(host, addrs)?);
+    let routable: Vec<SocketAddr> = addrs.iter().copied().filter(SocketAddr::is_ipv4).collect();
+    let addrs = if routable.is_empty() { &addrs[..1] } else { &routable[..] };
+
+    let netstack = resolver.netstack();
+    eyeballs::race(addrs, |addr| {
+        let netstack = netstack.clone();
+        async move { Ok((connect_tunnel_addr(netstack, addr.ip(), addr.port(), 0).await?, addr)) }
+    })
+    .await
+}
+
+/// Open a TLS connection to `host:port` through the tunnel.
+async fn open_tls(
+    resolver: Arc<dns::Resolver>,
+    host: String,
+    port: u16,
+    sni: String,
+    alpn: Vec<Vec<u8>>,
+) -> Result<Connection, TunnelError> {
+    log::info!("Connecting to {}:{} over TLS via WireGuard tunnel", host, port);
+    let (conn, addr) = connect_tunnel_host(&resolver, &host, port).await?;
+    let conn = Arc::new(conn);
+    let stream = https::tls_handshake(conn.clone(), &sni, alpn).await?;
+    Ok(Connection::tls(conn, stream, addr))
+}
+
+/// Open a TLS connection through the tunnel.
+/// 
+/// The returned handle works with the tcp* functions, which read and write
+/// plaintext. Certificates are verified against the Mozilla root store. The
+/// connection policy applies, but routing rules do not: the connection
+/// always goes through the tunnel.
+/// 
+/// @param host Hostname or IP address
+/// @param port Port number
+/// @param sni Server name to send and verify the certificate against; null for the host
+/// @param alpn ALPN protocols to offer in order of preference (e.g. "h2", "http/1.1"), or null
 /// @return Connection handle (>0) on success, -1 on error
 #[no_mangle]
-pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpConnect<'local>(
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tlsConnect<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    host: JString<'local>,
+    port: jint,
+    sni: JString<'local>,
+    alpn: JObjectArray<'local>,
+) -> jlong {
+    panic_guard::catch(&mut env, -1, |env| {
+        let args = get_string(env, &host).and_then(|host| {
+            let sni = get_optional_string(env, &sni)?.unwrap_or_else(|| host.clone());
+            Ok((host, sni, get_string_array(env, &alpn)?))
+        });
+        let (host, sni, alpn) = match args {
+            Ok(args) => args,
+            Err(e) => {
+                throw_exception(env, &e);
+                return -1;
+            }
+        };
+        let alpn = alpn.into_iter().map(String::into_bytes).collect();
+
+        let resolver = match global().resolver() {
+            Ok(r) => r,
+            Err(e) => {
+                throw_exception(env, &format!("Tunnel not available: {}", e));
+                return -1;
+            }
+        };
+
+        if let Err(e) = global().connections.check_capacity() {
+            throw_exception(env, &format!("Connection failed: {}", e));
+            return -1;
+        }
+
+        let result = global().run(async move {
+            tokio::time::timeout(TLS_CONNECT_TIMEOUT, open_tls(resolver, host, port as u16, sni, alpn))
+                .await
+                .map_err(|_| TunnelError::Timeout)?
+        });
+
+        match result {
+            Ok(conn) => {
+                let handle = global().connections.insert(conn);
+                log::debug!("TLS connection established, handle={}", handle);
+                handle
+            }
+            Err(e) => {
+                throw_exception(env, &format!("Connection failed: {}", e));
+                -1
+            }
+        }
+    })
+}
+
+/// Get the ALPN protocol the server picked for a TLS connection.
+/// 
+/// @param handle Connection handle from tlsConnect
+/// @return The protocol, or null if none was negotiated or the handle is not TLS
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tlsAlpnProtocol<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+        let Some(protocol) = conn.alpn_protocol() else {
+            return std::ptr::null_mut();
+        };
+        match env.new_string(String::from_utf8_lossy(protocol)) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// tcpRead result when a non-blocking connection has nothing to read.
+const READ_WOULD_BLOCK: jint = -2;
+
+/// Read data from a TCP connection.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param buffer Byte array to read into
+/// @return Number of bytes read, 0 on EOF, -1 on error, -2 if non-blocking and no data is available
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpRead<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    buffer: JByteArray<'local>,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        let Some(conn) = unringed_connection(env, handle) else {
+            return -1;
+        };
+
+        let buf_len = match env.get_array_length(&buffer) {
+            Ok(len) => len as usize,
+            Err(e) => {
+                throw_exception(env, &format!("Failed to get buffer length: {}", e));
+                return -1;
+            }
+        };
+
+        if conn.is_traced() {
+            log::debug!(
+                "tcpRead: waiting for data on handle {}, buf_len={}, state={:?}",
+                handle,
+                buf_len,
+                conn.socket_state()
+            );
+        }
+
+        let mut rust_buf = conn.take_read_buf(buf_len);
+
+        let io_conn = conn.clone();
+        let (result, rust_buf) = global().run(async move {
+            let result = io_conn.read(&mut rust_buf).await;
+            (result, rust_buf)
+        });
+
+        let ret = match result {
+            Ok(0) => {
+                if conn.is_traced() {
+                    log::debug!("tcpRead: returning EOF on handle {}", handle);
+                }
+                0
+            }
+            Err(TunnelError::WouldBlock) => READ_WOULD_BLOCK,
+            Ok(n) => {
+                if conn.is_traced() {
+                    log::debug!("tcpRead: returning {} bytes to Java from handle {}", n, handle);
+                }
+                match env.set_byte_array_region(&buffer, 0, as_jbytes(&rust_buf[..n])) {
+                    Ok(()) => n as jint,
+                    Err(e) => {
+                        throw_exception(env, &format!("Failed to copy to buffer: {}", e));
+                        -1
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("tcpRead: read returned error: {}", e);
+                throw_io_error(env, &conn, "Read", &e);
+                -1
+            }
+        };
+        conn.put_read_buf(rust_buf);
+        ret
+    })
+}
+
+/// Write data to a TCP connection.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param data Byte array to write
+/// @param offset Offset in the array
+/// @param length Number of bytes to write
+/// @return Number of bytes written, 0 if non-blocking or partial and nothing fits, -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpWrite<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    data: JByteArray<'local>,
+    offset: jint,
+    length: jint,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        let Some(conn) = unringed_connection(env, handle) else {
+            return -1;
+        };
+
+        // Get bytes from Java array
+        let mut rust_bytes = WRITE_BUF.take();
+        rust_bytes.resize(length.max(0) as usize, 0);
+        if let Err(e) = env.get_byte_array_region(&data, offset, as_jbytes_mut(&mut rust_bytes)) {
+            throw_exception(env, &format!("Failed to read from buffer: {}", e));
+            WRITE_BUF.set(rust_bytes);
+            return -1;
+        }
+
+        if conn.is_traced() {
+            log::debug!(
+                "tcpWrite: writing {} bytes to handle {}, state={:?}",
+                rust_bytes.len(),
+                handle,
+                conn.socket_state()
+            );
+        }
+
+        let io_conn = conn.clone();
+        let (result, rust_bytes) = global().run(async move {
+            let result = io_conn.write(&rust_bytes).await;
+            (result, rust_bytes)
+        });
+        WRITE_BUF.set(rust_bytes);
+
+        match result {
+            Ok(n) => {
+                if conn.is_traced() {
+                    log::debug!("tcpWrite: wrote {} bytes to handle {}", n, handle);
+                }
+                n as jint
+            }
+            Err(TunnelError::WouldBlock) => 0,
+            Err(e) => {
+                throw_io_error(env, &conn, "Write", &e);
+                -1
+            }
+        }
+    })
+}
+
+/// Copy the remaining bytes of a `java.nio.ByteBuffer`, leaving its position unchanged.
+///
+/// Direct buffers are read in place; heap buffers through their backing array.
+fn read_byte_buffer(env: &mut JNIEnv, buffer: JObject) -> Result<Vec<u8>, String> {
+    let buffer = JByteBuffer::from(buffer);
+    let int_call = |env: &mut JNIEnv, name: &str| {
+        env.call_method(&buffer, name, "()I", &[]).and_then(|v| v.i()).map_err(|e| {
+            let _ = env.exception_clear();
+            format!("ByteBuffer.{}() failed: {}", name, e)
+        })
+    };
+    let position = int_call(env, "position")?;
+    let limit = int_call(env, "limit")?;
+    let len = (limit - position).max(0) as usize;
+
+    if let Ok(address) = env.get_direct_buffer_address(&buffer) {
+        // SAFETY: a direct buffer's memory stays valid while the buffer is
+        // referenced, and position..limit lies within its capacity
+        let bytes = unsafe { std::slice::from_raw_parts(address.add(position as usize), len) };
+        return Ok(bytes.to_vec());
+    }
+
+    // Throws for read-only heap buffers, whose array is not accessible
+    let offset = int_call(env, "arrayOffset")?;
+    let array = env
+        .call_method(&buffer, "array", "()[B", &[])
+        .and_then(|v| v.l())
+        .map_err(|e| {
+            let _ = env.exception_clear();
+            format!("ByteBuffer.array() failed: {}", e)
+        })?;
+    let mut bytes = vec![0u8; len];
+    env.get_byte_array_region(JByteArray::from(array), offset + position, as_jbytes_mut(&mut bytes))
+        .map_err(|e| format!("Failed to read from buffer: {}", e))?;
+    Ok(bytes)
+}
+
+/// Write several buffers to a TCP connection in one call.
+/// 
+/// The remaining bytes of each buffer are sent in order, as one write.
+/// Buffer positions are not changed.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param buffers Array of java.nio.ByteBuffer, direct or heap
+/// @return Total number of bytes written, 0 if non-blocking and the send buffer is full, -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpWritev<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    buffers: JObjectArray<'local>,
+) -> jlong {
+    panic_guard::catch(&mut env, -1, |env| {
+        let Some(conn) = unringed_connection(env, handle) else {
+            return -1;
+        };
+
+        let count = match env.get_array_length(&buffers) {
+            Ok(n) => n,
+            Err(e) => {
+                throw_exception(env, &format!("Failed to read buffers: {}", e));
+                return -1;
+            }
+        };
+        let mut data = Vec::new();
+        for i in 0..count {
+            let bytes = env
+                .get_object_array_element(&buffers, i)
+                .map_err(|e| format!("Failed to read buffers: {}", e))
+                .and_then(|buffer| {
+                    if buffer.is_null() {
+                        return Err(format!("Buffer {} is null", i));
+                    }
+                    read_byte_buffer(env, buffer)
+                });
+            match bytes {
+                Ok(bytes) => data.extend_from_slice(&bytes),
+                Err(e) => {
+                    throw_exception(env, &e);
+                    return -1;
+                }
+            }
+        }
+
+        if conn.is_traced() {
+            log::debug!("tcpWritev: writing {} bytes from {} buffers to handle {}", data.len(), count, handle);
+        }
+
+        let io_conn = conn.clone();
+        match global().run(async move { io_conn.write(&data).await }) {
+            Ok(n) => n as jlong,
+            Err(TunnelError::WouldBlock) => 0,
+            Err(e) => {
+                throw_io_error(env, &conn, "Write", &e);
+                -1
+            }
+        }
+    })
+}
+
+/// Start reading from a TCP connection and return at once.
+/// 
+/// The callback's onComplete receives the number of bytes read, 0 on EOF,
+/// once they have been copied into `buffer`. Do not touch the buffer until
+/// then. Callbacks run on a native blocking thread, not a runtime worker, so
+/// they may call other Native methods.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param buffer Byte array to read into
+/// @param callback Object implementing IoCallback
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpReadAsync<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    buffer: JByteArray<'local>,
+    callback: JObject<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let state = global();
+        let Some(conn) = unringed_connection(env, handle) else {
+            return;
+        };
+        let buf_len = match env.get_array_length(&buffer) {
+            Ok(len) => len as usize,
+            Err(e) => {
+                throw_exception(env, &format!("Failed to get buffer length: {}", e));
+                return;
+            }
+        };
+        let (buffer, callback) = match env.new_global_ref(&buffer).and_then(|b| Ok((b, new_io_callback(env, &callback)?))) {
+            Ok(refs) => refs,
+            Err(e) => {
+                throw_exception(env, &format!("Failed to store callback: {}", e));
+                return;
+            }
+        };
+
+        state.handle.spawn(async move {
+            let mut data = conn.take_read_buf(buf_len);
+            let result = conn.read(&mut data).await;
+            // Off the runtime's workers, so the callback may call back into Native
+            let _ = tokio::task::spawn_blocking(move || {
+                match result {
+                    Ok(n) => {
+                        if let Err(e) = callback.complete_read(handle, &buffer, &data[..n]) {
+                            log::warn!("Failed to complete async read, handle={}: {}", handle, e);
+                        }
+                    }
+                    Err(e) => fail_async(&callback, handle, &conn, "Read", &e),
+                }
+                conn.put_read_buf(data);
+            })
+            .await;
+        });
+    })
+}
+
+/// Start writing to a TCP connection and return at once.
+/// 
+/// The data is copied before this returns, so the array may be reused
+/// immediately. The callback's onComplete receives the number of bytes written,
+/// on a native blocking thread like tcpReadAsync's.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param data Byte array to write
+/// @param offset Offset in the array
+/// @param length Number of bytes to write
+/// @param callback Object implementing IoCallback
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpWriteAsync<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    data: JByteArray<'local>,
+    offset: jint,
+    length: jint,
+    callback: JObject<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let state = global();
+        let Some(conn) = unringed_connection(env, handle) else {
+            return;
+        };
+        let mut bytes = vec![0u8; length.max(0) as usize];
+        if let Err(e) = env.get_byte_array_region(&data, offset, as_jbytes_mut(&mut bytes)) {
+            throw_exception(env, &format!("Failed to get data: {}", e));
+            return;
+        }
+        let callback = match new_io_callback(env, &callback) {
+            Ok(callback) => callback,
+            Err(e) => {
+                throw_exception(env, &format!("Failed to store callback: {}", e));
+                return;
+            }
+        };
+
+        state.handle.spawn(async move {
+            let result = conn.write(&bytes).await;
+            // Off the runtime's workers, so the callback may call back into Native
+            let _ = tokio::task::spawn_blocking(move || match result {
+                Ok(n) => {
+                    if let Err(e) = callback.complete(handle, n as i32) {
+                        log::warn!("Failed to complete async write, handle={}: {}", handle, e);
+                    }
+                }
+                Err(e) => fail_async(&callback, handle, &conn, "Write", &e),
+            })
+            .await;
+        });
+    })
+}
+
+/// Close a TCP connection.
+/// 
+/// Waits up to the setCloseLinger timeout for data already written to be
+/// sent and acknowledged by the peer. Throws if writes queued by
+/// write-behind could not be sent; the connection is closed either way.
+/// 
+/// @param handle Connection handle from tcpConnect
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpClose(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        if let Err(e) = close_tcp(handle) {
+            throw_handle_error(env, &e);
+        }
+    })
+}
+
+/// Close a connection, lingering as configured with `setCloseLinger`.
+///
+/// The linger bounds the whole close, including sending queued writes.
+fn close_tcp(handle: i64) -> Result<(), TunnelError> {
+    let state = global();
+    let conn = state.connections.remove(handle)?;
+    let linger = Duration::from_millis(state.connections.linger_ms.load(Ordering::Relaxed));
+    let drained = state.run(async move {
+        let started = Instant::now();
+        conn.shutdown(linger).await?;
+        Ok::<_, TunnelError>(linger.is_zero() || conn.drain(linger.saturating_sub(started.elapsed())).await)
+    })?;
+    if drained {
+        log::debug!("TCP connection closed, handle={}", handle);
+    } else {
+        log::debug!("TCP connection closed with data unacknowledged after {:?}, handle={}", linger, handle);
+    }
+    Ok(())
+}
+
+/// Close a connection whose Java owner was garbage collected.
+/// 
+/// Unlike tcpClose this never throws or lingers, since it runs on a cleaner
+/// thread; handles that were already closed are ignored.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return true if the connection was still open
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpReap(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) -> jboolean {
+    panic_guard::catch(&mut env, JNI_FALSE, |_| {
+        // After shutdownNative there is nothing left to reap, and global() would start a new runtime
+        let Some(state) = GLOBAL.read().clone() else {
+            return JNI_FALSE;
+        };
+        let Ok(conn) = state.connections.remove(handle) else {
+            return JNI_FALSE;
+        };
+        log::warn!(
+            "Connection to {} was garbage collected without tcpClose, closing it, handle={}",
+            conn.remote_addr(),
+            handle
+        );
+        state.handle.spawn(async move {
+            if let Err(e) = conn.shutdown(Duration::ZERO).await {
+                log::debug!("Reaped connection failed while closing, handle={}: {}", handle, e);
+            }
+        });
+        JNI_TRUE
+    })
+}
+
+/// Set how long tcpClose waits for written data to reach the peer, like
+/// SO_LINGER.
+/// 
+/// With 0, tcpClose returns at once and the netstack keeps sending in the
+/// background until the tunnel is shut down.
+/// 
+/// @param timeoutMs Longest wait in milliseconds, or 0 to not wait
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setCloseLinger(
+    mut env: JNIEnv,
+    _class: JClass,
+    timeout_ms: jint,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        let timeout_ms = timeout_ms.max(0) as u64;
+        log::info!("Setting close linger timeout to {} ms", timeout_ms);
+        global().connections.linger_ms.store(timeout_ms, Ordering::Relaxed);
+    })
+}
+
+/// Local address of a TCP connection as "ip:port".
+/// 
+/// Tunnel connections report the tunnel IP with port 0, since the netstack
+/// does not expose the port it picked.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return The address, or null if the tunnel it used is gone
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpLocalAddress<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let state = global();
+        let conn = match state.connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        let local = conn.local_addr().or_else(|| {
+            let tunnel = state.tunnel.read();
+            tunnel.as_ref().map(|t| SocketAddr::from((t.tunnel.tunnel_ip(), 0)))
+        });
+        let Some(local) = local else {
+            return std::ptr::null_mut();
+        };
+        match env.new_string(local.to_string()) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Remote address of a TCP connection as "ip:port".
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return The resolved address the connection was opened to
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpRemoteAddress<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        match env.new_string(conn.remote_addr().to_string()) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Shut down the sending side of a TCP connection.
+/// 
+/// Sends FIN once buffered data is out; reads continue until the peer
+/// closes. Waits up to the setCloseLinger timeout for writes queued by
+/// write-behind, throwing if they could not be sent. The handle stays valid
+/// and must still be closed with tcpClose.
+/// 
+/// @param handle Connection handle from tcpConnect
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpShutdownOutput(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        match global().connections.get(handle) {
+            Ok(conn) => {
+                let linger = Duration::from_millis(global().connections.linger_ms.load(Ordering::Relaxed));
+                let result = global().run({
+                    let conn = conn.clone();
+                    async move { conn.shutdown(linger).await }
+                });
+                match result {
+                    Ok(()) => log::debug!("TCP output shut down, handle={}", handle),
+                    Err(e) => throw_io_error(env, &conn, "Shutdown", &e),
+                }
+            }
+            Err(e) => throw_handle_error(env, &e),
+        }
+    })
+}
+
+/// Enable or disable TCP keepalive on a connection, like Socket#setKeepAlive.
+/// 
+/// Only direct connections support it. Tunnel connections are left
+/// unchanged; the WireGuard persistent keepalive already keeps their path
+/// through NATs and firewalls open.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param idleSecs Idle time before the first probe, or 0 to disable keepalive
+/// @param intervalSecs Time between probes
+/// @return true if applied, false if the connection does not support it
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpSetKeepAlive<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    idle_secs: jint,
+    interval_secs: jint,
+) -> jboolean {
+    panic_guard::catch(&mut env, JNI_FALSE, |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return JNI_FALSE;
+            }
+        };
+
+        let idle = (idle_secs > 0).then(|| Duration::from_secs(idle_secs as u64));
+        let interval = Duration::from_secs(interval_secs.max(1) as u64);
+        match conn.set_keepalive(idle, interval) {
+            Ok(applied) => applied as jboolean,
+            Err(e) => {
+                throw_exception(env, &format!("Failed to set keepalive: {}", e));
+                JNI_FALSE
+            }
+        }
+    })
+}
+
+/// Log every read and write on a connection, at debug level.
+/// 
+/// Off by default, so reads and writes skip the log filter entirely; the
+/// filter must still let debug records through for anything to be logged.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param trace true to log this connection's I/O
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpSetTrace<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    trace: jboolean,
+) {
+    panic_guard::catch(&mut env, (), |env| match global().connections.get(handle) {
+        Ok(conn) => conn.set_trace(trace != 0),
+        Err(e) => throw_handle_error(env, &e),
+    })
+}
+
+/// Get why the last read or write on a connection failed or hit EOF.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return One of the TCP_ERROR_* constants
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpLastError<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| match global().connections.get(handle) {
+        Ok(conn) => conn.last_error(),
+        Err(e) => {
+            throw_handle_error(env, &e);
+            -1
+        }
+    })
+}
+
+/// Get why the last read or write on a connection failed, with the message
+/// it was reported with.
+/// 
+/// The message is null when the code was recorded without reaching Java,
+/// e.g. EOF from a read that returned 0.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return JSON object with `code` (one of the TCP_ERROR_* constants) and `message`
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpLastErrorInfo<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        let error = metrics::LastError {
+            code: conn.last_error(),
+            message: conn.last_error_message(),
+            age_seconds: None,
+        };
+        let json = serde_json::to_string(&error).unwrap_or_else(|_| "{}".into());
+
+        match env.new_string(json) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// TCP state of a handle, as reported to Java.
+///
+/// Direct sockets expose no state without I/O, so they count as established.
+/// Handles that were closed or never existed count as closed.
+fn tcp_state(handle: jlong) -> TcpState {
+    match global().connections.get(handle) {
+        Ok(conn) => conn.socket_state().unwrap_or(TcpState::Established),
+        Err(_) => TcpState::Closed,
+    }
+}
+
+/// How often connections are checked for closes by the peer.
+const CLOSE_WATCH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Report connections closed by the peer to `listener` until the global
+/// state is dropped.
+async fn watch_peer_closes(state: Weak<GlobalState>, listener: listener::ConnectionListener) {
+    let mut ticker = tokio::time::interval(CLOSE_WATCH_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let closed: Vec<_> = state
+            .connections
+            .snapshot()
+            .into_iter()
+            .filter_map(|(handle, conn)| Some((handle, conn.take_peer_close()?)))
+            .collect();
+        drop(state);
+
+        for (handle, reason) in closed {
+            log::debug!("Connection closed by peer ({:?}), handle={}", reason, handle);
+            if let Err(e) = listener.notify(handle, reason) {
+                log::warn!("Connection listener failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Register a listener for connections closed by the peer.
+/// 
+/// Replaces any previous listener.
+/// 
+/// @param listener Object implementing ConnectionListener, or null to remove it
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_registerConnectionListener<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    listener: JObject<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let state = global();
+        let task = if listener.is_null() {
+            None
+        } else {
+            let listener = env
+                .get_java_vm()
+                .and_then(|vm| Ok(listener::ConnectionListener::new(vm, env.new_global_ref(&listener)?)));
+            match listener {
+                Ok(listener) => Some(state.handle.spawn(watch_peer_closes(Arc::downgrade(&state), listener))),
+                Err(e) => {
+                    throw_exception(env, &format!("Failed to store listener: {}", e));
+                    return;
+                }
+            }
+        };
+        let old = std::mem::replace(&mut *state.connections.close_watch.lock(), task);
+        if let Some(old) = old {
+            old.abort();
+        }
+    })
+}
+
+/// Forward handshake events to `listener` until it is replaced.
+async fn forward_handshake_events(
+    mut events: tokio::sync::broadcast::Receiver<tunnel::HandshakeEvent>,
+    listener: listener::TunnelListener,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                log::warn!("Tunnel listener fell behind, {} handshake events dropped", missed);
+                continue;
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+        };
+        if let Err(e) = listener.notify(event) {
+            log::warn!("Tunnel listener failed: {}", e);
+        }
+    }
+}
+
+/// Register a listener for WireGuard handshake events.
+/// 
+/// Replaces any previous listener.
+/// 
+/// @param listener Object implementing TunnelListener, or null to remove it
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_registerTunnelListener<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    listener: JObject<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let state = global();
+        let task = if listener.is_null() {
+            None
+        } else {
+            let listener = env
+                .get_java_vm()
+                .and_then(|vm| Ok(listener::TunnelListener::new(vm, env.new_global_ref(&listener)?)));
+            match listener {
+                Ok(listener) => {
+                    let events = state.handshakes.subscribe();
+                    Some(state.handle.spawn(forward_handshake_events(events, listener)))
+                }
+                Err(e) => {
+                    throw_exception(env, &format!("Failed to store listener: {}", e));
+                    return;
+                }
+            }
+        };
+        let old = std::mem::replace(&mut *state.tunnel_listener.lock(), task);
+        if let Some(old) = old {
+            old.abort();
+        }
+    })
+}
+
+/// Check whether a TCP connection is established and not yet closed by both sides.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return true if data may still flow in at least one direction
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpIsConnected(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) -> jboolean {
+    panic_guard::catch(&mut env, JNI_FALSE, |_| {
+        matches!(
+            tcp_state(handle),
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2 | TcpState::CloseWait
+        ) as jboolean
+    })
+}
+
+/// Check whether a TCP connection is closed.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return true if the handle was closed or the connection has fully shut down
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpIsClosed(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) -> jboolean {
+    panic_guard::catch(&mut env, JNI_FALSE, |_| {
+        matches!(tcp_state(handle), TcpState::Closed | TcpState::TimeWait) as jboolean
+    })
+}
+
+/// Get the TCP state of a connection.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return One of the SOCKET_STATE_* constants
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpSocketState(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |_| match tcp_state(handle) {
+        TcpState::Closed => 0,
+        TcpState::Listen => 1,
+        TcpState::SynSent => 2,
+        TcpState::SynReceived => 3,
+        TcpState::Established => 4,
+        TcpState::FinWait1 => 5,
+        TcpState::FinWait2 => 6,
+        TcpState::CloseWait => 7,
+        TcpState::Closing => 8,
+        TcpState::LastAck => 9,
+        TcpState::TimeWait => 10,
+    })
+}
+
+/// Flush a TCP connection.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return 0 on success, -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpFlush<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return -1;
+            }
+        };
+
+        let io_conn = conn.clone();
+        match global().run(async move { io_conn.flush().await }) {
+            Ok(()) => 0,
+            Err(e) => {
+                throw_io_error(env, &conn, "Flush", &e);
+                -1
+            }
+        }
+    })
+}
+
+/// Limit the bandwidth of a connection.
+/// 
+/// Reads and writes are limited separately, each to `bytesPerSec`.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param bytesPerSec Limit per direction in bytes per second, or 0 for no limit
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setRateLimit<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    bytes_per_sec: jlong,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        match global().connections.get(handle) {
+            Ok(conn) => conn.limit().set(bytes_per_sec.max(0) as u64),
+            Err(e) => throw_handle_error(env, &e),
+        }
+    })
+}
+
+/// Limit the combined bandwidth of all tunneled connections.
+/// 
+/// @param bytesPerSec Limit per direction in bytes per second, or 0 for no limit
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setGlobalRateLimit(
+    mut env: JNIEnv,
+    _class: JClass,
+    bytes_per_sec: jlong,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        let bytes_per_sec = bytes_per_sec.max(0) as u64;
+        log::info!("Setting tunnel bandwidth limit to {} bytes/s", bytes_per_sec);
+        global().connections.tunnel_limit.set(bytes_per_sec);
+    })
+}
+
+/// Send coalesced writes every `interval` until coalescing is switched off
+/// or the global state is dropped.
+async fn flush_coalesced(state: Weak<GlobalState>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        for (handle, conn) in state.connections.snapshot() {
+            if !conn.has_pending() {
+                continue;
+            }
+            // One connection stalled on its rate limit must not hold up the
+            // rest. A failure stays on the connection for its next write,
+            // flush or close to throw
+            tokio::spawn(async move {
+                if let Err(e) = conn.flush().await {
+                    log::debug!("Failed to flush coalesced writes, handle={}: {}", handle, e);
+                }
+            });
+        }
+    }
+}
+
+/// Hold back small writes and send them together.
+/// 
+/// Buffered writes go out every `flushIntervalMs`, on `tcpFlush`, or once
+/// enough has accumulated to fill a packet. If sending them fails, the next
+/// tcpWrite, tcpFlush or tcpClose on the connection throws.
+/// 
+/// @param flushIntervalMs Longest a write is held back, or 0 to disable coalescing
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setWriteCoalescing(
+    mut env: JNIEnv,
+    _class: JClass,
+    flush_interval_ms: jint,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        let state = global();
+        let enabled = flush_interval_ms > 0;
+        log::info!("Setting write coalescing interval to {} ms", flush_interval_ms.max(0));
+        state.connections.coalesce.store(enabled, Ordering::Relaxed);
+
+        let flusher = enabled.then(|| {
+            let interval = Duration::from_millis(flush_interval_ms as u64);
+            state.handle.spawn(flush_coalesced(Arc::downgrade(&state), interval))
+        });
+        if let Some(old) = std::mem::replace(&mut *state.connections.flusher.lock(), flusher) {
+            old.abort();
+        }
+
+        if !enabled {
+            // Idle connections would otherwise keep what they buffered until their next write
+            let connections = state.connections.snapshot();
+            state.run(async move {
+                for (_, conn) in connections {
+                    let _ = conn.flush().await;
+                }
+            });
+        }
+    })
+}
+
+/// Send every write on a connection immediately, like TCP_NODELAY.
+/// 
+/// Opts the connection out of write coalescing so latency-sensitive traffic
+/// is never held back. Anything already buffered is sent right away.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param noDelay true to bypass coalescing, false to follow setWriteCoalescing
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpSetNoDelay<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    no_delay: jboolean,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return;
+            }
+        };
+
+        conn.set_no_delay(no_delay != 0);
+        if no_delay != 0 && conn.has_pending() {
+            let io_conn = conn.clone();
+            if let Err(e) = global().run(async move { io_conn.flush().await }) {
+                throw_io_error(env, &conn, "Flush", &e);
+            }
+        }
+    })
+}
+
+/// Read ahead of Java on new connections.
+/// 
+/// A background task keeps up to `bufferKb` of received data ready, so most
+/// tcpRead calls return without waiting on the tunnel. Enabled with 64 KiB by
+/// default. Existing connections keep their current setting.
+/// 
+/// @param bufferKb Read-ahead buffer per connection in KiB, or 0 to disable
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setReadAhead(
+    mut env: JNIEnv,
+    _class: JClass,
+    buffer_kb: jint,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        let bytes = buffer_kb.max(0) as usize * 1024;
+        log::info!("Setting read-ahead buffer to {} KiB", buffer_kb.max(0));
+        global().connections.read_ahead.store(bytes, Ordering::Relaxed);
+    })
+}
+
+/// Send writes from a background task on new connections.
+/// 
+/// tcpWrite returns once its data is queued, waiting only while `bufferKb`
+/// is already queued; tcpFlush waits for the queue to be sent, and tcpClose
+/// for up to the setCloseLinger timeout. A failed send is thrown by the next
+/// write, flush or close. Disabled by default. Existing connections keep
+/// their current setting.
+/// 
+/// @param bufferKb Write-behind queue per connection in KiB, or 0 to disable
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setWriteBehind(
+    mut env: JNIEnv,
+    _class: JClass,
+    buffer_kb: jint,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        let bytes = buffer_kb.max(0) as usize * 1024;
+        log::info!("Setting write-behind queue to {} KiB", buffer_kb.max(0));
+        global().connections.write_behind.store(bytes, Ordering::Relaxed);
+    })
+}
+
+// ============================================================================
+// JNI Functions - Non-blocking I/O
+// ============================================================================
+
+/// How often tcpSelect rechecks readiness; the netstack has no wakeups of its own.
+const SELECT_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Make reads and writes on a connection return at once instead of waiting.
+/// 
+/// A non-blocking tcpRead returns -2 when there is nothing to read, and
+/// tcpWrite/tcpWritev accept only what fits in the send buffer.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param nonBlocking true for non-blocking mode
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpSetNonBlocking<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    non_blocking: jboolean,
+) {
+    panic_guard::catch(&mut env, (), |env| match global().connections.get(handle) {
+        Ok(conn) => conn.set_non_blocking(non_blocking != 0),
+        Err(e) => throw_handle_error(env, &e),
+    })
+}
+
+/// Make writes on a connection accept only what fits, while reads keep waiting.
+/// 
+/// tcpWrite/tcpWritev then return the bytes accepted, or 0 if none fit, as
+/// in non-blocking mode, so a stalled peer cannot hold up the writing thread.
+/// tcpAwaitWritable waits for room again.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param partial true to return from writes at once
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpSetPartialWrites<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    partial: jboolean,
+) {
+    panic_guard::catch(&mut env, (), |env| match global().connections.get(handle) {
+        Ok(conn) => conn.set_partial_writes(partial != 0),
+        Err(e) => throw_handle_error(env, &e),
+    })
+}
+
+/// Wait until a write on a connection would accept some data.
+/// 
+/// A closed connection counts as writable, so the next write reports the error.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param timeoutMs Longest wait in milliseconds, or 0 to wait indefinitely
+/// @return true if writable, false on timeout
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpAwaitWritable<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    timeout_ms: jlong,
+) -> jboolean {
+    panic_guard::catch(&mut env, JNI_FALSE, |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return JNI_FALSE;
+            }
+        };
+        global().run(async move {
+            if timeout_ms > 0 {
+                tokio::time::timeout(Duration::from_millis(timeout_ms as u64), conn.wait_writable())
+                    .await
+                    .is_ok()
+            } else {
+                conn.wait_writable().await;
+                true
+            }
+        }) as jboolean
+    })
+}
+
+/// Check which operations on a connection would not block.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return Bitmask of READY_READ (1) and READY_WRITE (4)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpReadyOps<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jint {
+    panic_guard::catch(&mut env, 0, |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return 0;
+            }
+        };
+        global().run(async move { conn.readiness().await })
+    })
+}
+
+/// Wait until at least one connection is ready for the operations of interest.
+/// 
+/// Closed or unknown handles count as ready for everything, so the next
+/// read or write reports the error.
+/// 
+/// @param handles Connection handles to watch
+/// @param interestOps READY_* bitmask for each handle
+/// @param readyOps Receives the ready READY_* bits for each handle
+/// @param timeoutMs Longest wait in milliseconds, 0 to wait indefinitely, or negative to not wait
+/// @return Number of ready handles; 0 on timeout or wakeupSelect
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpSelect<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handles: JLongArray<'local>,
+    interest_ops: JIntArray<'local>,
+    ready_ops: JIntArray<'local>,
+    timeout_ms: jlong,
+) -> jint {
+    panic_guard::catch(&mut env, -1, |env| {
+        let len = match (
+            env.get_array_length(&handles),
+            env.get_array_length(&interest_ops),
+            env.get_array_length(&ready_ops),
+        ) {
+            (Ok(a), Ok(b), Ok(c)) if a == b && a == c => a as usize,
+            (Ok(_), Ok(_), Ok(_)) => {
+                throw_exception(env, "handles, interestOps and readyOps must have the same length");
+                return -1;
+            }
+            _ => {
+                throw_exception(env, "Failed to get array lengths");
+                return -1;
+            }
+        };
+        let mut ids = vec![0i64; len];
+        let mut interest = vec![0i32; len];
+        if let Err(e) = env
+            .get_long_array_region(&handles, 0, &mut ids)
+            .and_then(|_| env.get_int_array_region(&interest_ops, 0, &mut interest))
+        {
+            throw_exception(env, &format!("Failed to read arrays: {}", e));
+            return -1;
+        }
+
+        let state = global();
+        let deadline = (timeout_ms > 0).then(|| tokio::time::Instant::now() + Duration::from_millis(timeout_ms as u64));
+        let select_state = state.clone();
+        let (count, ready) = state.run(async move {
+            let manager = &select_state.connections;
+            loop {
+                let mut ready = vec![0i32; len];
+                for (i, &handle) in ids.iter().enumerate() {
+                    ready[i] = match manager.get(handle) {
+                        Ok(conn) => conn.readiness().await & interest[i],
+                        Err(_) => interest[i],
+                    };
+                }
+                let count = ready.iter().filter(|&&r| r != 0).count();
+                if count > 0
+                    || timeout_ms < 0
+                    || manager.wakeup_pending.swap(false, Ordering::AcqRel)
+                    || deadline.is_some_and(|d| tokio::time::Instant::now() >= d)
+                {
+                    return (count, ready);
+                }
+                tokio::select! {
+                    _ = manager.select_wake.notified() => {}
+                    _ = tokio::time::sleep(SELECT_INTERVAL) => {}
+                }
+            }
+        });
+
+        if let Err(e) = env.set_int_array_region(&ready_ops, 0, &ready) {
+            throw_exception(env, &format!("Failed to write readyOps: {}", e));
+            return -1;
+        }
+        count as jint
+    })
+}
+
+/// Make a tcpSelect in progress return at once, or the next one if none is.
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_wakeupSelect(mut env: JNIEnv, _class: JClass) {
+    panic_guard::catch(&mut env, (), |_| {
+        let connections = &global().connections;
+        connections.wakeup_pending.store(true, Ordering::Release);
+        connections.select_wake.notify_one();
+    })
+}
+
+// ============================================================================
+// JNI Functions - Shared-memory Rings
+// ============================================================================
+
+/// Address and length of a direct ByteBuffer's whole capacity.
+fn direct_buffer(env: &mut JNIEnv, buffer: &JObject) -> Result<(GlobalRef, *mut u8, usize), String> {
+    let buffer = <&JByteBuffer>::from(buffer);
+    let address = env
+        .get_direct_buffer_address(buffer)
+        .map_err(|_| "Ring buffers must be direct ByteBuffers".to_string())?;
+    let capacity = env
+        .get_direct_buffer_capacity(buffer)
+        .map_err(|e| format!("Failed to get buffer capacity: {}", e))?;
+    let global = env
+        .new_global_ref(buffer)
+        .map_err(|e| format!("Failed to reference buffer: {}", e))?;
+    Ok((global, address, capacity))
+}
+
+/// Move a connection's data through shared-memory rings instead of tcpRead/tcpWrite.
+/// 
+/// Each buffer holds a 64-byte header (head and tail u64 byte counts at 0
+/// and 8, native state and error code i32 at 16 and 20) followed by the data
+/// area. Native tasks fill `rx` from the connection and send what Java puts
+/// in `tx`. The connection is switched to blocking mode, and tcpRead,
+/// tcpWrite and the like throw for it from then on. A ring whose head and
+/// tail end up more than its data area apart is put in the error state.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param rx Direct ByteBuffer for received data, produced natively
+/// @param tx Direct ByteBuffer for data to send, produced by Java
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpAttachRings<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    rx: JObject<'local>,
+    tx: JObject<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let state = global();
+        let conn = match state.connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return;
+            }
+        };
+        let buffers = direct_buffer(env, &rx).and_then(|rx| Ok((rx, direct_buffer(env, &tx)?)));
+        // SAFETY: the addresses come from direct buffers kept alive by their global references
+        let rings = match buffers.and_then(|(rx, tx)| unsafe { ring::Rings::new(rx, tx) }) {
+            Ok(rings) => Arc::new(rings),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+
+        let mut attached = state.connections.rings.lock();
+        if attached.contains_key(&handle) {
+            throw_exception(env, "Rings already attached to this connection");
+            return;
+        }
+        conn.set_non_blocking(false);
+        rings.start(conn, &state.handle);
+        attached.insert(handle, rings);
+        log::debug!("Shared-memory rings attached, handle={}", handle);
+    })
+}
+
+fn attached_rings(env: &mut JNIEnv, handle: i64) -> Option<Arc<ring::Rings>> {
+    let state = global();
+    if let Err(e) = state.connections.get(handle) {
+        throw_handle_error(env, &e);
+        return None;
+    }
+    let rings = state.connections.rings.lock().get(&handle).cloned();
+    if rings.is_none() {
+        throw_exception(env, "No rings attached to this connection");
+    }
+    rings
+}
+
+/// Look up a connection for tcpRead, tcpWrite and the like, which would race
+/// the ring tasks for its data once rings are attached.
+fn unringed_connection(env: &mut JNIEnv, handle: i64) -> Option<Arc<Connection>> {
+    let state = global();
+    let conn = match state.connections.get(handle) {
+        Ok(c) => c,
+        Err(e) => {
+            throw_handle_error(env, &e);
+            return None;
+        }
+    };
+    if state.connections.rings.lock().contains_key(&handle) {
+        throw_exception(env, "Rings are attached to this connection; move data through them instead");
+        return None;
+    }
+    Some(conn)
+}
+
+/// Wake the native side after writing to the send ring or reading from the receive ring.
+/// 
+/// @param handle Connection handle from tcpConnect
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpRingNotify(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        if let Some(rings) = attached_rings(env, handle) {
+            rings.notify();
+        }
+    })
+}
+
+/// Wait until the receive ring has data or the send ring has space.
+/// 
+/// A ring whose state is no longer open counts as ready.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @param interestOps READY_READ and/or READY_WRITE
+/// @param timeoutMs Longest wait in milliseconds, or 0 to wait indefinitely
+/// @return The ready ops, or 0 on timeout
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpRingWait(
+    mut env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+    interest_ops: jint,
+    timeout_ms: jlong,
+) -> jint {
+    panic_guard::catch(&mut env, 0, |env| {
+        let Some(rings) = attached_rings(env, handle) else {
+            return 0;
+        };
+        let timeout = (timeout_ms > 0).then(|| Duration::from_millis(timeout_ms as u64));
+        global().run(async move { rings.wait(interest_ops, timeout).await })
+    })
+}
+
+// ============================================================================
+// JNI Functions - Connection Pool
+// ============================================================================
+
+/// Connect timeout for pre-warmed connections.
+const PREWARM_CONNECT_TIMEOUT_MS: i64 = 10_000;
+
+/// Open one spare connection to a Minecraft server.
+///
+/// Follows the server's SRV record when the default port is used, like the
+/// vanilla client, so the spare matches the address the game connects to.
+async fn prewarm_once(host: &str, port: u16) -> Result<(), TunnelError> {
+    let policy = global().options.read().connect_policy;
+
+    let (host, port) = match global().resolver() {
+        Ok(resolver) if port == minecraft::DEFAULT_PORT && host.parse::<IpAddr>().is_err() => {
+            match resolver.resolve_minecraft_srv(host).await? {
+                Some(srv) => (srv.target, srv.port),
+                None => (host.to_string(), port),
+            }
+        }
+        _ => (host.to_string(), port),
+    };
+
+    let (addrs, resolver) = resolve_destination(&host, port, policy).await?;
+    let conn = connect_resolved(&host, &addrs, resolver, PREWARM_CONNECT_TIMEOUT_MS, policy).await?;
+    let addr = conn.remote_addr();
+    global().connections.pool.put(addr, conn);
+    log::debug!("Pre-warmed connection to {} ({})", host, addr);
+    Ok(())
+}
+
+/// Keep a spare connection to `host:port` ready, replacing it before the server times it out.
+async fn keep_warm(host: String, port: u16) {
+    loop {
+        if let Err(e) = prewarm_once(&host, port).await {
+            log::debug!("Pre-warming {}:{} failed: {}", host, port, e);
+        }
+        tokio::time::sleep(pool::MAX_IDLE).await;
+    }
+}
+
+/// Enable or disable reuse of pre-warmed connections.
+/// 
+/// Disabling drops all spares and stops pre-warming.
+/// 
+/// @param enabled true to enable the pool
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setConnectionPoolEnabled(
+    mut env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        let state = global();
+        let pool = &state.connections.pool;
+        pool.set_enabled(enabled != JNI_FALSE);
+        if enabled == JNI_FALSE {
+            let spares = pool.clear();
+            global().run(async move { drop(spares) });
+        }
+    })
+}
+
+/// Keep one pre-warmed connection to a server, replacing any previous target.
+/// 
+/// The spare is handed out by the next `tcpConnect` to the same address.
+/// Does nothing while the pool is disabled.
+/// 
+/// @param host Server hostname or IP address
+/// @param port Server port; 25565 follows the _minecraft._tcp SRV record
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_prewarmConnection<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    host: JString<'local>,
+    port: jint,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let host = match get_string(env, &host) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+
+        let state = global();
+        let pool = &state.connections.pool;
+        if !pool.is_enabled() {
+            return;
+        }
+        log::info!("Pre-warming connections to {}:{}", host, port);
+        pool.set_warm_task(Some(global().handle.spawn(keep_warm(host, port as u16))));
+    })
+}
+
+// ============================================================================
+// JNI Functions - Metrics
+// ============================================================================
+
+fn collect_metrics() -> metrics::Snapshot {
+    let now = Instant::now();
+    let (tunnel_state, tunnel) = match global().tunnel.read().as_ref() {
+        Some(active) => {
+            let rx = active.tunnel.rx_counters();
+            let tunnel = metrics::TunnelMetrics {
+                uptime_seconds: now.duration_since(active.tunnel.connected_at()).as_secs_f64(),
+                rx_packets: rx.packets.load(Ordering::Relaxed),
+                rx_bytes: rx.bytes.load(Ordering::Relaxed),
+                last_rx_age_seconds: rx.last_packet().map(|t| now.duration_since(t).as_secs_f64()),
+                handshake_age_seconds: active.tunnel.handshake_age().map(|age| age.as_secs_f64()),
+            };
+            let state = if active.tunnel.is_paused() { "paused" } else { "ready" };
+            (state, Some(tunnel))
+        }
+        None => ("stopped", None),
+    };
+
+    let state = global();
+    let connections = state.connections.snapshot();
+    let routes = [("tunnel", true), ("direct", false)]
+        .into_iter()
+        .map(|(route, tunneled)| {
+            let [rx_bytes, tx_bytes] = state.connections.route_totals(tunneled);
+            metrics::RouteMetrics {
+                route,
+                open_connections: connections.iter().filter(|(_, c)| c.is_tunneled() == tunneled).count(),
+                rx_bytes,
+                tx_bytes,
+            }
+        })
+        .collect();
+
+    let connections = connections
+        .iter()
+        .map(|(handle, conn)| {
+            let stats = conn.stats();
+            let age = now.duration_since(stats.opened).as_secs_f64();
+            let rx_bytes = stats.bytes_read.load(Ordering::Relaxed);
+            let tx_bytes = stats.bytes_written.load(Ordering::Relaxed);
+            let rate = |bytes: u64| if age > 0.0 { bytes as f64 / age } else { 0.0 };
+            metrics::ConnectionMetrics {
+                handle: *handle,
+                route: if conn.is_tunneled() { "tunnel" } else { "direct" },
+                age_seconds: age,
+                rx_bytes,
+                tx_bytes,
+                rx_bytes_per_second: rate(rx_bytes),
+                tx_bytes_per_second: rate(tx_bytes),
+            }
+        })
+        .collect();
+
+    let handshakes = metrics::HandshakeMetrics {
+        attempts: state.handshakes.attempts.load(Ordering::Relaxed),
+        successes: state.handshakes.successes.load(Ordering::Relaxed),
+        failures: state.handshakes.failures.load(Ordering::Relaxed),
+        rekeys: state.handshakes.rekeys.load(Ordering::Relaxed),
+    };
+
+    let runtime = global().handle.metrics();
+    metrics::Snapshot {
+        tunnel_state,
+        tunnel,
+        handshakes,
+        routes,
+        connections,
+        runtime: metrics::RuntimeMetrics {
+            workers: runtime.num_workers(),
+            alive_tasks: runtime.num_alive_tasks(),
+            global_queue_depth: runtime.global_queue_depth(),
+        },
+    }
+}
+
+/// Get a snapshot of tunnel, connection and runtime metrics.
+/// 
+/// @param format 0 for Prometheus text exposition format, 1 for JSON
+/// @return Metrics in the requested format
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_metricsSnapshot<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    format: jint,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let snapshot = collect_metrics();
+        let text = match format {
+            0 => snapshot.to_prometheus(),
+            1 => snapshot.to_json(),
+            _ => {
+                throw_exception(env, &format!("Invalid metrics format: {}", format));
+                return std::ptr::null_mut();
+            }
+        };
+
+        match env.new_string(text) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Payload totals per destination address and route, open and closed
+/// connections together, busiest first.
+fn collect_traffic_by_destination() -> Vec<metrics::DestinationMetrics> {
+    let state = global();
+    let mut totals: HashMap<(IpAddr, bool), (usize, DestinationTotals)> = state
+        .connections
+        .closed_by_destination
+        .lock()
+        .iter()
+        .map(|(destination, closed)| (*destination, (0, *closed)))
+        .collect();
+    for (_, conn) in state.connections.snapshot() {
+        let destination = (conn.remote_addr().ip().to_canonical(), conn.is_tunneled());
+        let (open, totals) = totals.entry(destination).or_default();
+        *open += 1;
+        totals.connections += 1;
+        totals.rx_bytes += conn.stats().bytes_read.load(Ordering::Relaxed);
+        totals.tx_bytes += conn.stats().bytes_written.load(Ordering::Relaxed);
+    }
+
+    let mut destinations: Vec<_> = totals
+        .into_iter()
+        .map(|((address, tunneled), (open, totals))| metrics::DestinationMetrics {
+            address: address.to_string(),
+            route: if tunneled { "tunnel" } else { "direct" },
+            open_connections: open,
+            connections: totals.connections,
+            rx_bytes: totals.rx_bytes,
+            tx_bytes: totals.tx_bytes,
+        })
+        .collect();
+    destinations.sort_by_key(|d| std::cmp::Reverse(d.rx_bytes + d.tx_bytes));
+    destinations
+}
+
+/// Get payload totals per destination address.
+/// 
+/// Covers every connection opened through tcpConnect and the like since
+/// initJNI, open or closed, so traffic to hosts other than the game server
+/// stands out. Native HTTP and WebSocket requests are not included.
+/// 
+/// @return JSON array of objects with `address`, `route` ("tunnel" or
+///         "direct"), `open_connections`, `connections`, `rx_bytes` and
+///         `tx_bytes`, busiest first
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_trafficByDestination<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let json = serde_json::to_string(&collect_traffic_by_destination()).unwrap_or_else(|_| "[]".into());
+        match env.new_string(json) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Get the tunnel's throughput over the last `seconds` seconds, one sample
+/// per second, for live bandwidth graphs.
+/// 
+/// Samples are payload bytes of tunneled connections, as in the per-route
+/// metrics. Up to ten minutes are kept while the tunnel runs; starting a
+/// tunnel clears them.
+/// 
+/// @param seconds How many of the latest samples to return
+/// @return Two arrays, bytes received and bytes sent per second, oldest
+///         first; shorter than `seconds` if the history is
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_throughputHistory<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    seconds: jint,
+) -> jobjectArray {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let (rx, tx) = global().throughput.recent(seconds.max(0) as usize);
+        match new_long_arrays(env, &[rx, tx]) {
+            Ok(arrays) => arrays,
+            Err(e) => {
+                throw_exception(env, &e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// List the handles of all open connections.
+/// 
+/// @return Array of connection handles
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_listConnections<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jlongArray {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let handles: Vec<i64> = global().connections.snapshot().into_iter().map(|(handle, _)| handle).collect();
+        let array = match env.new_long_array(handles.len() as i32) {
+            Ok(array) => array,
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create array: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
+        if let Err(e) = env.set_long_array_region(&array, 0, &handles) {
+            throw_exception(env, &format!("Failed to fill array: {}", e));
+            return std::ptr::null_mut();
+        }
+        array.into_raw()
+    })
+}
+
+fn connection_info(handle: jlong, conn: &Connection) -> metrics::ConnectionInfo {
+    let stats = conn.stats();
+    metrics::ConnectionInfo {
+        handle,
+        route: if conn.is_tunneled() { "tunnel" } else { "direct" },
+        remote: conn.remote_addr().to_string(),
+        local: conn.local_addr().map(|addr| addr.to_string()),
+        state: tcp_state(handle).to_string(),
+        age_seconds: stats.opened.elapsed().as_secs_f64(),
+        rx_bytes: stats.bytes_read.load(Ordering::Relaxed),
+        tx_bytes: stats.bytes_written.load(Ordering::Relaxed),
+        last_error: conn.last_error(),
+    }
+}
+
+/// Timeline of opening `conn`, or `None` if it was not opened by tcpConnect.
+fn connect_timeline(handle: jlong, conn: &Connection) -> Option<metrics::ConnectTimeline> {
+    let (trace, first_byte) = conn.connect_trace()?;
+    let since_start = |at: Option<Instant>| at.map(|at| at.duration_since(trace.started).as_secs_f64() * 1000.0);
+    Some(metrics::ConnectTimeline {
+        handle,
+        pooled: trace.pooled,
+        dns_start_ms: since_start(trace.dns_start),
+        dns_end_ms: since_start(trace.dns_end),
+        syn_sent_ms: since_start(trace.syn_sent),
+        established_ms: since_start(trace.established),
+        first_byte_ms: since_start(first_byte),
+    })
+}
+
+/// Describe an open connection as JSON.
+/// 
+/// Fields: handle, route, remote, local (null for tunnel connections),
+/// state, age_seconds, rx_bytes, tx_bytes and last_error.
+/// 
+/// @param handle Connection handle from tcpConnect
+/// @return JSON object describing the connection
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_connectionInfo<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        let json = serde_json::to_string(&connection_info(handle, &conn)).unwrap_or_else(|_| "{}".into());
+
+        match env.new_string(json) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Get round-trip and retransmission statistics of a connection as JSON.
+/// 
+/// Fields: handle, route, state, handshake_rtt_ms, rtt_ms, rtt_var_ms,
+/// retransmits, total_retransmits, cwnd_segments and mss. Tunnel
+/// connections only report the handshake RTT measured at connect, which
+/// covers the whole WARP path to the server; the rest come from the kernel
+/// for direct connections on Linux, and are null otherwise.
+/// 
+/// @param handle Connection handle
+/// @return JSON object with the statistics
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpStats<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        let handshake_rtt = conn.connect_trace().and_then(|(trace, _)| {
+            let rtt = trace.established?.checked_duration_since(trace.syn_sent?)?;
+            Some(rtt.as_secs_f64() * 1000.0)
+        });
+        let mut stats = metrics::TcpStats {
+            handle,
+            route: if conn.is_tunneled() { "tunnel" } else { "direct" },
+            state: tcp_state(handle).to_string(),
+            handshake_rtt_ms: handshake_rtt,
+            ..Default::default()
+        };
+        if let Some(info) = conn.kernel_tcp_info() {
+            stats.rtt_ms = Some(info.rtt.as_secs_f64() * 1000.0);
+            stats.rtt_var_ms = Some(info.rtt_var.as_secs_f64() * 1000.0);
+            stats.retransmits = Some(info.retransmits);
+            stats.total_retransmits = Some(info.total_retransmits);
+            stats.cwnd_segments = Some(info.cwnd);
+            stats.mss = Some(info.mss);
+        }
+        let json = serde_json::to_string(&stats).unwrap_or_else(|_| "{}".into());
+
+        match env.new_string(json) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Describe how a connection was opened, as JSON.
+/// 
+/// Each phase is in milliseconds since the connect call began, or null if it
+/// did not happen: DNS for IP literals, the handshake for pre-warmed spares.
+/// 
+/// @param handle Connection handle
+/// @return JSON timeline, or null if the connection was not opened by tcpConnect
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpConnectTrace<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let conn = match global().connections.get(handle) {
+            Ok(c) => c,
+            Err(e) => {
+                throw_handle_error(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        let Some(timeline) = connect_timeline(handle, &conn) else {
+            return std::ptr::null_mut();
+        };
+        let json = serde_json::to_string(&timeline).unwrap_or_else(|_| "{}".into());
+
+        match env.new_string(json) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+// ============================================================================
+// JNI Functions - Packet Capture
+// ============================================================================
+
+/// Start writing decrypted tunnel packets to a pcapng file.
+/// 
+/// Replaces any capture in progress. Only inbound packets are captured.
+/// 
+/// @param path File to write, overwritten if it exists
+/// @param maxBytes Stop once the file reaches this size, or 0 for no limit
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_startPacketCapture<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    path: JString<'local>,
+    max_bytes: jlong,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let path = match get_string(env, &path) {
+            Ok(s) => PathBuf::from(s),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+
+        match global().capture.start(&path, max_bytes.max(0) as u64) {
+            Ok(()) => log::info!("Packet capture started: {}", path.display()),
+            Err(e) => throw_exception(env, &format!("Failed to start packet capture: {}", e)),
+        }
+    })
+}
+
+/// Stop the packet capture and flush the file.
+/// 
+/// @return true if a capture was running
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_stopPacketCapture(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    panic_guard::catch(&mut env, JNI_FALSE, |env| {
+        match global().capture.stop() {
+            Ok(stopped) => {
+                if stopped {
+                    log::info!("Packet capture stopped");
+                }
+                stopped as jboolean
+            }
+            Err(e) => {
+                throw_exception(env, &format!("Failed to finish packet capture: {}", e));
+                JNI_FALSE
+            }
+        }
+    })
+}
+
+// ============================================================================
+// JNI Functions - Routing
+// ============================================================================
+
+/// Parse a CIDR argument, throwing on invalid input.
+fn get_cidr(env: &mut JNIEnv, cidr: &JString) -> Option<ipnet::IpNet> {
+    let cidr = match get_string(env, cidr) {
+        Ok(s) => s,
+        Err(e) => {
+            throw_exception(env, &e);
+            return None;
+        }
+    };
+    let net = routing::parse_cidr(&cidr);
+    if net.is_none() {
+        throw_exception(env, &format!("Invalid CIDR: {}", cidr));
+    }
+    net
+}
+
+/// Route a destination range around the tunnel.
+/// 
+/// @param cidr Network in CIDR notation (e.g. "192.168.0.0/16") or a single address
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_addBypassRoute<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    cidr: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        if let Some(net) = get_cidr(env, &cidr) {
+            log::info!("Adding bypass route {}", net);
+            global().router.write().add_bypass(net);
+        }
+    })
+}
+
+/// Route a destination range through the tunnel.
+/// 
+/// Once any tunnel route exists, destinations matching no route connect directly.
+/// 
+/// @param cidr Network in CIDR notation (e.g. "104.16.0.0/12") or a single address
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_addTunnelRoute<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    cidr: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        if let Some(net) = get_cidr(env, &cidr) {
+            log::info!("Adding tunnel route {}", net);
+            global().router.write().add_tunnel(net);
+        }
+    })
+}
+
+/// Route connections to matching hostnames through the tunnel or directly.
+/// 
+/// Domain rules take precedence over CIDR routes; the most specific pattern
+/// wins. Hostnames routed directly are resolved with the system resolver.
+/// 
+/// @param pattern "example.com", "*.example.com" (subdomains only) or "*" (any hostname)
+/// @param route ROUTE_TUNNEL (0) or ROUTE_DIRECT (1)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_addDomainRule<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    pattern: JString<'local>,
+    route: jint,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let pattern_str = match get_string(env, &pattern) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        let Some(pattern) = routing::DomainPattern::parse(&pattern_str) else {
+            throw_exception(env, &format!("Invalid domain pattern: {}", pattern_str));
+            return;
+        };
+        let Some(route) = routing::Route::from_jint(route) else {
+            throw_exception(env, &format!("Invalid route: {}", route));
+            return;
+        };
+
+        log::info!("Adding domain rule {} -> {:?}", pattern_str, route);
+        global().router.write().add_domain(pattern, route);
+    })
+}
+
+/// Restrict which destinations new connections may go to.
+/// 
+/// Rules are checked in order and the first match decides; destinations no
+/// rule matches get the default action. A rule matches when every criterion
+/// it sets does: `cidrs` against the destination IP, `ports` ("25565" or
+/// "25500-25600") against its port and `domains` (patterns as in
+/// `addDomainRule`) against the hostname connected to, which is the SRV
+/// target after an SRV lookup. Applies to connections opened afterwards,
+/// tunneled or direct.
+/// 
+/// @param jsonRules `{"default": "allow"|"deny", "rules": [{"action": "allow"|"deny",
+///                  "cidrs": [...], "ports": [...], "domains": [...]}]}`, or null
+///                  to allow everything
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_setConnectionPolicy<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    json_rules: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let json = match get_optional_string(env, &json_rules) {
+            Ok(json) => json.filter(|j| !j.trim().is_empty()),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        let policy = match json.as_deref().map(policy::Policy::parse).transpose() {
+            Ok(policy) => policy.unwrap_or_default(),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        *global().policy.write() = policy;
+    })
+}
+
+/// Send a HAProxy PROXY protocol v2 header on connections to matching destinations.
+/// 
+/// The header goes out right after connecting, before any data, so servers
+/// behind a PROXY-aware proxy see `sourceAddress` as the client. Only add
+/// rules for servers that expect the header; others reject the connection.
+/// Domain rules take precedence over CIDRs, and the most specific rule wins.
+/// 
+/// @param target "example.com", "*.example.com", "*", or a CIDR or single address
+/// @param sourceAddress Address to advertise, "ip" or "ip:port"; null for the
+///                      connection's own address (the tunnel IP when tunneled)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_addProxyProtocolRule<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    target: JString<'local>,
+    source_address: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let (target_str, source_str) = match (get_string(env, &target), get_optional_string(env, &source_address)) {
+            (Ok(t), Ok(s)) => (t, s),
+            (Err(e), _) | (_, Err(e)) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        let Some(target) = proxy_protocol::Target::parse(&target_str) else {
+            throw_exception(env, &format!("Invalid PROXY protocol target: {}", target_str));
+            return;
+        };
+        let source = match source_str.as_deref() {
+            None => proxy_protocol::Source::Connection,
+            Some(s) => match proxy_protocol::Source::parse(s) {
+                Some(source) => source,
+                None => {
+                    throw_exception(env, &format!("Invalid source address: {}", s));
+                    return;
+                }
+            },
+        };
+
+        log::info!("Adding PROXY protocol rule {} -> {:?}", target_str, source);
+        global().proxy_protocol.write().add(target, source);
+    })
+}
+
+/// Remove all routes, domain rules and PROXY protocol rules, sending
+/// everything through the tunnel.
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_clearRoutes(
+    mut env: JNIEnv,
+    _class: JClass,
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        let state = global();
+        state.router.write().clear();
+        state.proxy_protocol.write().clear();
+    })
+}
+
+// ============================================================================
+// JNI Functions - DNS
+// ============================================================================
+
+/// Resolve DNS records through the tunnel.
+/// 
+/// Answers are cached for their TTL, and missing names or record types for
+/// the negative TTL the server gives.
+/// 
+/// @param hostname Name to look up
+/// @param recordType DNS record type (1=A, 5=CNAME, 16=TXT, 28=AAAA, 33=SRV)
+/// @return Records in presentation format (empty if the name has none), or null on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_resolve<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    hostname: JString<'local>,
+    record_type: jint,
+) -> jobjectArray {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let hostname = match get_string(env, &hostname) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        let qtype = match u16::try_from(record_type) {
+            Ok(t) => t,
+            Err(_) => {
+                throw_exception(env, &format!("Invalid record type: {}", record_type));
+                return std::ptr::null_mut();
+            }
+        };
+
+        let resolver = match global().resolver() {
+            Ok(r) => r,
+            Err(e) => {
+                throw_exception(env, &format!("Tunnel not available: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
+
+        let result = global().run(async move { resolver.query(&hostname, qtype).await });
+
+        let records = match result {
+            Ok(records) => records,
+            Err(e) => {
+                throw_exception(env, &format!("DNS lookup failed: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
+
+        match new_string_array(env, &records) {
+            Ok(array) => array,
+            Err(e) => {
+                throw_exception(env, &e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Resolve the Minecraft SRV record of a domain through the tunnel.
+/// 
+/// @param domain Domain name, without the `_minecraft._tcp.` prefix
+/// @return "target:port" of the preferred record, or null if there is none
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_resolveSrv<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    domain: JString<'local>,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let domain = match get_string(env, &domain) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        let resolver = match global().resolver() {
+            Ok(r) => r,
+            Err(e) => {
+                throw_exception(env, &format!("Tunnel not available: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
+
+        let result = global().run(async move { resolver.resolve_minecraft_srv(&domain).await });
+
+        match result {
+            Ok(Some(srv)) => match env.new_string(format!("{}:{}", srv.target, srv.port)) {
+                Ok(s) => s.into_raw(),
+                Err(e) => {
+                    throw_exception(env, &format!("Failed to create string: {}", e));
+                    std::ptr::null_mut()
+                }
+            },
+            Ok(None) => std::ptr::null_mut(),
+            Err(e) => {
+                throw_exception(env, &format!("SRV lookup failed: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Drop the running tunnel's cached DNS answers.
+/// 
+/// @return Number of answers dropped, 0 if no tunnel is running
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_flushDnsCache(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    panic_guard::catch(&mut env, 0, |_| {
+        let flushed = match global().tunnel.read().as_ref() {
+            Some(active) => active.resolver.flush(),
+            None => return 0,
+        };
+        log::debug!("Flushed {} cached DNS answers", flushed);
+        flushed as jint
+    })
+}
+
+// ============================================================================
+// JNI Functions - HTTP
+// ============================================================================
+
+/// Largest response body `httpRequest` accepts, headers included.
+const MAX_HTTP_RESPONSE: usize = 16 * 1024 * 1024;
+
+/// Send one HTTP request through the tunnel.
+async fn http_request(
+    resolver: Arc<dns::Resolver>,
+    method: String,
+    url: https::Url,
+    request: Vec<u8>,
+) -> Result<https::Response, TunnelError> {
+    log::info!("{} {}:{}{} via WireGuard tunnel", method, url.host, url.port, url.target);
+    let (conn, _) = connect_tunnel_host(&resolver, &url.host, url.port).await?;
+    let conn = Arc::new(conn);
+    let bodyless = method.eq_ignore_ascii_case("HEAD");
+    if url.https {
+        let mut tls = https::tls_handshake(conn, &url.host, vec![b"http/1.1".to_vec()]).await?;
+        https::exchange(&mut tls, &request, MAX_HTTP_RESPONSE, bodyless).await
+    } else {
+        let mut stream = stream::TunnelStream::new(conn);
+        https::exchange(&mut stream, &request, MAX_HTTP_RESPONSE, bodyless).await
+    }
+}
+
+/// Build the Java `HttpResponse` for `response`.
+fn new_http_response(env: &mut JNIEnv, response: https::Response) -> Result<jobject, String> {
+    let headers: Vec<String> = response
+        .headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect();
+    let headers = new_string_array(env, &headers)?;
+    // SAFETY: `headers` was just created as a local reference
+    let headers = unsafe { JObject::from_raw(headers) };
+    let body = env
+        .byte_array_from_slice(&response.body)
+        .map_err(|e| format!("Failed to create array: {}", e))?;
+    env.new_object(
+        "codes/dreaming/wireguard/jni/HttpResponse",
+        "(I[Ljava/lang/String;[B)V",
+        &[(response.status as jint).into(), (&headers).into(), (&body).into()],
+    )
+    .map(JObject::into_raw)
+    .map_err(|e| format!("Failed to create HttpResponse: {}", e))
+}
+
+/// Send an HTTP request through the tunnel and wait for the response.
+/// 
+/// Connects to the URL's host over the tunnel, with TLS for https URLs,
+/// sends the request with `Connection: close` and reads the response to
+/// EOF. Redirects are not followed. Routing rules do not apply, so the
+/// request always egresses through the tunnel; the connection policy does.
+/// 
+/// @param method HTTP method, e.g. "GET" or "POST"
+/// @param url http or https URL
+/// @param headers Request headers as "Name: value", or null. `Host` defaults
+///                to the URL's; `Connection` and body framing are set automatically
+/// @param body Request body, or null for none
+/// @param timeoutMs Timeout for the whole request in milliseconds (0 = no timeout)
+/// @return The response, or null on error (with an exception thrown)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_httpRequest<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    method: JString<'local>,
+    url: JString<'local>,
+    headers: JObjectArray<'local>,
+    body: JByteArray<'local>,
+    timeout_ms: jlong,
+) -> jobject {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let args = (|| -> Result<_, String> {
+            let method = get_string(env, &method)?;
+            let url = https::Url::parse(&get_string(env, &url)?)?;
+            let headers = get_string_array(env, &headers)?
+                .iter()
+                .map(|header| {
+                    let (name, value) = header
+                        .split_once(':')
+                        .ok_or_else(|| format!("Expected \"Name: value\": {}", header))?;
+                    Ok((name.trim().to_string(), value.trim().to_string()))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            let body = if body.is_null() {
+                Vec::new()
+            } else {
+                env.convert_byte_array(&body)
+                    .map_err(|e| format!("Failed to read body: {}", e))?
+            };
+            let request = https::build_request(&method, &url, &headers, &body)?;
+            Ok((method, url, request))
+        })();
+        let (method, url, request) = match args {
+            Ok(args) => args,
+            Err(e) => {
+                throw_exception(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        let resolver = match global().resolver() {
+            Ok(r) => r,
+            Err(e) => {
+                throw_exception(env, &format!("Tunnel not available: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
+
+        let result = global().run(async move {
+            let request = http_request(resolver, method, url, request);
+            if timeout_ms > 0 {
+                tokio::time::timeout(Duration::from_millis(timeout_ms as u64), request)
+                    .await
+                    .map_err(|_| TunnelError::Timeout)?
+            } else {
+                request.await
+            }
+        });
+
+        match result.map_err(|e| format!("HTTP request failed: {}", e)) {
+            Ok(response) => new_http_response(env, response).unwrap_or_else(|e| {
+                throw_exception(env, &e);
+                std::ptr::null_mut()
+            }),
+            Err(e) => {
+                throw_exception(env, &e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+// ============================================================================
+// JNI Functions - WebSocket
+// ============================================================================
+
+/// wsReceive message types, matching the `WebSocketMessage` constants.
+const WS_TEXT: jint = 0;
+const WS_BINARY: jint = 1;
+const WS_CLOSE: jint = 2;
+
+/// Open a WebSocket through the tunnel.
+async fn ws_connect(
+    resolver: Arc<dns::Resolver>,
+    url: https::Url,
+    headers: Vec<(String, String)>,
+) -> Result<websocket::WebSocket, TunnelError> {
+    log::info!("Opening WebSocket to {}:{}{} via WireGuard tunnel", url.host, url.port, url.target);
+    let (conn, _) = connect_tunnel_host(&resolver, &url.host, url.port).await?;
+    let conn = Arc::new(conn);
+    let (reader, mut writer): (Box<dyn tokio::io::AsyncRead + Send + Unpin>, websocket::Writer) = if url.https {
+        let tls = https::tls_handshake(conn, &url.host, vec![b"http/1.1".to_vec()]).await?;
+        let (r, w) = tokio::io::split(tls);
+        (Box::new(r), Box::new(w))
+    } else {
+        let (r, w) = tokio::io::split(stream::TunnelStream::new(conn));
+        (Box::new(r), Box::new(w))
+    };
+    let host_header = match url.host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, url.port),
+        _ => format!("{}:{}", url.host, url.port),
+    };
+    let (reader, response) = websocket::handshake(reader, &mut writer, &host_header, &url.target, &headers).await?;
+    let protocol = response
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("sec-websocket-protocol"))
+        .map(|(_, value)| value);
+    Ok(websocket::WebSocket::new(reader, writer, protocol))
+}
+
+/// Look up a WebSocket handle, throwing if it is unknown.
+fn get_websocket(env: &mut JNIEnv, handle: jlong) -> Option<Arc<websocket::WebSocket>> {
+    let socket = global().websockets.get(handle);
+    if socket.is_none() {
+        throw_exception(env, &format!("Invalid WebSocket handle: {}", handle));
+    }
+    socket
+}
+
+/// Open a WebSocket connection through the tunnel.
+/// 
+/// Routing rules do not apply, so the connection always goes through the
+/// tunnel; the connection policy does.
+/// 
+/// @param url ws or wss URL
+/// @param protocols Subprotocols to offer, or null
+/// @param headers Extra handshake headers as "Name: value", or null
+/// @param timeoutMs Timeout for connecting and the handshake in milliseconds (0 = no timeout)
+/// @return WebSocket handle (>0) on success, -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_wsConnect<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    url: JString<'local>,
+    protocols: JObjectArray<'local>,
+    headers: JObjectArray<'local>,
+    timeout_ms: jlong,
+) -> jlong {
+    panic_guard::catch(&mut env, -1, |env| {
+        let args = (|| -> Result<_, String> {
+            let url_str = get_string(env, &url)?;
+            let http_url = match url_str.split_once("://") {
+                Some((scheme, rest)) if scheme.eq_ignore_ascii_case("ws") => format!("http://{}", rest),
+                Some((scheme, rest)) if scheme.eq_ignore_ascii_case("wss") => format!("https://{}", rest),
+                _ => return Err(format!("Expected a ws:// or wss:// URL: {}", url_str)),
+            };
+            let url = https::Url::parse(&http_url)?;
+            let mut headers = get_string_array(env, &headers)?
+                .iter()
+                .map(|header| {
+                    let (name, value) = header
+                        .split_once(':')
+                        .ok_or_else(|| format!("Expected \"Name: value\": {}", header))?;
+                    if value.contains(['\r', '\n']) {
+                        return Err(format!("Invalid header: {}", name));
+                    }
+                    Ok((name.trim().to_string(), value.trim().to_string()))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            let protocols = get_string_array(env, &protocols)?;
+            if !protocols.is_empty() {
+                headers.push(("Sec-WebSocket-Protocol".to_string(), protocols.join(", ")));
+            }
+            Ok((url, headers))
+        })();
+        let (url, headers) = match args {
+            Ok(args) => args,
+            Err(e) => {
+                throw_exception(env, &e);
+                return -1;
+            }
+        };
+
+        let resolver = match global().resolver() {
+            Ok(r) => r,
+            Err(e) => {
+                throw_exception(env, &format!("Tunnel not available: {}", e));
+                return -1;
+            }
+        };
+
+        let result = global().run(async move {
+            let connect = ws_connect(resolver, url, headers);
+            if timeout_ms > 0 {
+                tokio::time::timeout(Duration::from_millis(timeout_ms as u64), connect)
+                    .await
+                    .map_err(|_| TunnelError::Timeout)?
+            } else {
+                connect.await
+            }
+        });
+
+        match result {
+            Ok(socket) => {
+                let handle = global().websockets.insert(socket);
+                log::debug!("WebSocket connected, handle={}", handle);
+                handle
+            }
+            Err(e) => {
+                throw_exception(env, &format!("WebSocket connection failed: {}", e));
+                -1
+            }
+        }
+    })
+}
+
+/// Get the subprotocol the server picked for a WebSocket.
+/// 
+/// @param handle WebSocket handle from wsConnect
+/// @return The subprotocol, or null if none was negotiated
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_wsProtocol<'local>(
     mut env: JNIEnv<'local>,
     _class: JClass<'local>,
-    host: JString<'local>,
-    port: jint,
-    _timeout_ms: jlong,
-) -> jlong {
-    let host = match get_string(&mut env, &host) {
-        Ok(s) => s,
-        Err(e) => {
-            throw_exception(&mut env, &e);
-            return -1;
+    handle: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let Some(socket) = get_websocket(env, handle) else {
+            return std::ptr::null_mut();
+        };
+        let Some(protocol) = socket.protocol() else {
+            return std::ptr::null_mut();
+        };
+        match env.new_string(protocol) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
         }
-    };
+    })
+}
 
-    let netstack = match global().netstack() {
-        Ok(ns) => ns,
-        Err(e) => {
-            throw_exception(&mut env, &format!("Tunnel not available: {}", e));
-            return -1;
-        }
+/// Send a message on a WebSocket.
+fn ws_send(env: &mut JNIEnv, handle: jlong, opcode: u8, payload: Vec<u8>) {
+    let Some(socket) = get_websocket(env, handle) else {
+        return;
     };
+    let result = global().run(async move { socket.send(opcode, &payload).await });
+    if let Err(e) = result {
+        throw_exception(env, &format!("WebSocket send failed: {}", e));
+    }
+}
 
-    let addr_str = format!("{}:{}", host, port);
-    log::info!("Connecting to {} via WireGuard tunnel", addr_str);
+/// Send a text message on a WebSocket.
+/// 
+/// @param handle WebSocket handle from wsConnect
+/// @param text Message text
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_wsSendText<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    text: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| match get_string(env, &text) {
+        Ok(text) => ws_send(env, handle, websocket::OP_TEXT, text.into_bytes()),
+        Err(e) => throw_exception(env, &e),
+    })
+}
 
-    let result = global().run(async move {
-        // Parse address - for now just try as IP:port
-        let addr: SocketAddr = addr_str.parse()
-            .map_err(|e| TunnelError::ConnectionFailed(format!("Invalid address {}: {}", addr_str, e)))?;
-        
-        let conn = TcpConnection::connect(netstack, addr)
-            .await
-            .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
-        
-        Ok::<_, TunnelError>(conn)
-    });
+/// Send a binary message on a WebSocket.
+/// 
+/// @param handle WebSocket handle from wsConnect
+/// @param data Message bytes
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_wsSendBinary<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    data: JByteArray<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| match env.convert_byte_array(&data) {
+        Ok(data) => ws_send(env, handle, websocket::OP_BINARY, data),
+        Err(e) => throw_exception(env, &format!("Failed to read data: {}", e)),
+    })
+}
 
-    match result {
-        Ok(conn) => {
-            let handle = global().connections.insert(conn);
-            log::debug!("TCP connection established, handle={}", handle);
-            handle
-        }
-        Err(e) => {
-            throw_exception(&mut env, &format!("Connection failed: {}", e));
-            -1
-        }
-    }
+/// Wait for the next message on a WebSocket.
+/// 
+/// Pings are answered while waiting. When the peer closes the connection,
+/// a close message is returned and the handle is released.
+/// 
+/// @param handle WebSocket handle from wsConnect
+/// @return The message, or null on error (with an exception thrown)
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_wsReceive<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) -> jobject {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let Some(socket) = get_websocket(env, handle) else {
+            return std::ptr::null_mut();
+        };
+        let (kind, data, code) = match global().run(async move { socket.receive().await }) {
+            Ok(websocket::Message::Text(text)) => (WS_TEXT, text.into_bytes(), 0),
+            Ok(websocket::Message::Binary(data)) => (WS_BINARY, data, 0),
+            Ok(websocket::Message::Close(code, reason)) => {
+                log::debug!("WebSocket closed by peer, handle={}, code={}", handle, code);
+                global().websockets.remove(handle);
+                (WS_CLOSE, reason.into_bytes(), code as jint)
+            }
+            Err(e) => {
+                throw_exception(env, &format!("WebSocket receive failed: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
+        let message = (|| -> jni::errors::Result<jobject> {
+            let data = env.byte_array_from_slice(&data)?;
+            let message = env.new_object(
+                "codes/dreaming/wireguard/jni/WebSocketMessage",
+                "(I[BI)V",
+                &[kind.into(), (&data).into(), code.into()],
+            )?;
+            Ok(message.into_raw())
+        })();
+        message.unwrap_or_else(|e| {
+            throw_exception(env, &format!("Failed to create WebSocketMessage: {}", e));
+            std::ptr::null_mut()
+        })
+    })
 }
 
-/// Read data from a TCP connection.
+/// Close a WebSocket and release its handle.
 /// 
-/// @param handle Connection handle from tcpConnect
-/// @param buffer Byte array to read into
-/// @return Number of bytes read, 0 on EOF, -1 on error
+/// Sends a close frame without waiting for the peer's reply. A wsReceive in
+/// progress fails.
+/// 
+/// @param handle WebSocket handle from wsConnect
+/// @param code Close code, e.g. 1000 for a normal closure
+/// @param reason Close reason, or null
 #[no_mangle]
-pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpRead<'local>(
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_wsClose<'local>(
     mut env: JNIEnv<'local>,
     _class: JClass<'local>,
     handle: jlong,
-    buffer: JByteArray<'local>,
-) -> jint {
-    let conn = match global().connections.get(handle) {
-        Some(c) => c,
-        None => {
-            throw_exception(&mut env, &format!("Invalid handle: {}", handle));
-            return -1;
+    code: jint,
+    reason: JString<'local>,
+) {
+    panic_guard::catch(&mut env, (), |env| {
+        let reason = match get_optional_string(env, &reason) {
+            Ok(reason) => reason.unwrap_or_default(),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+        let Ok(code) = u16::try_from(code) else {
+            throw_exception(env, &format!("Invalid close code: {}", code));
+            return;
+        };
+        let Some(socket) = global().websockets.remove(handle) else {
+            return;
+        };
+        if let Err(e) = global().run(async move { socket.close(code, &reason).await }) {
+            log::debug!("WebSocket close failed, handle={}: {}", handle, e);
         }
-    };
+    })
+}
 
-    let buf_len = match env.get_array_length(&buffer) {
-        Ok(len) => len as usize,
-        Err(e) => {
-            throw_exception(&mut env, &format!("Failed to get buffer length: {}", e));
-            return -1;
-        }
-    };
+// ============================================================================
+// JNI Functions - Minecraft
+// ============================================================================
+
+/// Query a Minecraft server's status (Server List Ping) through the tunnel.
+/// 
+/// @param host Hostname or IP address, also sent in the handshake
+/// @param port Port number
+/// @param timeoutMs Timeout for the whole exchange in milliseconds (0 = no timeout)
+/// @return JSON status payload, or null on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_serverStatus<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    host: JString<'local>,
+    port: jint,
+    timeout_ms: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let host = match get_string(env, &host) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
 
-    log::debug!("tcpRead: waiting for data on handle {}, buf_len={}", handle, buf_len);
+        let resolver = match global().resolver() {
+            Ok(r) => r,
+            Err(e) => {
+                throw_exception(env, &format!("Tunnel not available: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
 
-    let result = global().run(async move {
-        let mut rust_buf = vec![0u8; buf_len];
-        
-        // Check socket state before reading
-        let can_recv = conn.netstack.can_recv(conn.handle);
-        let may_recv = conn.netstack.may_recv(conn.handle);
-        let state = conn.netstack.socket_state(conn.handle);
-        log::debug!("tcpRead: socket state before read: can_recv={}, may_recv={}, state={:?}", 
-                   can_recv, may_recv, state);
-        
-        match conn.read(&mut rust_buf).await {
-            Ok(n) => {
-                log::debug!("tcpRead: read returned {} bytes", n);
-                Ok((n, rust_buf))
+        log::debug!("Querying server status of {}:{}", host, port);
+
+        let result = global().run(async move {
+            let port = port as u16;
+            let status = async {
+                let conn = connect_via_tunnel(resolver, host.clone(), port, 0).await?;
+                let status = minecraft::server_status(&conn, &host, port).await;
+                conn.shutdown();
+                status
+            };
+
+            if timeout_ms > 0 {
+                tokio::time::timeout(Duration::from_millis(timeout_ms as u64), status)
+                    .await
+                    .map_err(|_| TunnelError::Timeout)?
+            } else {
+                status.await
             }
+        });
+
+        match result {
+            Ok(json) => match env.new_string(json) {
+                Ok(s) => s.into_raw(),
+                Err(e) => {
+                    throw_exception(env, &format!("Failed to create string: {}", e));
+                    std::ptr::null_mut()
+                }
+            },
             Err(e) => {
-                log::error!("tcpRead: read returned error: {}", e);
-                Err(e)
+                throw_exception(env, &format!("Server status failed: {}", e));
+                std::ptr::null_mut()
             }
         }
-    });
+    })
+}
 
-    match result {
-        Ok((0, _)) => {
-            log::debug!("tcpRead: returning EOF (0 bytes)");
-            0
-        }
-        Ok((n, rust_buf)) => {
-            log::debug!("tcpRead: returning {} bytes to Java", n);
-            // Copy to Java array
-            let bytes: Vec<i8> = rust_buf[..n].iter().map(|&b| b as i8).collect();
-            if let Err(e) = env.set_byte_array_region(&buffer, 0, &bytes) {
-                throw_exception(&mut env, &format!("Failed to copy to buffer: {}", e));
+/// Forward a local UDP port to a Bedrock server through the tunnel.
+/// 
+/// Each client address gets its own UDP port in the tunnel and its session
+/// ends after 10 seconds without traffic, as RakNet's own does. The server
+/// address is resolved once, through the tunnel; only IPv4 servers can be
+/// reached. The forward keeps running across tunnel restarts until
+/// udpForwardStop, dropping datagrams while no tunnel is up.
+/// 
+/// @param bindAddress Local "ip:port" to receive datagrams on, e.g. "127.0.0.1:19132"
+/// @param host Server hostname or IPv4 address
+/// @param port Server port, 19132 for Bedrock by default
+/// @return Handle for udpForwardStop, or -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_udpForwardStart<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    bind_address: JString<'local>,
+    host: JString<'local>,
+    port: jint,
+) -> jlong {
+    panic_guard::catch(&mut env, -1, |env| {
+        let (bind_address, host) = match (get_string(env, &bind_address), get_string(env, &host)) {
+            (Ok(bind_address), Ok(host)) => (bind_address, host),
+            (Err(e), _) | (_, Err(e)) => {
+                throw_exception(env, &e);
                 return -1;
             }
-            n as jint
-        }
-        Err(e) => {
-            throw_exception(&mut env, &format!("Read error: {}", e));
-            -1
+        };
+        let Ok(local) = bind_address.parse::<SocketAddr>() else {
+            throw_exception(env, &format!("Invalid bind address: {}", bind_address));
+            return -1;
+        };
+        let Ok(port) = u16::try_from(port) else {
+            throw_exception(env, &format!("Invalid port: {}", port));
+            return -1;
+        };
+        let resolver = match global().resolver() {
+            Ok(r) => r,
+            Err(e) => {
+                throw_exception(env, &format!("Tunnel not available: {}", e));
+                return -1;
+            }
+        };
+
+        let result = global().run(async move {
+            let ip = match resolver.resolve_host(&host).await? {
+                IpAddr::V4(ip) => ip,
+                IpAddr::V6(ip) => {
+                    return Err(TunnelError::ConnectionFailed(format!(
+                        "IPv6 destination {} is not supported by the tunnel yet",
+                        ip
+                    )))
+                }
+            };
+            let tunnel: udp_forward::CurrentTunnel = Arc::new(|| {
+                let state = GLOBAL.read().clone()?;
+                let tunnel = state.tunnel.read().as_ref().map(|active| active.tunnel.clone());
+                tunnel.filter(|tunnel| !tunnel.is_paused())
+            });
+            udp_forward::UdpForward::start(local, SocketAddrV4::new(ip, port), tunnel)
+                .await
+                .map_err(|e| TunnelError::ConnectionFailed(format!("Failed to bind {}: {}", local, e)))
+        });
+
+        match result {
+            Ok(forward) => global().udp_forwards.insert(forward),
+            Err(e) => {
+                throw_exception(env, &format!("UDP forward failed: {}", e));
+                -1
+            }
         }
-    }
+    })
 }
 
-/// Write data to a TCP connection.
+/// Stop a UDP forward and drop its sessions.
 /// 
-/// @param handle Connection handle from tcpConnect
-/// @param data Byte array to write
-/// @param offset Offset in the array
-/// @param length Number of bytes to write
-/// @return Number of bytes written, -1 on error
+/// @param handle Handle from udpForwardStart; unknown handles are ignored
 #[no_mangle]
-pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpWrite<'local>(
-    mut env: JNIEnv<'local>,
-    _class: JClass<'local>,
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_udpForwardStop(
+    mut env: JNIEnv,
+    _class: JClass,
     handle: jlong,
-    data: JByteArray<'local>,
-    offset: jint,
-    length: jint,
-) -> jint {
-    let conn = match global().connections.get(handle) {
-        Some(c) => c,
-        None => {
-            throw_exception(&mut env, &format!("Invalid handle: {}", handle));
-            return -1;
+) {
+    panic_guard::catch(&mut env, (), |_| {
+        if let Some(forward) = global().udp_forwards.remove(handle) {
+            log::info!("Stopped forwarding UDP from {}", forward.local_addr());
         }
-    };
+    })
+}
 
-    // Get bytes from Java array
-    let mut bytes = vec![0i8; length as usize];
-    if let Err(e) = env.get_byte_array_region(&data, offset, &mut bytes) {
-        throw_exception(&mut env, &format!("Failed to read from buffer: {}", e));
-        return -1;
-    }
+// ============================================================================
+// JNI Functions - Diagnostics
+// ============================================================================
 
-    let rust_bytes: Vec<u8> = bytes.iter().map(|&b| b as u8).collect();
-    log::debug!("tcpWrite: writing {} bytes to handle {}", rust_bytes.len(), handle);
+/// Fetch Cloudflare's `/cdn-cgi/trace` through the tunnel.
+/// 
+/// Shows whether traffic exits via WARP: `warp` is "on" or "plus" when it
+/// does, alongside the egress `ip` and data center `colo`.
+/// 
+/// @return JSON object of the trace's fields
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_warpTrace<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let resolver = match global().resolver() {
+            Ok(r) => r,
+            Err(e) => {
+                throw_exception(env, &format!("Tunnel not available: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
 
-    let result = global().run(async move {
-        // Check socket state before writing
-        let can_send = conn.netstack.can_send(conn.handle);
-        let may_send = conn.netstack.may_send(conn.handle);
-        let state = conn.netstack.socket_state(conn.handle);
-        log::debug!("tcpWrite: socket state before write: can_send={}, may_send={}, state={:?}", 
-                   can_send, may_send, state);
-        
-        let result = conn.write(&rust_bytes).await;
-        
-        // Poll after write to ensure packets are sent
-        conn.netstack.poll();
-        
-        result
-    });
+        let result = global().run(async move { trace::fetch(&resolver).await });
 
-    match result {
-        Ok(n) => {
-            log::debug!("tcpWrite: wrote {} bytes successfully", n);
-            n as jint
+        match result {
+            Ok(fields) => {
+                let json = serde_json::to_string(&fields).unwrap_or_else(|_| "{}".into());
+                match env.new_string(json) {
+                    Ok(s) => s.into_raw(),
+                    Err(e) => {
+                        throw_exception(env, &format!("Failed to create string: {}", e));
+                        std::ptr::null_mut()
+                    }
+                }
+            }
+            Err(e) => {
+                throw_exception(env, &format!("WARP trace failed: {}", e));
+                std::ptr::null_mut()
+            }
         }
-        Err(e) => {
-            throw_exception(&mut env, &format!("Write error: {}", e));
-            -1
+    })
+}
+
+fn pretty_json(value: &impl serde::Serialize) -> Vec<u8> {
+    serde_json::to_vec_pretty(value).unwrap_or_else(|_| b"{}".to_vec())
+}
+
+/// Files for `exportDiagnostics`, each a name and its contents.
+fn collect_diagnostics() -> Vec<(String, Vec<u8>)> {
+    let state = global();
+    let snapshot = collect_metrics();
+    let mut files = Vec::new();
+
+    let (account_type, endpoint, config) = match state.tunnel.read().as_ref() {
+        Some(active) => {
+            let endpoint = active.endpoints[active.endpoint_index];
+            let config = profile::export(&active.config, endpoint, &active.addresses, false);
+            (Some(format!("{:?}", active.account_type)), Some(endpoint.to_string()), Some(config))
         }
+        None => (None, None, None),
+    };
+    let environment = diagnostics::Environment {
+        library_version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        cpu_simd: cpu::simd_level(),
+        cpus: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        generated_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        tunnel_state: snapshot.tunnel_state,
+        account_type,
+        endpoint,
+    };
+    files.push(("environment.json".to_string(), pretty_json(&environment)));
+    if let Some(config) = config {
+        files.push(("tunnel.conf".to_string(), config.into_bytes()));
     }
+
+    let options = state.options.read().clone();
+    let options = diagnostics::Options {
+        mtu: options.mtu,
+        keepalive_seconds: options.keepalive_seconds,
+        connect_policy: format!("{:?}", options.connect_policy),
+        endpoint_override: options.endpoint_override.map(|endpoint| format!("{:?}", endpoint)),
+        transport: diagnostics::redacted_transport(&options.outer.transport),
+        obfuscation: options.outer.obfuscation.map(|obfuscation| format!("{:?}", obfuscation)),
+        socket: format!("{:?}", options.outer.socket),
+        preshared_key: options.preshared_key.is_some(),
+        dns_servers: options
+            .dns_servers
+            .map(|servers| servers.iter().map(ToString::to_string).collect()),
+    };
+    files.push(("options.json".to_string(), pretty_json(&options)));
+
+    files.push(("metrics.json".to_string(), pretty_json(&snapshot)));
+    files.push(("traffic_by_destination.json".to_string(), pretty_json(&collect_traffic_by_destination())));
+    let (rx_bytes, tx_bytes) = state.throughput.recent(history::CAPACITY);
+    files.push(("throughput.json".to_string(), pretty_json(&diagnostics::Throughput { rx_bytes, tx_bytes })));
+
+    let connections: Vec<_> = state
+        .connections
+        .snapshot()
+        .into_iter()
+        .map(|(handle, conn)| diagnostics::Connection {
+            info: connection_info(handle, &conn),
+            connect_trace: connect_timeline(handle, &conn),
+        })
+        .collect();
+    files.push(("connections.json".to_string(), pretty_json(&connections)));
+
+    let mut log = logging::recent().join("\n");
+    log.push('\n');
+    files.push(("native.log".to_string(), log.into_bytes()));
+    files
 }
 
-/// Close a TCP connection.
+/// Write a zip of diagnostics for bug reports.
 /// 
-/// @param handle Connection handle from tcpConnect
+/// Contains the last 2000 native log lines, environment info (library
+/// version, OS, architecture, tunnel state), the running tunnel's config and
+/// the tunnel options with private keys, preshared keys and proxy passwords
+/// left out, a metrics snapshot, per-destination traffic, the throughput
+/// history and the open connections with their connect traces. Nothing is
+/// sent anywhere; the caller decides what to do with the file.
+/// 
+/// @param path File to write, overwritten if it exists
 #[no_mangle]
-pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpClose(
-    _env: JNIEnv,
-    _class: JClass,
-    handle: jlong,
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_exportDiagnostics<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    path: JString<'local>,
 ) {
-    if let Some(conn) = global().connections.remove(handle) {
-        global().run(async move {
-            conn.shutdown();
+    panic_guard::catch(&mut env, (), |env| {
+        let path = match get_string(env, &path) {
+            Ok(s) => PathBuf::from(s),
+            Err(e) => {
+                throw_exception(env, &e);
+                return;
+            }
+        };
+
+        match diagnostics::write_zip(&path, &collect_diagnostics()) {
+            Ok(()) => log::info!("Diagnostics written to {}", path.display()),
+            Err(e) => throw_exception(env, &format!("Failed to write diagnostics: {}", e)),
+        }
+    })
+}
+
+/// Ping a host through the tunnel with ICMP echo.
+/// 
+/// Hostnames are resolved through the tunnel. Only IPv4 destinations can be
+/// reached, like for TCP.
+/// 
+/// @param host Hostname or IPv4 address
+/// @param timeoutMs Longest wait for the reply in milliseconds
+/// @return Round-trip time in milliseconds, or -1 on error
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_icmpPing<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    host: JString<'local>,
+    timeout_ms: jlong,
+) -> jlong {
+    panic_guard::catch(&mut env, -1, |env| {
+        let host = match get_string(env, &host) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return -1;
+            }
+        };
+        let (tunnel, resolver) = match global().tunnel.read().as_ref() {
+            Some(active) if !active.tunnel.is_paused() => (active.tunnel.clone(), active.resolver.clone()),
+            _ => {
+                throw_exception(env, "Tunnel not available");
+                return -1;
+            }
+        };
+
+        let timeout = Duration::from_millis(timeout_ms.max(0) as u64);
+        let result = global().run(async move {
+            let ip = match resolver.resolve_host(&host).await? {
+                IpAddr::V4(ip) => ip,
+                IpAddr::V6(ip) => {
+                    return Err(TunnelError::ConnectionFailed(format!(
+                        "IPv6 destination {} is not supported by the tunnel yet",
+                        ip
+                    )))
+                }
+            };
+            tunnel.ping(ip, timeout).await
         });
-        log::debug!("TCP connection closed, handle={}", handle);
-    }
+
+        match result {
+            Ok(rtt) => rtt.as_millis() as jlong,
+            Err(e) => {
+                throw_exception(env, &format!("Ping failed: {}", e));
+                -1
+            }
+        }
+    })
 }
 
-/// Flush a TCP connection.
+/// Detect the NAT in front of this machine with STUN.
 /// 
-/// @param handle Connection handle from tcpConnect
-/// @return 0 on success, -1 on error
+/// Outside the tunnel, one UDP socket sends binding requests to two public
+/// STUN servers; matching public addresses mean an endpoint-independent
+/// (cone) NAT, different ones an address-dependent (symmetric) NAT. If a
+/// tunnel is running, a binding request over TCP through it also reports
+/// the tunnel's public address. The direct test sends traffic outside the
+/// tunnel.
+/// 
+/// @return JSON report: `direct` with `nat_type` ("open", "endpoint-independent",
+///         "address-dependent", "unknown" or "udp-blocked"), `local_address`,
+///         `mapped_addresses` and `errors`; `tunnel` with `public_address` or
+///         `error`, or null without a tunnel
 #[no_mangle]
-pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_tcpFlush<'local>(
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_detectNat<'local>(
     mut env: JNIEnv<'local>,
     _class: JClass<'local>,
-    handle: jlong,
-) -> jint {
-    let conn = match global().connections.get(handle) {
-        Some(c) => c,
-        None => {
-            throw_exception(&mut env, &format!("Invalid handle: {}", handle));
-            return -1;
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let resolver = global().resolver().ok();
+        let report = global().run(stun::detect(resolver));
+        let json = serde_json::to_string(&report).unwrap_or_else(|_| "{}".into());
+        match env.new_string(json) {
+            Ok(s) => s.into_raw(),
+            Err(e) => {
+                throw_exception(env, &format!("Failed to create string: {}", e));
+                std::ptr::null_mut()
+            }
         }
-    };
+    })
+}
 
-    // TcpConnection doesn't have an explicit flush - data is sent immediately.
-    // NetStack::poll internally tokio::spawn()s, so it must run on a Tokio runtime.
-    global().run(async move {
-        conn.netstack.poll();
-    });
+/// Measure the tunnel's handshake time, round-trip time and throughput.
+/// 
+/// The endpoint must speak Cloudflare's speed test protocol over HTTPS, like
+/// speed.cloudflare.com.
+/// 
+/// @param host Endpoint hostname
+/// @param bytes Bytes to transfer in each direction (0 for the default of 10 MiB)
+/// @param timeoutMs Timeout for the whole test in milliseconds (0 for no timeout)
+/// @return JSON report
+#[no_mangle]
+pub extern "system" fn Java_codes_dreaming_wireguard_jni_Native_runSelfTest<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    host: JString<'local>,
+    bytes: jint,
+    timeout_ms: jlong,
+) -> jstring {
+    panic_guard::catch(&mut env, std::ptr::null_mut(), |env| {
+        let host = match get_string(env, &host) {
+            Ok(s) => s,
+            Err(e) => {
+                throw_exception(env, &e);
+                return std::ptr::null_mut();
+            }
+        };
+        let bytes = if bytes > 0 { bytes as usize } else { selftest::DEFAULT_BYTES };
+
+        let resolver = match global().resolver() {
+            Ok(r) => r,
+            Err(e) => {
+                throw_exception(env, &format!("Tunnel not available: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
 
-    0
+        let result = global().run(async move {
+            let test = selftest::run(&resolver, &host, bytes);
+            if timeout_ms > 0 {
+                tokio::time::timeout(Duration::from_millis(timeout_ms as u64), test)
+                    .await
+                    .map_err(|_| TunnelError::Timeout)?
+            } else {
+                test.await
+            }
+        });
+
+        match result {
+            Ok(report) => {
+                let json = serde_json::to_string(&report).unwrap_or_else(|_| "{}".into());
+                match env.new_string(json) {
+                    Ok(s) => s.into_raw(),
+                    Err(e) => {
+                        throw_exception(env, &format!("Failed to create string: {}", e));
+                        std::ptr::null_mut()
+                    }
+                }
+            }
+            Err(e) => {
+                throw_exception(env, &format!("Self-test failed: {}", e));
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_enrollment_is_not_overwritten() {
+        let path = std::env::temp_dir().join(format!("wgt-enrollment-test-{}.json", std::process::id()));
+        let consumer = concat!(
+            r#"{"device_id":"d","access_token":"t","#,
+            r#""private_key":"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=","license_key":"k"}"#
+        );
+        fs::write(&path, consumer).unwrap();
+
+        let cred_file = CredentialFile { path: path.to_string_lossy().into_owned(), key: None };
+        let options = RegistrationOptions {
+            teams: Some(TeamsEnrollment {
+                jwt_token: "token".into(),
+                device_name: None,
+                serial_number: None,
+            }),
+            ..RegistrationOptions::default()
+        };
+        let result = global().run(async move { load_or_register_warp(&cred_file, options).await.map(|_| ()) });
+        let contents = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert!(matches!(result, Err(TunnelError::CredentialPersistence(m)) if m.contains("consumer WARP enrollment")));
+        assert_eq!(contents, consumer);
+    }
 }
@@ -0,0 +1,74 @@
+//! Java callbacks for connections closed by the peer and tunnel events.
+//!
+//! Tunnel connections are watched through their TCP state, so a FIN or RST
+//! is reported as soon as the netstack processes it. Direct sockets expose no
+//! state, so their closes are reported once a read sees them.
+
+use jni::objects::{GlobalRef, JValue};
+use jni::JavaVM;
+
+use crate::connection::PeerClose;
+use crate::tunnel::HandshakeEvent;
+
+pub const CLOSED_METHOD_SIG: &str = "(JI)V";
+pub const HANDSHAKE_METHOD_SIG: &str = "(I)V";
+
+/// Call `method` on `listener` from the current thread, attaching it if needed.
+fn call(vm: &JavaVM, listener: &GlobalRef, method: &str, sig: &str, args: &[JValue]) -> jni::errors::Result<()> {
+    let mut env = vm.attach_current_thread_as_daemon()?;
+    if env.exception_check()? {
+        return Err(jni::errors::Error::JavaException);
+    }
+
+    let result = env.call_method(listener, method, sig, args).map(|_| ());
+    if result.is_err() {
+        let _ = env.exception_clear();
+    }
+    result
+}
+
+/// Java object implementing `ConnectionListener`.
+pub struct ConnectionListener {
+    vm: JavaVM,
+    listener: GlobalRef,
+}
+
+impl ConnectionListener {
+    pub fn new(vm: JavaVM, listener: GlobalRef) -> Self {
+        Self { vm, listener }
+    }
+
+    /// Call `onPeerClosed` for `handle`.
+    pub fn notify(&self, handle: i64, reason: PeerClose) -> jni::errors::Result<()> {
+        call(
+            &self.vm,
+            &self.listener,
+            "onPeerClosed",
+            CLOSED_METHOD_SIG,
+            &[JValue::Long(handle), JValue::Int(reason as i32)],
+        )
+    }
+}
+
+/// Java object implementing `TunnelListener`.
+pub struct TunnelListener {
+    vm: JavaVM,
+    listener: GlobalRef,
+}
+
+impl TunnelListener {
+    pub fn new(vm: JavaVM, listener: GlobalRef) -> Self {
+        Self { vm, listener }
+    }
+
+    /// Call `onHandshakeEvent` for `event`.
+    pub fn notify(&self, event: HandshakeEvent) -> jni::errors::Result<()> {
+        call(
+            &self.vm,
+            &self.listener,
+            "onHandshakeEvent",
+            HANDSHAKE_METHOD_SIG,
+            &[JValue::Int(event as i32)],
+        )
+    }
+}
@@ -0,0 +1,65 @@
+//! Log output to Android's logcat.
+//!
+//! Android apps have stderr connected to /dev/null, so records that are not
+//! forwarded to a Java logger go here instead.
+
+use std::ffi::{c_char, c_int, CStr, CString};
+
+use log::{Level, Record};
+
+const TAG: &CStr = c"WireguardTunnel";
+
+/// Logcat drops the end of longer messages, so they are split at line breaks.
+const MAX_MESSAGE: usize = 4000;
+
+#[link(name = "log")]
+extern "C" {
+    fn __android_log_write(priority: c_int, tag: *const c_char, text: *const c_char) -> c_int;
+}
+
+/// Priority from `android/log.h`.
+fn priority(level: Level) -> c_int {
+    match level {
+        Level::Trace => 2,
+        Level::Debug => 3,
+        Level::Info => 4,
+        Level::Warn => 5,
+        Level::Error => 6,
+    }
+}
+
+fn write_message(priority: c_int, message: &str) {
+    // CString rejects interior NULs
+    let Ok(text) = CString::new(message.replace('\0', "\\0")) else {
+        return;
+    };
+    // SAFETY: both strings are NUL-terminated and outlive the call
+    unsafe {
+        __android_log_write(priority, TAG.as_ptr(), text.as_ptr());
+    }
+}
+
+/// Write `record` as "target: message".
+pub fn write(record: &Record) {
+    let priority = priority(record.level());
+    let message = format!("{}: {}", record.target(), record.args());
+    if message.len() <= MAX_MESSAGE {
+        write_message(priority, &message);
+        return;
+    }
+
+    let mut chunk = String::new();
+    for line in message.lines() {
+        if !chunk.is_empty() && chunk.len() + 1 + line.len() > MAX_MESSAGE {
+            write_message(priority, &chunk);
+            chunk.clear();
+        }
+        if !chunk.is_empty() {
+            chunk.push('\n');
+        }
+        chunk.push_str(line);
+    }
+    if !chunk.is_empty() {
+        write_message(priority, &chunk);
+    }
+}
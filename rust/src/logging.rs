@@ -0,0 +1,186 @@
+//! Rust `log` output, optionally forwarded to a Java logger.
+//!
+//! Records are filtered as before by env_logger (`RUST_LOG`, default "info"),
+//! and the filter can be replaced at runtime with `set_filter`.
+//! Without a registered Java logger they are written to stderr, or to logcat
+//! on Android, where apps have no stderr; with one they are passed to
+//! `NativeLogger.log` so they land in the game's log file.
+//! Either way the last records that pass the filter are kept for
+//! `exportDiagnostics`.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jni::objects::{GlobalRef, JValue};
+use jni::JavaVM;
+use log::{Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+use parking_lot::{Mutex, RwLock};
+
+pub const LOG_METHOD_SIG: &str = "(ILjava/lang/String;Ljava/lang/String;)V";
+
+/// Records kept for diagnostics.
+const RECENT_CAPACITY: usize = 2000;
+
+/// Java object implementing `NativeLogger`.
+pub struct JavaLogger {
+    vm: JavaVM,
+    logger: GlobalRef,
+}
+
+impl JavaLogger {
+    pub fn new(vm: JavaVM, logger: GlobalRef) -> Self {
+        Self { vm, logger }
+    }
+
+    fn send(&self, record: &Record) -> jni::errors::Result<()> {
+        // Worker threads stay attached; detaching per record would be far too slow
+        let mut env = self.vm.attach_current_thread_as_daemon()?;
+        if env.exception_check()? {
+            // Calling into Java with an exception pending is not allowed
+            return Err(jni::errors::Error::JavaException);
+        }
+
+        let result = env.with_local_frame(4, |env| -> jni::errors::Result<()> {
+            let target = env.new_string(record.target())?;
+            let message = env.new_string(record.args().to_string())?;
+            env.call_method(
+                &self.logger,
+                "log",
+                LOG_METHOD_SIG,
+                &[
+                    JValue::Int(record.level() as i32),
+                    JValue::Object(&target),
+                    JValue::Object(&message),
+                ],
+            )?;
+            Ok(())
+        });
+        if result.is_err() {
+            let _ = env.exception_clear();
+        }
+        result
+    }
+}
+
+struct Bridge {
+    /// Swapped out whole when the filter changes, since env_logger filters are immutable.
+    stderr: RwLock<env_logger::Logger>,
+    java: RwLock<Option<Arc<JavaLogger>>>,
+    /// The last records that passed the filter, formatted, oldest first.
+    recent: Mutex<VecDeque<String>>,
+}
+
+static BRIDGE: OnceCell<Bridge> = OnceCell::new();
+
+thread_local! {
+    /// Set while a record is being forwarded, so logging from inside the
+    /// Java call goes to stderr instead of recursing.
+    static FORWARDING: Cell<bool> = const { Cell::new(false) };
+}
+
+impl Bridge {
+    fn forward(&self, record: &Record) -> bool {
+        let Some(java) = self.java.read().clone() else {
+            return false;
+        };
+        FORWARDING.with(|busy| {
+            if busy.replace(true) {
+                return false;
+            }
+            let sent = java.send(record).is_ok();
+            busy.set(false);
+            sent
+        })
+    }
+
+    fn remember(&self, record: &Record) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = format!(
+            "{}.{:03} {:<5} {}: {}",
+            now.as_secs(),
+            now.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        let mut recent = self.recent.lock();
+        if recent.len() == RECENT_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(line);
+    }
+}
+
+impl Log for Bridge {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.read().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.stderr.read().matches(record) {
+            return;
+        }
+        self.remember(record);
+        // The jni crate logs thread attachment itself, which would recurse
+        if record.target().starts_with("jni") || !self.forward(record) {
+            #[cfg(target_os = "android")]
+            crate::logcat::write(record);
+            #[cfg(not(target_os = "android"))]
+            self.stderr.read().log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.read().flush();
+    }
+}
+
+fn default_builder() -> env_logger::Builder {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+}
+
+/// Install the logger. Later calls are no-ops.
+pub fn init() {
+    let bridge = BRIDGE.get_or_init(|| Bridge {
+        stderr: RwLock::new(default_builder().build()),
+        java: RwLock::new(None),
+        recent: Mutex::new(VecDeque::with_capacity(RECENT_CAPACITY)),
+    });
+    if log::set_logger(bridge).is_ok() {
+        log::set_max_level(bridge.stderr.read().filter());
+    }
+}
+
+/// Replace the log filter, using `RUST_LOG` syntax (e.g. "info,wireguard_netstack=trace").
+///
+/// `None` restores the filter from `RUST_LOG`, or "info" if unset.
+pub fn set_filter(filter: Option<&str>) {
+    let Some(bridge) = BRIDGE.get() else {
+        return;
+    };
+
+    let logger = match filter {
+        Some(filter) => env_logger::Builder::new().parse_filters(filter).build(),
+        None => default_builder().build(),
+    };
+    log::set_max_level(logger.filter());
+    *bridge.stderr.write() = logger;
+}
+
+/// Forward records to a Java logger, or back to stderr with `None`.
+pub fn set_java_logger(logger: Option<JavaLogger>) {
+    if let Some(bridge) = BRIDGE.get() {
+        *bridge.java.write() = logger.map(Arc::new);
+    }
+}
+
+/// The last records logged, oldest first, each as "unix-seconds.millis LEVEL target: message".
+pub fn recent() -> Vec<String> {
+    BRIDGE
+        .get()
+        .map(|bridge| bridge.recent.lock().iter().cloned().collect())
+        .unwrap_or_default()
+}
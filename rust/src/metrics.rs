@@ -0,0 +1,331 @@
+//! Point-in-time metrics, rendered as Prometheus text or JSON.
+//!
+//! Byte counts on connections are application payload. Tunnel counters are
+//! decrypted IP packets received from WireGuard; outbound packets cannot be
+//! observed yet (see UPSTREAM.md), so tunnel TX is the payload written by
+//! tunneled connections.
+
+use std::fmt::Write;
+
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    pub tunnel_state: &'static str,
+    pub tunnel: Option<TunnelMetrics>,
+    pub handshakes: HandshakeMetrics,
+    pub routes: Vec<RouteMetrics>,
+    pub connections: Vec<ConnectionMetrics>,
+    pub runtime: RuntimeMetrics,
+}
+
+#[derive(Serialize)]
+pub struct TunnelMetrics {
+    pub uptime_seconds: f64,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Seconds since the last packet from the peer; stands in for handshake
+    /// age, which wireguard-netstack does not expose.
+    pub last_rx_age_seconds: Option<f64>,
+    /// Seconds since the last completed handshake; only known when packets
+    /// go through the relay, see UPSTREAM.md.
+    pub handshake_age_seconds: Option<f64>,
+}
+
+/// Handshakes of every tunnel since initJNI. Tunnels sending directly only
+/// count the handshakes we start, and no rekeys.
+#[derive(Serialize)]
+pub struct HandshakeMetrics {
+    pub attempts: u64,
+    /// Completed handshakes, rekeys included.
+    pub successes: u64,
+    pub failures: u64,
+    pub rekeys: u64,
+}
+
+/// Connection totals per route, including closed connections.
+#[derive(Serialize)]
+pub struct RouteMetrics {
+    pub route: &'static str,
+    pub open_connections: usize,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// Connection totals to one destination address over one route, including
+/// closed connections.
+#[derive(Serialize)]
+pub struct DestinationMetrics {
+    pub address: String,
+    pub route: &'static str,
+    pub open_connections: usize,
+    /// Connections ever opened, the open ones included.
+    pub connections: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionMetrics {
+    pub handle: i64,
+    pub route: &'static str,
+    pub age_seconds: f64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Average over the connection's lifetime.
+    pub rx_bytes_per_second: f64,
+    pub tx_bytes_per_second: f64,
+}
+
+/// The most recent failure of the tunnel or of a connection.
+#[derive(Serialize)]
+pub struct LastError {
+    /// `TUNNEL_ERROR_*` or `TCP_ERROR_*` code.
+    pub code: i32,
+    /// Message of the exception or callback error that reported it, if any.
+    pub message: Option<String>,
+    /// Seconds since the failure; only tracked for the tunnel.
+    pub age_seconds: Option<f64>,
+}
+
+/// Details of one open connection, for debug screens.
+#[derive(Serialize)]
+pub struct ConnectionInfo {
+    pub handle: i64,
+    pub route: &'static str,
+    pub remote: String,
+    /// Not known for tunnel connections, see UPSTREAM.md.
+    pub local: Option<String>,
+    /// smoltcp TCP state name; direct connections report ESTABLISHED.
+    pub state: String,
+    pub age_seconds: f64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// `TCP_ERROR_*` code of the last failed read or write.
+    pub last_error: i32,
+}
+
+/// Timeline of opening one connection, in milliseconds since the connect began.
+#[derive(Serialize)]
+pub struct ConnectTimeline {
+    pub handle: i64,
+    /// A pre-warmed spare was used, so there was no handshake.
+    pub pooled: bool,
+    pub dns_start_ms: Option<f64>,
+    pub dns_end_ms: Option<f64>,
+    pub syn_sent_ms: Option<f64>,
+    /// SYN-ACK received.
+    pub established_ms: Option<f64>,
+    pub first_byte_ms: Option<f64>,
+}
+
+/// Round-trip and retransmission statistics of one connection.
+///
+/// The kernel fields are only known for direct connections on Linux; see
+/// UPSTREAM.md for tunnel connections.
+#[derive(Serialize, Default)]
+pub struct TcpStats {
+    pub handle: i64,
+    pub route: &'static str,
+    pub state: String,
+    /// Time from SYN to SYN-ACK, one RTT sample taken at connect. Null for
+    /// pre-warmed spares and connections not opened by tcpConnect.
+    pub handshake_rtt_ms: Option<f64>,
+    /// Smoothed RTT and its variance.
+    pub rtt_ms: Option<f64>,
+    pub rtt_var_ms: Option<f64>,
+    /// Times the oldest unacknowledged segment was retransmitted.
+    pub retransmits: Option<u32>,
+    pub total_retransmits: Option<u32>,
+    /// Congestion window, in segments of `mss` bytes.
+    pub cwnd_segments: Option<u32>,
+    pub mss: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct RuntimeMetrics {
+    pub workers: usize,
+    pub alive_tasks: usize,
+    pub global_queue_depth: usize,
+}
+
+/// Append one metric family with its HELP and TYPE lines.
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+fn no_labels(value: f64) -> Vec<(String, f64)> {
+    vec![(String::new(), value)]
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".into())
+    }
+
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        family(
+            &mut out,
+            "wireguard_tunnel_state",
+            "gauge",
+            "Tunnel state.",
+            &[(format!("{{state=\"{}\"}}", self.tunnel_state), 1.0)],
+        );
+
+        if let Some(tunnel) = &self.tunnel {
+            family(
+                &mut out,
+                "wireguard_tunnel_uptime_seconds",
+                "gauge",
+                "Seconds since the tunnel was started.",
+                &no_labels(tunnel.uptime_seconds),
+            );
+            family(
+                &mut out,
+                "wireguard_tunnel_rx_packets_total",
+                "counter",
+                "Packets received from the peer.",
+                &no_labels(tunnel.rx_packets as f64),
+            );
+            family(
+                &mut out,
+                "wireguard_tunnel_rx_bytes_total",
+                "counter",
+                "IP bytes received from the peer.",
+                &no_labels(tunnel.rx_bytes as f64),
+            );
+            if let Some(age) = tunnel.last_rx_age_seconds {
+                family(
+                    &mut out,
+                    "wireguard_tunnel_last_rx_age_seconds",
+                    "gauge",
+                    "Seconds since the last packet from the peer.",
+                    &no_labels(age),
+                );
+            }
+            if let Some(age) = tunnel.handshake_age_seconds {
+                family(
+                    &mut out,
+                    "wireguard_tunnel_handshake_age_seconds",
+                    "gauge",
+                    "Seconds since the last completed handshake.",
+                    &no_labels(age),
+                );
+            }
+        }
+
+        let handshakes = [
+            ("attempts", "Handshake initiations sent.", self.handshakes.attempts),
+            ("successes", "Handshakes completed, rekeys included.", self.handshakes.successes),
+            ("failures", "Tunnels that gave up waiting for their first handshake.", self.handshakes.failures),
+            ("rekeys", "Handshakes that replaced an existing session.", self.handshakes.rekeys),
+        ];
+        for (name, help, value) in handshakes {
+            family(
+                &mut out,
+                &format!("wireguard_handshake_{}_total", name),
+                "counter",
+                help,
+                &no_labels(value as f64),
+            );
+        }
+
+        let by_route = |f: fn(&RouteMetrics) -> f64| -> Vec<(String, f64)> {
+            self.routes
+                .iter()
+                .map(|r| (format!("{{route=\"{}\"}}", r.route), f(r)))
+                .collect()
+        };
+        family(
+            &mut out,
+            "wireguard_connections_open",
+            "gauge",
+            "Open connections.",
+            &by_route(|r| r.open_connections as f64),
+        );
+        family(
+            &mut out,
+            "wireguard_connection_rx_bytes_total",
+            "counter",
+            "Payload bytes read from connections.",
+            &by_route(|r| r.rx_bytes as f64),
+        );
+        family(
+            &mut out,
+            "wireguard_connection_tx_bytes_total",
+            "counter",
+            "Payload bytes written to connections.",
+            &by_route(|r| r.tx_bytes as f64),
+        );
+
+        let by_connection = |f: fn(&ConnectionMetrics) -> f64| -> Vec<(String, f64)> {
+            self.connections
+                .iter()
+                .map(|c| {
+                    (
+                        format!("{{handle=\"{}\",route=\"{}\"}}", c.handle, c.route),
+                        f(c),
+                    )
+                })
+                .collect()
+        };
+        family(
+            &mut out,
+            "wireguard_connection_rx_bytes",
+            "gauge",
+            "Payload bytes read from an open connection.",
+            &by_connection(|c| c.rx_bytes as f64),
+        );
+        family(
+            &mut out,
+            "wireguard_connection_tx_bytes",
+            "gauge",
+            "Payload bytes written to an open connection.",
+            &by_connection(|c| c.tx_bytes as f64),
+        );
+        family(
+            &mut out,
+            "wireguard_connection_rx_bytes_per_second",
+            "gauge",
+            "Average read throughput of an open connection.",
+            &by_connection(|c| c.rx_bytes_per_second),
+        );
+        family(
+            &mut out,
+            "wireguard_connection_tx_bytes_per_second",
+            "gauge",
+            "Average write throughput of an open connection.",
+            &by_connection(|c| c.tx_bytes_per_second),
+        );
+
+        family(
+            &mut out,
+            "wireguard_runtime_workers",
+            "gauge",
+            "Tokio worker threads.",
+            &no_labels(self.runtime.workers as f64),
+        );
+        family(
+            &mut out,
+            "wireguard_runtime_alive_tasks",
+            "gauge",
+            "Tokio tasks currently alive.",
+            &no_labels(self.runtime.alive_tasks as f64),
+        );
+        family(
+            &mut out,
+            "wireguard_runtime_global_queue_depth",
+            "gauge",
+            "Tasks waiting in the Tokio global queue.",
+            &no_labels(self.runtime.global_queue_depth as f64),
+        );
+
+        out
+    }
+}
@@ -0,0 +1,172 @@
+//! Minecraft protocol helpers that run entirely on the native side.
+//!
+//! Implements the Server List Ping exchange so the server list can query
+//! tunneled servers without framing packets over the handle API.
+
+use wireguard_netstack::TcpConnection;
+
+use crate::TunnelError;
+
+/// Port the vanilla client assumes when none is given. Only connections to
+/// it follow `_minecraft._tcp` SRV records.
+pub const DEFAULT_PORT: u16 = 25565;
+
+/// Protocol version sent in the status handshake. -1 asks the server to
+/// report its own version instead of rejecting ours.
+const STATUS_PROTOCOL_VERSION: i32 = -1;
+
+/// Handshake "next state" value requesting the status protocol.
+const NEXT_STATE_STATUS: i32 = 1;
+
+/// Upper bound on a status response packet. Vanilla caps the JSON at 32767
+/// UTF-16 units; this leaves headroom for modded servers with large favicons.
+const MAX_STATUS_PACKET: usize = 1024 * 1024;
+
+fn write_varint(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7F == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    write_varint(buf, s.len() as i32);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Prefix a packet (id + body) with its VarInt length.
+fn frame(packet_id: i32, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    write_varint(&mut packet, packet_id);
+    packet.extend_from_slice(body);
+
+    let mut framed = Vec::with_capacity(packet.len() + 5);
+    write_varint(&mut framed, packet.len() as i32);
+    framed.extend_from_slice(&packet);
+    framed
+}
+
+/// Decode a VarInt from the start of `data`, returning the value and its encoded length.
+fn decode_varint(data: &[u8]) -> Option<(i32, usize)> {
+    let mut value: u32 = 0;
+    for (i, &byte) in data.iter().take(5).enumerate() {
+        value |= ((byte & 0x7F) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value as i32, i + 1));
+        }
+    }
+    None
+}
+
+fn protocol_error(msg: impl Into<String>) -> TunnelError {
+    TunnelError::ConnectionFailed(format!("Invalid status response: {}", msg.into()))
+}
+
+/// Buffered reader over a tunnel connection.
+struct PacketReader<'a> {
+    conn: &'a TcpConnection,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> PacketReader<'a> {
+    fn new(conn: &'a TcpConnection) -> Self {
+        Self {
+            conn,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    async fn read_byte(&mut self) -> Result<u8, TunnelError> {
+        if self.pos == self.buf.len() {
+            let mut chunk = [0u8; 4096];
+            let n = self
+                .conn
+                .read(&mut chunk)
+                .await
+                .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+            if n == 0 {
+                return Err(protocol_error("connection closed"));
+            }
+            self.buf.clear();
+            self.buf.extend_from_slice(&chunk[..n]);
+            self.pos = 0;
+        }
+        let byte = self.buf[self.pos];
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    async fn read_varint(&mut self) -> Result<i32, TunnelError> {
+        let mut value: u32 = 0;
+        for i in 0..5 {
+            let byte = self.read_byte().await?;
+            value |= ((byte & 0x7F) as u32) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value as i32);
+            }
+        }
+        Err(protocol_error("VarInt too long"))
+    }
+
+    async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, TunnelError> {
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            if self.pos == self.buf.len() {
+                let byte = self.read_byte().await?;
+                out.push(byte);
+                continue;
+            }
+            let take = (len - out.len()).min(self.buf.len() - self.pos);
+            out.extend_from_slice(&self.buf[self.pos..self.pos + take]);
+            self.pos += take;
+        }
+        Ok(out)
+    }
+}
+
+/// Perform the Server List Ping handshake and status request.
+///
+/// `host` and `port` are sent in the handshake as the address the player
+/// typed, which some proxies use for virtual hosting.
+///
+/// Returns the raw JSON status payload.
+pub async fn server_status(conn: &TcpConnection, host: &str, port: u16) -> Result<String, TunnelError> {
+    let mut handshake = Vec::new();
+    write_varint(&mut handshake, STATUS_PROTOCOL_VERSION);
+    write_string(&mut handshake, host);
+    handshake.extend_from_slice(&port.to_be_bytes());
+    write_varint(&mut handshake, NEXT_STATE_STATUS);
+
+    let mut request = frame(0x00, &handshake);
+    request.extend_from_slice(&frame(0x00, &[]));
+    conn.write_all(&request)
+        .await
+        .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+
+    let mut reader = PacketReader::new(conn);
+    let length = reader.read_varint().await?;
+    if length <= 0 || length as usize > MAX_STATUS_PACKET {
+        return Err(protocol_error(format!("bad packet length {}", length)));
+    }
+    let packet = reader.read_exact(length as usize).await?;
+
+    let (packet_id, id_len) = decode_varint(&packet).ok_or_else(|| protocol_error("truncated packet id"))?;
+    if packet_id != 0x00 {
+        return Err(protocol_error(format!("unexpected packet id {:#x}", packet_id)));
+    }
+    let rest = &packet[id_len..];
+    let (json_len, len_len) = decode_varint(rest).ok_or_else(|| protocol_error("truncated string length"))?;
+    let json = usize::try_from(json_len)
+        .ok()
+        .and_then(|len| rest.get(len_len..len_len + len))
+        .ok_or_else(|| protocol_error(format!("bad string length {}", json_len)))?;
+
+    String::from_utf8(json.to_vec()).map_err(|_| protocol_error("status is not UTF-8"))
+}
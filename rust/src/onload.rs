@@ -0,0 +1,321 @@
+//! `JNI_OnLoad`: ABI check and explicit registration of native methods.
+//!
+//! Every `Native` method is bound with RegisterNatives when the library is
+//! loaded, so a Java declaration that no longer matches its Rust function
+//! fails `System.load` with a message naming it, instead of throwing
+//! UnsatisfiedLinkError on first call, possibly deep inside a Netty handler.
+//! Methods the jar does not declare are skipped, since an older jar cannot
+//! call them anyway. The `Java_...` symbols stay exported, so JVMs that look
+//! methods up by name still find them.
+
+use std::collections::HashSet;
+use std::ffi::c_void;
+
+use jni::objects::{JClass, JObjectArray, JString};
+use jni::sys::{jint, JNI_ERR, JNI_VERSION_1_8};
+use jni::{JNIEnv, JavaVM, NativeMethod};
+
+use crate::{io_callback, listener, logging, panic_guard};
+
+/// Version of the contract between `Native.java` and this library.
+///
+/// Bump it together with `Native.ABI_VERSION` when a native method changes
+/// signature or meaning or is removed; adding methods needs no bump.
+const ABI_VERSION: i32 = 1;
+
+const NATIVE_CLASS: &str = "codes/dreaming/wireguard/jni/Native";
+const FUNCTION_PREFIX: &str = "Java_codes_dreaming_wireguard_jni_Native_";
+/// `java.lang.reflect.Modifier.NATIVE`.
+const MODIFIER_NATIVE: i32 = 0x100;
+
+/// Java methods and constructors native code calls, as class, name and signature.
+///
+/// Classes missing from the jar are skipped, like undeclared natives.
+const CALLBACKS: [(&str, &str, &str); 10] = [
+    ("codes/dreaming/wireguard/jni/NativeLogger", "log", logging::LOG_METHOD_SIG),
+    ("codes/dreaming/wireguard/jni/ConnectionListener", "onPeerClosed", listener::CLOSED_METHOD_SIG),
+    ("codes/dreaming/wireguard/jni/TunnelListener", "onHandshakeEvent", listener::HANDSHAKE_METHOD_SIG),
+    ("codes/dreaming/wireguard/jni/IoCallback", "onComplete", io_callback::COMPLETE_METHOD_SIG),
+    ("codes/dreaming/wireguard/jni/IoCallback", "onError", io_callback::ERROR_METHOD_SIG),
+    ("codes/dreaming/wireguard/jni/CredentialKeyProvider", "getCredentialKey", "()[B"),
+    ("codes/dreaming/wireguard/jni/HttpResponse", "<init>", "(I[Ljava/lang/String;[B)V"),
+    ("codes/dreaming/wireguard/jni/WebSocketMessage", "<init>", "(I[BI)V"),
+    (
+        "codes/dreaming/wireguard/jni/InvalidConfigException",
+        "<init>",
+        "(Ljava/lang/String;Ljava/lang/String;)V",
+    ),
+    ("codes/dreaming/wireguard/jni/StaleHandleException", "<init>", "(Ljava/lang/String;)V"),
+];
+
+macro_rules! natives {
+    ($($function:ident: $sig:literal),* $(,)?) => {
+        vec![$((stringify!($function), $sig, crate::$function as *mut c_void)),*]
+    };
+}
+
+/// Every native method: exported function name, signature and address.
+fn natives() -> Vec<(&'static str, &'static str, *mut c_void)> {
+    natives![
+        Java_codes_dreaming_wireguard_jni_Native_configureRuntime: "(III)V",
+        Java_codes_dreaming_wireguard_jni_Native_initJNI: "()V",
+        Java_codes_dreaming_wireguard_jni_Native_shutdownNative: "()V",
+        Java_codes_dreaming_wireguard_jni_Native_registerLogger: "(Lcodes/dreaming/wireguard/jni/NativeLogger;)V",
+        Java_codes_dreaming_wireguard_jni_Native_setLogLevel: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_ping: "()Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_version: "()Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_nativeFeatures: "()J",
+        Java_codes_dreaming_wireguard_jni_Native_setCredentialPassphrase: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_setCredentialKeyProvider:
+            "(Lcodes/dreaming/wireguard/jni/CredentialKeyProvider;)V",
+        Java_codes_dreaming_wireguard_jni_Native_setCacheDirectory: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_setMtu: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_setPersistentKeepalive: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_setEndpointOverride: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_setPresharedKey: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_setDnsServers: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_setOuterTransport: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_setObfuscation: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_setOuterSocketOptions: "(Ljava/lang/String;Ljava/lang/String;I)V",
+        Java_codes_dreaming_wireguard_jni_Native_setOuterDscp: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_setOuterBatching: "(Z)V",
+        Java_codes_dreaming_wireguard_jni_Native_setPreserveConnections: "(Z)V",
+        Java_codes_dreaming_wireguard_jni_Native_setConnectPolicy: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_setMaxConnections: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_setIdleTimeout: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_startWarpTunnel: "(Ljava/lang/String;Ljava/lang/String;I)I",
+        Java_codes_dreaming_wireguard_jni_Native_startWarpTeamsTunnel:
+            "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;I)I",
+        Java_codes_dreaming_wireguard_jni_Native_importWireGuardProfile: "(Ljava/lang/String;I)I",
+        Java_codes_dreaming_wireguard_jni_Native_validateConfig: "(Ljava/lang/String;)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_reloadTunnelConfig: "(Ljava/lang/String;)I",
+        Java_codes_dreaming_wireguard_jni_Native_rotateKeys: "()Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_confirmKeyRotation: "()V",
+        Java_codes_dreaming_wireguard_jni_Native_tunnelState: "()I",
+        Java_codes_dreaming_wireguard_jni_Native_lastTunnelError: "()Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_pauseTunnel: "()I",
+        Java_codes_dreaming_wireguard_jni_Native_resumeTunnel: "()I",
+        Java_codes_dreaming_wireguard_jni_Native_notifyNetworkChanged: "()Z",
+        Java_codes_dreaming_wireguard_jni_Native_notifyResumed: "()Z",
+        Java_codes_dreaming_wireguard_jni_Native_deleteWarpDevice: "(Ljava/lang/String;)I",
+        Java_codes_dreaming_wireguard_jni_Native_warpAccountType: "()I",
+        Java_codes_dreaming_wireguard_jni_Native_tunnelAddresses: "()[Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_tunnelPublicKey: "()Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_exportConfig: "(Z)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_warpDeviceId: "()Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_shutdownTunnel: "()V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpConnect: "(Ljava/lang/String;IJ)J",
+        Java_codes_dreaming_wireguard_jni_Native_tcpConnectWithPolicy: "(Ljava/lang/String;IJI)J",
+        Java_codes_dreaming_wireguard_jni_Native_tcpConnectSrv: "(Ljava/lang/String;IJ)J",
+        Java_codes_dreaming_wireguard_jni_Native_tlsConnect:
+            "(Ljava/lang/String;ILjava/lang/String;[Ljava/lang/String;)J",
+        Java_codes_dreaming_wireguard_jni_Native_tlsAlpnProtocol: "(J)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_tcpRead: "(J[B)I",
+        Java_codes_dreaming_wireguard_jni_Native_tcpWrite: "(J[BII)I",
+        Java_codes_dreaming_wireguard_jni_Native_tcpWritev: "(J[Ljava/nio/ByteBuffer;)J",
+        Java_codes_dreaming_wireguard_jni_Native_tcpReadAsync: "(J[BLcodes/dreaming/wireguard/jni/IoCallback;)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpWriteAsync: "(J[BIILcodes/dreaming/wireguard/jni/IoCallback;)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpClose: "(J)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpReap: "(J)Z",
+        Java_codes_dreaming_wireguard_jni_Native_setCloseLinger: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpLocalAddress: "(J)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_tcpRemoteAddress: "(J)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_tcpShutdownOutput: "(J)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpSetKeepAlive: "(JII)Z",
+        Java_codes_dreaming_wireguard_jni_Native_tcpSetTrace: "(JZ)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpLastError: "(J)I",
+        Java_codes_dreaming_wireguard_jni_Native_tcpLastErrorInfo: "(J)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_registerConnectionListener:
+            "(Lcodes/dreaming/wireguard/jni/ConnectionListener;)V",
+        Java_codes_dreaming_wireguard_jni_Native_registerTunnelListener:
+            "(Lcodes/dreaming/wireguard/jni/TunnelListener;)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpIsConnected: "(J)Z",
+        Java_codes_dreaming_wireguard_jni_Native_tcpIsClosed: "(J)Z",
+        Java_codes_dreaming_wireguard_jni_Native_tcpSocketState: "(J)I",
+        Java_codes_dreaming_wireguard_jni_Native_tcpFlush: "(J)I",
+        Java_codes_dreaming_wireguard_jni_Native_setConnectionPoolEnabled: "(Z)V",
+        Java_codes_dreaming_wireguard_jni_Native_prewarmConnection: "(Ljava/lang/String;I)V",
+        Java_codes_dreaming_wireguard_jni_Native_setRateLimit: "(JJ)V",
+        Java_codes_dreaming_wireguard_jni_Native_setGlobalRateLimit: "(J)V",
+        Java_codes_dreaming_wireguard_jni_Native_setWriteCoalescing: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpSetNoDelay: "(JZ)V",
+        Java_codes_dreaming_wireguard_jni_Native_setReadAhead: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_setWriteBehind: "(I)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpSetNonBlocking: "(JZ)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpSetPartialWrites: "(JZ)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpAwaitWritable: "(JJ)Z",
+        Java_codes_dreaming_wireguard_jni_Native_tcpReadyOps: "(J)I",
+        Java_codes_dreaming_wireguard_jni_Native_tcpSelect: "([J[I[IJ)I",
+        Java_codes_dreaming_wireguard_jni_Native_tcpAttachRings: "(JLjava/nio/ByteBuffer;Ljava/nio/ByteBuffer;)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpRingNotify: "(J)V",
+        Java_codes_dreaming_wireguard_jni_Native_tcpRingWait: "(JIJ)I",
+        Java_codes_dreaming_wireguard_jni_Native_wakeupSelect: "()V",
+        Java_codes_dreaming_wireguard_jni_Native_metricsSnapshot: "(I)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_trafficByDestination: "()Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_throughputHistory: "(I)[[J",
+        Java_codes_dreaming_wireguard_jni_Native_listConnections: "()[J",
+        Java_codes_dreaming_wireguard_jni_Native_connectionInfo: "(J)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_tcpStats: "(J)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_tcpConnectTrace: "(J)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_startPacketCapture: "(Ljava/lang/String;J)V",
+        Java_codes_dreaming_wireguard_jni_Native_stopPacketCapture: "()Z",
+        Java_codes_dreaming_wireguard_jni_Native_addBypassRoute: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_addTunnelRoute: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_addDomainRule: "(Ljava/lang/String;I)V",
+        Java_codes_dreaming_wireguard_jni_Native_addProxyProtocolRule: "(Ljava/lang/String;Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_clearRoutes: "()V",
+        Java_codes_dreaming_wireguard_jni_Native_setConnectionPolicy: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_resolve: "(Ljava/lang/String;I)[Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_resolveSrv: "(Ljava/lang/String;)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_flushDnsCache: "()I",
+        Java_codes_dreaming_wireguard_jni_Native_httpRequest:
+            "(Ljava/lang/String;Ljava/lang/String;[Ljava/lang/String;[BJ)Lcodes/dreaming/wireguard/jni/HttpResponse;",
+        Java_codes_dreaming_wireguard_jni_Native_wsConnect:
+            "(Ljava/lang/String;[Ljava/lang/String;[Ljava/lang/String;J)J",
+        Java_codes_dreaming_wireguard_jni_Native_wsProtocol: "(J)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_wsSendText: "(JLjava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_wsSendBinary: "(J[B)V",
+        Java_codes_dreaming_wireguard_jni_Native_wsReceive: "(J)Lcodes/dreaming/wireguard/jni/WebSocketMessage;",
+        Java_codes_dreaming_wireguard_jni_Native_wsClose: "(JILjava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_serverStatus: "(Ljava/lang/String;IJ)Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_udpForwardStart: "(Ljava/lang/String;Ljava/lang/String;I)J",
+        Java_codes_dreaming_wireguard_jni_Native_udpForwardStop: "(J)V",
+        Java_codes_dreaming_wireguard_jni_Native_warpTrace: "()Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_exportDiagnostics: "(Ljava/lang/String;)V",
+        Java_codes_dreaming_wireguard_jni_Native_icmpPing: "(Ljava/lang/String;J)J",
+        Java_codes_dreaming_wireguard_jni_Native_detectNat: "()Ljava/lang/String;",
+        Java_codes_dreaming_wireguard_jni_Native_runSelfTest: "(Ljava/lang/String;IJ)Ljava/lang/String;",
+    ]
+}
+
+/// Compare `Native.ABI_VERSION` with ours.
+fn check_abi(env: &mut JNIEnv, class: &JClass) -> Result<(), String> {
+    // Jars from before the check declare no version; their ABI is version 1
+    let version = match env.get_static_field(class, "ABI_VERSION", "I").and_then(|v| v.i()) {
+        Ok(version) => version,
+        Err(_) => {
+            let _ = env.exception_clear();
+            1
+        }
+    };
+    if version != ABI_VERSION {
+        return Err(format!(
+            "Native ABI mismatch: Native.ABI_VERSION is {} but the native library implements version {}; \
+             the mod jar and the native library must come from the same release",
+            version, ABI_VERSION
+        ));
+    }
+    Ok(())
+}
+
+/// Check the methods native code calls into Java, returning the ones that do not match.
+fn check_callbacks(env: &mut JNIEnv) -> Vec<String> {
+    let mut mismatched = Vec::new();
+    for (class_name, method, sig) in CALLBACKS {
+        let class = match env.find_class(class_name) {
+            Ok(class) => class,
+            Err(_) => {
+                let _ = env.exception_clear();
+                log::debug!("{} not found, not checking it", class_name);
+                continue;
+            }
+        };
+        if env.get_method_id(&class, method, sig).is_err() {
+            let _ = env.exception_clear();
+            mismatched.push(format!("{}.{}{}", class_name, method, sig));
+        }
+        let _ = env.delete_local_ref(class);
+    }
+    mismatched
+}
+
+/// Names of the native methods `class` declares.
+fn declared_natives(env: &mut JNIEnv, class: &JClass) -> jni::errors::Result<HashSet<String>> {
+    let methods = env
+        .call_method(class, "getDeclaredMethods", "()[Ljava/lang/reflect/Method;", &[])?
+        .l()?;
+    let methods = JObjectArray::from(methods);
+    let mut names = HashSet::new();
+    for i in 0..env.get_array_length(&methods)? {
+        let method = env.get_object_array_element(&methods, i)?;
+        if env.call_method(&method, "getModifiers", "()I", &[])?.i()? & MODIFIER_NATIVE != 0 {
+            let name = JString::from(env.call_method(&method, "getName", "()Ljava/lang/String;", &[])?.l()?);
+            names.insert(env.get_string(&name)?.into());
+            env.delete_local_ref(name)?;
+        }
+        env.delete_local_ref(method)?;
+    }
+    Ok(names)
+}
+
+/// Register every native method the jar declares, returning the ones that
+/// could not be registered because their signatures differ.
+fn register(env: &mut JNIEnv, class: &JClass) -> Result<Vec<String>, String> {
+    let mut declared = declared_natives(env, class).map_err(|e| {
+        let _ = env.exception_clear();
+        format!("Failed to list the native methods of {}: {}", NATIVE_CLASS, e)
+    })?;
+
+    let mut mismatched = Vec::new();
+    for (function, sig, fn_ptr) in natives() {
+        let name = function.strip_prefix(FUNCTION_PREFIX).unwrap_or(function);
+        if !declared.remove(name) {
+            log::debug!("Native.{} is not declared by this jar, not registering it", name);
+            continue;
+        }
+        let method = NativeMethod {
+            name: name.into(),
+            sig: sig.into(),
+            fn_ptr,
+        };
+        if env.register_native_methods(class, &[method]).is_err() {
+            let _ = env.exception_clear();
+            mismatched.push(format!("Native.{}{}", name, sig));
+        }
+    }
+    // A newer jar may declare methods this library predates; calling them
+    // throws UnsatisfiedLinkError, which `Native.hasFeature` guards against
+    for name in declared {
+        log::warn!("Native.{} is not implemented by this native library", name);
+    }
+    Ok(mismatched)
+}
+
+fn load(env: &mut JNIEnv) -> Result<(), String> {
+    let class = env.find_class(NATIVE_CLASS).map_err(|e| format!("{} not found: {}", NATIVE_CLASS, e))?;
+    check_abi(env, &class)?;
+    let mut mismatched = check_callbacks(env);
+    mismatched.extend(register(env, &class)?);
+    if !mismatched.is_empty() {
+        return Err(format!(
+            "Java declarations do not match the native library, which expects {}",
+            mismatched.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Called by the JVM when the library is loaded.
+///
+/// On a mismatch, throws an UnsatisfiedLinkError describing it, which
+/// `System.load` passes on to its caller.
+#[no_mangle]
+pub extern "system" fn JNI_OnLoad(vm: *mut jni::sys::JavaVM, _reserved: *mut c_void) -> jint {
+    logging::init();
+    // SAFETY: the JVM passes its own, valid, JavaVM pointer
+    let Ok(vm) = (unsafe { JavaVM::from_raw(vm) }) else {
+        return JNI_ERR;
+    };
+    let Ok(mut env) = vm.get_env() else {
+        return JNI_ERR;
+    };
+    panic_guard::catch(&mut env, JNI_ERR, |env| match load(env) {
+        Ok(()) => JNI_VERSION_1_8,
+        Err(message) => {
+            log::error!("{}", message);
+            let _ = env.exception_clear();
+            let _ = env.throw_new("java/lang/UnsatisfiedLinkError", message);
+            JNI_ERR
+        }
+    })
+}
@@ -0,0 +1,147 @@
+//! Panic containment for JNI entry points.
+//!
+//! Unwinding across the JNI boundary is undefined behaviour and usually
+//! takes the whole game down. Every entry point runs its body through
+//! [`catch`], which turns a panic into a `NativePanicException` carrying the
+//! panic message and a Rust backtrace. The C API uses [`run`] to report
+//! panics as an error code.
+
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+use jni::JNIEnv;
+
+const PANIC_EXCEPTION_CLASS: &str = "codes/dreaming/wireguard/jni/NativePanicException";
+
+thread_local! {
+    /// Report of the last panic on this thread, left by the hook for `catch`.
+    static LAST_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Install the panic hook that records a report for `catch`.
+///
+/// Replaces the default hook, which would only print to stderr; the report is
+/// logged instead so it reaches the game log once a Java logger is set.
+fn install_hook() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        panic::set_hook(Box::new(|info| {
+            let report = format!("{}\n\nRust backtrace:\n{}", info, Backtrace::force_capture());
+            log::error!("Native panic: {}", report);
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some(report));
+        }));
+    });
+}
+
+/// Run a JNI entry point body, converting a panic into a Java exception.
+///
+/// @param env The JNI environment, passed through to `f`
+/// @param default Value returned to Java if `f` panics
+/// @param f The entry point body
+pub fn catch<'local, T>(
+    env: &mut JNIEnv<'local>,
+    default: T,
+    f: impl FnOnce(&mut JNIEnv<'local>) -> T,
+) -> T {
+    match run(|| f(env)) {
+        Ok(value) => value,
+        Err(report) => {
+            // A panic may leave an exception pending; the panic takes precedence
+            let _ = env.exception_clear();
+            let _ = env.throw_new(PANIC_EXCEPTION_CLASS, report);
+            default
+        }
+    }
+}
+
+/// Run `f`, returning the panic report instead if it panics.
+pub fn run<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    install_hook();
+    // A report left by a panic caught elsewhere, e.g. in a Tokio task, is not ours
+    LAST_PANIC.with(|last| last.borrow_mut().take());
+
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        LAST_PANIC
+            .with(|last| last.borrow_mut().take())
+            .unwrap_or_else(|| payload_message(payload.as_ref()).to_string())
+    })
+}
+
+fn payload_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Native code panicked"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use jni::objects::JString;
+    use jni::{InitArgsBuilder, JavaVM};
+
+    use super::*;
+
+    #[test]
+    fn run_reports_the_panic_once() {
+        let report = run(|| -> i32 { panic!("induced panic") }).unwrap_err();
+        assert!(report.contains("induced panic"));
+        assert!(report.contains("Rust backtrace"));
+        assert!(LAST_PANIC.with(|last| last.borrow().is_none()));
+        assert_eq!(run(|| 7), Ok(7));
+    }
+
+    #[test]
+    fn stale_report_is_not_reused() {
+        LAST_PANIC.with(|last| *last.borrow_mut() = Some("stale".into()));
+        let report = run(|| -> i32 { panic!("fresh panic") }).unwrap_err();
+        assert!(report.contains("fresh panic"));
+    }
+
+    /// Compile the exception class, so the JVM started here can load it.
+    fn exception_classes() -> PathBuf {
+        let out = std::env::temp_dir().join(format!("wgt-panic-test-{}", std::process::id()));
+        let source = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../src/main/java/codes/dreaming/wireguard/jni/NativePanicException.java"
+        );
+        let javac = std::env::var_os("JAVA_HOME")
+            .map(|home| PathBuf::from(home).join("bin/javac"))
+            .unwrap_or_else(|| "javac".into());
+        let status = Command::new(javac).arg("-d").arg(&out).arg(source).status().expect("javac");
+        assert!(status.success());
+        out
+    }
+
+    #[test]
+    fn catch_throws_native_panic_exception() {
+        let classes = exception_classes();
+        let args = InitArgsBuilder::new()
+            .option(format!("-Djava.class.path={}", classes.display()))
+            .build()
+            .unwrap();
+        let vm = JavaVM::new(args).unwrap();
+        let mut env = vm.attach_current_thread().unwrap();
+
+        let value = catch(&mut env, -1, |_| -> i32 { panic!("induced panic") });
+        assert_eq!(value, -1);
+        let thrown = env.exception_occurred().unwrap();
+        env.exception_clear().unwrap();
+        assert!(env.is_instance_of(&thrown, PANIC_EXCEPTION_CLASS).unwrap());
+        let message = env.call_method(&thrown, "getMessage", "()Ljava/lang/String;", &[]).unwrap().l().unwrap();
+        let message: String = env.get_string(&JString::from(message)).unwrap().into();
+        assert!(message.contains("induced panic"));
+        assert!(message.contains("Rust backtrace"));
+
+        assert_eq!(catch(&mut env, -1, |_| 7), 7);
+        assert!(!env.exception_check().unwrap());
+        let _ = std::fs::remove_dir_all(classes);
+    }
+}
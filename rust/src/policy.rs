@@ -0,0 +1,137 @@
+//! Allow/deny rules on the destinations of new connections.
+//!
+//! A policy is a list of rules checked in order, plus a default action for
+//! destinations no rule matches. A rule matches when every criterion it
+//! sets matches: the destination IP is in one of its CIDRs, the port in
+//! one of its ranges, and the hostname the caller connected to matches one
+//! of its domain patterns. IP literals never match domain patterns.
+//!
+//! ```json
+//! {
+//!   "default": "deny",
+//!   "rules": [
+//!     { "action": "deny", "cidrs": ["10.0.0.0/8"] },
+//!     { "action": "allow", "ports": ["25565", "25500-25600"] },
+//!     { "action": "allow", "domains": ["*.example.com"], "ports": ["443"] }
+//!   ]
+//! }
+//! ```
+
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::routing::{normalize_host, parse_cidr, DomainPattern};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicySpec {
+    #[serde(default)]
+    default: Action,
+    #[serde(default)]
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    action: Action,
+    #[serde(default)]
+    cidrs: Vec<String>,
+    #[serde(default)]
+    ports: Vec<String>,
+    #[serde(default)]
+    domains: Vec<String>,
+}
+
+struct Rule {
+    action: Action,
+    cidrs: Vec<IpNet>,
+    ports: Vec<RangeInclusive<u16>>,
+    domains: Vec<DomainPattern>,
+}
+
+impl Rule {
+    fn parse(spec: RuleSpec) -> Result<Self, String> {
+        let cidrs = spec
+            .cidrs
+            .iter()
+            .map(|cidr| parse_cidr(cidr).ok_or_else(|| format!("Invalid CIDR: {}", cidr)))
+            .collect::<Result<_, _>>()?;
+        let ports = spec.ports.iter().map(|ports| parse_ports(ports)).collect::<Result<_, _>>()?;
+        let domains = spec
+            .domains
+            .iter()
+            .map(|domain| DomainPattern::parse(domain).ok_or_else(|| format!("Invalid domain pattern: {}", domain)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            action: spec.action,
+            cidrs,
+            ports,
+            domains,
+        })
+    }
+
+    fn matches(&self, host: Option<&str>, addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        (self.cidrs.is_empty() || self.cidrs.iter().any(|net| net.contains(&ip)))
+            && (self.ports.is_empty() || self.ports.iter().any(|range| range.contains(&addr.port())))
+            && (self.domains.is_empty()
+                || host.is_some_and(|host| self.domains.iter().any(|pattern| pattern.matches(host).is_some())))
+    }
+}
+
+/// Parse "port" or "first-last".
+fn parse_ports(spec: &str) -> Result<RangeInclusive<u16>, String> {
+    let invalid = || format!("Invalid port or port range: {}", spec);
+    let (first, last) = spec.split_once('-').unwrap_or((spec, spec));
+    let first: u16 = first.trim().parse().map_err(|_| invalid())?;
+    let last: u16 = last.trim().parse().map_err(|_| invalid())?;
+    if first > last {
+        return Err(invalid());
+    }
+    Ok(first..=last)
+}
+
+#[derive(Default)]
+pub struct Policy {
+    default: Action,
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Parse a policy from its JSON form.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let spec: PolicySpec = serde_json::from_str(json).map_err(|e| format!("Invalid connection policy: {}", e))?;
+        let rules = spec.rules.into_iter().map(Rule::parse).collect::<Result<_, _>>()?;
+        Ok(Self {
+            default: spec.default,
+            rules,
+        })
+    }
+
+    /// Decide whether a connection to `host`, resolved to `addr`, may be opened.
+    pub fn allows(&self, host: &str, addr: SocketAddr) -> bool {
+        let host = normalize_host(host);
+        let host = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(_) => None,
+            Err(_) => Some(host.as_str()),
+        };
+        let action = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(host, addr))
+            .map_or(self.default, |rule| rule.action);
+        action == Action::Allow
+    }
+}
@@ -0,0 +1,70 @@
+//! Pre-connected spare connections, keyed by resolved destination address.
+//!
+//! A TCP stream cannot be handed to a second protocol session, so the pool
+//! only holds connections that were opened ahead of time and never given out.
+//! Spares are dropped once they have been idle long enough that the server
+//! may have timed them out.
+//!
+//! Connections close their netstack socket on drop, which spawns onto the
+//! runtime, so anything removed from the pool must be dropped there.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::connection::Connection;
+
+/// How long a spare may sit unused. Minecraft servers drop connections that
+/// stay silent for 30 seconds.
+pub const MAX_IDLE: Duration = Duration::from_secs(20);
+
+#[derive(Default)]
+pub struct ConnectionPool {
+    enabled: AtomicBool,
+    idle: Mutex<HashMap<SocketAddr, (Connection, Instant)>>,
+    /// Background task keeping a spare ready for one destination.
+    warm_task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ConnectionPool {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Take a spare for `addr` if one is fresh and still open.
+    pub fn take(&self, addr: SocketAddr) -> Option<Connection> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let (conn, since) = self.idle.lock().remove(&addr)?;
+        (since.elapsed() < MAX_IDLE && conn.is_open()).then_some(conn)
+    }
+
+    /// Offer a spare for `addr`, replacing any previous one.
+    pub fn put(&self, addr: SocketAddr, conn: Connection) {
+        if self.is_enabled() {
+            self.idle.lock().insert(addr, (conn, Instant::now()));
+        }
+    }
+
+    /// Replace the pre-warming task, stopping the previous one.
+    pub fn set_warm_task(&self, task: Option<JoinHandle<()>>) {
+        if let Some(old) = std::mem::replace(&mut *self.warm_task.lock(), task) {
+            old.abort();
+        }
+    }
+
+    /// Stop pre-warming and remove all spares, returning them to be dropped.
+    pub fn clear(&self) -> Vec<Connection> {
+        self.set_warm_task(None);
+        self.idle.lock().drain().map(|(_, (conn, _))| conn).collect()
+    }
+}
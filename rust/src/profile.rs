@@ -0,0 +1,582 @@
+//! WireGuard profiles in wg-quick `.conf` format.
+//!
+//! Accepts what providers such as Mullvad and Proton export and what wgcf
+//! generates: one `[Interface]` and one `[Peer]` section. Every bad or
+//! missing field is reported, not just the first, so a config screen can
+//! point at all of them. Keys only wg-quick itself acts on (`PostUp`,
+//! `Table`, ...) are accepted and ignored, with a warning.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use base64::Engine;
+use ipnet::IpNet;
+use serde::Serialize;
+use wireguard_netstack::WireGuardConfig;
+
+use crate::endpoint::EndpointOverride;
+use crate::routing::parse_cidr;
+
+/// Keys handled by wg-quick rather than WireGuard, and so ignored here.
+const WG_QUICK_KEYS: [&str; 8] = [
+    "listenport", "fwmark", "table", "preup", "postup", "predown", "postdown", "saveconfig",
+];
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The profile cannot be used.
+    Error,
+    /// The profile works, but not entirely as written.
+    Warning,
+}
+
+/// One problem with a profile field.
+#[derive(Serialize, Clone, Debug)]
+pub struct ConfigError {
+    pub severity: Severity,
+    /// 1-based line of the field, or 0 if it is missing.
+    pub line: usize,
+    pub section: String,
+    pub field: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.line > 0 {
+            write!(f, "line {}: ", self.line)?;
+        }
+        match (self.section.is_empty(), self.field.is_empty()) {
+            (false, false) => write!(f, "[{}] {}: {}", self.section, self.field, self.message),
+            (false, true) => write!(f, "[{}] {}", self.section, self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+pub struct Profile {
+    pub private_key: [u8; 32],
+    /// Interface addresses; the first IPv4 one is used.
+    pub addresses: Vec<IpNet>,
+    pub mtu: Option<u16>,
+    /// IPv4 DNS servers, queried over TCP through the tunnel.
+    pub dns: Vec<Ipv4Addr>,
+    pub peer_public_key: [u8; 32],
+    pub preshared_key: Option<[u8; 32]>,
+    /// Endpoint host and port; hostnames are resolved when the tunnel starts.
+    pub endpoint: EndpointOverride,
+    pub keepalive_seconds: Option<u16>,
+}
+
+impl Profile {
+    /// Our IPv4 address inside the tunnel; parsing guarantees there is one.
+    pub fn tunnel_ip(&self) -> Ipv4Addr {
+        self.addresses
+            .iter()
+            .find_map(|net| match net.addr() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .unwrap_or(Ipv4Addr::UNSPECIFIED)
+    }
+
+    /// The tunnel config, with the endpoint resolved to `endpoint`.
+    pub fn config(&self, endpoint: SocketAddr) -> WireGuardConfig {
+        WireGuardConfig {
+            private_key: self.private_key,
+            peer_public_key: self.peer_public_key,
+            peer_endpoint: endpoint,
+            tunnel_ip: self.tunnel_ip(),
+            preshared_key: self.preshared_key,
+            keepalive_seconds: self.keepalive_seconds,
+            mtu: self.mtu,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    None,
+    Interface,
+    Peer,
+}
+
+impl Section {
+    fn name(self) -> &'static str {
+        match self {
+            Section::None => "",
+            Section::Interface => "Interface",
+            Section::Peer => "Peer",
+        }
+    }
+}
+
+struct Parser {
+    errors: Vec<ConfigError>,
+}
+
+impl Parser {
+    fn error(&mut self, line: usize, section: Section, field: &str, message: impl Into<String>) {
+        self.report(Severity::Error, line, section, field, message);
+    }
+
+    fn warning(&mut self, line: usize, section: Section, field: &str, message: impl Into<String>) {
+        self.report(Severity::Warning, line, section, field, message);
+    }
+
+    fn report(&mut self, severity: Severity, line: usize, section: Section, field: &str, message: impl Into<String>) {
+        self.errors.push(ConfigError {
+            severity,
+            line,
+            section: section.name().to_string(),
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn key(&mut self, line: usize, section: Section, field: &str, value: &str) -> Option<[u8; 32]> {
+        let key = decode_key(value);
+        if key.is_none() {
+            self.error(line, section, field, "Expected a base64-encoded 32-byte key");
+        }
+        key
+    }
+
+    fn cidrs(&mut self, line: usize, section: Section, field: &str, value: &str) -> Vec<IpNet> {
+        let mut nets = Vec::new();
+        for item in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match parse_cidr(item) {
+                Some(net) => nets.push(net),
+                None => self.error(line, section, field, format!("Invalid address or CIDR: {}", item)),
+            }
+        }
+        nets
+    }
+
+    fn number(&mut self, line: usize, section: Section, field: &str, value: &str) -> Option<u16> {
+        let number = value.parse().ok();
+        if number.is_none() {
+            self.error(line, section, field, format!("Expected a number from 0 to 65535: {}", value));
+        }
+        number
+    }
+
+    /// Report `field` as missing from `section` if it never appeared.
+    fn require<T>(&mut self, value: Option<T>, seen: bool, section: Section, field: &str) -> Option<T> {
+        if value.is_none() && !seen {
+            self.error(0, section, field, format!("{} is required", field));
+        }
+        value
+    }
+}
+
+/// Decode a base64 WireGuard key, as `wg genkey` and `wg genpsk` print them.
+pub fn decode_key(value: &str) -> Option<[u8; 32]> {
+    base64::engine::general_purpose::STANDARD
+        .decode(value.trim())
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+}
+
+pub fn encode_key(key: &[u8; 32]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key)
+}
+
+/// Write a tunnel config as a wg-quick profile routing all traffic through `endpoint`.
+///
+/// `addresses` are plain IPs and get host prefixes. Without the private key
+/// the profile is for reference only, as wg-quick will not load it.
+pub fn export(
+    config: &WireGuardConfig,
+    endpoint: SocketAddr,
+    addresses: &[String],
+    include_private_key: bool,
+) -> String {
+    let mut out = String::from("[Interface]\n");
+    if include_private_key {
+        out += &format!("PrivateKey = {}\n", encode_key(&config.private_key));
+    } else {
+        out += "# PrivateKey omitted\n";
+    }
+    let addresses: Vec<String> = addresses
+        .iter()
+        .map(|addr| match addr.parse::<IpAddr>() {
+            Ok(IpAddr::V4(_)) => format!("{}/32", addr),
+            Ok(IpAddr::V6(_)) => format!("{}/128", addr),
+            Err(_) => addr.clone(),
+        })
+        .collect();
+    out += &format!("Address = {}\n", addresses.join(", "));
+    if let Some(mtu) = config.mtu {
+        out += &format!("MTU = {}\n", mtu);
+    }
+
+    out += "\n[Peer]\n";
+    out += &format!("PublicKey = {}\n", encode_key(&config.peer_public_key));
+    if let Some(psk) = config.preshared_key {
+        if include_private_key {
+            out += &format!("PresharedKey = {}\n", encode_key(&psk));
+        } else {
+            out += "# PresharedKey omitted\n";
+        }
+    }
+    out += "AllowedIPs = 0.0.0.0/0, ::/0\n";
+    out += &format!("Endpoint = {}\n", endpoint);
+    if let Some(keepalive) = config.keepalive_seconds {
+        out += &format!("PersistentKeepalive = {}\n", keepalive);
+    }
+    out
+}
+
+/// Parse a wg-quick profile, returning every error found if it is invalid.
+pub fn parse(text: &str) -> Result<Profile, Vec<ConfigError>> {
+    match check(text) {
+        (Some(profile), _) => Ok(profile),
+        (None, mut errors) => {
+            errors.retain(|e| e.severity == Severity::Error);
+            Err(errors)
+        }
+    }
+}
+
+/// Check a wg-quick profile without using it, returning its errors and warnings.
+pub fn validate(text: &str) -> Vec<ConfigError> {
+    check(text).1
+}
+
+fn check(text: &str) -> (Option<Profile>, Vec<ConfigError>) {
+    let mut parser = Parser { errors: Vec::new() };
+    let mut section = Section::None;
+    let (mut interfaces, mut peers) = (0, 0);
+
+    let mut private_key = None;
+    let mut addresses = Vec::new();
+    let mut mtu = None;
+    let mut dns = Vec::new();
+    let mut peer_public_key = None;
+    let mut preshared_key = None;
+    let mut endpoint = None;
+    let mut keepalive_seconds = None;
+    let mut allowed_ips: Vec<(usize, IpNet)> = Vec::new();
+    // Fields present, valid or not, so that invalid ones are not also reported as missing
+    let mut seen = HashSet::new();
+
+    for (index, raw) in text.lines().enumerate() {
+        let line = index + 1;
+        let content = raw.split('#').next().unwrap_or_default().trim();
+        if content.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = content.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            section = match name.trim().to_ascii_lowercase().as_str() {
+                "interface" => {
+                    interfaces += 1;
+                    Section::Interface
+                }
+                "peer" => {
+                    peers += 1;
+                    Section::Peer
+                }
+                _ => {
+                    parser.error(line, Section::None, name, format!("Unknown section [{}]", name));
+                    Section::None
+                }
+            };
+            if interfaces > 1 && section == Section::Interface {
+                parser.error(line, section, "", "Only one [Interface] section is allowed");
+            }
+            if peers > 1 && section == Section::Peer {
+                parser.error(line, section, "", "Only one [Peer] section is supported");
+            }
+            continue;
+        }
+
+        let Some((field, value)) = content.split_once('=') else {
+            parser.error(line, section, content, "Expected Key = Value");
+            continue;
+        };
+        let (field, value) = (field.trim(), value.trim());
+        let key = field.to_ascii_lowercase();
+
+        match (section, key.as_str()) {
+            (Section::None, _) => {
+                parser.error(line, section, field, "Field outside of [Interface] or [Peer]");
+                continue;
+            }
+            (Section::Interface, "privatekey") => private_key = parser.key(line, section, field, value),
+            (Section::Interface, "address") => addresses.extend(parser.cidrs(line, section, field, value)),
+            (Section::Interface, "dns") => {
+                // Search domains are allowed next to server addresses
+                for entry in value.split(',').map(str::trim) {
+                    let problem = match entry.parse::<IpAddr>() {
+                        Ok(IpAddr::V4(ip)) => {
+                            dns.push(ip);
+                            continue;
+                        }
+                        Ok(IpAddr::V6(_)) => format!("IPv6 server {} is not used", entry),
+                        Err(_) if entry.is_empty() => {
+                            parser.error(line, section, field, "Empty DNS entry");
+                            continue;
+                        }
+                        Err(_) => format!("Search domain {} is not used", entry),
+                    };
+                    parser.warning(line, section, field, problem);
+                }
+            }
+            (Section::Interface, "mtu") => {
+                mtu = parser.number(line, section, field, value);
+                if mtu.is_some_and(|mtu| mtu < 576) {
+                    parser.error(line, section, field, "MTU must be at least 576");
+                    mtu = None;
+                }
+            }
+            (Section::Interface, key) if WG_QUICK_KEYS.contains(&key) => {
+                parser.warning(line, section, field, "Only used by wg-quick; ignored");
+            }
+            (Section::Peer, "publickey") => peer_public_key = parser.key(line, section, field, value),
+            (Section::Peer, "presharedkey") => preshared_key = parser.key(line, section, field, value),
+            (Section::Peer, "allowedips") => {
+                let nets = parser.cidrs(line, section, field, value);
+                allowed_ips.extend(nets.into_iter().map(|net| (line, net)));
+            }
+            (Section::Peer, "endpoint") => match EndpointOverride::parse(value) {
+                Ok(parsed) if parsed.port().is_some() => endpoint = Some(parsed),
+                Ok(_) => parser.error(line, section, field, "Endpoint needs a port, as host:port"),
+                Err(e) => parser.error(line, section, field, e),
+            },
+            (Section::Peer, "persistentkeepalive") => {
+                keepalive_seconds = match value {
+                    "off" => None,
+                    value => parser.number(line, section, field, value).filter(|&s| s > 0),
+                };
+            }
+            _ => {
+                parser.error(line, section, field, format!("Unknown field in [{}]", section.name()));
+                continue;
+            }
+        }
+        if !seen.insert(key) {
+            parser.warning(line, section, field, "Set more than once; the last value is used");
+        }
+    }
+
+    if interfaces == 0 {
+        parser.error(0, Section::Interface, "", "[Interface] section is missing");
+    }
+    if peers == 0 {
+        parser.error(0, Section::Peer, "", "[Peer] section is missing");
+    }
+    let private_key = parser.require(private_key, seen.contains("privatekey"), Section::Interface, "PrivateKey");
+    let peer_public_key = parser.require(peer_public_key, seen.contains("publickey"), Section::Peer, "PublicKey");
+    let endpoint = parser.require(endpoint, seen.contains("endpoint"), Section::Peer, "Endpoint");
+    if !addresses.iter().any(|net| net.addr().is_ipv4()) {
+        // The netstack only speaks IPv4
+        let message = if seen.contains("address") { "An IPv4 address is required" } else { "Address is required" };
+        parser.error(0, Section::Interface, "Address", message);
+    } else if addresses.iter().any(|net| net.addr().is_ipv6()) {
+        parser.warning(0, Section::Interface, "Address", "IPv6 addresses are not used; the tunnel is IPv4 only");
+    }
+    for (i, &(line, net)) in allowed_ips.iter().enumerate() {
+        let overlap = allowed_ips[..i]
+            .iter()
+            .find(|(_, earlier)| earlier.contains(&net) || net.contains(earlier));
+        if let Some((_, earlier)) = overlap {
+            parser.warning(line, Section::Peer, "AllowedIPs", format!("{} overlaps {}", net, earlier));
+        }
+    }
+    if !allowed_ips.is_empty() && !allowed_ips.iter().any(|(_, net)| net.prefix_len() == 0 && net.addr().is_ipv4()) {
+        // The netstack sends everything to the single peer
+        parser.warning(
+            0,
+            Section::Peer,
+            "AllowedIPs",
+            "All traffic goes through the tunnel, not just AllowedIPs",
+        );
+    }
+
+    let failed = parser.errors.iter().any(|e| e.severity == Severity::Error);
+    let profile = match (private_key, peer_public_key, endpoint) {
+        (Some(private_key), Some(peer_public_key), Some(endpoint)) if !failed => Some(Profile {
+            private_key,
+            addresses,
+            mtu,
+            dns,
+            peer_public_key,
+            preshared_key,
+            endpoint,
+            keepalive_seconds,
+        }),
+        _ => None,
+    };
+    (profile, parser.errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use blake2::digest::consts::U16;
+    use blake2::digest::{Digest, Mac};
+    use blake2::{Blake2s256, Blake2sMac};
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::capture::PacketCapture;
+    use crate::transport::OuterConfig;
+    use crate::tunnel::Tunnel;
+
+    const PRIVATE_KEY: [u8; 32] = [1; 32];
+    const PUBLIC_KEY: [u8; 32] = [2; 32];
+    const PSK: [u8; 32] = [3; 32];
+
+    fn profile_text(preshared_key: Option<&[u8; 32]>) -> String {
+        let mut text = format!(
+            "[Interface]\nPrivateKey = {}\nAddress = 172.16.0.2/32\n\n[Peer]\nPublicKey = {}\n",
+            encode_key(&PRIVATE_KEY),
+            encode_key(&PUBLIC_KEY)
+        );
+        if let Some(psk) = preshared_key {
+            text += &format!("PresharedKey = {}\n", encode_key(psk));
+        }
+        text + "AllowedIPs = 0.0.0.0/0\nEndpoint = 203.0.113.7:51820\n"
+    }
+
+    fn endpoint() -> SocketAddr {
+        "203.0.113.7:51820".parse().unwrap()
+    }
+
+    #[test]
+    fn preshared_key_reaches_the_tunnel_config() {
+        let profile = parse(&profile_text(Some(&PSK))).unwrap();
+        assert_eq!(profile.preshared_key, Some(PSK));
+        let config = profile.config(endpoint());
+        assert_eq!(config.preshared_key, Some(PSK));
+        assert_eq!(config.peer_public_key, PUBLIC_KEY);
+
+        let without = parse(&profile_text(None)).unwrap();
+        assert_eq!(without.config(endpoint()).preshared_key, None);
+    }
+
+    #[test]
+    fn preshared_key_survives_export_and_parse() {
+        let config = parse(&profile_text(Some(&PSK))).unwrap().config(endpoint());
+        let exported = export(&config, endpoint(), &["172.16.0.2".into()], true);
+        assert!(exported.contains(&format!("PresharedKey = {}", encode_key(&PSK))));
+
+        let reparsed = parse(&exported).unwrap();
+        assert_eq!(reparsed.preshared_key, Some(PSK));
+        assert_eq!(reparsed.private_key, PRIVATE_KEY);
+        assert_eq!(reparsed.config(endpoint()).preshared_key, Some(PSK));
+    }
+
+    #[test]
+    fn export_without_private_keys_omits_the_preshared_key() {
+        let config = parse(&profile_text(Some(&PSK))).unwrap().config(endpoint());
+        let exported = export(&config, endpoint(), &["172.16.0.2".into()], false);
+        assert!(exported.contains("# PresharedKey omitted"));
+        assert!(!exported.contains(&encode_key(&PSK)));
+    }
+
+    #[test]
+    fn invalid_preshared_key_is_reported() {
+        let text = profile_text(None).replace("AllowedIPs", "PresharedKey = not-a-key\nAllowedIPs");
+        let Err(errors) = parse(&text) else {
+            panic!("invalid PresharedKey accepted");
+        };
+        assert!(errors.iter().any(|e| e.field == "PresharedKey" && e.line == 7));
+    }
+
+    /// WireGuard's handshake is this Noise pattern, with its own prologue.
+    const NOISE: &str = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+    const PROLOGUE: &[u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+
+    /// A WireGuard peer on loopback that answers handshake initiations using `psk`.
+    struct Peer {
+        addr: SocketAddr,
+        public_key: [u8; 32],
+        /// Initiations it answered.
+        answered: Arc<AtomicUsize>,
+    }
+
+    async fn peer(psk: [u8; 32]) -> Peer {
+        let keys = snow::Builder::new(NOISE.parse().unwrap()).generate_keypair().unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let peer = Peer {
+            addr: socket.local_addr().unwrap(),
+            public_key: keys.public.clone().try_into().unwrap(),
+            answered: Arc::default(),
+        };
+        let answered = peer.answered.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                // Type 1, sender index, ephemeral, static and timestamp, then mac1 and mac2
+                if n != 148 || buf[..4] != [1, 0, 0, 0] {
+                    continue;
+                }
+                let mut noise = snow::Builder::new(NOISE.parse().unwrap())
+                    .local_private_key(&keys.private)
+                    .prologue(PROLOGUE)
+                    .psk(2, &psk)
+                    .build_responder()
+                    .unwrap();
+                let mut timestamp = [0u8; 64];
+                if noise.read_message(&buf[8..116], &mut timestamp).is_err() {
+                    continue;
+                }
+                let initiator: [u8; 32] = noise.get_remote_static().unwrap().try_into().unwrap();
+
+                // Type 2, our index, theirs, ephemeral and empty payload, then mac1 and mac2
+                let mut response = vec![2, 0, 0, 0, 1, 0, 0, 0];
+                response.extend_from_slice(&buf[4..8]);
+                let mut message = [0u8; 48];
+                let len = noise.write_message(&[], &mut message).unwrap();
+                response.extend_from_slice(&message[..len]);
+                response.extend_from_slice(&mac1(&initiator, &response));
+                response.extend_from_slice(&[0; 16]);
+                answered.fetch_add(1, Ordering::Relaxed);
+                let _ = socket.send_to(&response, from).await;
+            }
+        });
+        peer
+    }
+
+    /// MAC1 of a handshake message to the holder of `public_key`.
+    fn mac1(public_key: &[u8; 32], message: &[u8]) -> [u8; 16] {
+        let key = Blake2s256::new().chain_update(b"mac1----").chain_update(public_key).finalize();
+        let mut mac = <Blake2sMac<U16> as Mac>::new_from_slice(&key).unwrap();
+        mac.update(message);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Start a tunnel to `peer` from a profile with `preshared_key`.
+    async fn connect(peer: &Peer, preshared_key: Option<&[u8; 32]>) -> Result<Tunnel, crate::TunnelError> {
+        let text = profile_text(preshared_key)
+            .replace(&encode_key(&PUBLIC_KEY), &encode_key(&peer.public_key))
+            .replace("203.0.113.7:51820", &peer.addr.to_string());
+        let config = parse(&text).unwrap().config(peer.addr);
+        let timeout = Duration::from_secs(2);
+        Tunnel::connect(config, &OuterConfig::default(), PacketCapture::default(), Arc::default(), timeout).await
+    }
+
+    #[test]
+    fn handshake_needs_the_matching_preshared_key() {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let peer = peer(PSK).await;
+            let tunnel = connect(&peer, Some(&PSK)).await;
+            assert!(tunnel.is_ok(), "handshake with the matching key failed: {:?}", tunnel.err());
+
+            for preshared_key in [Some(&[4; 32]), None] {
+                let answered = peer.answered.load(Ordering::Relaxed);
+                assert!(connect(&peer, preshared_key).await.is_err(), "handshake with {:?} completed", preshared_key);
+                // It failed on the key, not for want of an answer
+                assert!(peer.answered.load(Ordering::Relaxed) > answered);
+            }
+        });
+    }
+}
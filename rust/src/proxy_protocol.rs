@@ -0,0 +1,136 @@
+//! HAProxy PROXY protocol v2 headers on outgoing connections.
+//!
+//! Servers behind a proxy that speaks the PROXY protocol (Velocity,
+//! BungeeCord, HAProxy) read the client's address from a header sent before
+//! any other data. Rules pick the destinations that get one and the source
+//! address it carries; connections to other destinations are left alone,
+//! since a server not expecting the header would reject the connection.
+
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+
+use crate::routing::{normalize_host, parse_cidr, DomainPattern};
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, PROXY command.
+const VERSION_COMMAND: u8 = 0x21;
+/// TCP over IPv4 and over IPv6.
+const TCP4: u8 = 0x11;
+const TCP6: u8 = 0x21;
+
+/// Build a v2 header for a TCP connection from `src` to `dst`.
+///
+/// Mixed families are sent as IPv6, with the IPv4 side mapped.
+pub fn header(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+    out.push(VERSION_COMMAND);
+    match (src.ip().to_canonical(), dst.ip().to_canonical()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            out.push(TCP4);
+            out.extend_from_slice(&12u16.to_be_bytes());
+            out.extend_from_slice(&s.octets());
+            out.extend_from_slice(&d.octets());
+        }
+        (s, d) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            out.push(TCP6);
+            out.extend_from_slice(&36u16.to_be_bytes());
+            out.extend_from_slice(&v6(s).octets());
+            out.extend_from_slice(&v6(d).octets());
+        }
+    }
+    out.extend_from_slice(&src.port().to_be_bytes());
+    out.extend_from_slice(&dst.port().to_be_bytes());
+    out
+}
+
+/// Which destinations a rule applies to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Target {
+    Domain(DomainPattern),
+    Cidr(IpNet),
+}
+
+impl Target {
+    /// Parse a CIDR, a single address, or a domain pattern as in `addDomainRule`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        parse_cidr(spec)
+            .map(Target::Cidr)
+            .or_else(|| DomainPattern::parse(spec).map(Target::Domain))
+    }
+}
+
+/// The source address a header carries.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Source {
+    /// The connection's own address: the tunnel IP, or the local socket for
+    /// direct connections.
+    Connection,
+    Fixed(SocketAddr),
+}
+
+impl Source {
+    /// Parse "ip", "ip:port" or "[ipv6]:port"; a bare IP gets port 0.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let spec = spec.trim();
+        spec.parse::<SocketAddr>()
+            .or_else(|_| spec.trim_matches(['[', ']']).parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+            .ok()
+            .map(Source::Fixed)
+    }
+}
+
+#[derive(Default)]
+pub struct Rules {
+    rules: Vec<(Target, Source)>,
+}
+
+impl Rules {
+    /// Add a rule, replacing any existing rule for the same target.
+    pub fn add(&mut self, target: Target, source: Source) {
+        match self.rules.iter_mut().find(|(t, _)| *t == target) {
+            Some(rule) => rule.1 = source,
+            None => self.rules.push((target, source)),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    /// The source to advertise for a connection to `host`, resolved to `ip`,
+    /// or `None` if it gets no header.
+    ///
+    /// As with routing, domain rules are checked first and the most specific
+    /// one wins, then the longest matching CIDR.
+    pub fn source_for(&self, host: &str, ip: IpAddr) -> Option<Source> {
+        let host = normalize_host(host);
+        if host.trim_matches(['[', ']']).parse::<IpAddr>().is_err() {
+            let domain = self
+                .rules
+                .iter()
+                .filter_map(|(target, source)| match target {
+                    Target::Domain(pattern) => pattern.matches(&host).map(|score| (score, *source)),
+                    Target::Cidr(_) => None,
+                })
+                .max_by_key(|(score, _)| *score);
+            if let Some((_, source)) = domain {
+                return Some(source);
+            }
+        }
+
+        let ip = ip.to_canonical();
+        self.rules
+            .iter()
+            .filter_map(|(target, source)| match target {
+                Target::Cidr(net) if net.contains(&ip) => Some((net.prefix_len(), *source)),
+                _ => None,
+            })
+            .max_by_key(|(prefix, _)| *prefix)
+            .map(|(_, source)| source)
+    }
+}
@@ -0,0 +1,95 @@
+//! Token-bucket bandwidth limits for connection reads and writes.
+//!
+//! Each direction has its own bucket holding up to one second of traffic.
+//! Transfers are charged after the fact and may drive a bucket negative; the
+//! caller then sleeps until the debt is repaid, so large writes are never
+//! split.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+struct Bucket {
+    /// Bytes per second; 0 means unlimited.
+    rate: AtomicU64,
+    state: Mutex<BucketState>,
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Self {
+            rate: AtomicU64::new(0),
+            state: Mutex::new(BucketState {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    fn set_rate(&self, bytes_per_sec: u64) {
+        let mut state = self.state.lock();
+        self.rate.store(bytes_per_sec, Ordering::Relaxed);
+        // Start full so a new limit does not stall traffic already in flight
+        state.tokens = bytes_per_sec as f64;
+        state.last_refill = Instant::now();
+    }
+
+    /// Charge `bytes`, returning how long to wait before continuing.
+    fn charge(&self, bytes: usize) -> Option<Duration> {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return None;
+        }
+        let rate = rate as f64;
+
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(rate);
+        state.last_refill = now;
+        state.tokens -= bytes as f64;
+
+        (state.tokens < 0.0).then(|| Duration::from_secs_f64(-state.tokens / rate))
+    }
+}
+
+/// Read and write limits sharing one configured rate.
+pub struct RateLimit {
+    read: Bucket,
+    write: Bucket,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            read: Bucket::new(),
+            write: Bucket::new(),
+        }
+    }
+}
+
+impl RateLimit {
+    /// Set the limit for each direction, or 0 to remove it.
+    pub fn set(&self, bytes_per_sec: u64) {
+        self.read.set_rate(bytes_per_sec);
+        self.write.set_rate(bytes_per_sec);
+    }
+
+    pub async fn on_read(&self, bytes: usize) {
+        if let Some(delay) = self.read.charge(bytes) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    pub async fn on_write(&self, bytes: usize) {
+        if let Some(delay) = self.write.charge(bytes) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
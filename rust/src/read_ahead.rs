@@ -0,0 +1,122 @@
+//! Read-ahead buffering for connections.
+//!
+//! A background task keeps reading from the transport into a bounded buffer,
+//! so `tcpRead` is usually served without waiting on the netstack, and the
+//! netstack's receive window reopens as soon as data arrives rather than when
+//! Java gets around to reading it.
+
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::TunnelError;
+
+/// How the transport stopped producing data.
+enum End {
+    Eof,
+    /// Failure message; the error code is already recorded on the connection.
+    Failed(String),
+}
+
+#[derive(Default)]
+struct State {
+    data: VecDeque<u8>,
+    end: Option<End>,
+}
+
+pub struct ReadAhead {
+    capacity: usize,
+    state: Mutex<State>,
+    /// Signalled when data or the end of the stream is pushed.
+    filled: Notify,
+    /// Signalled when a read makes room in the buffer.
+    drained: Notify,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ReadAhead {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(State::default()),
+            filled: Notify::new(),
+            drained: Notify::new(),
+            task: Mutex::new(None),
+        }
+    }
+
+    pub fn set_task(&self, task: JoinHandle<()>) {
+        if let Some(old) = self.task.lock().replace(task) {
+            old.abort();
+        }
+    }
+
+    /// Stop the filling task; buffered data can still be read.
+    pub fn stop(&self) {
+        if let Some(task) = self.task.lock().take() {
+            task.abort();
+        }
+    }
+
+    /// Whether a read would return without waiting.
+    pub fn is_readable(&self) -> bool {
+        let state = self.state.lock();
+        !state.data.is_empty() || state.end.is_some()
+    }
+
+    /// Wait until the buffer has room for more data.
+    pub async fn wait_for_space(&self) {
+        loop {
+            let drained = self.drained.notified();
+            if self.state.lock().data.len() < self.capacity {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    /// Store the result of a transport read, returning false once the stream has ended.
+    pub fn push(&self, result: Result<&[u8], TunnelError>) -> bool {
+        let more = {
+            let mut state = self.state.lock();
+            match result {
+                Ok([]) => state.end = Some(End::Eof),
+                Ok(data) => state.data.extend(data),
+                Err(e) => state.end = Some(End::Failed(e.to_string())),
+            }
+            state.end.is_none()
+        };
+        self.filled.notify_waiters();
+        more
+    }
+
+    /// Read buffered data into `buf`, waiting for some unless `non_blocking`.
+    ///
+    /// Returns 0 on EOF. Data received before an error is returned first.
+    pub async fn read(&self, buf: &mut [u8], non_blocking: bool) -> Result<usize, TunnelError> {
+        loop {
+            let filled = self.filled.notified();
+            {
+                let mut state = self.state.lock();
+                if !state.data.is_empty() {
+                    let n = buf.len().min(state.data.len());
+                    for (dst, src) in buf.iter_mut().zip(state.data.drain(..n)) {
+                        *dst = src;
+                    }
+                    drop(state);
+                    self.drained.notify_one();
+                    return Ok(n);
+                }
+                match &state.end {
+                    Some(End::Eof) => return Ok(0),
+                    Some(End::Failed(message)) => return Err(TunnelError::ConnectionFailed(message.clone())),
+                    None if non_blocking => return Err(TunnelError::WouldBlock),
+                    None => {}
+                }
+            }
+            filled.await;
+        }
+    }
+}
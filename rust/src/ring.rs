@@ -0,0 +1,289 @@
+//! Shared-memory ring buffers between Java and a connection.
+//!
+//! Each ring lives in a direct `ByteBuffer` allocated by Java: a 64-byte
+//! header followed by the data area. The header holds the producer's `head`
+//! and the consumer's `tail` as native-endian u64 byte counts that only grow,
+//! plus a state word written by the native side. Java reads and writes the
+//! indices with acquire/release semantics (e.g. a `byteBufferViewVarHandle`),
+//! so data moves without a JNI call; JNI is only needed to block and to wake
+//! the native side.
+//!
+//! The receive ring is filled by a native task reading the connection, and
+//! the send ring is drained by a native task writing it.
+
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use jni::objects::GlobalRef;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::connection::{Connection, ErrorCode, READY_READ, READY_WRITE};
+use crate::TunnelError;
+
+pub const HEADER_LEN: usize = 64;
+const HEAD_OFFSET: usize = 0;
+const TAIL_OFFSET: usize = 8;
+const STATE_OFFSET: usize = 16;
+const ERROR_OFFSET: usize = 20;
+
+/// Ring states; only the native side changes them.
+pub const STATE_OPEN: i32 = 0;
+pub const STATE_EOF: i32 = 1;
+pub const STATE_ERROR: i32 = 2;
+
+/// How often the native side rechecks a ring Java has not notified it about.
+const RECHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Largest single write made from the send ring.
+const MAX_WRITE: usize = 64 * 1024;
+
+/// One ring in a Java direct buffer.
+struct Ring {
+    base: *mut u8,
+    capacity: usize,
+    /// Keeps the buffer, and so its memory, alive.
+    _buffer: GlobalRef,
+}
+
+// SAFETY: the memory is owned by the Java buffer kept alive by `_buffer`, and
+// all shared fields are accessed atomically. The data area is split between
+// producer and consumer by the indices.
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// # Safety
+    ///
+    /// `base` must point to `len` bytes that stay valid while `buffer` is referenced.
+    unsafe fn new(buffer: GlobalRef, base: *mut u8, len: usize) -> Result<Self, String> {
+        if len <= HEADER_LEN {
+            return Err(format!("Ring buffer must be larger than {} bytes", HEADER_LEN));
+        }
+        if !(base as usize).is_multiple_of(std::mem::align_of::<AtomicU64>()) {
+            return Err("Ring buffer is not 8-byte aligned".into());
+        }
+        Ok(Self {
+            base,
+            capacity: len - HEADER_LEN,
+            _buffer: buffer,
+        })
+    }
+
+    fn index(&self, offset: usize) -> &AtomicU64 {
+        // SAFETY: in bounds and aligned, checked in `new`
+        unsafe { &*(self.base.add(offset) as *const AtomicU64) }
+    }
+
+    fn word(&self, offset: usize) -> &AtomicI32 {
+        // SAFETY: in bounds and aligned, checked in `new`
+        unsafe { &*(self.base.add(offset) as *const AtomicI32) }
+    }
+
+    fn len(&self) -> usize {
+        let head = self.index(HEAD_OFFSET).load(Ordering::Acquire);
+        let tail = self.index(TAIL_OFFSET).load(Ordering::Acquire);
+        self.used(head, tail).unwrap_or(self.capacity)
+    }
+
+    /// Bytes between `tail` and `head`, or `None` if Java moved an index so
+    /// that they are more than the capacity apart.
+    fn used(&self, head: u64, tail: u64) -> Option<usize> {
+        let used = head.wrapping_sub(tail);
+        (used <= self.capacity as u64).then_some(used as usize)
+    }
+
+    fn state(&self) -> i32 {
+        self.word(STATE_OFFSET).load(Ordering::Acquire)
+    }
+
+    fn set_state(&self, state: i32, error: i32) {
+        self.word(ERROR_OFFSET).store(error, Ordering::Relaxed);
+        self.word(STATE_OFFSET).store(state, Ordering::Release);
+    }
+
+    /// Contiguous free space after `head`, for the producer, or `None` if the
+    /// indices are corrupt.
+    ///
+    /// # Safety
+    ///
+    /// Only the producer may call this, and only until it advances `head`.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn free_slice(&self) -> Option<&mut [u8]> {
+        let head = self.index(HEAD_OFFSET).load(Ordering::Relaxed);
+        let tail = self.index(TAIL_OFFSET).load(Ordering::Acquire);
+        let free = self.capacity - self.used(head, tail)?;
+        let start = (head % self.capacity as u64) as usize;
+        let len = free.min(self.capacity - start);
+        Some(std::slice::from_raw_parts_mut(self.base.add(HEADER_LEN + start), len))
+    }
+
+    /// Contiguous data after `tail`, for the consumer, or `None` if the
+    /// indices are corrupt.
+    ///
+    /// # Safety
+    ///
+    /// Only the consumer may call this, and only until it advances `tail`.
+    unsafe fn data_slice(&self) -> Option<&[u8]> {
+        let tail = self.index(TAIL_OFFSET).load(Ordering::Relaxed);
+        let head = self.index(HEAD_OFFSET).load(Ordering::Acquire);
+        let len = self.used(head, tail)?;
+        let start = (tail % self.capacity as u64) as usize;
+        let len = len.min(self.capacity - start);
+        Some(std::slice::from_raw_parts(self.base.add(HEADER_LEN + start), len))
+    }
+
+    fn advance(&self, offset: usize, n: usize) {
+        self.index(offset).fetch_add(n as u64, Ordering::Release);
+    }
+}
+
+/// Receive and send rings attached to one connection, with their tasks.
+pub struct Rings {
+    rx: Ring,
+    tx: Ring,
+    /// Signalled by Java after it writes to `tx` or frees space in `rx`.
+    rx_space: Notify,
+    tx_data: Notify,
+    /// Signalled by the native side after it makes progress on either ring.
+    progress: Notify,
+    tasks: parking_lot::Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Rings {
+    /// # Safety
+    ///
+    /// Each base pointer must point to its length in bytes, valid while the
+    /// matching buffer is referenced.
+    pub unsafe fn new(rx: (GlobalRef, *mut u8, usize), tx: (GlobalRef, *mut u8, usize)) -> Result<Self, String> {
+        Ok(Self {
+            rx: Ring::new(rx.0, rx.1, rx.2)?,
+            tx: Ring::new(tx.0, tx.1, tx.2)?,
+            rx_space: Notify::new(),
+            tx_data: Notify::new(),
+            progress: Notify::new(),
+            tasks: parking_lot::Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Start moving data between the rings and `conn`.
+    pub fn start(self: &Arc<Self>, conn: Arc<Connection>, runtime: &tokio::runtime::Handle) {
+        let tasks = vec![
+            runtime.spawn(self.clone().fill_rx(conn.clone())),
+            runtime.spawn(self.clone().drain_tx(conn)),
+        ];
+        *self.tasks.lock() = tasks;
+    }
+
+    pub fn stop(&self) {
+        for task in self.tasks.lock().drain(..) {
+            task.abort();
+        }
+    }
+
+    /// Wake the native side after Java used either ring.
+    pub fn notify(&self) {
+        self.rx_space.notify_one();
+        self.tx_data.notify_one();
+    }
+
+    fn readiness(&self) -> i32 {
+        let mut ready = 0;
+        if self.rx.len() > 0 || self.rx.state() != STATE_OPEN {
+            ready |= READY_READ;
+        }
+        if self.tx.len() < self.tx.capacity || self.tx.state() != STATE_OPEN {
+            ready |= READY_WRITE;
+        }
+        ready
+    }
+
+    /// Wait until one of `interest` is ready or `timeout` passes, returning the ready ops.
+    pub async fn wait(&self, interest: i32, timeout: Option<Duration>) -> i32 {
+        let wait = async {
+            loop {
+                let progress = self.progress.notified();
+                let ready = self.readiness() & interest;
+                if ready != 0 {
+                    return ready;
+                }
+                progress.await;
+            }
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, wait).await.unwrap_or(0),
+            None => wait.await,
+        }
+    }
+
+    /// Stop using `ring` after Java left its indices out of range, rather than
+    /// touch memory outside the data area.
+    fn corrupt(&self, ring: &Ring, name: &str) {
+        log::warn!("Shared-memory {} ring has head and tail more than its capacity apart", name);
+        ring.set_state(STATE_ERROR, ErrorCode::Other as i32);
+        self.progress.notify_waiters();
+    }
+
+    async fn fill_rx(self: Arc<Self>, conn: Arc<Connection>) {
+        loop {
+            // SAFETY: this task is the receive ring's only producer
+            let Some(free) = (unsafe { self.rx.free_slice() }) else {
+                self.corrupt(&self.rx, "receive");
+                return;
+            };
+            if free.is_empty() {
+                tokio::select! {
+                    _ = self.rx_space.notified() => {}
+                    _ = tokio::time::sleep(RECHECK_INTERVAL) => {}
+                }
+                continue;
+            }
+            match conn.read(free).await {
+                Ok(0) => {
+                    self.rx.set_state(STATE_EOF, conn.last_error());
+                    self.progress.notify_waiters();
+                    return;
+                }
+                Ok(n) => self.rx.advance(HEAD_OFFSET, n),
+                // Java never sees this read, so retry it here
+                Err(TunnelError::Reconnecting) => continue,
+                Err(e) => {
+                    log::debug!("Ring read failed: {}", e);
+                    self.rx.set_state(STATE_ERROR, conn.last_error());
+                    self.progress.notify_waiters();
+                    return;
+                }
+            }
+            self.progress.notify_waiters();
+        }
+    }
+
+    async fn drain_tx(self: Arc<Self>, conn: Arc<Connection>) {
+        loop {
+            // SAFETY: this task is the send ring's only consumer
+            let Some(data) = (unsafe { self.tx.data_slice() }) else {
+                self.corrupt(&self.tx, "send");
+                return;
+            };
+            if data.is_empty() {
+                tokio::select! {
+                    _ = self.tx_data.notified() => {}
+                    _ = tokio::time::sleep(RECHECK_INTERVAL) => {}
+                }
+                continue;
+            }
+            let len = data.len().min(MAX_WRITE);
+            match conn.write(&data[..len]).await {
+                Ok(n) => self.tx.advance(TAIL_OFFSET, n),
+                Err(e) => {
+                    log::debug!("Ring write failed: {}", e);
+                    self.tx.set_state(STATE_ERROR, conn.last_error());
+                    self.progress.notify_waiters();
+                    return;
+                }
+            }
+            self.progress.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Per-destination routing decisions for new connections.
+//!
+//! Split tunneling is expressed as two CIDR lists. A destination takes the
+//! most specific matching route; ties go to the tunnel. Destinations matching
+//! neither list use the tunnel, unless tunnel routes are configured, in which
+//! case only those destinations are tunneled.
+//!
+//! Domain rules are checked first, against the hostname the caller connected
+//! to, before it is resolved. The most specific matching pattern decides the
+//! route; CIDR routes only apply when no domain rule matches or the caller
+//! gave an IP literal.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Where a connection should go.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Route {
+    Tunnel,
+    Direct,
+}
+
+impl Route {
+    pub fn from_jint(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Route::Tunnel),
+            1 => Some(Route::Direct),
+            _ => None,
+        }
+    }
+}
+
+/// A hostname pattern: `example.com`, `*.example.com` or `*`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DomainPattern {
+    Exact(String),
+    /// Matches subdomains of the suffix, not the suffix itself.
+    Suffix(String),
+    Any,
+}
+
+impl DomainPattern {
+    pub fn parse(pattern: &str) -> Option<Self> {
+        let pattern = normalize_host(pattern);
+        if pattern == "*" {
+            return Some(DomainPattern::Any);
+        }
+        let (suffix, name) = match pattern.strip_prefix("*.") {
+            Some(suffix) => (true, suffix),
+            None => (false, pattern.as_str()),
+        };
+        if name.is_empty() || name.contains('*') || name.split('.').any(str::is_empty) {
+            return None;
+        }
+        Some(if suffix {
+            DomainPattern::Suffix(name.to_string())
+        } else {
+            DomainPattern::Exact(name.to_string())
+        })
+    }
+
+    /// Specificity of a match against `host`, higher is more specific.
+    pub fn matches(&self, host: &str) -> Option<usize> {
+        match self {
+            // Exact names beat any wildcard of the same length
+            DomainPattern::Exact(name) => (host == name).then(|| name.len() * 2 + 1),
+            DomainPattern::Suffix(suffix) => host
+                .strip_suffix(suffix.as_str())
+                .filter(|rest| rest.ends_with('.'))
+                .map(|_| suffix.len() * 2),
+            DomainPattern::Any => Some(0),
+        }
+    }
+}
+
+pub fn normalize_host(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[derive(Default)]
+pub struct Router {
+    bypass: Vec<IpNet>,
+    tunnel: Vec<IpNet>,
+    domains: Vec<(DomainPattern, Route)>,
+}
+
+/// Parse a CIDR, accepting a bare address as a single-host network.
+pub fn parse_cidr(cidr: &str) -> Option<IpNet> {
+    let cidr = cidr.trim();
+    cidr.parse::<IpNet>()
+        .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+        .map(|net| net.trunc())
+}
+
+fn longest_match(nets: &[IpNet], ip: IpAddr) -> Option<u8> {
+    nets.iter().filter(|net| net.contains(&ip)).map(|net| net.prefix_len()).max()
+}
+
+impl Router {
+    pub fn add_bypass(&mut self, net: IpNet) {
+        if !self.bypass.contains(&net) {
+            self.bypass.push(net);
+        }
+    }
+
+    pub fn add_tunnel(&mut self, net: IpNet) {
+        if !self.tunnel.contains(&net) {
+            self.tunnel.push(net);
+        }
+    }
+
+    /// Add a domain rule, replacing any existing rule with the same pattern.
+    pub fn add_domain(&mut self, pattern: DomainPattern, route: Route) {
+        match self.domains.iter_mut().find(|(p, _)| *p == pattern) {
+            Some(rule) => rule.1 = route,
+            None => self.domains.push((pattern, route)),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bypass.clear();
+        self.tunnel.clear();
+        self.domains.clear();
+    }
+
+    /// The route the most specific domain rule matching `host` gives, if
+    /// any; IP literals match none.
+    ///
+    /// Known before `host` is resolved, so a direct host can be resolved
+    /// outside the tunnel.
+    pub fn domain_route(&self, host: &str) -> Option<Route> {
+        let host = normalize_host(host);
+        if host.trim_matches(['[', ']']).parse::<IpAddr>().is_ok() {
+            return None;
+        }
+        self.domains
+            .iter()
+            .filter_map(|(pattern, route)| pattern.matches(&host).map(|score| (score, *route)))
+            .max_by_key(|(score, _)| *score)
+            .map(|(_, route)| route)
+    }
+
+    /// Decide the route for a connection to `host`, which resolved to `ip`.
+    pub fn route(&self, host: &str, ip: IpAddr) -> Route {
+        if let Some(route) = self.domain_route(host) {
+            return route;
+        }
+
+        let ip = ip.to_canonical();
+        match (longest_match(&self.tunnel, ip), longest_match(&self.bypass, ip)) {
+            (Some(tunnel), Some(bypass)) if bypass > tunnel => Route::Direct,
+            (Some(_), _) => Route::Tunnel,
+            (None, Some(_)) => Route::Direct,
+            (None, None) if self.tunnel.is_empty() => Route::Tunnel,
+            (None, None) => Route::Direct,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn net(s: &str) -> IpNet {
+        parse_cidr(s).unwrap()
+    }
+
+    fn domain(pattern: &str) -> DomainPattern {
+        DomainPattern::parse(pattern).unwrap()
+    }
+
+    #[test]
+    fn everything_tunneled_without_routes() {
+        let router = Router::default();
+        assert_eq!(router.route("example.com", ip("93.184.216.34")), Route::Tunnel);
+        assert_eq!(router.route("192.168.1.10", ip("192.168.1.10")), Route::Tunnel);
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut router = Router::default();
+        router.add_bypass(net("10.0.0.0/8"));
+        router.add_tunnel(net("10.1.0.0/16"));
+        router.add_bypass(net("10.1.2.0/24"));
+        assert_eq!(router.route("10.9.9.9", ip("10.9.9.9")), Route::Direct);
+        assert_eq!(router.route("10.1.9.9", ip("10.1.9.9")), Route::Tunnel);
+        assert_eq!(router.route("10.1.2.3", ip("10.1.2.3")), Route::Direct);
+    }
+
+    #[test]
+    fn ties_go_to_the_tunnel() {
+        let mut router = Router::default();
+        router.add_bypass(net("172.16.0.0/12"));
+        router.add_tunnel(net("172.16.0.0/12"));
+        assert_eq!(router.route("172.20.1.1", ip("172.20.1.1")), Route::Tunnel);
+    }
+
+    #[test]
+    fn unmatched_goes_direct_once_tunnel_routes_exist() {
+        let mut router = Router::default();
+        router.add_bypass(net("192.168.0.0/16"));
+        assert_eq!(router.route("1.1.1.1", ip("1.1.1.1")), Route::Tunnel);
+
+        router.add_tunnel(net("104.16.0.0/12"));
+        assert_eq!(router.route("104.16.1.1", ip("104.16.1.1")), Route::Tunnel);
+        assert_eq!(router.route("1.1.1.1", ip("1.1.1.1")), Route::Direct);
+    }
+
+    #[test]
+    fn mapped_ipv4_matches_ipv4_routes() {
+        let mut router = Router::default();
+        router.add_bypass(net("127.0.0.0/8"));
+        assert_eq!(router.route("localhost", ip("::ffff:127.0.0.1")), Route::Direct);
+    }
+
+    #[test]
+    fn domain_rules_beat_cidr_routes() {
+        let mut router = Router::default();
+        router.add_bypass(net("0.0.0.0/0"));
+        router.add_domain(domain("*.hypixel.net"), Route::Tunnel);
+        assert_eq!(router.route("mc.hypixel.net", ip("209.222.115.1")), Route::Tunnel);
+        assert_eq!(router.route("example.com", ip("209.222.115.1")), Route::Direct);
+    }
+
+    #[test]
+    fn ip_literals_skip_domain_rules() {
+        let mut router = Router::default();
+        router.add_domain(domain("*"), Route::Direct);
+        assert_eq!(router.route("1.1.1.1", ip("1.1.1.1")), Route::Tunnel);
+        assert_eq!(router.route("[2606:4700::1111]", ip("2606:4700::1111")), Route::Tunnel);
+        assert_eq!(router.domain_route("1.1.1.1"), None);
+        assert_eq!(router.domain_route("example.com"), Some(Route::Direct));
+    }
+
+    #[test]
+    fn exact_beats_suffix_beats_any() {
+        let mut router = Router::default();
+        router.add_domain(domain("*"), Route::Direct);
+        router.add_domain(domain("*.hypixel.net"), Route::Tunnel);
+        router.add_domain(domain("store.hypixel.net"), Route::Direct);
+        assert_eq!(router.domain_route("mc.hypixel.net"), Some(Route::Tunnel));
+        assert_eq!(router.domain_route("store.hypixel.net"), Some(Route::Direct));
+        assert_eq!(router.domain_route("example.com"), Some(Route::Direct));
+    }
+
+    #[test]
+    fn longer_suffix_beats_shorter() {
+        let mut router = Router::default();
+        router.add_domain(domain("*.net"), Route::Direct);
+        router.add_domain(domain("*.hypixel.net"), Route::Tunnel);
+        assert_eq!(router.domain_route("mc.hypixel.net"), Some(Route::Tunnel));
+        assert_eq!(router.domain_route("example.net"), Some(Route::Direct));
+    }
+
+    #[test]
+    fn suffix_does_not_match_itself_or_lookalikes() {
+        let pattern = domain("*.hypixel.net");
+        assert_eq!(pattern.matches("hypixel.net"), None);
+        assert_eq!(pattern.matches("nothypixel.net"), None);
+        assert!(pattern.matches("a.b.hypixel.net").is_some());
+    }
+
+    #[test]
+    fn hosts_are_normalized() {
+        let mut router = Router::default();
+        router.add_domain(domain("Play.Example.COM."), Route::Direct);
+        assert_eq!(router.domain_route("play.example.com"), Some(Route::Direct));
+        assert_eq!(router.domain_route(" PLAY.example.com. "), Some(Route::Direct));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        for pattern in ["", "a.*.com", "**.com", "a..com", "*example.com"] {
+            assert_eq!(DomainPattern::parse(pattern), None, "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn later_rule_replaces_same_pattern() {
+        let mut router = Router::default();
+        router.add_domain(domain("example.com"), Route::Direct);
+        router.add_domain(domain("example.com"), Route::Tunnel);
+        assert_eq!(router.domain_route("example.com"), Some(Route::Tunnel));
+    }
+}
@@ -0,0 +1,155 @@
+//! Tokio runtime construction, and blocking on it from JNI threads.
+//!
+//! The runtime is built once, when global state is first created. Its sizing
+//! can be configured from Java before that happens. With zero worker threads
+//! a current-thread runtime is used instead, driven by a single background
+//! thread, for memory-constrained clients.
+
+use std::io;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
+use tokio::sync::Notify;
+
+#[derive(Clone, Copy)]
+pub struct RuntimeConfig {
+    /// Worker threads; 0 selects the current-thread runtime.
+    pub worker_threads: usize,
+    /// Cap on the blocking thread pool; `None` keeps Tokio's default of 512.
+    pub max_blocking_threads: Option<usize>,
+    /// Stack size for runtime threads; `None` keeps Tokio's default of 2 MiB.
+    pub thread_stack_size: Option<usize>,
+}
+
+const DEFAULT_CONFIG: RuntimeConfig = RuntimeConfig {
+    worker_threads: 4,
+    max_blocking_threads: None,
+    thread_stack_size: None,
+};
+
+static CONFIG: Mutex<RuntimeConfig> = Mutex::new(DEFAULT_CONFIG);
+
+/// Set the configuration used by the next `NativeRuntime::build`.
+pub fn configure(config: RuntimeConfig) {
+    *CONFIG.lock() = config;
+}
+
+pub struct NativeRuntime {
+    runtime: Arc<Runtime>,
+    /// Thread driving a current-thread runtime, and the signal that stops it.
+    driver: Option<(Arc<Notify>, JoinHandle<()>)>,
+}
+
+impl NativeRuntime {
+    pub fn build() -> io::Result<Self> {
+        let config = *CONFIG.lock();
+
+        let mut builder = if config.worker_threads == 0 {
+            Builder::new_current_thread()
+        } else {
+            let mut builder = Builder::new_multi_thread();
+            builder.worker_threads(config.worker_threads);
+            builder
+        };
+        if let Some(max) = config.max_blocking_threads {
+            builder.max_blocking_threads(max);
+        }
+        if let Some(size) = config.thread_stack_size {
+            builder.thread_stack_size(size);
+        }
+        let runtime = Arc::new(builder.enable_all().build()?);
+
+        let mut driver = None;
+        if config.worker_threads == 0 {
+            // A current-thread runtime only makes progress inside block_on, so
+            // park one thread there for spawned tasks to run on. It also drives
+            // I/O and timers for futures blocked on from JNI threads
+            let rt = runtime.clone();
+            let stop = Arc::new(Notify::new());
+            let stopped = stop.clone();
+            let mut thread = std::thread::Builder::new().name("wireguard-tunnel-runtime".into());
+            if let Some(size) = config.thread_stack_size {
+                thread = thread.stack_size(size);
+            }
+            let thread = thread.spawn(move || rt.block_on(stopped.notified()))?;
+            driver = Some((stop, thread));
+        }
+
+        log::debug!(
+            "Tokio runtime started: workers={}, max_blocking={:?}, stack_size={:?}",
+            config.worker_threads,
+            config.max_blocking_threads,
+            config.thread_stack_size
+        );
+        Ok(Self { runtime, driver })
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.runtime.handle().clone()
+    }
+
+    /// Stop all tasks and release the runtime's threads.
+    ///
+    /// Tasks are cancelled at their next await point; blocking tasks get up
+    /// to `timeout` to finish before their threads are abandoned. Must not be
+    /// called from a runtime thread.
+    pub fn shutdown(self, timeout: Duration) {
+        if let Some((stop, thread)) = self.driver {
+            stop.notify_one();
+            let _ = thread.join();
+        }
+        match Arc::try_unwrap(self.runtime) {
+            Ok(runtime) => runtime.shutdown_timeout(timeout),
+            Err(_) => log::warn!("Tokio runtime still referenced, leaving it to shut down on drop"),
+        }
+    }
+}
+
+/// Run `future` on the runtime behind `handle`, safe to call from any thread.
+///
+/// JNI threads are never runtime threads, so the future is normally polled
+/// in place on the calling thread, with the runtime driving its I/O and
+/// timers. This avoids a task spawn, a channel and two thread switches per
+/// call. Threads of a multi-threaded runtime, such as those running
+/// callbacks, do the same after handing their other tasks to another
+/// worker. Only a current-thread runtime's own thread cannot block on it,
+/// so there the future is spawned and waited for instead.
+pub fn block_on<F, T>(handle: &Handle, future: F) -> T
+where
+    F: std::future::Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    match Handle::try_current() {
+        Err(_) => handle.block_on(future),
+        Ok(current) if current.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| handle.block_on(future))
+        }
+        Ok(_) => {
+            let (tx, rx) = std::sync::mpsc::channel();
+            handle.spawn(async move {
+                let result = future.await;
+                let _ = tx.send(result);
+            });
+            rx.recv().expect("Runtime task panicked")
+        }
+    }
+}
+
+/// Like `block_on`, for a future that borrows from the caller, such as a
+/// buffer it reads into.
+///
+/// Without the `'static` bound the future cannot be moved to a runtime
+/// thread, so on a current-thread runtime's own thread this returns `None`
+/// and the caller has to copy instead.
+pub fn block_on_borrowed<F: std::future::Future>(handle: &Handle, future: F) -> Option<F::Output> {
+    match Handle::try_current() {
+        Err(_) => Some(handle.block_on(future)),
+        Ok(current) if current.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            Some(tokio::task::block_in_place(|| handle.block_on(future)))
+        }
+        Ok(_) => None,
+    }
+}
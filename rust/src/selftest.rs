@@ -0,0 +1,165 @@
+//! Connection self-test through the tunnel.
+//!
+//! Measures handshake time, round-trip time and download/upload throughput
+//! against an HTTPS endpoint speaking Cloudflare's speed test protocol
+//! (`GET /__down?bytes=N`, `POST /__up`), such as `speed.cloudflare.com`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::client::TlsStream;
+use wireguard_netstack::{NetStack, TcpConnection};
+
+use crate::dns::Resolver;
+use crate::https;
+use crate::stream::TunnelStream;
+use crate::TunnelError;
+
+/// Transfer size used when the caller does not pick one.
+pub const DEFAULT_BYTES: usize = 10 * 1024 * 1024;
+
+/// Chunk size for reading the download and writing the upload.
+const CHUNK: usize = 64 * 1024;
+
+/// Upper bound on the upload response, which only acknowledges the data.
+const MAX_UPLOAD_RESPONSE: usize = 64 * 1024;
+
+#[derive(Serialize)]
+pub struct Report {
+    pub host: String,
+    pub address: String,
+    pub bytes: usize,
+    pub dns_ms: f64,
+    /// TCP and TLS handshake of the first connection.
+    pub handshake_ms: f64,
+    /// Fastest TCP handshake, one round trip through the tunnel.
+    pub rtt_ms: f64,
+    pub download_mbps: f64,
+    pub upload_mbps: f64,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn mbps(bytes: usize, d: Duration) -> f64 {
+    bytes as f64 * 8.0 / d.as_secs_f64().max(f64::EPSILON) / 1_000_000.0
+}
+
+fn test_error(msg: impl Into<String>) -> TunnelError {
+    TunnelError::ConnectionFailed(msg.into())
+}
+
+/// Open a TLS connection, returning it with the TCP handshake time.
+async fn connect(
+    netstack: Arc<NetStack>,
+    addr: SocketAddr,
+    host: &str,
+) -> Result<(TlsStream<TunnelStream>, Duration), TunnelError> {
+    let start = Instant::now();
+    let conn = TcpConnection::connect(netstack, addr)
+        .await
+        .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+    let rtt = start.elapsed();
+    Ok((https::tls_connect(conn, host).await?, rtt))
+}
+
+/// Read a `Connection: close` response to EOF, returning its status and body length.
+async fn drain_response(tls: &mut TlsStream<TunnelStream>) -> Result<(u16, usize), TunnelError> {
+    let mut head = Vec::new();
+    let mut body_len = None;
+    let mut buf = vec![0u8; CHUNK];
+    loop {
+        let n = match tls.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        match body_len.as_mut() {
+            Some(len) => *len += n,
+            None => {
+                head.extend_from_slice(&buf[..n]);
+                if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+                    body_len = Some(head.len() - end - 4);
+                    head.truncate(end);
+                }
+            }
+        }
+    }
+
+    let status = std::str::from_utf8(&head)
+        .ok()
+        .and_then(|head| head.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| test_error("Malformed HTTP response"))?;
+    Ok((status, body_len.unwrap_or(0)))
+}
+
+/// Run the self-test against `host`, transferring `bytes` in each direction.
+pub async fn run(resolver: &Resolver, host: &str, bytes: usize) -> Result<Report, TunnelError> {
+    let start = Instant::now();
+    let ip = resolver.resolve_host(host).await?;
+    let dns = start.elapsed();
+    let addr = SocketAddr::new(ip, 443);
+
+    let start = Instant::now();
+    let (mut tls, down_rtt) = connect(resolver.netstack(), addr, host).await?;
+    let handshake = start.elapsed();
+
+    let request = format!(
+        "GET /__down?bytes={} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Connection: close\r\n\
+         \r\n",
+        bytes, host
+    );
+    let start = Instant::now();
+    tls.write_all(request.as_bytes()).await?;
+    tls.flush().await?;
+    let (status, received) = drain_response(&mut tls).await?;
+    let download = start.elapsed();
+    if status != 200 {
+        return Err(test_error(format!("Download returned HTTP {}", status)));
+    }
+
+    let (mut tls, up_rtt) = connect(resolver.netstack(), addr, host).await?;
+    let request = format!(
+        "POST /__up HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: application/octet-stream\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n",
+        host, bytes
+    );
+    let chunk = vec![0u8; CHUNK];
+    let start = Instant::now();
+    tls.write_all(request.as_bytes()).await?;
+    let mut remaining = bytes;
+    while remaining > 0 {
+        let n = remaining.min(CHUNK);
+        tls.write_all(&chunk[..n]).await?;
+        remaining -= n;
+    }
+    tls.flush().await?;
+    let (status, _) = https::read_response(&mut tls, MAX_UPLOAD_RESPONSE).await?;
+    let upload = start.elapsed();
+    if status != 200 {
+        return Err(test_error(format!("Upload returned HTTP {}", status)));
+    }
+
+    Ok(Report {
+        host: host.to_string(),
+        address: ip.to_string(),
+        bytes,
+        dns_ms: millis(dns),
+        handshake_ms: millis(handshake),
+        rtt_ms: millis(down_rtt.min(up_rtt)),
+        download_mbps: mbps(received, download),
+        upload_mbps: mbps(bytes, upload),
+    })
+}
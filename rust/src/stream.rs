@@ -0,0 +1,96 @@
+//! Tokio I/O adapter over a tunnel TCP connection.
+//!
+//! Lets tokio-based code (TLS, HTTP framing) run on top of a netstack socket.
+//! The netstack has no readiness notifications, so a pending operation
+//! re-wakes its task after a short sleep, like the netstack's own helpers.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use wireguard_netstack::TcpConnection;
+
+/// Interval between readiness checks while an operation is pending.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+pub struct TunnelStream {
+    conn: Arc<TcpConnection>,
+}
+
+impl TunnelStream {
+    pub fn new(conn: Arc<TcpConnection>) -> Self {
+        Self { conn }
+    }
+}
+
+fn wake_later(cx: &Context<'_>) {
+    let waker = cx.waker().clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        waker.wake();
+    });
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let conn = &self.conn;
+        conn.netstack.poll();
+
+        if conn.netstack.can_recv(conn.handle) {
+            match conn.netstack.recv(conn.handle, buf.initialize_unfilled()) {
+                Ok(n) if n > 0 => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(_) => {}
+                Err(e) => return Poll::Ready(Err(io::Error::other(e.to_string()))),
+            }
+        }
+
+        if !conn.netstack.may_recv(conn.handle) {
+            // Connection closed, report EOF
+            return Poll::Ready(Ok(()));
+        }
+
+        wake_later(cx);
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let conn = &self.conn;
+        conn.netstack.poll();
+
+        if conn.netstack.can_send(conn.handle) {
+            return match conn.netstack.send(conn.handle, buf) {
+                Ok(n) => {
+                    conn.netstack.poll();
+                    Poll::Ready(Ok(n))
+                }
+                Err(e) => Poll::Ready(Err(io::Error::other(e.to_string()))),
+            };
+        }
+
+        if !conn.netstack.may_send(conn.handle) {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed")));
+        }
+
+        wake_later(cx);
+        Poll::Pending
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.conn.netstack.poll();
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.conn.shutdown();
+        self.conn.netstack.poll();
+        Poll::Ready(Ok(()))
+    }
+}
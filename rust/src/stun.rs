@@ -0,0 +1,264 @@
+//! STUN (RFC 8489) binding requests and NAT mapping detection.
+//!
+//! Outside the tunnel, one UDP socket asks two servers for its public
+//! address. The same mapping from both means the NAT maps endpoint-
+//! independently (full, restricted or port-restricted cone), so peers can
+//! reach us at the address a server saw; different mappings mean a
+//! symmetric NAT, where direct paths usually need a relay. Filtering
+//! behaviour needs a server with a second address (RFC 5780) and is not
+//! tested.
+//!
+//! The netstack only carries TCP, so through the tunnel a binding request
+//! goes over TCP and only reports the tunnel's public address.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use wireguard_netstack::{NetStack, TcpConnection};
+
+use crate::dns::{self, Resolver};
+use crate::stream::TunnelStream;
+use crate::TunnelError;
+
+/// Servers queried outside the tunnel; two operators, so two addresses.
+const DIRECT_SERVERS: [(&str, u16); 2] = [("stun.cloudflare.com", 3478), ("stun.l.google.com", 19302)];
+/// Server queried over TCP through the tunnel.
+const TUNNEL_SERVER: (&str, u16) = ("stun.cloudflare.com", 3478);
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+
+/// UDP requests are sent this many times before a server counts as unreachable.
+const UDP_ATTEMPTS: u32 = 3;
+const UDP_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn stun_error(msg: impl Into<String>) -> TunnelError {
+    TunnelError::ConnectionFailed(msg.into())
+}
+
+fn binding_request() -> ([u8; 12], Vec<u8>) {
+    let mut txid = [0u8; 12];
+    OsRng.fill_bytes(&mut txid);
+    let mut request = Vec::with_capacity(HEADER_LEN);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&txid);
+    (txid, request)
+}
+
+/// The address from a binding success response to `txid`, or `None` if the
+/// message is not one.
+fn parse_response(msg: &[u8], txid: &[u8; 12]) -> Option<SocketAddr> {
+    let kind = u16::from_be_bytes(msg.get(0..2)?.try_into().ok()?);
+    let len = u16::from_be_bytes(msg.get(2..4)?.try_into().ok()?) as usize;
+    if kind != BINDING_SUCCESS || msg.get(4..8)? != MAGIC_COOKIE.to_be_bytes() || msg.get(8..20)? != txid {
+        return None;
+    }
+    let mut attrs = msg.get(HEADER_LEN..HEADER_LEN + len)?;
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + len)?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(txid)),
+            ATTR_MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+        // Attributes are padded to 4 bytes
+        attrs = attrs.get((4 + len).next_multiple_of(4)..).unwrap_or_default();
+    }
+    mapped
+}
+
+/// Decode a (XOR-)MAPPED-ADDRESS value; `txid` is given for the XOR form.
+fn parse_address(value: &[u8], txid: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let mut port = u16::from_be_bytes(value.get(2..4)?.try_into().ok()?);
+    let mut ip = value.get(4..)?.to_vec();
+    if let Some(txid) = txid {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+        let key: Vec<u8> = cookie.iter().chain(txid.iter()).copied().collect();
+        ip.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+    }
+    let ip = match (value[1], ip.len()) {
+        (0x01, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?)),
+        (0x02, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Ask `server` for the public address of `socket`, retrying on loss.
+async fn query_udp(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr, TunnelError> {
+    let (txid, request) = binding_request();
+    let mut buf = [0u8; 512];
+    for _ in 0..UDP_ATTEMPTS {
+        socket.send_to(&request, server).await?;
+        let response = tokio::time::timeout(UDP_RETRY_INTERVAL, async {
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await?;
+                if from == server {
+                    if let Some(mapped) = parse_response(&buf[..n], &txid) {
+                        return Ok::<_, TunnelError>(mapped);
+                    }
+                }
+            }
+        })
+        .await;
+        if let Ok(mapped) = response {
+            return mapped;
+        }
+    }
+    Err(stun_error(format!("No STUN response from {}", server)))
+}
+
+/// Ask `server` over TCP through the tunnel for the tunnel's public address.
+async fn query_tcp(netstack: Arc<NetStack>, server: SocketAddr) -> Result<SocketAddr, TunnelError> {
+    let conn = TcpConnection::connect(netstack, server)
+        .await
+        .map_err(|e| stun_error(e.to_string()))?;
+    let mut stream = TunnelStream::new(Arc::new(conn));
+    let (txid, request) = binding_request();
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let mut response = header.to_vec();
+    response.resize(HEADER_LEN + len, 0);
+    stream.read_exact(&mut response[HEADER_LEN..]).await?;
+    parse_response(&response, &txid).ok_or_else(|| stun_error("Malformed STUN response"))
+}
+
+#[derive(Serialize, Default)]
+pub struct DirectReport {
+    /// "open" (no NAT), "endpoint-independent", "address-dependent",
+    /// "unknown" (only one server answered) or "udp-blocked".
+    pub nat_type: String,
+    pub local_address: Option<String>,
+    /// Public addresses seen by each server that answered.
+    pub mapped_addresses: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct TunnelReport {
+    pub public_address: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub direct: DirectReport,
+    /// `None` when no tunnel is running.
+    pub tunnel: Option<TunnelReport>,
+}
+
+/// Classify the NAT in front of this machine from outside the tunnel.
+async fn detect_direct() -> DirectReport {
+    let mut report = DirectReport::default();
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            report.nat_type = "unknown".into();
+            report.errors.push(format!("Failed to bind UDP socket: {}", e));
+            return report;
+        }
+    };
+
+    let mut mapped = Vec::new();
+    let mut answered = None;
+    for (host, port) in DIRECT_SERVERS {
+        let server = dns::resolve_system_all(host, port)
+            .await
+            .and_then(|ips| {
+                let ip = ips.into_iter().find(IpAddr::is_ipv4);
+                ip.ok_or_else(|| stun_error(format!("No IPv4 address for {}", host)))
+            })
+            .map(|ip| SocketAddr::new(ip, port));
+        match server {
+            Ok(server) => match query_udp(&socket, server).await {
+                Ok(addr) => {
+                    answered.get_or_insert(server);
+                    mapped.push(addr);
+                }
+                Err(e) => report.errors.push(format!("{}: {}", host, e)),
+            },
+            Err(e) => report.errors.push(format!("{}: {}", host, e)),
+        }
+    }
+
+    let local_port = socket.local_addr().map(|addr| addr.port()).unwrap_or_default();
+    let local_ip = match answered {
+        Some(server) => local_ip_towards(server).await,
+        None => None,
+    };
+    report.local_address = local_ip.map(|ip| SocketAddr::new(ip, local_port).to_string());
+    report.nat_type = match mapped.as_slice() {
+        [] => "udp-blocked",
+        [first, ..] if Some(first.ip()) == local_ip && first.port() == local_port => "open",
+        [_] => "unknown",
+        [first, rest @ ..] if rest.iter().all(|addr| addr == first) => "endpoint-independent",
+        _ => "address-dependent",
+    }
+    .into();
+    report.mapped_addresses = mapped.iter().map(ToString::to_string).collect();
+    report
+}
+
+/// The local IP the OS picks toward `server`.
+///
+/// The STUN socket is bound to the unspecified address, so a connected probe
+/// socket asks the routing table instead; nothing is sent.
+async fn local_ip_towards(server: SocketAddr) -> Option<IpAddr> {
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.ok()?;
+    probe.connect(server).await.ok()?;
+    probe.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// Find the tunnel's public address with a binding request over TCP.
+async fn detect_tunnel(resolver: &Resolver) -> TunnelReport {
+    let (host, port) = TUNNEL_SERVER;
+    let query = async {
+        let ip = resolver.resolve_host(host).await?;
+        tokio::time::timeout(TCP_TIMEOUT, query_tcp(resolver.netstack(), SocketAddr::new(ip, port)))
+            .await
+            .map_err(|_| TunnelError::Timeout)?
+    };
+    match query.await {
+        Ok(addr) => TunnelReport {
+            public_address: Some(addr.to_string()),
+            error: None,
+        },
+        Err(e) => TunnelReport {
+            public_address: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Run the direct test, and the tunnel one if `resolver` is given, concurrently.
+pub async fn detect(resolver: Option<Arc<Resolver>>) -> Report {
+    let tunnel = async {
+        match &resolver {
+            Some(resolver) => Some(detect_tunnel(resolver).await),
+            None => None,
+        }
+    };
+    let (direct, tunnel) = tokio::join!(detect_direct(), tunnel);
+    Report { direct, tunnel }
+}
@@ -0,0 +1,45 @@
+//! Detection of the machine sleeping.
+//!
+//! A task checking the clocks at a fixed interval notices a sleep as a check
+//! that comes far too late. Most platforms stop the monotonic clock while
+//! asleep, so the gap is measured on the wall clock as well.
+
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the clocks are checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How late a check must be to count as a sleep; well above scheduling
+/// delays on a busy machine.
+const MIN_SLEEP: Duration = Duration::from_secs(20);
+
+pub struct SleepDetector {
+    instant: Instant,
+    wall: SystemTime,
+}
+
+impl SleepDetector {
+    pub fn new() -> Self {
+        Self {
+            instant: Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+
+    /// Check the clocks, returning how long the machine slept since the
+    /// last check, if it did.
+    ///
+    /// A wall clock set forward, e.g. by NTP, also reads as a sleep; the
+    /// resulting re-handshake is harmless.
+    pub fn check(&mut self) -> Option<Duration> {
+        let (instant, wall) = (Instant::now(), SystemTime::now());
+        let monotonic = instant.duration_since(self.instant);
+        // A wall clock set backwards reads as no time passing
+        let wall_clock = wall.duration_since(self.wall).unwrap_or_default();
+        self.instant = instant;
+        self.wall = wall;
+
+        let gap = monotonic.max(wall_clock).saturating_sub(CHECK_INTERVAL);
+        (gap >= MIN_SLEEP).then_some(gap)
+    }
+}
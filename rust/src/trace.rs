@@ -0,0 +1,57 @@
+//! Cloudflare trace through the tunnel.
+//!
+//! `/cdn-cgi/trace` reports how Cloudflare sees the request: the egress IP,
+//! the data center (`colo`) and whether it arrived over WARP (`warp=on` or
+//! `warp=plus`). Fetching it through the tunnel confirms traffic really exits
+//! via WARP.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::dns::Resolver;
+use crate::https;
+use crate::TunnelError;
+
+const TRACE_HOSTNAME: &str = "www.cloudflare.com";
+
+/// The trace is a few hundred bytes of `key=value` lines.
+const MAX_RESPONSE: usize = 16 * 1024;
+
+const TRACE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetch the trace and return its fields.
+pub async fn fetch(resolver: &Resolver) -> Result<BTreeMap<String, String>, TunnelError> {
+    tokio::time::timeout(TRACE_TIMEOUT, fetch_inner(resolver))
+        .await
+        .map_err(|_| TunnelError::Timeout)?
+}
+
+async fn fetch_inner(resolver: &Resolver) -> Result<BTreeMap<String, String>, TunnelError> {
+    let ip = resolver.resolve_host(TRACE_HOSTNAME).await?;
+    let request = format!(
+        "GET /cdn-cgi/trace HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Accept: text/plain\r\n\
+         Connection: close\r\n\
+         \r\n",
+        TRACE_HOSTNAME
+    );
+    let (status, body) = https::request(
+        resolver.netstack(),
+        SocketAddr::new(ip, 443),
+        TRACE_HOSTNAME,
+        request.as_bytes(),
+        MAX_RESPONSE,
+    )
+    .await?;
+    if status != 200 {
+        return Err(TunnelError::ConnectionFailed(format!("Trace returned HTTP {}", status)));
+    }
+
+    Ok(String::from_utf8_lossy(&body)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect())
+}
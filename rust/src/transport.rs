@@ -0,0 +1,751 @@
+//! Outer transports for encrypted WireGuard packets.
+//!
+//! `WireGuardTunnel` always sends over its own UDP socket to the peer
+//! endpoint. To carry packets some other way, the tunnel is pointed at a
+//! loopback relay instead, which forwards each datagram over a `Transport`
+//! and sends replies back from the same address, so the tunnel sees a normal
+//! peer.
+//!
+//! Transports are selected with a spec string:
+//! - `udp`: plain UDP to the endpoint, the default; no relay is used.
+//! - `socks5://[user:pass@]host:port`: UDP through a SOCKS5 proxy's UDP ASSOCIATE.
+//! - `ws://host[:port]/path` or `wss://...`: one WebSocket binary message per
+//!   datagram, for a WebSocket-to-UDP bridge in front of the WireGuard server.
+//!
+//! Obfuscation (see `awg`) wraps whichever transport is chosen, and outer
+//! socket options apply to every socket a transport opens; either one also
+//! sends plain UDP through the relay.
+//!
+//! Preserving connections across endpoint changes also needs the relay: it
+//! can switch transports under a running tunnel, where the tunnel's own
+//! socket is fixed to one endpoint.
+//!
+//! With batching on (Linux only, see `udp_batch`), the relay and plain UDP
+//! move datagrams in batches with segmentation offload, and plain UDP goes
+//! through the relay too. The tunnel's own socket belongs to
+//! wireguard-netstack and still takes a call per datagram, so the relay
+//! adds a loopback hop, with a call per datagram on the tunnel's side of it,
+//! in front of the batched network path. It only pays off where the network
+//! side's syscalls are the bottleneck: on a single core, `benches/outer_path.rs`
+//! shows direct UDP delivering more both ways. Until wireguard-netstack can
+//! batch its own socket, batching stays off unless measured to help.
+
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+
+use crate::awg::{self, Obfuscated, Obfuscation};
+use crate::tunnel::{HandshakeEvent, HandshakeStats};
+use crate::udp_batch::{BatchSocket, RecvBatch, MAX_BATCH};
+use crate::websocket::{
+    self, read_frame, write_frame, Frame, Reader, Writer, OP_BINARY, OP_CLOSE, OP_CONTINUATION, OP_PING, OP_PONG,
+};
+
+/// Largest datagram forwarded; WireGuard packets stay well below it.
+const MAX_DATAGRAM: usize = 65535;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+fn transport_error(msg: impl Into<String>) -> io::Error {
+    io::Error::other(msg.into())
+}
+
+/// Carries encrypted WireGuard datagrams to and from the peer.
+pub trait Transport: Send + Sync {
+    /// Send one datagram.
+    fn send<'a>(&'a self, packet: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Receive one datagram into `buf`, returning its length.
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Send several datagrams in order; one at a time unless the transport can batch.
+    fn send_batch<'a>(&'a self, packets: &'a [&'a [u8]]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            for packet in packets {
+                self.send(packet).await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Receive at least one datagram into `batch`; one unless the transport can batch.
+    fn recv_batch<'a>(&'a self, batch: &'a mut RecvBatch) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let n = self.recv(batch.single_slot()).await?;
+            batch.push_single(n, None);
+            Ok(())
+        })
+    }
+
+    /// Change the DSCP codepoint on the sockets datagrams leave through, or
+    /// clear it. Transports that cannot change it in place return `Unsupported`.
+    fn set_dscp(&self, _dscp: Option<u8>) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Which transport to use, parsed from a spec string.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum TransportConfig {
+    #[default]
+    Udp,
+    Socks5 {
+        proxy: String,
+        auth: Option<(String, String)>,
+    },
+    WebSocket {
+        tls: bool,
+        host: String,
+        port: u16,
+        path: String,
+    },
+}
+
+impl TransportConfig {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        if spec.is_empty() || spec.eq_ignore_ascii_case("udp") {
+            return Ok(Self::Udp);
+        }
+        let (scheme, rest) = spec
+            .split_once("://")
+            .ok_or_else(|| format!("Invalid transport: {}", spec))?;
+        match scheme.to_ascii_lowercase().as_str() {
+            "socks5" => {
+                let (auth, proxy) = match rest.rsplit_once('@') {
+                    Some((auth, proxy)) => {
+                        let (user, pass) = auth
+                            .split_once(':')
+                            .ok_or_else(|| "SOCKS5 credentials must be user:pass".to_string())?;
+                        if user.len() > 255 || pass.len() > 255 {
+                            return Err("SOCKS5 credentials are too long".into());
+                        }
+                        (Some((user.to_string(), pass.to_string())), proxy)
+                    }
+                    None => (None, rest),
+                };
+                let proxy = proxy.trim_end_matches('/');
+                if proxy.rsplit_once(':').is_none_or(|(_, port)| port.parse::<u16>().is_err()) {
+                    return Err(format!("SOCKS5 proxy must be host:port: {}", proxy));
+                }
+                Ok(Self::Socks5 { proxy: proxy.to_string(), auth })
+            }
+            scheme @ ("ws" | "wss") => {
+                let tls = scheme == "wss";
+                let (authority, path) = match rest.find('/') {
+                    Some(i) => (&rest[..i], &rest[i..]),
+                    None => (rest, "/"),
+                };
+                let (host, port) = match authority.rsplit_once(':') {
+                    // The colon is not the one inside a bracketed IPv6 address
+                    Some((host, port)) if !port.contains(']') => {
+                        let port = port.parse().map_err(|_| format!("Invalid WebSocket port: {}", port))?;
+                        (host, port)
+                    }
+                    _ => (authority, if tls { 443 } else { 80 }),
+                };
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                if host.is_empty() {
+                    return Err(format!("Invalid WebSocket URL: {}", spec));
+                }
+                Ok(Self::WebSocket {
+                    tls,
+                    host: host.to_string(),
+                    port,
+                    path: path.to_string(),
+                })
+            }
+            _ => Err(format!("Unknown transport: {}", scheme)),
+        }
+    }
+
+    /// Whether datagrams are addressed to the WireGuard endpoint. A WebSocket
+    /// bridge forwards to a server of its own choosing, so trying other
+    /// endpoints through it is pointless.
+    pub fn reaches_endpoint(&self) -> bool {
+        !matches!(self, Self::WebSocket { .. })
+    }
+}
+
+/// Where outer sockets send from, so they bypass a system VPN or match
+/// host firewall rules.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Local address to bind to.
+    pub bind_address: Option<IpAddr>,
+    /// Network interface to send through (Linux and Android).
+    pub interface: Option<String>,
+    /// Firewall mark, SO_MARK (Linux and Android; needs CAP_NET_ADMIN).
+    pub mark: Option<u32>,
+    /// DSCP codepoint for outgoing packets, so routers running SQM can
+    /// prioritize them, e.g. 46 (EF) or 32 (CS4).
+    pub dscp: Option<u8>,
+}
+
+impl SocketOptions {
+    fn socket(&self, peer: SocketAddr, ty: Type) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(peer), ty, None)?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            if let Some(interface) = &self.interface {
+                socket.bind_device(Some(interface.as_bytes()))?;
+            }
+            if let Some(mark) = self.mark {
+                socket.set_mark(mark)?;
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        if self.interface.is_some() || self.mark.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Interface binding and firewall marks need Linux or Android; bind to an address instead",
+            ));
+        }
+        match self.bind_address {
+            Some(ip) => socket.bind(&SocketAddr::new(ip, 0).into())?,
+            None if ty == Type::DGRAM => socket.bind(&unspecified_for(peer).into())?,
+            None => {}
+        }
+        if let Some(dscp) = self.dscp {
+            set_dscp(SockRef::from(&socket), peer.is_ipv6(), dscp)?;
+        }
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+
+    /// A UDP socket for sending to `peer`.
+    fn udp(&self, peer: SocketAddr) -> io::Result<UdpSocket> {
+        UdpSocket::from_std(self.socket(peer, Type::DGRAM)?.into())
+    }
+
+    /// A TCP connection to `peer`.
+    async fn tcp(&self, peer: SocketAddr) -> io::Result<TcpStream> {
+        let socket = TcpSocket::from_std_stream(self.socket(peer, Type::STREAM)?.into());
+        socket.connect(peer).await
+    }
+
+    /// A TCP connection to the first address `host` resolves to that accepts one.
+    async fn tcp_host(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in tokio::net::lookup_host((host, port)).await? {
+            match self.tcp(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| transport_error(format!("{} did not resolve", host))))
+    }
+}
+
+/// Set the DSCP bits of the IPv4 TOS or IPv6 traffic class, leaving ECN clear.
+fn set_dscp(socket: SockRef, ipv6: bool, dscp: u8) -> io::Result<()> {
+    let tos = u32::from(dscp) << 2;
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    if ipv6 {
+        return socket.set_tclass_v6(tos);
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    if ipv6 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "DSCP marking over IPv6 needs Linux, Android or macOS",
+        ));
+    }
+    socket.set_tos_v4(tos)
+}
+
+/// Mark `socket` with `dscp`, or clear the mark, after it was created.
+fn update_dscp(socket: &UdpSocket, dscp: Option<u8>) -> io::Result<()> {
+    let ipv6 = socket.local_addr()?.is_ipv6();
+    set_dscp(SockRef::from(socket), ipv6, dscp.unwrap_or(0))
+}
+
+/// Outer transport, obfuscation and socket options, chosen at tunnel start.
+#[derive(Clone, Debug, Default)]
+pub struct OuterConfig {
+    pub transport: TransportConfig,
+    pub obfuscation: Option<Obfuscation>,
+    pub socket: SocketOptions,
+    /// Batch outer UDP sends and receives, on Linux.
+    pub batching: bool,
+    /// Keep tunneled connections open across endpoint changes by moving the
+    /// relay to the new endpoint instead of rebuilding the tunnel.
+    pub preserve_connections: bool,
+}
+
+impl OuterConfig {
+    /// Whether the tunnel can send straight from its own UDP socket.
+    pub fn is_direct(&self) -> bool {
+        self.transport == TransportConfig::Udp
+            && self.obfuscation.is_none()
+            && self.socket == SocketOptions::default()
+            && !self.batching
+            && !self.preserve_connections
+    }
+}
+
+/// Open the outer transport to `peer`, obfuscated if configured.
+pub async fn open(outer: &OuterConfig, peer: SocketAddr) -> io::Result<Box<dyn Transport>> {
+    let transport = connect(&outer.transport, &outer.socket, outer.batching, peer).await?;
+    Ok(match &outer.obfuscation {
+        Some(params) => Box::new(Obfuscated::new(transport, params.clone())),
+        None => transport,
+    })
+}
+
+/// Open a transport to `peer`.
+async fn connect(
+    config: &TransportConfig,
+    options: &SocketOptions,
+    batching: bool,
+    peer: SocketAddr,
+) -> io::Result<Box<dyn Transport>> {
+    Ok(match config {
+        TransportConfig::Udp => Box::new(UdpTransport::connect(options, batching, peer).await?),
+        TransportConfig::Socks5 { proxy, auth } => {
+            Box::new(Socks5Transport::connect(options, proxy, auth.as_ref(), peer).await?)
+        }
+        TransportConfig::WebSocket { tls, host, port, path } => {
+            Box::new(WebSocketTransport::connect(options, *tls, host, *port, path).await?)
+        }
+    })
+}
+
+// ============================================================================
+// Plain UDP
+// ============================================================================
+
+struct UdpTransport {
+    socket: BatchSocket,
+}
+
+impl UdpTransport {
+    async fn connect(options: &SocketOptions, batching: bool, peer: SocketAddr) -> io::Result<Self> {
+        let socket = options.udp(peer)?;
+        socket.connect(peer).await?;
+        Ok(Self {
+            socket: BatchSocket::new(socket, batching),
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send<'a>(&'a self, packet: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { self.socket.socket().send(packet).await.map(|_| ()) })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(self.socket.socket().recv(buf))
+    }
+
+    fn send_batch<'a>(&'a self, packets: &'a [&'a [u8]]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(self.socket.send_batch(packets, None))
+    }
+
+    fn recv_batch<'a>(&'a self, batch: &'a mut RecvBatch) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { self.socket.recv_batch(batch).await.map(|_| ()) })
+    }
+
+    fn set_dscp(&self, dscp: Option<u8>) -> io::Result<()> {
+        update_dscp(self.socket.socket(), dscp)
+    }
+}
+
+fn unspecified_for(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+// ============================================================================
+// SOCKS5 UDP ASSOCIATE (RFC 1928)
+// ============================================================================
+
+struct Socks5Transport {
+    socket: UdpSocket,
+    /// SOCKS5 UDP request header addressing `peer`, prepended to each datagram.
+    header: Vec<u8>,
+    /// Reused per datagram; only the relay's forwarding tasks send and
+    /// receive, one each, so neither lock is contended.
+    send_buf: Mutex<Vec<u8>>,
+    recv_buf: Mutex<Box<[u8]>>,
+    /// The association lasts as long as this connection stays open.
+    _control: TcpStream,
+}
+
+/// Encode `addr` as a SOCKS5 ATYP, address and port.
+fn socks_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut out = Vec::with_capacity(19);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(1);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(4);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+impl Socks5Transport {
+    async fn connect(
+        options: &SocketOptions,
+        proxy: &str,
+        auth: Option<&(String, String)>,
+        peer: SocketAddr,
+    ) -> io::Result<Self> {
+        let (host, port) = proxy
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host.trim_start_matches('[').trim_end_matches(']'), port.parse().ok()?)))
+            .ok_or_else(|| transport_error(format!("Invalid SOCKS5 proxy: {}", proxy)))?;
+        let mut control = options.tcp_host(host, port).await?;
+        let proxy_ip = control.peer_addr()?.ip();
+
+        // Greeting: no authentication, or username/password (RFC 1929)
+        let method = if auth.is_some() { 2 } else { 0 };
+        control.write_all(&[5, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        control.read_exact(&mut reply).await?;
+        if reply != [5, method] {
+            return Err(transport_error("SOCKS5 proxy refused the authentication method"));
+        }
+        if let Some((user, pass)) = auth {
+            let mut request = vec![1, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(pass.len() as u8);
+            request.extend_from_slice(pass.as_bytes());
+            control.write_all(&request).await?;
+            control.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(transport_error("SOCKS5 authentication failed"));
+            }
+        }
+
+        // UDP ASSOCIATE; our sending address is not known yet, so send zeros
+        let unspecified = unspecified_for(SocketAddr::new(proxy_ip, 0));
+        let socket = options.udp(unspecified)?;
+        let mut request = vec![5, 3, 0];
+        request.extend_from_slice(&socks_addr(unspecified));
+        control.write_all(&request).await?;
+        let mut head = [0u8; 4];
+        control.read_exact(&mut head).await?;
+        if head[1] != 0 {
+            return Err(transport_error(format!("SOCKS5 UDP ASSOCIATE failed with code {}", head[1])));
+        }
+        let relay_ip: IpAddr = match head[3] {
+            1 => {
+                let mut ip = [0u8; 4];
+                control.read_exact(&mut ip).await?;
+                ip.into()
+            }
+            4 => {
+                let mut ip = [0u8; 16];
+                control.read_exact(&mut ip).await?;
+                ip.into()
+            }
+            atyp => return Err(transport_error(format!("Unsupported SOCKS5 relay address type {}", atyp))),
+        };
+        let mut port = [0u8; 2];
+        control.read_exact(&mut port).await?;
+        // Proxies often report an unspecified address, meaning their own
+        let relay_ip = if relay_ip.is_unspecified() { proxy_ip } else { relay_ip };
+        let relay = SocketAddr::new(relay_ip, u16::from_be_bytes(port));
+        socket.connect(relay).await?;
+        log::info!("SOCKS5 proxy {} relays UDP through {}", proxy, relay);
+
+        let mut header = vec![0, 0, 0];
+        header.extend_from_slice(&socks_addr(peer));
+        Ok(Self {
+            socket,
+            send_buf: Mutex::new(Vec::with_capacity(header.len() + MAX_DATAGRAM)),
+            recv_buf: Mutex::new(vec![0u8; MAX_DATAGRAM].into_boxed_slice()),
+            header,
+            _control: control,
+        })
+    }
+}
+
+impl Transport for Socks5Transport {
+    fn send<'a>(&'a self, packet: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut datagram = self.send_buf.lock().await;
+            datagram.clear();
+            datagram.extend_from_slice(&self.header);
+            datagram.extend_from_slice(packet);
+            self.socket.send(&datagram).await.map(|_| ())
+        })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            let mut datagram = self.recv_buf.lock().await;
+            loop {
+                let n = self.socket.recv(&mut datagram).await?;
+                let header_len = match datagram.get(3) {
+                    Some(1) => 10,
+                    Some(4) => 22,
+                    _ => continue,
+                };
+                // Fragments (non-zero FRAG) are dropped, as RFC 1928 allows
+                if n < header_len || datagram[2] != 0 {
+                    continue;
+                }
+                let payload = &datagram[header_len..n];
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                return Ok(len);
+            }
+        })
+    }
+
+    fn set_dscp(&self, dscp: Option<u8>) -> io::Result<()> {
+        update_dscp(&self.socket, dscp)
+    }
+}
+
+// ============================================================================
+// WebSocket (RFC 6455)
+// ============================================================================
+
+struct WebSocketTransport {
+    reader: Mutex<Reader>,
+    writer: Arc<Mutex<Writer>>,
+}
+
+impl WebSocketTransport {
+    async fn connect(options: &SocketOptions, tls: bool, host: &str, port: u16, path: &str) -> io::Result<Self> {
+        let tcp = options.tcp_host(host, port).await?;
+        tcp.set_nodelay(true)?;
+        let (reader, mut writer): (Box<dyn AsyncRead + Send + Unpin>, Writer) = if tls {
+            let server_name = ServerName::try_from(host.to_string())
+                .map_err(|_| transport_error(format!("Invalid hostname: {}", host)))?;
+            let connector = crate::https::tls_connector().map_err(|e| transport_error(e.to_string()))?;
+            let (r, w) = tokio::io::split(connector.connect(server_name, tcp).await?);
+            (Box::new(r), Box::new(w))
+        } else {
+            let (r, w) = tcp.into_split();
+            (Box::new(r), Box::new(w))
+        };
+
+        let host_header = format!("{}:{}", host, port);
+        let (reader, _) = websocket::handshake(reader, &mut writer, &host_header, path, &[]).await?;
+        log::info!("WebSocket transport connected to {}:{}{}", host, port, path);
+
+        Ok(Self {
+            reader: Mutex::new(reader),
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn send<'a>(&'a self, packet: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { write_frame(&mut *self.writer.lock().await, OP_BINARY, packet).await })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            let mut reader = self.reader.lock().await;
+            loop {
+                let Frame { fin, opcode, payload } = read_frame(&mut reader, MAX_DATAGRAM).await?;
+                if !fin || opcode == OP_CONTINUATION {
+                    // Bridges send each datagram as one frame
+                    return Err(transport_error("Fragmented WebSocket messages are not supported"));
+                }
+                match opcode {
+                    OP_BINARY => {
+                        let len = payload.len().min(buf.len());
+                        buf[..len].copy_from_slice(&payload[..len]);
+                        return Ok(len);
+                    }
+                    OP_PING => write_frame(&mut *self.writer.lock().await, OP_PONG, &payload).await?,
+                    OP_CLOSE => return Err(io::ErrorKind::ConnectionAborted.into()),
+                    // Pongs and text messages carry no datagrams
+                    _ => {}
+                }
+            }
+        })
+    }
+}
+
+// ============================================================================
+// Loopback relay
+// ============================================================================
+
+/// A loopback UDP socket standing in for the peer, forwarding over a transport.
+///
+/// With batching, each direction forwards whatever has queued up, up to
+/// `MAX_BATCH` messages, per call on either side. The transport can be
+/// switched while the tunnel keeps sending to the same socket. Stops
+/// forwarding when dropped.
+pub struct Relay {
+    local: SocketAddr,
+    socket: Arc<BatchSocket>,
+    /// The tunnel's socket, pinned to the source of the first datagram;
+    /// other local processes cannot redirect the peer's replies to themselves.
+    tunnel: Arc<parking_lot::Mutex<Option<SocketAddr>>>,
+    handshakes: Arc<HandshakeStats>,
+    /// Whether a handshake has completed; later ones replace the session.
+    established: Arc<AtomicBool>,
+    transport: Arc<dyn Transport>,
+    tasks: JoinSet<()>,
+}
+
+impl Relay {
+    /// Handshake messages passing through are counted in `handshakes`.
+    pub async fn start(
+        transport: Box<dyn Transport>,
+        handshakes: Arc<HandshakeStats>,
+        batching: bool,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let local = socket.local_addr()?;
+        let mut relay = Self {
+            local,
+            socket: Arc::new(BatchSocket::new(socket, batching)),
+            tunnel: Arc::default(),
+            handshakes,
+            established: Arc::default(),
+            transport: transport.into(),
+            tasks: JoinSet::new(),
+        };
+        relay.spawn_tasks();
+        Ok(relay)
+    }
+
+    fn spawn_tasks(&mut self) {
+        let batch_size = if self.socket.is_batched() { MAX_BATCH } else { 1 };
+
+        let (sock, trans, from) = (self.socket.clone(), self.transport.clone(), self.tunnel.clone());
+        let stats = self.handshakes.clone();
+        self.tasks.spawn(async move {
+            let mut batch = RecvBatch::new(batch_size);
+            loop {
+                if let Err(e) = sock.recv_batch(&mut batch).await {
+                    log::warn!("Transport relay receive failed: {}", e);
+                    return;
+                }
+                let mut packets = Vec::with_capacity(batch_size);
+                for (packet, src) in batch.datagrams() {
+                    let Some(src) = src.filter(|src| src.ip().is_loopback()) else {
+                        continue;
+                    };
+                    if *from.lock().get_or_insert(src) != src {
+                        log::trace!("Dropped datagram to the transport relay from {}", src);
+                        continue;
+                    }
+                    if awg::message_type(packet) == Some(awg::HANDSHAKE_INIT) {
+                        stats.record(HandshakeEvent::Attempt);
+                    }
+                    packets.push(packet);
+                }
+                if let Err(e) = trans.send_batch(&packets).await {
+                    log::debug!("Transport send failed: {}", e);
+                }
+            }
+        });
+
+        let (socket, trans, tunnel) = (self.socket.clone(), self.transport.clone(), self.tunnel.clone());
+        let (handshakes, established) = (self.handshakes.clone(), self.established.clone());
+        self.tasks.spawn(async move {
+            let mut batch = RecvBatch::new(batch_size);
+            loop {
+                if let Err(e) = trans.recv_batch(&mut batch).await {
+                    // The endpoint watchdog notices the silence and reconnects
+                    log::warn!("Transport closed: {}", e);
+                    return;
+                }
+                let mut packets = Vec::with_capacity(batch_size);
+                for (packet, _) in batch.datagrams() {
+                    if awg::message_type(packet) == Some(awg::HANDSHAKE_RESPONSE) {
+                        let event = if established.swap(true, Ordering::Relaxed) {
+                            HandshakeEvent::Rekey
+                        } else {
+                            HandshakeEvent::Success
+                        };
+                        handshakes.record(event);
+                    }
+                    packets.push(packet);
+                }
+                let Some(to) = *tunnel.lock() else {
+                    continue;
+                };
+                if let Err(e) = socket.send_batch(&packets, Some(to)).await {
+                    log::debug!("Transport relay send failed: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Address the tunnel sends to in place of the peer.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    /// Forward over `transport` from now on, dropping the current one.
+    ///
+    /// The tunnel keeps its socket and session; packets in flight on the old
+    /// transport are lost, as on any path change.
+    pub fn switch(&mut self, transport: Box<dyn Transport>) {
+        self.tasks.abort_all();
+        self.transport = transport.into();
+        self.spawn_tasks();
+    }
+
+    /// Change the DSCP codepoint on the transport's sockets.
+    pub fn set_dscp(&self, dscp: Option<u8>) -> io::Result<()> {
+        self.transport.set_dscp(dscp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap()
+    }
+
+    #[test]
+    fn relay_only_serves_the_first_local_sender() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let peer = bind().await;
+            let options = SocketOptions::default();
+            let transport = UdpTransport::connect(&options, false, peer.local_addr().unwrap()).await.unwrap();
+            let relay = Relay::start(Box::new(transport), Arc::default(), false).await.unwrap();
+            let (tunnel, other) = (bind().await, bind().await);
+            let mut buf = [0u8; 64];
+
+            tunnel.send_to(b"tunnel", relay.local_addr()).await.unwrap();
+            let (n, outer) = peer.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"tunnel");
+
+            other.send_to(b"other", relay.local_addr()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            peer.send_to(b"reply", outer).await.unwrap();
+            let wait = Duration::from_millis(200);
+            let n = tokio::time::timeout(wait, tunnel.recv(&mut buf)).await.expect("no reply").unwrap();
+            assert_eq!(&buf[..n], b"reply");
+
+            assert!(tokio::time::timeout(wait, peer.recv_from(&mut buf)).await.is_err(), "forwarded the other sender");
+            assert!(tokio::time::timeout(wait, other.recv(&mut buf)).await.is_err(), "replied to the other sender");
+        });
+    }
+}
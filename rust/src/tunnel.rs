@@ -0,0 +1,491 @@
+//! WireGuard tunnel with pausable background tasks.
+//!
+//! Assembles the same pieces as wireguard-netstack's `ManagedTunnel`, but owns
+//! the task set so the WireGuard and netstack loops can be stopped and
+//! restarted without tearing down the netstack or its sockets.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::JoinSet;
+use wireguard_netstack::{NetStack, WireGuardConfig, WireGuardTunnel};
+
+use crate::capture::PacketCapture;
+use crate::icmp::Pinger;
+use crate::transport::{self, OuterConfig, Relay};
+use crate::udp::{UdpPorts, UdpSocket};
+use crate::TunnelError;
+
+/// How long to wait for the initial handshake, as in `ManagedTunnel::connect`.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the netstack is polled when no packets arrive, to run TCP
+/// timers such as retransmits and delayed ACKs. Kept under smoltcp's 10ms
+/// ACK delay.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Counters for packets received from WireGuard, updated by the RX loop.
+#[derive(Default)]
+pub struct RxCounters {
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+    last_packet: Mutex<Option<Instant>>,
+}
+
+impl RxCounters {
+    fn record(&self, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        *self.last_packet.lock() = Some(Instant::now());
+    }
+
+    /// When the last packet arrived, if any.
+    pub fn last_packet(&self) -> Option<Instant> {
+        *self.last_packet.lock()
+    }
+}
+
+/// Events sent to the tunnel listener; values match `HANDSHAKE_EVENT_*` in Native.java.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(i32)]
+pub enum HandshakeEvent {
+    /// A handshake initiation was sent.
+    Attempt = 0,
+    /// The first handshake of a tunnel completed.
+    Success = 1,
+    /// A tunnel gave up waiting for its first handshake.
+    Failure = 2,
+    /// A later handshake completed, replacing the session.
+    Rekey = 3,
+}
+
+/// Events buffered per listener before the oldest are dropped.
+const HANDSHAKE_EVENT_BACKLOG: usize = 64;
+
+/// Handshake counters across all tunnels.
+///
+/// Through the relay, every initiation sent and response received is seen.
+/// A direct tunnel's socket belongs to wireguard-netstack, so only the
+/// handshakes we start are counted there, and rekeys are not seen at all
+/// (see UPSTREAM.md).
+pub struct HandshakeStats {
+    pub attempts: AtomicU64,
+    /// Completed handshakes, rekeys included.
+    pub successes: AtomicU64,
+    pub failures: AtomicU64,
+    pub rekeys: AtomicU64,
+    last_success: Mutex<Option<Instant>>,
+    events: broadcast::Sender<HandshakeEvent>,
+}
+
+impl Default for HandshakeStats {
+    fn default() -> Self {
+        Self {
+            attempts: AtomicU64::new(0),
+            successes: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            rekeys: AtomicU64::new(0),
+            last_success: Mutex::new(None),
+            events: broadcast::channel(HANDSHAKE_EVENT_BACKLOG).0,
+        }
+    }
+}
+
+impl HandshakeStats {
+    pub fn record(&self, event: HandshakeEvent) {
+        let counter = match event {
+            HandshakeEvent::Attempt => &self.attempts,
+            HandshakeEvent::Success => &self.successes,
+            HandshakeEvent::Failure => &self.failures,
+            HandshakeEvent::Rekey => {
+                self.successes.fetch_add(1, Ordering::Relaxed);
+                &self.rekeys
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if matches!(event, HandshakeEvent::Success | HandshakeEvent::Rekey) {
+            *self.last_success.lock() = Some(Instant::now());
+        }
+        // No receivers just means no listener is registered
+        let _ = self.events.send(event);
+    }
+
+    /// When the last handshake we saw completed.
+    pub fn last_success(&self) -> Option<Instant> {
+        *self.last_success.lock()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<HandshakeEvent> {
+        self.events.subscribe()
+    }
+}
+
+pub struct Tunnel {
+    wg_tunnel: Arc<WireGuardTunnel>,
+    netstack: Arc<NetStack>,
+    /// Decrypted packets from WireGuard. Held here rather than moved into the
+    /// RX loop so the loop can be restarted after a pause.
+    incoming: Arc<tokio::sync::Mutex<mpsc::Receiver<BytesMut>>>,
+    /// Background loops; dropping the set aborts them.
+    tasks: Mutex<JoinSet<()>>,
+    paused: AtomicBool,
+    capture: PacketCapture,
+    rx: Arc<RxCounters>,
+    /// Matches echo replies for `ping` before inbound packets reach the netstack.
+    pinger: Arc<Pinger>,
+    /// Takes inbound datagrams for sockets from `bind_udp`, likewise.
+    udp: Arc<UdpPorts>,
+    /// Wakes the netstack driver when inbound packets are queued.
+    poll_wake: Arc<Notify>,
+    connected_at: Instant,
+    /// WARP endpoint the tunnel sends to.
+    endpoint: Mutex<SocketAddr>,
+    /// Forwards packets over the outer transport, unless the tunnel sends directly.
+    relay: Mutex<Option<Relay>>,
+    handshakes: Arc<HandshakeStats>,
+    /// Whether the relay sees handshakes on the wire; otherwise only those
+    /// we start are counted.
+    observes_handshakes: bool,
+}
+
+impl Tunnel {
+    /// Create the tunnel over `outer`, start its background loops and wait up
+    /// to `handshake_timeout` for the handshake.
+    ///
+    /// Inbound packets are recorded to `capture` whenever it is running, and
+    /// handshakes are counted in `handshakes`.
+    pub async fn connect(
+        mut config: WireGuardConfig,
+        outer: &OuterConfig,
+        capture: PacketCapture,
+        handshakes: Arc<HandshakeStats>,
+        handshake_timeout: Duration,
+    ) -> Result<Self, TunnelError> {
+        let endpoint = config.peer_endpoint;
+        let relay = if outer.is_direct() {
+            None
+        } else {
+            let transport = transport::open(outer, endpoint)
+                .await
+                .map_err(|e| TunnelError::ConnectionFailed(format!("Outer transport failed: {}", e)))?;
+            let relay = Relay::start(transport, handshakes.clone(), outer.batching).await?;
+            config.peer_endpoint = relay.local_addr();
+            Some(relay)
+        };
+        let wg_tunnel = WireGuardTunnel::new(config)
+            .await
+            .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+
+        let incoming = wg_tunnel
+            .take_incoming_receiver()
+            .ok_or_else(|| TunnelError::ConnectionFailed("Failed to get incoming receiver".into()))?;
+
+        let netstack = NetStack::new(wg_tunnel.clone());
+
+        let tunnel = Self {
+            wg_tunnel,
+            netstack,
+            incoming: Arc::new(tokio::sync::Mutex::new(incoming)),
+            tasks: Mutex::new(JoinSet::new()),
+            paused: AtomicBool::new(false),
+            capture,
+            rx: Arc::default(),
+            pinger: Arc::new(Pinger::new()),
+            udp: Arc::new(UdpPorts::new()),
+            poll_wake: Arc::default(),
+            connected_at: Instant::now(),
+            endpoint: Mutex::new(endpoint),
+            observes_handshakes: relay.is_some(),
+            relay: Mutex::new(relay),
+            handshakes,
+        };
+        *tunnel.tasks.lock() = tunnel.spawn_tasks();
+
+        // Give tasks time to start
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        log::info!("Initiating WireGuard handshake...");
+        tunnel.initiate_handshake().await?;
+        if let Err(e) = tunnel.wg_tunnel.wait_for_handshake(handshake_timeout).await {
+            tunnel.handshakes.record(HandshakeEvent::Failure);
+            return Err(TunnelError::ConnectionFailed(e.to_string()));
+        }
+        if !tunnel.observes_handshakes {
+            tunnel.handshakes.record(HandshakeEvent::Success);
+        }
+
+        log::info!("WireGuard tunnel established");
+        Ok(tunnel)
+    }
+
+    fn spawn_tasks(&self) -> JoinSet<()> {
+        let mut tasks = JoinSet::new();
+
+        let wg = self.wg_tunnel.clone();
+        tasks.spawn(async move {
+            if let Err(e) = wg.run_receive_loop().await {
+                log::error!("WireGuard receive loop error: {}", e);
+            }
+        });
+
+        let wg = self.wg_tunnel.clone();
+        tasks.spawn(async move {
+            if let Err(e) = wg.run_send_loop().await {
+                log::error!("WireGuard send loop error: {}", e);
+            }
+        });
+
+        let wg = self.wg_tunnel.clone();
+        tasks.spawn(async move {
+            if let Err(e) = wg.run_timer_loop().await {
+                log::error!("WireGuard timer loop error: {}", e);
+            }
+        });
+
+        // Drives the netstack in place of NetStack::run_poll_loop, which polls
+        // every millisecond whether or not anything happened. Sockets poll on
+        // their own when written to, so only inbound packets and timers are
+        // left to this task.
+        let ns = self.netstack.clone();
+        let wake = self.poll_wake.clone();
+        tasks.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = wake.notified() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+                ns.poll();
+            }
+        });
+
+        // Equivalent of NetStack::run_rx_loop that leaves the receiver in place
+        let ns = self.netstack.clone();
+        let incoming = self.incoming.clone();
+        let capture = self.capture.clone();
+        let counters = self.rx.clone();
+        let pinger = self.pinger.clone();
+        let udp = self.udp.clone();
+        let wake = self.poll_wake.clone();
+        tasks.spawn(async move {
+            let mut rx = incoming.lock().await;
+            while let Some(packet) = rx.recv().await {
+                counters.record(packet.len());
+                capture.record_inbound(&packet);
+                if pinger.accept(&packet) || udp.accept(&packet) {
+                    continue;
+                }
+                ns.push_rx_packet(packet);
+                // A burst queued before the driver runs is handled by one poll
+                wake.notify_one();
+            }
+        });
+
+        tasks
+    }
+
+    /// Our address inside the tunnel.
+    pub fn tunnel_ip(&self) -> Ipv4Addr {
+        self.wg_tunnel.tunnel_ip()
+    }
+
+    pub fn netstack(&self) -> Arc<NetStack> {
+        self.netstack.clone()
+    }
+
+    pub fn rx_counters(&self) -> &RxCounters {
+        &self.rx
+    }
+
+    pub fn endpoint(&self) -> SocketAddr {
+        *self.endpoint.lock()
+    }
+
+    /// When the tunnel was created.
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Stop all background loops, keeping the netstack and its sockets.
+    ///
+    /// Returns false if the tunnel was already paused.
+    pub async fn pause(&self) -> bool {
+        if self.paused.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        let mut tasks = std::mem::take(&mut *self.tasks.lock());
+        tasks.abort_all();
+        while tasks.join_next().await.is_some() {}
+
+        log::info!("WireGuard tunnel paused");
+        true
+    }
+
+    /// Restart the background loops after a pause.
+    ///
+    /// A fresh handshake is started since the session has likely expired, but
+    /// not waited for; connections stall briefly until it completes.
+    /// Returns false if the tunnel was not paused.
+    pub async fn resume(&self) -> Result<bool, TunnelError> {
+        if !self.paused.swap(false, Ordering::SeqCst) {
+            return Ok(false);
+        }
+
+        *self.tasks.lock() = self.spawn_tasks();
+        self.initiate_handshake().await?;
+
+        log::info!("WireGuard tunnel resumed");
+        Ok(true)
+    }
+
+    /// Send an ICMP echo request to `destination` and wait up to `timeout`
+    /// for the reply, returning the round-trip time.
+    pub async fn ping(&self, destination: Ipv4Addr, timeout: Duration) -> Result<Duration, TunnelError> {
+        if self.is_paused() {
+            return Err(TunnelError::NotReady);
+        }
+        let mut echo = self.pinger.request(self.tunnel_ip(), destination);
+        let sent = Instant::now();
+        self.wg_tunnel
+            .send_ip_packet(std::mem::take(&mut echo.packet))
+            .await
+            .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+        match tokio::time::timeout(timeout, &mut echo.reply).await {
+            Ok(Ok(())) => Ok(sent.elapsed()),
+            Ok(Err(_)) => Err(TunnelError::ConnectionFailed("Ping cancelled".into())),
+            Err(_) => Err(TunnelError::Timeout),
+        }
+    }
+
+    /// Open a UDP socket inside the tunnel.
+    pub fn bind_udp(&self) -> Result<UdpSocket, TunnelError> {
+        UdpSocket::bind(self.udp.clone(), self.tunnel_ip(), self.wg_tunnel.clone())
+    }
+
+    /// Start a new handshake right away, e.g. after the local network changed.
+    ///
+    /// Through the relay, the outer transport is first reopened over `outer`,
+    /// so its sockets are bound afresh on the new network. A direct tunnel's
+    /// socket belongs to wireguard-netstack and cannot be rebound, but it is
+    /// bound to the wildcard address, so once the handshake reaches the peer
+    /// it roams to our new source address. Either way the netstack carries on
+    /// unchanged. Returns false if the tunnel is paused.
+    pub async fn rehandshake(&self, outer: &OuterConfig) -> Result<bool, TunnelError> {
+        if self.is_paused() {
+            return Ok(false);
+        }
+
+        if self.relay.lock().is_some() {
+            let endpoint = *self.endpoint.lock();
+            let transport = transport::open(outer, endpoint)
+                .await
+                .map_err(|e| TunnelError::ConnectionFailed(format!("Outer transport failed: {}", e)))?;
+            if let Some(relay) = &mut *self.relay.lock() {
+                relay.switch(transport);
+                log::debug!("Rebound the outer transport to {}", endpoint);
+            }
+        }
+        self.initiate_handshake().await?;
+        // Let pending retransmits go out over the new path without waiting for the timer
+        self.poll_wake.notify_one();
+        Ok(true)
+    }
+
+    /// Move the outer session to `endpoint` over `outer`, keeping the netstack
+    /// and its connections, and wait up to `handshake_timeout` for a handshake
+    /// there.
+    ///
+    /// Only a tunnel sending through the relay can move; returns false for a
+    /// direct or paused one, which has to be rebuilt instead. Connections
+    /// stall until the handshake completes, then retransmit what was lost.
+    pub async fn move_to(
+        &self,
+        endpoint: SocketAddr,
+        outer: &OuterConfig,
+        handshake_timeout: Duration,
+    ) -> Result<bool, TunnelError> {
+        if self.relay.lock().is_none() || self.is_paused() {
+            return Ok(false);
+        }
+        let transport = transport::open(outer, endpoint)
+            .await
+            .map_err(|e| TunnelError::ConnectionFailed(format!("Outer transport failed: {}", e)))?;
+        match &mut *self.relay.lock() {
+            Some(relay) => relay.switch(transport),
+            None => return Ok(false),
+        }
+        *self.endpoint.lock() = endpoint;
+
+        let mut events = self.handshakes.subscribe();
+        self.initiate_handshake().await?;
+        let completed = tokio::time::timeout(handshake_timeout, async {
+            loop {
+                match events.recv().await {
+                    Ok(HandshakeEvent::Success | HandshakeEvent::Rekey) => return true,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return false,
+                }
+            }
+        })
+        .await;
+        if completed != Ok(true) {
+            self.handshakes.record(HandshakeEvent::Failure);
+            return Err(TunnelError::ConnectionFailed(format!("No handshake with {}", endpoint)));
+        }
+        // Let pending retransmits go out over the new path without waiting for the timer
+        self.poll_wake.notify_one();
+        log::info!("WireGuard session moved to {}", endpoint);
+        Ok(true)
+    }
+
+    /// Send a handshake initiation, counting it unless the relay will.
+    async fn initiate_handshake(&self) -> Result<(), TunnelError> {
+        self.wg_tunnel
+            .initiate_handshake()
+            .await
+            .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))?;
+        if !self.observes_handshakes {
+            self.handshakes.record(HandshakeEvent::Attempt);
+        }
+        Ok(())
+    }
+
+    /// Time since the last handshake completed, if it can be known: only the
+    /// relay sees rekeys, so a direct tunnel reports `None`.
+    pub fn handshake_age(&self) -> Option<Duration> {
+        if !self.observes_handshakes {
+            return None;
+        }
+        self.handshakes.last_success().map(|t| t.elapsed())
+    }
+
+    /// Change the DSCP codepoint on outgoing WireGuard packets.
+    ///
+    /// Only possible through the relay; a direct tunnel sends from
+    /// wireguard-netstack's own socket, which cannot be reached.
+    pub fn set_dscp(&self, dscp: Option<u8>) -> io::Result<()> {
+        match &*self.relay.lock() {
+            Some(relay) => relay.set_dscp(dscp),
+            None if dscp.is_none() => Ok(()),
+            None => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    /// Stop all background loops and wait for them to exit.
+    pub async fn shutdown(&self) {
+        let mut tasks = std::mem::take(&mut *self.tasks.lock());
+        tasks.abort_all();
+        while tasks.join_next().await.is_some() {}
+        self.relay.lock().take();
+    }
+}
@@ -0,0 +1,245 @@
+//! UDP through the tunnel.
+//!
+//! Like ICMP echo, datagrams bypass the netstack, which only has TCP
+//! sockets: they are built here as raw IPv4 packets and handed straight to
+//! WireGuard. The tunnel's receive loop offers every inbound packet to
+//! `UdpPorts::accept` before the netstack sees it, which queues datagrams
+//! for the bound ports. Fragmented datagrams are not reassembled, so they
+//! are lost; RakNet sizes its datagrams to the path MTU, so it never sends
+//! any.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Packet, Ipv4Repr, UdpPacket, UdpRepr};
+use tokio::sync::mpsc;
+use wireguard_netstack::WireGuardTunnel;
+
+use crate::TunnelError;
+
+/// Datagrams queued for a socket that is not reading; later ones are dropped,
+/// as a full socket buffer would.
+const QUEUE: usize = 256;
+
+/// Local ports handed out, from the IANA ephemeral range.
+const FIRST_PORT: u16 = 49152;
+const PORTS: u16 = u16::MAX - FIRST_PORT + 1;
+
+const HOP_LIMIT: u8 = 64;
+
+type Datagram = (SocketAddrV4, Bytes);
+
+/// Ports bound by tunnel UDP sockets, and where their datagrams go.
+pub struct UdpPorts {
+    bound: Mutex<Bound>,
+}
+
+struct Bound {
+    sockets: HashMap<u16, mpsc::Sender<Datagram>>,
+    /// Where the search for a free port starts, so ports are not reused at once.
+    next: u16,
+}
+
+impl UdpPorts {
+    pub fn new() -> Self {
+        Self {
+            bound: Mutex::new(Bound { sockets: HashMap::new(), next: 0 }),
+        }
+    }
+
+    fn bind(&self) -> Result<(u16, mpsc::Receiver<Datagram>), TunnelError> {
+        let mut bound = self.bound.lock();
+        for i in 0..PORTS {
+            let port = FIRST_PORT + (bound.next.wrapping_add(i) % PORTS);
+            if bound.sockets.contains_key(&port) {
+                continue;
+            }
+            bound.next = port - FIRST_PORT + 1;
+            let (tx, rx) = mpsc::channel(QUEUE);
+            bound.sockets.insert(port, tx);
+            return Ok((port, rx));
+        }
+        Err(TunnelError::ConnectionFailed("No free UDP port in the tunnel".into()))
+    }
+
+    fn unbind(&self, port: u16) {
+        self.bound.lock().sockets.remove(&port);
+    }
+
+    /// Queue `packet` for the socket bound to its destination port, returning
+    /// false if it is not a UDP datagram for one.
+    pub fn accept(&self, packet: &[u8]) -> bool {
+        let Ok(ip) = Ipv4Packet::new_checked(packet) else {
+            return false;
+        };
+        if ip.next_header() != IpProtocol::Udp || ip.more_frags() || ip.frag_offset() != 0 {
+            return false;
+        }
+        let Ok(udp) = UdpPacket::new_checked(ip.payload()) else {
+            return false;
+        };
+        let Some(tx) = self.bound.lock().sockets.get(&udp.dst_port()).cloned() else {
+            return false;
+        };
+        let source = SocketAddrV4::new(ip.src_addr(), udp.src_port());
+        if tx.try_send((source, Bytes::copy_from_slice(udp.payload()))).is_err() {
+            log::trace!("Dropped UDP datagram from {} for busy port {}", source, udp.dst_port());
+        }
+        true
+    }
+}
+
+/// A UDP socket inside the tunnel, bound to a free local port until dropped.
+pub struct UdpSocket {
+    ports: Arc<UdpPorts>,
+    port: u16,
+    address: Ipv4Addr,
+    wg_tunnel: Arc<WireGuardTunnel>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<Datagram>>,
+}
+
+impl UdpSocket {
+    /// Bind a socket on `address`, our address in the tunnel, sending through `wg_tunnel`.
+    pub fn bind(
+        ports: Arc<UdpPorts>,
+        address: Ipv4Addr,
+        wg_tunnel: Arc<WireGuardTunnel>,
+    ) -> Result<Self, TunnelError> {
+        let (port, incoming) = ports.bind()?;
+        Ok(Self {
+            ports,
+            port,
+            address,
+            wg_tunnel,
+            incoming: tokio::sync::Mutex::new(incoming),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.address, self.port)
+    }
+
+    /// Send `data` to `destination` as one datagram.
+    ///
+    /// Fails if the packet would not fit the tunnel MTU, since it is never
+    /// fragmented.
+    pub async fn send_to(&self, data: &[u8], destination: SocketAddrV4) -> Result<(), TunnelError> {
+        let packet = datagram(self.local_addr(), destination, data);
+        if packet.len() > self.wg_tunnel.mtu() as usize {
+            return Err(TunnelError::ConnectionFailed(format!(
+                "Datagram of {} bytes exceeds the tunnel MTU of {}",
+                data.len(),
+                self.wg_tunnel.mtu()
+            )));
+        }
+        self.wg_tunnel
+            .send_ip_packet(packet)
+            .await
+            .map_err(|e| TunnelError::ConnectionFailed(e.to_string()))
+    }
+
+    /// Wait for the next datagram and its sender, or `None` once the tunnel
+    /// stopped delivering them.
+    pub async fn recv_from(&self) -> Option<(SocketAddrV4, Bytes)> {
+        self.incoming.lock().await.recv().await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.ports.unbind(self.port);
+    }
+}
+
+/// Build the IPv4 packet carrying `data` from `source` to `destination`.
+fn datagram(source: SocketAddrV4, destination: SocketAddrV4, data: &[u8]) -> BytesMut {
+    let udp = UdpRepr { src_port: source.port(), dst_port: destination.port() };
+    let ip = Ipv4Repr {
+        src_addr: *source.ip(),
+        dst_addr: *destination.ip(),
+        next_header: IpProtocol::Udp,
+        payload_len: udp.header_len() + data.len(),
+        hop_limit: HOP_LIMIT,
+    };
+    let mut packet = BytesMut::zeroed(ip.buffer_len() + ip.payload_len);
+    let checksums = ChecksumCapabilities::default();
+    let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet[..]);
+    ip.emit(&mut ip_packet, &checksums);
+    // Path MTU discovery, like RakNet's, relies on oversized datagrams being dropped
+    ip_packet.set_dont_frag(true);
+    ip_packet.fill_checksum();
+    udp.emit(
+        &mut UdpPacket::new_unchecked(ip_packet.payload_mut()),
+        &IpAddress::Ipv4(*source.ip()),
+        &IpAddress::Ipv4(*destination.ip()),
+        data.len(),
+        |payload| payload.copy_from_slice(data),
+        &checksums,
+    );
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 2);
+
+    fn addr(ip: Ipv4Addr, port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(ip, port)
+    }
+
+    #[test]
+    fn datagram_is_valid_udp() {
+        let server = addr(Ipv4Addr::new(203, 0, 113, 7), 19132);
+        let packet = datagram(addr(LOCAL, 50000), server, b"\x01unconnected ping");
+        let ip = Ipv4Packet::new_checked(&packet[..]).unwrap();
+        assert!(ip.verify_checksum());
+        assert!(ip.dont_frag());
+        let udp = UdpPacket::new_checked(ip.payload()).unwrap();
+        assert!(udp.verify_checksum(&IpAddress::Ipv4(LOCAL), &IpAddress::Ipv4(*server.ip())));
+        assert_eq!((udp.src_port(), udp.dst_port()), (50000, 19132));
+        assert_eq!(udp.payload(), b"\x01unconnected ping");
+    }
+
+    #[test]
+    fn datagrams_reach_their_port() {
+        let ports = UdpPorts::new();
+        let (first, mut first_rx) = ports.bind().unwrap();
+        let (second, mut second_rx) = ports.bind().unwrap();
+        assert_ne!(first, second);
+
+        let server = addr(Ipv4Addr::new(203, 0, 113, 7), 19132);
+        assert!(ports.accept(&datagram(server, addr(LOCAL, second), b"pong")));
+        assert_eq!(second_rx.try_recv().unwrap(), (server, Bytes::from_static(b"pong")));
+        assert!(first_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn unbound_ports_and_other_packets_pass_through() {
+        let ports = UdpPorts::new();
+        let (port, _rx) = ports.bind().unwrap();
+        let server = addr(Ipv4Addr::new(203, 0, 113, 7), 19132);
+        assert!(!ports.accept(&datagram(server, addr(LOCAL, port + 1), b"pong")));
+
+        let mut fragment = datagram(server, addr(LOCAL, port), b"pong");
+        Ipv4Packet::new_unchecked(&mut fragment[..]).set_more_frags(true);
+        assert!(!ports.accept(&fragment));
+
+        ports.unbind(port);
+        assert!(!ports.accept(&datagram(server, addr(LOCAL, port), b"pong")));
+    }
+
+    #[test]
+    fn ports_are_not_reused_at_once() {
+        let ports = UdpPorts::new();
+        let (first, _) = ports.bind().unwrap();
+        ports.unbind(first);
+        let (second, _) = ports.bind().unwrap();
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,363 @@
+//! Batched UDP sends and receives.
+//!
+//! On Linux a batch takes one `sendmmsg` or `recvmmsg` call. Runs of
+//! equal-sized datagrams are also handed to the kernel as a single message
+//! with UDP segmentation offload (GSO), and receive offload (GRO) lets the
+//! kernel coalesce arriving datagrams the same way, so a bulk download costs
+//! a fraction of the syscalls and per-packet work. Either offload is dropped
+//! for a socket once the kernel refuses it. Elsewhere each datagram still
+//! takes its own call.
+//!
+//! Kept free of other crate items so `benches/udp_batch.rs` can include it.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::net::UdpSocket;
+
+/// Most datagrams, or coalesced runs of them, moved per call.
+pub const MAX_BATCH: usize = 16;
+
+/// Receive buffer per message; a coalesced run fills up to 64 KiB.
+const SLOT_SIZE: usize = 65535;
+
+/// Largest run sent as one GSO message, staying within one IP packet's
+/// length field as the kernel requires.
+#[cfg(target_os = "linux")]
+const MAX_GSO_BYTES: usize = 65000;
+
+/// Datagrams received by one `BatchSocket::recv_batch`.
+pub struct RecvBatch {
+    slots: Vec<Vec<u8>>,
+    /// Length, GRO segment size (0 if not coalesced) and source of each message.
+    messages: Vec<(usize, usize, Option<SocketAddr>)>,
+}
+
+impl RecvBatch {
+    /// Room for `messages` messages per call, at most `MAX_BATCH`.
+    pub fn new(messages: usize) -> Self {
+        let messages = messages.clamp(1, MAX_BATCH);
+        Self {
+            slots: (0..messages).map(|_| vec![0u8; SLOT_SIZE]).collect(),
+            messages: Vec::with_capacity(messages),
+        }
+    }
+
+    /// Start over, returning the slot to receive a single datagram into.
+    pub fn single_slot(&mut self) -> &mut [u8] {
+        self.messages.clear();
+        &mut self.slots[0]
+    }
+
+    /// Record a datagram of `len` bytes received into `single_slot`.
+    pub fn push_single(&mut self, len: usize, source: Option<SocketAddr>) {
+        self.messages.push((len, 0, source));
+    }
+
+    /// Each datagram received, with its source when the socket reports one,
+    /// splitting coalesced messages back into the datagrams they were.
+    pub fn datagrams(&self) -> impl Iterator<Item = (&[u8], Option<SocketAddr>)> {
+        self.messages.iter().zip(&self.slots).flat_map(|(&(len, segment, source), slot)| {
+            let data = &slot[..len];
+            let segment = if segment == 0 { len.max(1) } else { segment };
+            data.chunks(segment).map(move |datagram| (datagram, source))
+        })
+    }
+}
+
+/// A UDP socket that can move several datagrams per call.
+pub struct BatchSocket {
+    socket: UdpSocket,
+    batched: bool,
+    /// Whether sends still try segmentation offload.
+    gso: AtomicBool,
+    /// Whether the kernel agreed to coalesce what the socket receives.
+    gro: bool,
+}
+
+impl BatchSocket {
+    /// Wrap `socket`, batching its sends and receives if `batched` and the
+    /// platform supports it, with both offloads where the kernel allows.
+    pub fn new(socket: UdpSocket, batched: bool) -> Self {
+        Self::with_offload(socket, batched, true)
+    }
+
+    /// Like `new`, but segmentation and receive offload only if `offload`.
+    pub fn with_offload(socket: UdpSocket, batched: bool, offload: bool) -> Self {
+        let batched = batched && Self::supported();
+        #[cfg(target_os = "linux")]
+        let gro = batched && offload && sys::enable_gro(&socket);
+        #[cfg(not(target_os = "linux"))]
+        let gro = false;
+        Self {
+            socket,
+            batched,
+            gso: AtomicBool::new(batched && offload),
+            gro,
+        }
+    }
+
+    /// Whether batching is available on this platform.
+    pub fn supported() -> bool {
+        cfg!(target_os = "linux")
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn is_batched(&self) -> bool {
+        self.batched
+    }
+
+    /// Wait for datagrams and receive as many as are queued, up to the
+    /// batch's size, returning how many messages arrived.
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        #[cfg(target_os = "linux")]
+        if self.batched {
+            batch.messages.clear();
+            let n = self
+                .socket
+                .async_io(tokio::io::Interest::READABLE, || sys::recvmmsg(&self.socket, batch))
+                .await?;
+            // Without GRO, a message is one datagram however long
+            if !self.gro {
+                for message in &mut batch.messages {
+                    message.1 = 0;
+                }
+            }
+            return Ok(n);
+        }
+        let (len, source) = self.socket.recv_from(batch.single_slot()).await?;
+        batch.push_single(len, Some(source));
+        Ok(1)
+    }
+
+    /// Send `datagrams` in order, to `to` or the connected peer.
+    pub async fn send_batch(&self, datagrams: &[&[u8]], to: Option<SocketAddr>) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.batched {
+            let to = to.map(socket2::SockAddr::from);
+            let mut sent = 0;
+            let mut gather = Vec::new();
+            while sent < datagrams.len() {
+                let gso = self.gso.load(Ordering::Relaxed);
+                let result = self
+                    .socket
+                    .async_io(tokio::io::Interest::WRITABLE, || {
+                        sys::sendmmsg(&self.socket, &datagrams[sent..], to.as_ref(), gso, &mut gather)
+                    })
+                    .await;
+                match result {
+                    Ok(n) => sent += n,
+                    // Kernels before 4.18 and devices without checksum offload refuse GSO
+                    Err(e) if gso && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EIO)) => {
+                        self.gso.store(false, Ordering::Relaxed);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(());
+        }
+        for datagram in datagrams {
+            match to {
+                Some(to) => self.socket.send_to(datagram, to).await?,
+                None => self.socket.send(datagram).await?,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// How many of `datagrams`, from the first, go out as one GSO message: a run
+/// of equal sizes, where only the last may be shorter.
+#[cfg(target_os = "linux")]
+fn gso_run(datagrams: &[&[u8]]) -> usize {
+    let segment = datagrams[0].len();
+    let mut total = 0;
+    let mut run = 0;
+    for datagram in datagrams {
+        if datagram.len() > segment || total + datagram.len() > MAX_GSO_BYTES || segment == 0 {
+            break;
+        }
+        total += datagram.len();
+        run += 1;
+        if datagram.len() < segment {
+            break;
+        }
+    }
+    run.max(1)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::mem::{size_of, zeroed};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::fd::AsRawFd;
+
+    use super::{gso_run, RecvBatch, MAX_BATCH};
+
+    /// Control buffer per message, aligned for `cmsghdr`; room for one int.
+    type Control = [u64; 4];
+
+    pub fn enable_gro(socket: &impl AsRawFd) -> bool {
+        let on: libc::c_int = 1;
+        // SAFETY: passes a valid int of the size given
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                (&on as *const libc::c_int).cast(),
+                size_of::<libc::c_int>() as libc::socklen_t,
+            ) == 0
+        }
+    }
+
+    fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the family says the storage holds a sockaddr_in
+                let addr = unsafe { &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Some(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the family says the storage holds a sockaddr_in6
+                let addr = unsafe { &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                let port = u16::from_be(addr.sin6_port);
+                Some(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id).into())
+            }
+            _ => None,
+        }
+    }
+
+    /// One non-blocking `recvmmsg` into `batch`.
+    pub fn recvmmsg(socket: &impl AsRawFd, batch: &mut RecvBatch) -> io::Result<usize> {
+        // SAFETY: all of these are plain C structs for which zero is valid
+        let mut iovs: [libc::iovec; MAX_BATCH] = unsafe { zeroed() };
+        let mut names: [libc::sockaddr_storage; MAX_BATCH] = unsafe { zeroed() };
+        let mut controls = [Control::default(); MAX_BATCH];
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { zeroed() };
+        let count = batch.slots.len();
+        for (i, slot) in batch.slots.iter_mut().enumerate() {
+            iovs[i].iov_base = slot.as_mut_ptr().cast();
+            iovs[i].iov_len = slot.len();
+            let hdr = &mut msgs[i].msg_hdr;
+            hdr.msg_name = (&mut names[i] as *mut libc::sockaddr_storage).cast();
+            hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iovs[i];
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = controls[i].as_mut_ptr().cast();
+            hdr.msg_controllen = size_of::<Control>() as _;
+        }
+
+        // SAFETY: every header points at buffers that outlive the call
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let n = n as usize;
+        for (msg, name) in msgs[..n].iter().zip(&names) {
+            let mut segment = 0;
+            // SAFETY: the kernel filled in the control buffer for this header
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+                while !cmsg.is_null() {
+                    if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                        segment = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::c_int>()) as usize;
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&msg.msg_hdr, cmsg);
+                }
+            }
+            batch.messages.push((msg.msg_len as usize, segment, socket_addr(name)));
+        }
+        Ok(n)
+    }
+
+    /// One non-blocking `sendmmsg` of as many of `datagrams` as fit in a batch,
+    /// returning how many were sent. Runs are gathered into `gather` for GSO.
+    pub fn sendmmsg(
+        socket: &impl AsRawFd,
+        datagrams: &[&[u8]],
+        to: Option<&socket2::SockAddr>,
+        gso: bool,
+        gather: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        // Each message: first datagram and how many follow it in its run
+        let mut runs = [(0usize, 0usize); MAX_BATCH];
+        let mut count = 0;
+        let mut next = 0;
+        while next < datagrams.len() && count < MAX_BATCH {
+            let run = if gso { gso_run(&datagrams[next..]) } else { 1 };
+            runs[count] = (next, run);
+            count += 1;
+            next += run;
+        }
+        gather.clear();
+        let mut offsets = [0usize; MAX_BATCH];
+        for (i, &(first, run)) in runs[..count].iter().enumerate() {
+            offsets[i] = gather.len();
+            if run > 1 {
+                for datagram in &datagrams[first..first + run] {
+                    gather.extend_from_slice(datagram);
+                }
+            }
+        }
+
+        // SAFETY: all of these are plain C structs for which zero is valid
+        let mut iovs: [libc::iovec; MAX_BATCH] = unsafe { zeroed() };
+        let mut controls = [Control::default(); MAX_BATCH];
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { zeroed() };
+        for i in 0..count {
+            let (first, run) = runs[i];
+            let (base, len) = if run > 1 {
+                let len = datagrams[first..first + run].iter().map(|d| d.len()).sum();
+                (gather[offsets[i]..].as_ptr(), len)
+            } else {
+                (datagrams[first].as_ptr(), datagrams[first].len())
+            };
+            iovs[i].iov_base = base as *mut libc::c_void;
+            iovs[i].iov_len = len;
+            let hdr = &mut msgs[i].msg_hdr;
+            if let Some(to) = to {
+                hdr.msg_name = to.as_ptr() as *mut libc::c_void;
+                hdr.msg_namelen = to.len();
+            }
+            hdr.msg_iov = &mut iovs[i];
+            hdr.msg_iovlen = 1;
+            if run > 1 {
+                hdr.msg_control = controls[i].as_mut_ptr().cast();
+                // SAFETY: the control buffer has room for one u16 message
+                unsafe {
+                    hdr.msg_controllen = libc::CMSG_SPACE(size_of::<u16>() as u32) as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as u32) as _;
+                    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u16>(), datagrams[first].len() as u16);
+                }
+            }
+        }
+
+        // SAFETY: every header points at buffers that outlive the call
+        let n = unsafe {
+            libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), count as libc::c_uint, libc::MSG_DONTWAIT)
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(runs[..n as usize].iter().map(|&(_, run)| run).sum())
+    }
+}
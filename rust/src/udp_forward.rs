@@ -0,0 +1,197 @@
+//! Local UDP port forwards through the tunnel, for Bedrock Edition.
+//!
+//! Geyser or a Bedrock client sends RakNet datagrams to a local UDP socket.
+//! Each client address gets its own UDP socket in the tunnel, NAT-style, so
+//! the server tells clients apart, and its replies go back to that client
+//! from the local socket. RakNet drops a connection after 10 s without
+//! traffic, so a session idle that long is dropped here too. A session
+//! follows the tunnel: once it is replaced, the client's next datagram
+//! starts a new session on the new one.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::tunnel::Tunnel;
+use crate::udp;
+
+/// Drop a session after this long without datagrams either way.
+const SESSION_IDLE: Duration = Duration::from_secs(10);
+
+/// Most clients forwarded at once; datagrams from others are dropped.
+const MAX_SESSIONS: usize = 1024;
+
+/// Largest datagram read from clients; RakNet stays under the path MTU.
+const MAX_DATAGRAM: usize = 2048;
+
+/// The running tunnel, if any, looked up for each new session.
+pub type CurrentTunnel = Arc<dyn Fn() -> Option<Arc<Tunnel>> + Send + Sync>;
+
+/// A running forward; dropping it stops the forward and its sessions.
+pub struct UdpForward {
+    local: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl UdpForward {
+    /// Bind `local` and relay its datagrams to `target` through the tunnel.
+    pub async fn start(local: SocketAddr, target: SocketAddrV4, tunnel: CurrentTunnel) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(local).await?);
+        let local = socket.local_addr()?;
+        let task = tokio::spawn(run(socket, target, tunnel));
+        log::info!("Forwarding UDP from {} to {} through the tunnel", local, target);
+        Ok(Self { local, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+}
+
+impl Drop for UdpForward {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Session {
+    id: u64,
+    tunnel: Arc<Tunnel>,
+    socket: Arc<udp::UdpSocket>,
+    activity: Activity,
+}
+
+async fn run(local: Arc<UdpSocket>, target: SocketAddrV4, tunnel: CurrentTunnel) {
+    let started = Instant::now();
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+    // Each session's return path; aborted along with this task
+    let mut replies = JoinSet::new();
+    let mut next_id = 0;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            received = local.recv_from(&mut buf) => {
+                let (n, client) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        // Such as ICMP port unreachable from a client that went away
+                        log::trace!("UDP forward receive failed: {}", e);
+                        continue;
+                    }
+                };
+                let Some(current) = tunnel() else {
+                    log::trace!("Dropped datagram from {} while the tunnel is down", client);
+                    continue;
+                };
+                let current_session = sessions.get(&client).is_some_and(|s| Arc::ptr_eq(&s.tunnel, &current));
+                if !current_session {
+                    if sessions.len() >= MAX_SESSIONS && !sessions.contains_key(&client) {
+                        log::debug!("Dropped datagram from {}: {} sessions open", client, MAX_SESSIONS);
+                        continue;
+                    }
+                    let socket = match current.bind_udp() {
+                        Ok(socket) => Arc::new(socket),
+                        Err(e) => {
+                            log::warn!("Failed to open a tunnel UDP socket for {}: {}", client, e);
+                            continue;
+                        }
+                    };
+                    next_id += 1;
+                    let activity = Activity { started, last_active: Arc::default() };
+                    let relay = relay_replies(local.clone(), client, target, socket.clone(), activity.clone(), next_id);
+                    replies.spawn(relay);
+                    log::debug!("UDP forward session for {} on {}", client, socket.local_addr());
+                    sessions.insert(client, Session { id: next_id, tunnel: current, socket, activity });
+                }
+                let session = &sessions[&client];
+                session.activity.touch();
+                if let Err(e) = session.socket.send_to(&buf[..n], target).await {
+                    log::debug!("Failed to forward datagram from {}: {}", client, e);
+                }
+            }
+            Some(Ok((client, id))) = replies.join_next() => {
+                // Unless a newer session replaced it
+                if sessions.get(&client).is_some_and(|session| session.id == id) {
+                    log::debug!("UDP forward session for {} ended", client);
+                    sessions.remove(&client);
+                }
+            }
+        }
+    }
+}
+
+/// When a session last saw a datagram either way.
+#[derive(Clone)]
+struct Activity {
+    started: Instant,
+    /// Milliseconds since `started`, shared with the session.
+    last_active: Arc<AtomicU64>,
+}
+
+impl Activity {
+    fn touch(&self) {
+        self.last_active.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle_at(&self) -> Instant {
+        self.started + Duration::from_millis(self.last_active.load(Ordering::Relaxed)) + SESSION_IDLE
+    }
+}
+
+/// Send the server's datagrams on `socket` back to `client` until the
+/// session goes idle or the tunnel stops, returning the session.
+async fn relay_replies(
+    local: Arc<UdpSocket>,
+    client: SocketAddr,
+    target: SocketAddrV4,
+    socket: Arc<udp::UdpSocket>,
+    activity: Activity,
+    id: u64,
+) -> (SocketAddr, u64) {
+    loop {
+        match tokio::time::timeout_at(activity.idle_at().into(), socket.recv_from()).await {
+            Ok(Some((from, data))) => {
+                if from != target {
+                    continue;
+                }
+                activity.touch();
+                if let Err(e) = local.send_to(&data, client).await {
+                    log::debug!("Failed to return datagram to {}: {}", client, e);
+                }
+            }
+            Ok(None) => return (client, id),
+            // Datagrams from the client may have kept it active meanwhile
+            Err(_) if Instant::now() < activity.idle_at() => {}
+            Err(_) => return (client, id),
+        }
+    }
+}
+
+/// Running forwards by handle.
+#[derive(Default)]
+pub struct Registry {
+    next: AtomicI64,
+    forwards: parking_lot::Mutex<HashMap<i64, UdpForward>>,
+}
+
+impl Registry {
+    pub fn insert(&self, forward: UdpForward) -> i64 {
+        let handle = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        self.forwards.lock().insert(handle, forward);
+        handle
+    }
+
+    pub fn remove(&self, handle: i64) -> Option<UdpForward> {
+        self.forwards.lock().remove(&handle)
+    }
+
+    pub fn drain(&self) -> Vec<UdpForward> {
+        self.forwards.lock().drain().map(|(_, forward)| forward).collect()
+    }
+}
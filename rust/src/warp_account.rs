@@ -0,0 +1,207 @@
+//! WARP account calls that warp-wireguard-gen does not expose.
+//!
+//! Talks to the same client API as warp-wireguard-gen, authenticated with the
+//! device's access token from the persisted `WarpCredentials`.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use warp_wireguard_gen::WarpCredentials;
+
+use crate::warp_retry::{self, Retry};
+use crate::TunnelError;
+
+/// Cloudflare WARP API base URL (same API version as warp-wireguard-gen).
+const WARP_API_URL: &str = "https://api.cloudflareclient.com/v0a2483";
+
+/// CF-Client-Version header value expected by the API.
+const WARP_CLIENT_VERSION: &str = "a-6.81-2410012252.0";
+
+/// Kind of WARP account the registered device belongs to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(i32)]
+pub enum AccountType {
+    Unknown = -1,
+    Free = 0,
+    Plus = 1,
+    Team = 2,
+}
+
+#[derive(Deserialize)]
+struct DeviceResponse {
+    account: AccountInfo,
+    /// The device's WireGuard public key, base64.
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    config: Option<DeviceConfig>,
+}
+
+#[derive(Deserialize)]
+struct DeviceConfig {
+    interface: DeviceInterface,
+}
+
+#[derive(Deserialize)]
+struct DeviceInterface {
+    addresses: InterfaceAddresses,
+}
+
+#[derive(Deserialize)]
+struct InterfaceAddresses {
+    #[serde(default)]
+    v4: Option<String>,
+    #[serde(default)]
+    v6: Option<String>,
+}
+
+/// What the API knows about the registered device.
+pub struct DeviceInfo {
+    pub account_type: AccountType,
+    /// WireGuard public key registered for the device, base64.
+    pub public_key: Option<String>,
+    /// Interface addresses assigned to the device, IPv4 first.
+    pub addresses: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct AccountInfo {
+    #[serde(default)]
+    account_type: String,
+    #[serde(default)]
+    warp_plus: bool,
+}
+
+/// Build an HTTP client for the WARP API.
+///
+/// The API rejects TLS 1.3, so this mirrors warp-wireguard-gen and pins TLS 1.2.
+fn client(access_token: &str) -> Result<reqwest::Client, TunnelError> {
+    let tls_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS12])
+    .map_err(|e| TunnelError::WarpApi(format!("TLS config: {}", e)))?
+    .with_root_certificates(Arc::new(rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }))
+    .with_no_client_auth();
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        "CF-Client-Version",
+        reqwest::header::HeaderValue::from_static(WARP_CLIENT_VERSION),
+    );
+    headers.insert(
+        reqwest::header::AUTHORIZATION,
+        format!("Bearer {}", access_token)
+            .parse()
+            .map_err(|_| TunnelError::WarpApi("Invalid access token".into()))?,
+    );
+
+    reqwest::Client::builder()
+        .use_preconfigured_tls(tls_config)
+        .user_agent("1.1.1.1/6.81")
+        .default_headers(headers)
+        .http1_only()
+        .build()
+        .map_err(|e| TunnelError::WarpApi(e.to_string()))
+}
+
+/// A request that failed in a way worth retrying.
+enum ApiFailure {
+    Network(reqwest::Error),
+    Status(reqwest::StatusCode, Option<Duration>),
+}
+
+impl ApiFailure {
+    fn retry(&self) -> Retry {
+        match self {
+            ApiFailure::Network(e) if e.is_builder() => Retry::No,
+            ApiFailure::Network(_) => Retry::After(None),
+            ApiFailure::Status(_, retry_after) => Retry::After(*retry_after),
+        }
+    }
+}
+
+impl fmt::Display for ApiFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiFailure::Network(e) => write!(f, "{}", e),
+            ApiFailure::Status(status, _) => write!(f, "HTTP {}", status),
+        }
+    }
+}
+
+/// Send the request built by `request`, retrying rate limits, server errors
+/// and network failures.
+///
+/// Other error statuses are returned as responses for the caller to handle.
+async fn send(what: &str, request: impl Fn() -> reqwest::RequestBuilder) -> Result<reqwest::Response, TunnelError> {
+    let request = &request;
+    warp_retry::retrying(what, ApiFailure::retry, move || async move {
+        let response = request().send().await.map_err(ApiFailure::Network)?;
+        let status = response.status();
+        match warp_retry::classify_status(status, None) {
+            Retry::After(_) => Err(ApiFailure::Status(status, warp_retry::retry_after(&response))),
+            Retry::No => Ok(response),
+        }
+    })
+    .await
+    .map_err(|e| TunnelError::WarpApi(e.to_string()))
+}
+
+/// Query the account type, public key and interface addresses of the registered device.
+pub async fn device_info(credentials: &WarpCredentials) -> Result<DeviceInfo, TunnelError> {
+    let client = client(&credentials.access_token)?;
+    let url = format!("{}/reg/{}", WARP_API_URL, credentials.device_id);
+    let device: DeviceResponse = send("WARP device lookup", || client.get(&url))
+        .await?
+        .error_for_status()
+        .map_err(|e| TunnelError::WarpApi(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| TunnelError::WarpApi(format!("Failed to parse account: {}", e)))?;
+
+    let account = device.account;
+    let account_type = match account.account_type.as_str() {
+        _ if credentials.is_teams => AccountType::Team,
+        "team" => AccountType::Team,
+        "limited" | "unlimited" => AccountType::Plus,
+        _ if account.warp_plus => AccountType::Plus,
+        _ => AccountType::Free,
+    };
+    let addresses = device
+        .config
+        .map(|config| config.interface.addresses)
+        .map(|addrs| addrs.v4.into_iter().chain(addrs.v6).collect())
+        .unwrap_or_default();
+
+    Ok(DeviceInfo {
+        account_type,
+        public_key: device.key,
+        addresses,
+    })
+}
+
+/// Delete the registered device from the WARP account.
+///
+/// A device the API no longer knows about counts as deleted.
+pub async fn delete_device(credentials: &WarpCredentials) -> Result<(), TunnelError> {
+    let client = client(&credentials.access_token)?;
+    let url = format!("{}/reg/{}", WARP_API_URL, credentials.device_id);
+    let response = send("WARP device deletion", || client.delete(&url)).await?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::UNAUTHORIZED {
+        log::warn!("WARP device {} already gone (HTTP {})", credentials.device_id, status);
+        return Ok(());
+    }
+    response
+        .error_for_status()
+        .map_err(|e| TunnelError::WarpApi(e.to_string()))?;
+
+    log::info!("WARP device {} deleted", credentials.device_id);
+    Ok(())
+}
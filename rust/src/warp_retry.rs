@@ -0,0 +1,101 @@
+//! Retrying WARP API calls through transient failures.
+//!
+//! Rate limits (HTTP 429), server errors and network failures are retried
+//! with jittered exponential backoff, waiting at least as long as the API's
+//! `Retry-After` asks when the response is available. warp-wireguard-gen
+//! turns responses into errors without their headers, so its calls are
+//! retried on backoff alone.
+
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+
+/// Attempts made in total, including the first.
+const MAX_ATTEMPTS: u32 = 4;
+
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Cap on a single wait, including one asked for by `Retry-After`.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Whether a failed call may succeed if repeated.
+pub enum Retry {
+    No,
+    /// Retry, after the delay the server asked for if it gave one.
+    After(Option<Duration>),
+}
+
+/// Classify an error from warp-wireguard-gen.
+pub fn classify(e: &warp_wireguard_gen::Error) -> Retry {
+    use warp_wireguard_gen::Error;
+    match e {
+        Error::Http(e) => match e.status() {
+            Some(status) => classify_status(status, None),
+            // No response at all: timeouts, refused connections, resets
+            None => Retry::After(None),
+        },
+        Error::DnsResolution(_) => Retry::After(None),
+        _ => Retry::No,
+    }
+}
+
+/// Classify an HTTP status, with the response's `Retry-After` if known.
+pub fn classify_status(status: reqwest::StatusCode, retry_after: Option<Duration>) -> Retry {
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        Retry::After(retry_after)
+    } else {
+        Retry::No
+    }
+}
+
+/// Parse a `Retry-After` header given in seconds; HTTP dates are ignored.
+pub fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    value.to_str().ok()?.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Backoff before retry number `attempt` (from 1), between half and all of
+/// `BASE_DELAY * 2^(attempt - 1)` so clients failing together spread out.
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = BASE_DELAY.saturating_mul(1 << (attempt - 1).min(8)).min(MAX_DELAY);
+    let jitter = OsRng.next_u32() as f64 / u32::MAX as f64;
+    ceiling.mul_f64(0.5 + jitter / 2.0)
+}
+
+/// Run `call` until it succeeds, fails in a way `classify` says not to
+/// retry, or `MAX_ATTEMPTS` is reached, returning the last error.
+pub async fn retrying<T, E, F, Fut>(what: &str, classify: impl Fn(&E) -> Retry, mut call: F) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        let e = match call().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let Retry::After(retry_after) = classify(&e) else {
+            return Err(e);
+        };
+        if attempt >= MAX_ATTEMPTS {
+            return Err(e);
+        }
+
+        let delay = backoff(attempt).max(retry_after.unwrap_or_default()).min(MAX_DELAY);
+        log::warn!(
+            "{} failed (attempt {}/{}): {}, retrying in {}ms",
+            what,
+            attempt,
+            MAX_ATTEMPTS,
+            e,
+            delay.as_millis()
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
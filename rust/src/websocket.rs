@@ -0,0 +1,293 @@
+//! WebSocket (RFC 6455) client framing.
+//!
+//! Used by the `ws`/`wss` outer transport, which carries one datagram per
+//! binary frame, and by the `ws*` JNI functions, which carry whole messages
+//! over the tunnel. The server's `Sec-WebSocket-Accept` is not checked;
+//! TLS already authenticates the server for `wss`.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{Mutex, Notify};
+
+pub type Reader = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xA;
+
+/// Largest message `WebSocket::receive` accepts, fragments included.
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+/// Close code sent when the caller gives none (RFC 6455 "normal closure").
+pub const CLOSE_NORMAL: u16 = 1000;
+/// Close code reported when the peer's close frame carried none.
+const CLOSE_NO_STATUS: u16 = 1005;
+
+fn ws_error(msg: impl Into<String>) -> io::Error {
+    io::Error::other(msg.into())
+}
+
+/// Send the upgrade request and read the response head, leaving any frames
+/// that follow buffered.
+///
+/// Returns the reader for frames and the response headers.
+pub async fn handshake(
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    writer: &mut Writer,
+    host_header: &str,
+    path: &str,
+    headers: &[(String, String)],
+) -> io::Result<(Reader, Vec<(String, String)>)> {
+    let mut key = [0u8; 16];
+    OsRng.fill_bytes(&mut key);
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        path,
+        host_header,
+        base64::engine::general_purpose::STANDARD.encode(key)
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    writer.write_all(request.as_bytes()).await?;
+    writer.flush().await?;
+
+    let mut reader = BufReader::new(reader);
+    let mut status = String::new();
+    reader.read_line(&mut status).await?;
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(ws_error(format!("WebSocket upgrade refused: {}", status.trim())));
+    }
+    let mut response_headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if line == "\r\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            response_headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok((reader, response_headers))
+}
+
+/// Write one masked client frame.
+pub async fn write_frame(writer: &mut Writer, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mut mask = [0u8; 4];
+    OsRng.fill_bytes(&mut mask);
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// One frame as read from the peer.
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Read one frame of at most `max_len` payload bytes, unmasking it.
+pub async fn read_frame(reader: &mut Reader, max_len: usize) -> io::Result<Frame> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let (fin, opcode) = (head[0] & 0x80 != 0, head[0] & 0x0F);
+    let len = match head[1] & 0x7F {
+        126 => reader.read_u16().await? as usize,
+        127 => reader.read_u64().await? as usize,
+        len => len as usize,
+    };
+    if len > max_len {
+        return Err(ws_error(format!("WebSocket frame of {} bytes is too large", len)));
+    }
+    let mask = if head[1] & 0x80 != 0 {
+        let mut mask = [0u8; 4];
+        reader.read_exact(&mut mask).await?;
+        Some(mask)
+    } else {
+        None
+    };
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    if let Some(mask) = mask {
+        payload.iter_mut().zip(mask.iter().cycle()).for_each(|(b, m)| *b ^= m);
+    }
+    Ok(Frame { fin, opcode, payload })
+}
+
+/// A complete message received from the peer.
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// The peer closed the connection with this code and reason.
+    Close(u16, String),
+}
+
+/// A WebSocket connection opened with `wsConnect`.
+pub struct WebSocket {
+    reader: Mutex<Reader>,
+    writer: Mutex<Writer>,
+    /// Subprotocol the server picked.
+    protocol: Option<String>,
+    /// Set once either side sent a close frame.
+    closed: AtomicBool,
+    /// Wakes a receive in progress when the socket is closed locally.
+    close_signal: Notify,
+}
+
+impl WebSocket {
+    pub fn new(reader: Reader, writer: Writer, protocol: Option<String>) -> Self {
+        Self {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            protocol,
+            closed: AtomicBool::new(false),
+            close_signal: Notify::new(),
+        }
+    }
+
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    pub async fn send(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        write_frame(&mut *self.writer.lock().await, opcode, payload).await
+    }
+
+    /// Wait for the next complete message, answering pings along the way.
+    ///
+    /// A receive in progress when `close` is called ends with `NotConnected`.
+    pub async fn receive(&self) -> io::Result<Message> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let closed = self.close_signal.notified();
+        tokio::select! {
+            message = self.read_message() => message,
+            _ = closed => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    async fn read_message(&self) -> io::Result<Message> {
+        let mut reader = self.reader.lock().await;
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let frame = read_frame(&mut reader, MAX_MESSAGE).await?;
+            match frame.opcode {
+                OP_PING => {
+                    write_frame(&mut *self.writer.lock().await, OP_PONG, &frame.payload).await?;
+                    continue;
+                }
+                OP_PONG => continue,
+                OP_CLOSE => {
+                    let (code, reason) = match frame.payload.get(..2) {
+                        Some(code) => (
+                            u16::from_be_bytes([code[0], code[1]]),
+                            String::from_utf8_lossy(&frame.payload[2..]).into_owned(),
+                        ),
+                        None => (CLOSE_NO_STATUS, String::new()),
+                    };
+                    // Echo the close unless we started it
+                    if !self.closed.swap(true, Ordering::AcqRel) {
+                        let echo = if code == CLOSE_NO_STATUS { CLOSE_NORMAL } else { code };
+                        let mut writer = self.writer.lock().await;
+                        write_frame(&mut writer, OP_CLOSE, &echo.to_be_bytes()).await?;
+                        let _ = writer.shutdown().await;
+                    }
+                    return Ok(Message::Close(code, reason));
+                }
+                OP_TEXT | OP_BINARY if message.is_none() => message = Some((frame.opcode, frame.payload)),
+                OP_CONTINUATION if message.is_some() => {
+                    let (_, data) = message.as_mut().expect("checked above");
+                    if data.len() + frame.payload.len() > MAX_MESSAGE {
+                        return Err(ws_error("WebSocket message is too large"));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                }
+                opcode => return Err(ws_error(format!("Unexpected WebSocket frame, opcode {}", opcode))),
+            }
+            if frame.fin {
+                let (opcode, data) = message.take().expect("set by a data frame");
+                return match opcode {
+                    OP_TEXT => String::from_utf8(data)
+                        .map(Message::Text)
+                        .map_err(|_| ws_error("WebSocket text message is not UTF-8")),
+                    _ => Ok(Message::Binary(data)),
+                };
+            }
+        }
+    }
+
+    /// Send a close frame and stop sending; the peer's reply is not awaited.
+    pub async fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        self.close_signal.notify_waiters();
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        let mut writer = self.writer.lock().await;
+        write_frame(&mut writer, OP_CLOSE, &payload).await?;
+        writer.shutdown().await
+    }
+}
+
+/// Open WebSockets by handle.
+#[derive(Default)]
+pub struct Registry {
+    next: AtomicI64,
+    sockets: parking_lot::Mutex<HashMap<i64, Arc<WebSocket>>>,
+}
+
+impl Registry {
+    pub fn insert(&self, socket: WebSocket) -> i64 {
+        let handle = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        self.sockets.lock().insert(handle, Arc::new(socket));
+        handle
+    }
+
+    pub fn get(&self, handle: i64) -> Option<Arc<WebSocket>> {
+        self.sockets.lock().get(&handle).cloned()
+    }
+
+    pub fn remove(&self, handle: i64) -> Option<Arc<WebSocket>> {
+        self.sockets.lock().remove(&handle)
+    }
+
+    pub fn drain(&self) -> Vec<Arc<WebSocket>> {
+        self.sockets.lock().drain().map(|(_, socket)| socket).collect()
+    }
+}
@@ -0,0 +1,231 @@
+//! Write-behind buffering for connections.
+//!
+//! A background task sends queued data to the transport, so `tcpWrite` returns
+//! as soon as its data is queued instead of waiting for the netstack or the
+//! peer's window, and only waits while the queue is full. Together with
+//! read-ahead this gives each connection a long-lived reader and writer, with
+//! Java exchanging bytes with them through bounded buffers.
+
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::TunnelError;
+
+#[derive(Default)]
+struct State {
+    data: VecDeque<u8>,
+    /// Whether the task has taken data it has not finished sending.
+    sending: bool,
+    /// Message of the send that failed; the error code is already recorded
+    /// on the connection.
+    failed: Option<String>,
+    /// Whether the task is running.
+    running: bool,
+    /// Whether the connection is closing, so the task exits once the queue is empty.
+    closing: bool,
+    /// Whether the task has exited after closing.
+    closed: bool,
+}
+
+pub struct WriteBehind {
+    capacity: usize,
+    state: Mutex<State>,
+    /// Signalled when data is queued.
+    queued: Notify,
+    /// Signalled when the task makes room in the queue, finishes a send, fails or exits.
+    drained: Notify,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl WriteBehind {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(State::default()),
+            queued: Notify::new(),
+            drained: Notify::new(),
+            task: Mutex::new(None),
+        }
+    }
+
+    pub fn set_task(&self, task: JoinHandle<()>) {
+        self.state.lock().running = true;
+        if let Some(old) = self.task.lock().replace(task) {
+            old.abort();
+        }
+    }
+
+    /// Stop the sending task; anything still queued is dropped.
+    pub fn stop(&self) {
+        if let Some(task) = self.task.lock().take() {
+            task.abort();
+        }
+        {
+            let mut state = self.state.lock();
+            state.data.clear();
+            state.running = false;
+            state.closed = true;
+        }
+        self.drained.notify_waiters();
+    }
+
+    /// Have the task exit once everything queued is sent, returning false if
+    /// it is not running to do so.
+    pub fn close(&self) -> bool {
+        let running = {
+            let mut state = self.state.lock();
+            state.closing = true;
+            state.running
+        };
+        // Wake the task if it waits for data
+        self.queued.notify_one();
+        running
+    }
+
+    /// Called by the task as it exits, returning whether the connection is
+    /// closing, so the task should close the sending side.
+    pub fn finish(&self) -> bool {
+        let mut state = self.state.lock();
+        state.running = false;
+        state.closing
+    }
+
+    /// Called by the task once it has closed the sending side, with how
+    /// sending what the connection still held back went.
+    pub fn set_closed(&self, result: Result<(), TunnelError>) {
+        {
+            let mut state = self.state.lock();
+            state.closed = true;
+            if let Err(e) = result {
+                state.failed.get_or_insert_with(|| e.to_string());
+            }
+        }
+        self.drained.notify_waiters();
+    }
+
+    /// Whether a write would return without waiting.
+    pub fn is_writable(&self) -> bool {
+        let state = self.state.lock();
+        state.data.len() < self.capacity || state.failed.is_some()
+    }
+
+    /// Completes the next time the task makes room, finishes a send, fails or exits.
+    pub fn drained(&self) -> Notified<'_> {
+        self.drained.notified()
+    }
+
+    fn failure(state: &State) -> Option<TunnelError> {
+        state.failed.as_ref().map(|message| TunnelError::ConnectionFailed(message.clone()))
+    }
+
+    /// The error a queued send failed with, if one has.
+    pub fn failed(&self) -> Option<TunnelError> {
+        Self::failure(&self.state.lock())
+    }
+
+    /// Queue `data`, waiting for room unless `non_blocking`.
+    ///
+    /// Blocking writes queue all of `data`; non-blocking ones only what fits,
+    /// failing with `WouldBlock` if that is nothing. Fails once a send has.
+    pub async fn push(&self, data: &[u8], non_blocking: bool) -> Result<usize, TunnelError> {
+        let mut written = 0;
+        loop {
+            let drained = self.drained.notified();
+            {
+                let mut state = self.state.lock();
+                if let Some(e) = Self::failure(&state) {
+                    return Err(e);
+                }
+                let n = self.capacity.saturating_sub(state.data.len()).min(data.len() - written);
+                state.data.extend(&data[written..written + n]);
+                written += n;
+            }
+            if written > 0 {
+                self.queued.notify_one();
+            }
+            if written == data.len() {
+                return Ok(written);
+            }
+            if non_blocking {
+                return if written > 0 { Ok(written) } else { Err(TunnelError::WouldBlock) };
+            }
+            drained.await;
+        }
+    }
+
+    /// Wait for queued data and take up to `max` bytes of it to send, or
+    /// return `None` once the queue is empty and the connection closing.
+    pub async fn take(&self, max: usize) -> Option<Vec<u8>> {
+        loop {
+            let queued = self.queued.notified();
+            {
+                let mut state = self.state.lock();
+                if !state.data.is_empty() {
+                    let n = max.min(state.data.len());
+                    state.sending = true;
+                    let chunk = state.data.drain(..n).collect();
+                    drop(state);
+                    self.drained.notify_waiters();
+                    return Some(chunk);
+                }
+                if state.closing {
+                    return None;
+                }
+            }
+            queued.await;
+        }
+    }
+
+    /// Record how sending the last chunk went, returning false if it failed.
+    ///
+    /// A failure drops whatever is still queued; later writes report it.
+    pub fn sent(&self, result: Result<(), TunnelError>) -> bool {
+        let ok = {
+            let mut state = self.state.lock();
+            state.sending = false;
+            if let Err(e) = result {
+                state.data.clear();
+                state.failed = Some(e.to_string());
+            }
+            state.failed.is_none()
+        };
+        self.drained.notify_waiters();
+        ok
+    }
+
+    /// Wait until everything queued has been handed to the transport.
+    pub async fn wait_empty(&self) -> Result<(), TunnelError> {
+        loop {
+            let drained = self.drained.notified();
+            {
+                let state = self.state.lock();
+                if let Some(e) = Self::failure(&state) {
+                    return Err(e);
+                }
+                if state.data.is_empty() && !state.sending {
+                    return Ok(());
+                }
+            }
+            drained.await;
+        }
+    }
+
+    /// Wait until the task has sent everything queued and exited after
+    /// `close`, failing with the error a send ran into.
+    pub async fn wait_closed(&self) -> Result<(), TunnelError> {
+        loop {
+            let drained = self.drained.notified();
+            {
+                let state = self.state.lock();
+                if state.closed {
+                    return Self::failure(&state).map_or(Ok(()), Err);
+                }
+            }
+            drained.await;
+        }
+    }
+}
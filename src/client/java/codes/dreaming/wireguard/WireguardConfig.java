@@ -2,6 +2,7 @@ package codes.dreaming.wireguard;
 
 import com.google.gson.Gson;
 import com.google.gson.GsonBuilder;
+import com.google.gson.JsonObject;
 import net.fabricmc.loader.api.FabricLoader;
 import org.slf4j.Logger;
 import org.slf4j.LoggerFactory;
@@ -29,6 +30,138 @@ public class WireguardConfig {
      */
     private boolean warpEnabled = true;
 
+    /**
+     * Optional WARP+ license key applied to the registered device.
+     * Null or empty keeps the free account.
+     */
+    private String warpLicenseKey = null;
+
+    /**
+     * MTU of the tunnel interface. 0 uses the native default (1420).
+     */
+    private int mtu = 0;
+
+    /**
+     * WireGuard persistent keepalive interval in seconds. 0 disables it.
+     */
+    private int persistentKeepalive = 25;
+
+    /**
+     * WARP endpoint to use instead of the one from the WARP config, as
+     * "ip", "ip:port", "host" or "host:port". Null or empty uses the config's.
+     */
+    private String endpointOverride = null;
+
+    /**
+     * Base64 preshared key for servers that require one. Null or empty uses none.
+     */
+    private String presharedKey = null;
+
+    /**
+     * DNS servers for lookups through the tunnel, comma-separated: "ip" for
+     * DNS over TCP or "ip#hostname" for DoH. Null or empty uses the default.
+     */
+    private String dnsServers = null;
+
+    /**
+     * How encrypted packets reach the endpoint: "udp", "socks5://[user:pass@]host:port",
+     * "ws://..." or "wss://...". Null or empty uses plain UDP.
+     */
+    private String outerTransport = null;
+
+    /**
+     * AmneziaWG obfuscation parameters, e.g. "Jc=4, Jmin=40, Jmax=70".
+     * Null or empty disables obfuscation.
+     */
+    private String obfuscation = null;
+
+    /**
+     * Local IP address the outer WireGuard sockets bind to. Null or empty binds to any.
+     */
+    private String outerBindAddress = null;
+
+    /**
+     * Network interface the outer WireGuard sockets send through (Linux only).
+     * Null or empty uses the routing table.
+     */
+    private String outerInterface = null;
+
+    /**
+     * Firewall mark (SO_MARK) for the outer WireGuard sockets (Linux only). 0 sets none.
+     */
+    private int outerMark = 0;
+
+    /**
+     * DSCP codepoint for outgoing WireGuard packets, e.g. 46 (EF) or 32 (CS4). 0 sets none.
+     */
+    private int outerDscp = 0;
+
+    /**
+     * Whether to block connections while the tunnel is down.
+     * When disabled, connections fall back to a direct route instead.
+     */
+    private boolean killSwitch = true;
+
+    /**
+     * Bandwidth limit for all tunneled connections in KiB/s, per direction.
+     * 0 disables the limit.
+     */
+    private int rateLimitKiBps = 0;
+
+    /**
+     * Whether to keep a spare connection to the last joined server ready,
+     * making reconnects faster.
+     */
+    private boolean connectionPool = false;
+
+    /**
+     * Longest time in milliseconds small writes are held back to be sent
+     * together. 0 disables write coalescing.
+     */
+    private int writeCoalescingMs = 0;
+
+    /**
+     * Longest time in milliseconds closing a connection waits for data
+     * already written, such as a final disconnect packet, to be delivered.
+     */
+    private int closeLingerMs = 500;
+
+    /**
+     * Received data in KiB buffered per connection ahead of the game reading
+     * it. 0 disables read-ahead.
+     */
+    private int readAheadKb = 256;
+
+    /**
+     * Most native connections open at once. 0 means no limit.
+     */
+    private int maxConnections = 256;
+
+    /**
+     * Seconds without reads or writes after which a native connection is
+     * closed. 0 never closes idle connections.
+     */
+    private int idleTimeoutSeconds = 0;
+
+    /**
+     * Allow/deny rules on connection destinations, in the format of
+     * {@link codes.dreaming.wireguard.jni.Native#setConnectionPolicy(String)}.
+     * Null allows everything.
+     */
+    private JsonObject connectionPolicy = null;
+
+    /**
+     * Native runtime worker threads. 0 uses a single-threaded runtime
+     * with a smaller memory footprint. Takes effect on the next game start.
+     */
+    private int runtimeWorkerThreads = 4;
+
+    /**
+     * HTTPS endpoint used by the connection self-test. Must speak
+     * Cloudflare's speed test protocol.
+     */
+    private String selfTestHost = "speed.cloudflare.com";
+
     private WireguardConfig() {
         // Private constructor - use getInstance()
     }
@@ -78,6 +211,467 @@ public class WireguardConfig {
         return this.warpEnabled;
     }
 
+    /**
+     * Get the WARP+ license key.
+     *
+     * @return the license key, or null if none is configured
+     */
+    public String getWarpLicenseKey() {
+        return warpLicenseKey;
+    }
+
+    /**
+     * Set the WARP+ license key.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param licenseKey the license key, or null to clear it
+     */
+    public void setWarpLicenseKey(String licenseKey) {
+        this.warpLicenseKey = licenseKey;
+        save();
+    }
+
+    /**
+     * Get the tunnel MTU.
+     *
+     * @return the MTU, or 0 for the native default
+     */
+    public int getMtu() {
+        return mtu;
+    }
+
+    /**
+     * Set the tunnel MTU.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param mtu the MTU (576-1500), or 0 for the native default
+     */
+    public void setMtu(int mtu) {
+        this.mtu = mtu;
+        save();
+    }
+
+    /**
+     * Get the persistent keepalive interval.
+     *
+     * @return the interval in seconds, or 0 if disabled
+     */
+    public int getPersistentKeepalive() {
+        return persistentKeepalive;
+    }
+
+    /**
+     * Set the persistent keepalive interval.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param seconds the interval in seconds, or 0 to disable
+     */
+    public void setPersistentKeepalive(int seconds) {
+        this.persistentKeepalive = seconds;
+        save();
+    }
+
+    /**
+     * Get the pinned WARP endpoint.
+     *
+     * @return the endpoint, or null to use the WARP config's
+     */
+    public String getEndpointOverride() {
+        return endpointOverride;
+    }
+
+    /**
+     * Pin the WARP endpoint.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param endpointOverride the endpoint, or null to use the WARP config's
+     */
+    public void setEndpointOverride(String endpointOverride) {
+        this.endpointOverride = endpointOverride;
+        save();
+    }
+
+    /**
+     * Get the preshared key.
+     *
+     * @return the base64 key, or null for none
+     */
+    public String getPresharedKey() {
+        return presharedKey;
+    }
+
+    /**
+     * Set the preshared key.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param presharedKey the base64 key, or null for none
+     */
+    public void setPresharedKey(String presharedKey) {
+        this.presharedKey = presharedKey;
+        save();
+    }
+
+    /**
+     * Get the DNS servers.
+     *
+     * @return the server list, or null for the default
+     */
+    public String getDnsServers() {
+        return dnsServers;
+    }
+
+    /**
+     * Set the DNS servers.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param dnsServers the server list, or null for the default
+     */
+    public void setDnsServers(String dnsServers) {
+        this.dnsServers = dnsServers;
+        save();
+    }
+
+    /**
+     * Get the outer transport spec.
+     *
+     * @return the spec, or null for plain UDP
+     */
+    public String getOuterTransport() {
+        return outerTransport;
+    }
+
+    /**
+     * Set the outer transport spec.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param outerTransport the spec, or null for plain UDP
+     */
+    public void setOuterTransport(String outerTransport) {
+        this.outerTransport = outerTransport;
+        save();
+    }
+
+    /**
+     * Get the AmneziaWG obfuscation parameters.
+     *
+     * @return the parameters, or null if obfuscation is disabled
+     */
+    public String getObfuscation() {
+        return obfuscation;
+    }
+
+    /**
+     * Set the AmneziaWG obfuscation parameters.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param obfuscation the parameters, or null to disable obfuscation
+     */
+    public void setObfuscation(String obfuscation) {
+        this.obfuscation = obfuscation;
+        save();
+    }
+
+    /**
+     * Get the local address the outer sockets bind to.
+     *
+     * @return the address, or null for any
+     */
+    public String getOuterBindAddress() {
+        return outerBindAddress;
+    }
+
+    /**
+     * Set the local address the outer sockets bind to.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param outerBindAddress the address, or null for any
+     */
+    public void setOuterBindAddress(String outerBindAddress) {
+        this.outerBindAddress = outerBindAddress;
+        save();
+    }
+
+    /**
+     * Get the network interface the outer sockets send through.
+     *
+     * @return the interface name, or null to use the routing table
+     */
+    public String getOuterInterface() {
+        return outerInterface;
+    }
+
+    /**
+     * Set the network interface the outer sockets send through.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param outerInterface the interface name, or null to use the routing table
+     */
+    public void setOuterInterface(String outerInterface) {
+        this.outerInterface = outerInterface;
+        save();
+    }
+
+    /**
+     * Get the firewall mark for the outer sockets.
+     *
+     * @return the mark, or 0 for none
+     */
+    public int getOuterMark() {
+        return outerMark;
+    }
+
+    /**
+     * Set the firewall mark for the outer sockets.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start.
+     *
+     * @param outerMark the mark, or 0 for none
+     */
+    public void setOuterMark(int outerMark) {
+        this.outerMark = outerMark;
+        save();
+    }
+
+    /**
+     * Get the DSCP codepoint for outgoing WireGuard packets.
+     *
+     * @return the codepoint, or 0 for none
+     */
+    public int getOuterDscp() {
+        return outerDscp;
+    }
+
+    /**
+     * Set the DSCP codepoint for outgoing WireGuard packets.
+     * Automatically saves the config to disk. Takes effect on the next tunnel start;
+     * use {@link codes.dreaming.wireguard.jni.Native#setOuterDscp(int)} to change the running tunnel.
+     *
+     * @param outerDscp the codepoint from 1 to 63, or 0 for none
+     */
+    public void setOuterDscp(int outerDscp) {
+        this.outerDscp = outerDscp;
+        save();
+    }
+
+    /**
+     * Check if the kill switch is enabled.
+     *
+     * @return true if connections fail while the tunnel is down
+     */
+    public boolean isKillSwitch() {
+        return killSwitch;
+    }
+
+    /**
+     * Set whether connections fail while the tunnel is down.
+     * Automatically saves the config to disk.
+     *
+     * @param killSwitch true to fail, false to fall back to a direct connection
+     */
+    public void setKillSwitch(boolean killSwitch) {
+        this.killSwitch = killSwitch;
+        save();
+    }
+
+    /**
+     * Get the tunnel bandwidth limit.
+     *
+     * @return the limit in KiB/s per direction, or 0 if unlimited
+     */
+    public int getRateLimitKiBps() {
+        return rateLimitKiBps;
+    }
+
+    /**
+     * Set the tunnel bandwidth limit.
+     * Automatically saves the config to disk.
+     *
+     * @param rateLimitKiBps the limit in KiB/s per direction, or 0 for no limit
+     */
+    public void setRateLimitKiBps(int rateLimitKiBps) {
+        this.rateLimitKiBps = rateLimitKiBps;
+        save();
+    }
+
+    /**
+     * Check if connection pre-warming is enabled.
+     *
+     * @return true if a spare connection to the last joined server is kept ready
+     */
+    public boolean isConnectionPool() {
+        return connectionPool;
+    }
+
+    /**
+     * Set whether to keep a spare connection to the last joined server ready.
+     * Automatically saves the config to disk.
+     *
+     * @param connectionPool true to enable pre-warming
+     */
+    public void setConnectionPool(boolean connectionPool) {
+        this.connectionPool = connectionPool;
+        save();
+    }
+
+    /**
+     * Get the write coalescing interval.
+     *
+     * @return the longest time a write is held back in milliseconds, or 0 if disabled
+     */
+    public int getWriteCoalescingMs() {
+        return writeCoalescingMs;
+    }
+
+    /**
+     * Set the write coalescing interval.
+     * Automatically saves the config to disk.
+     *
+     * @param writeCoalescingMs the longest time a write is held back in milliseconds, or 0 to disable
+     */
+    public void setWriteCoalescingMs(int writeCoalescingMs) {
+        this.writeCoalescingMs = writeCoalescingMs;
+        save();
+    }
+
+    /**
+     * Get the close linger timeout.
+     *
+     * @return the longest time closing a connection waits in milliseconds, or 0 to not wait
+     */
+    public int getCloseLingerMs() {
+        return closeLingerMs;
+    }
+
+    /**
+     * Set the close linger timeout.
+     * Automatically saves the config to disk.
+     *
+     * @param closeLingerMs the longest time closing a connection waits in milliseconds, or 0 to not wait
+     */
+    public void setCloseLingerMs(int closeLingerMs) {
+        this.closeLingerMs = closeLingerMs;
+        save();
+    }
+
+    /**
+     * Get the read-ahead buffer size.
+     *
+     * @return the read-ahead buffer per connection in KiB, or 0 if disabled
+     */
+    public int getReadAheadKb() {
+        return readAheadKb;
+    }
+
+    /**
+     * Set the read-ahead buffer size.
+     * Automatically saves the config to disk.
+     *
+     * @param readAheadKb the read-ahead buffer per connection in KiB, or 0 to disable
+     */
+    public void setReadAheadKb(int readAheadKb) {
+        this.readAheadKb = readAheadKb;
+        save();
+    }
+
+    /**
+     * Get the connection limit.
+     *
+     * @return the most native connections open at once, or 0 for no limit
+     */
+    public int getMaxConnections() {
+        return maxConnections;
+    }
+
+    /**
+     * Set the connection limit.
+     * Automatically saves the config to disk.
+     *
+     * @param maxConnections the most native connections open at once, or 0 for no limit
+     */
+    public void setMaxConnections(int maxConnections) {
+        this.maxConnections = maxConnections;
+        save();
+    }
+
+    /**
+     * Get the connection idle timeout.
+     *
+     * @return seconds without traffic before a connection is closed, or 0 if disabled
+     */
+    public int getIdleTimeoutSeconds() {
+        return idleTimeoutSeconds;
+    }
+
+    /**
+     * Set the connection idle timeout.
+     * Automatically saves the config to disk.
+     *
+     * @param idleTimeoutSeconds seconds without traffic before a connection is closed, or 0 to disable
+     */
+    public void setIdleTimeoutSeconds(int idleTimeoutSeconds) {
+        this.idleTimeoutSeconds = idleTimeoutSeconds;
+        save();
+    }
+
+    /**
+     * Get the connection policy.
+     *
+     * @return the policy rules, or null to allow everything
+     */
+    public JsonObject getConnectionPolicy() {
+        return connectionPolicy;
+    }
+
+    /**
+     * Set the connection policy.
+     * Automatically saves the config to disk.
+     *
+     * @param connectionPolicy the policy rules, or null to allow everything
+     */
+    public void setConnectionPolicy(JsonObject connectionPolicy) {
+        this.connectionPolicy = connectionPolicy;
+        save();
+    }
+
+    /**
+     * Get the number of native runtime worker threads.
+     *
+     * @return the worker thread count, or 0 for the single-threaded runtime
+     */
+    public int getRuntimeWorkerThreads() {
+        return runtimeWorkerThreads;
+    }
+
+    /**
+     * Set the number of native runtime worker threads.
+     * Automatically saves the config to disk. Takes effect on the next game start.
+     *
+     * @param runtimeWorkerThreads the worker thread count, or 0 for the single-threaded runtime
+     */
+    public void setRuntimeWorkerThreads(int runtimeWorkerThreads) {
+        this.runtimeWorkerThreads = runtimeWorkerThreads;
+        save();
+    }
+
+    /**
+     * Get the endpoint used by the connection self-test.
+     *
+     * @return the self-test hostname
+     */
+    public String getSelfTestHost() {
+        return selfTestHost;
+    }
+
+    /**
+     * Set the endpoint used by the connection self-test.
+     * Automatically saves the config to disk.
+     *
+     * @param selfTestHost the self-test hostname
+     */
+    public void setSelfTestHost(String selfTestHost) {
+        this.selfTestHost = selfTestHost;
+        save();
+    }
+
     /**
      * Get the config file path.
      *
@@ -1,6 +1,7 @@
 package codes.dreaming.wireguard;
 
 import codes.dreaming.wireguard.jni.Native;
+import codes.dreaming.wireguard.netty.WgSocketChannel;
 import net.fabricmc.api.ClientModInitializer;
 import net.fabricmc.fabric.api.client.event.lifecycle.v1.ClientTickEvents;
 import net.fabricmc.loader.api.FabricLoader;
@@ -9,6 +10,8 @@ import net.minecraft.network.chat.Component;
 import org.slf4j.Logger;
 import org.slf4j.LoggerFactory;
 
+import java.io.IOException;
+import java.nio.file.Files;
 import java.nio.file.Path;
 
 /**
@@ -20,6 +23,7 @@ import java.nio.file.Path;
  */
 public class WireguardTunnelClient implements ClientModInitializer {
 	private static final Logger LOGGER = LoggerFactory.getLogger("wireguard-tunnel");
+	private static final Logger NATIVE_LOGGER = LoggerFactory.getLogger("wireguard-tunnel/native");
 
 	/**
 	 * Path to store WARP credentials, relative to the game config directory.
@@ -47,10 +51,22 @@ public class WireguardTunnelClient implements ClientModInitializer {
 		// Load native library and initialize JNI
 		try {
 			NativeLibraryLoader.loadLibrary("wireguard_tunnel_jni");
+			Native.configureRuntime(WireguardConfig.getInstance().getRuntimeWorkerThreads(), 0, 0);
 			Native.initJNI();
+			Native.registerLogger(WireguardTunnelClient::logNative);
+			Native.registerConnectionListener(WgSocketChannel::onPeerClosed);
+			if (Platform.isAndroid()) {
+				// No VpnService: only the game's own connections go through the userspace tunnel
+				LOGGER.info("Running on Android, tunneling game connections without a VPN service");
+				Path cacheDir = Platform.androidCacheDir();
+				if (cacheDir != null) {
+					Native.setCacheDirectory(cacheDir.resolve("wireguard-tunnel").toString());
+				}
+			}
 			LOGGER.info("Native library loaded successfully!");
 			LOGGER.info("Native ping: {}", Native.ping());
 			LOGGER.info("Native version: {}", Native.version());
+			LOGGER.info("Native features: 0x{}", Long.toHexString(Native.nativeFeatures()));
 		} catch (Throwable t) {
 			LOGGER.error("Failed to load native library", t);
 			tunnelFailed = true;
@@ -112,13 +128,13 @@ public class WireguardTunnelClient implements ClientModInitializer {
 	 * If credentials don't exist, a new WARP device will be registered.
 	 */
 	private void startTunnel() {
-		// Get the config directory path
-		Path configDir = FabricLoader.getInstance().getConfigDir();
-		Path credPath = configDir.resolve(WARP_CREDENTIALS_PATH);
+		Path credPath = credentialsPath();
 
 		LOGGER.info("Starting WARP tunnel with credentials from: {}", credPath);
 
-		int state = Native.startWarpTunnel(credPath.toString());
+		applyTunnelOptions();
+		WireguardConfig config = WireguardConfig.getInstance();
+		int state = Native.startWarpTunnel(credPath.toString(), config.getWarpLicenseKey(), config.getMtu());
 
 		if (state != Native.TUNNEL_STATE_READY) {
 			throw new RuntimeException("Tunnel failed to start, state: " +
@@ -126,7 +142,94 @@ public class WireguardTunnelClient implements ClientModInitializer {
 		}
 
 		tunnelReady = true;
-		LOGGER.info("WARP tunnel started successfully!");
+		LOGGER.info("WARP tunnel started successfully! Account type: {}",
+				Native.warpAccountTypeToString(Native.warpAccountType()));
+	}
+
+	/**
+	 * Get the path of the WARP credentials file.
+	 * <p>
+	 * On Android it is kept in the app's private storage, since launchers often
+	 * keep the game directory on shared storage that other apps can read.
+	 * Credentials an earlier version left in the game directory are moved there.
+	 */
+	private static Path credentialsPath() {
+		Path gamePath = FabricLoader.getInstance().getConfigDir().resolve(WARP_CREDENTIALS_PATH);
+		Path filesDir = Platform.androidFilesDir();
+		if (filesDir == null) {
+			return gamePath;
+		}
+
+		Path privatePath = filesDir.resolve(WARP_CREDENTIALS_PATH);
+		if (Files.exists(gamePath) && !Files.exists(privatePath)) {
+			try {
+				Files.createDirectories(privatePath.getParent());
+				Files.move(gamePath, privatePath);
+				LOGGER.info("Moved WARP credentials to app-private storage: {}", privatePath);
+			} catch (IOException e) {
+				LOGGER.warn("Failed to move WARP credentials to {}, keeping {}", privatePath, gamePath, e);
+				return gamePath;
+			}
+		}
+		return privatePath;
+	}
+
+	/**
+	 * Push tunnel options from the config to the native side.
+	 * <p>
+	 * Called before each start, since keepalive and the endpoint only apply to the next tunnel.
+	 * The MTU is passed to the start call itself.
+	 */
+	private static void applyTunnelOptions() {
+		WireguardConfig config = WireguardConfig.getInstance();
+		Native.setPersistentKeepalive(config.getPersistentKeepalive());
+		Native.setEndpointOverride(config.getEndpointOverride());
+		Native.setPresharedKey(config.getPresharedKey());
+		Native.setDnsServers(config.getDnsServers());
+		Native.setOuterTransport(config.getOuterTransport());
+		Native.setObfuscation(config.getObfuscation());
+		int outerMark = config.getOuterMark();
+		if (outerMark != 0 && Platform.isAndroid()) {
+			// Apps lack CAP_NET_ADMIN, so SO_MARK would fail every start
+			LOGGER.warn("Ignoring outerMark {}: firewall marks are not available to Android apps", outerMark);
+			outerMark = 0;
+		}
+		Native.setOuterSocketOptions(config.getOuterBindAddress(), config.getOuterInterface(), outerMark);
+		Native.setOuterDscp(config.getOuterDscp());
+		Native.setConnectPolicy(config.isKillSwitch()
+				? Native.CONNECT_POLICY_KILL_SWITCH
+				: Native.CONNECT_POLICY_FALLBACK_DIRECT);
+		Native.setGlobalRateLimit(config.getRateLimitKiBps() * 1024L);
+		Native.setConnectionPoolEnabled(config.isConnectionPool());
+		Native.setWriteCoalescing(config.getWriteCoalescingMs());
+		Native.setCloseLinger(config.getCloseLingerMs());
+		Native.setReadAhead(config.getReadAheadKb());
+		Native.setMaxConnections(config.getMaxConnections());
+		Native.setIdleTimeout(config.getIdleTimeoutSeconds());
+		Native.setConnectionPolicy(config.getConnectionPolicy() == null ? null : config.getConnectionPolicy().toString());
+	}
+
+	/**
+	 * Forward a native log record to SLF4J so it lands in the game log.
+	 */
+	private static void logNative(int level, String target, String message) {
+		switch (level) {
+			case Native.LOG_LEVEL_ERROR:
+				NATIVE_LOGGER.error("[{}] {}", target, message);
+				break;
+			case Native.LOG_LEVEL_WARN:
+				NATIVE_LOGGER.warn("[{}] {}", target, message);
+				break;
+			case Native.LOG_LEVEL_INFO:
+				NATIVE_LOGGER.info("[{}] {}", target, message);
+				break;
+			case Native.LOG_LEVEL_DEBUG:
+				NATIVE_LOGGER.debug("[{}] {}", target, message);
+				break;
+			default:
+				NATIVE_LOGGER.trace("[{}] {}", target, message);
+				break;
+		}
 	}
 
 	/**
@@ -234,10 +337,12 @@ public class WireguardTunnelClient implements ClientModInitializer {
 					LOGGER.info("Starting WARP tunnel (attempt {})", attempt);
 
 					try {
-						Path configDir = FabricLoader.getInstance().getConfigDir();
-						Path credPath = configDir.resolve(WARP_CREDENTIALS_PATH);
+						Path credPath = credentialsPath();
 
-						int state = Native.startWarpTunnel(credPath.toString());
+						applyTunnelOptions();
+						WireguardConfig config = WireguardConfig.getInstance();
+						int state = Native.startWarpTunnel(credPath.toString(), config.getWarpLicenseKey(),
+								config.getMtu());
 
 						if (state == Native.TUNNEL_STATE_READY) {
 							tunnelReady = true;
@@ -0,0 +1,51 @@
+package codes.dreaming.wireguard.mixin.client;
+
+import codes.dreaming.wireguard.WireguardConfig;
+import codes.dreaming.wireguard.jni.Native;
+import net.minecraft.client.Minecraft;
+import net.minecraft.client.gui.screens.ConnectScreen;
+import net.minecraft.client.gui.screens.Screen;
+import net.minecraft.client.multiplayer.ServerData;
+import net.minecraft.client.multiplayer.resolver.ServerAddress;
+import org.jetbrains.annotations.Nullable;
+import org.slf4j.Logger;
+import org.slf4j.LoggerFactory;
+import org.spongepowered.asm.mixin.Mixin;
+import org.spongepowered.asm.mixin.Unique;
+import org.spongepowered.asm.mixin.injection.At;
+import org.spongepowered.asm.mixin.injection.Inject;
+import org.spongepowered.asm.mixin.injection.callback.CallbackInfo;
+
+/**
+ * Mixin to pre-warm a spare tunnel connection to the server being joined,
+ * so that reconnecting to it skips the connection setup.
+ * <p>
+ * Server list pings do not go through this screen, so only joined servers
+ * are pre-warmed.
+ */
+@Mixin(ConnectScreen.class)
+public class ConnectScreenMixin {
+
+    @Unique
+    private static final Logger LOGGER = LoggerFactory.getLogger("wireguard-tunnel");
+
+    @Inject(method = "startConnecting", at = @At("HEAD"))
+    private static void wireguard_tunnel$prewarm(
+            Screen screen,
+            Minecraft minecraft,
+            ServerAddress serverAddress,
+            @Nullable ServerData serverData,
+            CallbackInfo ci
+    ) {
+        WireguardConfig config = WireguardConfig.getInstance();
+        if (!config.isWarpEnabled() || !config.isConnectionPool() || !Native.isTunnelReady()) {
+            return;
+        }
+
+        try {
+            Native.prewarmConnection(serverAddress.getHost(), serverAddress.getPort());
+        } catch (Exception e) {
+            LOGGER.debug("Failed to pre-warm connection to {}: {}", serverAddress, e.getMessage());
+        }
+    }
+}
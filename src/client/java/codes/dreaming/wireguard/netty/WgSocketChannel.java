@@ -1,5 +1,6 @@
 package codes.dreaming.wireguard.netty;
 
+import codes.dreaming.wireguard.jni.ConnectionCleaner;
 import codes.dreaming.wireguard.jni.Native;
 import io.netty.buffer.ByteBuf;
 import io.netty.channel.*;
@@ -8,9 +9,15 @@ import org.slf4j.Logger;
 import org.slf4j.LoggerFactory;
 
 import java.io.IOException;
+import java.lang.ref.Cleaner;
+import java.lang.ref.WeakReference;
 import java.net.InetSocketAddress;
 import java.net.SocketAddress;
+import java.nio.ByteBuffer;
 import java.nio.channels.ClosedChannelException;
+import java.util.Arrays;
+import java.util.Map;
+import java.util.concurrent.ConcurrentHashMap;
 import java.util.concurrent.atomic.AtomicBoolean;
 import java.util.concurrent.atomic.AtomicLong;
 
@@ -27,6 +34,12 @@ public class WgSocketChannel extends AbstractChannel {
     private static final int READ_BUFFER_SIZE = 16384;
     private static final long CONNECT_TIMEOUT_MS = 30000;
 
+    /**
+     * Connected channels by native handle, for peer close notifications.
+     * Held weakly, so a channel dropped without closing can still be reaped.
+     */
+    private static final Map<Long, WeakReference<WgSocketChannel>> CHANNELS = new ConcurrentHashMap<>();
+
     private final WgChannelConfig config;
     private final AtomicLong nativeHandle = new AtomicLong(-1);
     private final AtomicBoolean inputShutdown = new AtomicBoolean(false);
@@ -37,6 +50,8 @@ public class WgSocketChannel extends AbstractChannel {
     private volatile InetSocketAddress localAddress;
 
     private volatile Thread readerThread;
+    /** Closes the native handle if this channel is collected without being closed. */
+    private volatile Cleaner.Cleanable cleanable;
 
     public WgSocketChannel() {
         super(null);
@@ -89,12 +104,19 @@ public class WgSocketChannel extends AbstractChannel {
 
         long handle = nativeHandle.getAndSet(-1);
         if (handle > 0) {
+            CHANNELS.remove(handle);
             try {
                 Native.tcpClose(handle);
             } catch (Exception e) {
                 LOGGER.debug("Error closing native handle: {}", e.getMessage());
             }
         }
+
+        Cleaner.Cleanable registered = cleanable;
+        if (registered != null) {
+            cleanable = null;
+            registered.clean();
+        }
     }
 
     @Override
@@ -112,10 +134,25 @@ public class WgSocketChannel extends AbstractChannel {
         }
     }
 
+    /**
+     * Shut down the output side, sending FIN while reads continue.
+     *
+     * @throws ClosedChannelException if the channel is closed
+     */
+    public void shutdownOutput() throws ClosedChannelException {
+        long handle = nativeHandle.get();
+        if (handle <= 0) {
+            throw new ClosedChannelException();
+        }
+        if (outputShutdown.compareAndSet(false, true)) {
+            Native.tcpShutdownOutput(handle);
+        }
+    }
+
     @Override
     protected void doWrite(ChannelOutboundBuffer in) throws Exception {
         long handle = nativeHandle.get();
-        if (handle <= 0) {
+        if (handle <= 0 || outputShutdown.get()) {
             throw new ClosedChannelException();
         }
 
@@ -126,23 +163,21 @@ public class WgSocketChannel extends AbstractChannel {
             }
 
             if (msg instanceof ByteBuf) {
-                ByteBuf buf = (ByteBuf) msg;
-                int readableBytes = buf.readableBytes();
-                if (readableBytes == 0) {
+                // Send every queued buffer in one native call
+                ByteBuffer[] nioBuffers = in.nioBuffers();
+                int count = in.nioBufferCount();
+                if (count == 0) {
                     in.remove();
                     continue;
                 }
 
-                byte[] data = new byte[readableBytes];
-                buf.readBytes(data);
-
                 try {
-                    int written = Native.tcpWrite(handle, data, 0, data.length);
-                    LOGGER.debug("Wrote {} bytes to handle {}", written, handle);
+                    long written = Native.tcpWritev(handle, Arrays.copyOf(nioBuffers, count));
+                    LOGGER.debug("Wrote {} bytes from {} buffers to handle {}", written, count, handle);
                     if (written < 0) {
                         throw new IOException("Write failed");
                     }
-                    in.remove();
+                    in.removeBytes(written);
                 } catch (Exception e) {
                     LOGGER.error("Write error: {}", e.getMessage());
                     in.remove(e);
@@ -157,6 +192,30 @@ public class WgSocketChannel extends AbstractChannel {
 
     }
 
+    /**
+     * Handle a connection closed by the peer, registered with
+     * {@link Native#registerConnectionListener}.
+     * <p>
+     * An orderly close is left to the reader, which sees EOF once the
+     * remaining data is read. A reset closes the channel with an error right
+     * away, so the disconnect screen shows why.
+     */
+    public static void onPeerClosed(long handle, int reason) {
+        WeakReference<WgSocketChannel> ref = CHANNELS.get(handle);
+        WgSocketChannel channel = ref != null ? ref.get() : null;
+        if (channel == null) {
+            return;
+        }
+
+        LOGGER.debug("Connection on handle {} closed by peer: {}", handle, Native.closeReasonToString(reason));
+        if (reason == Native.CLOSE_REASON_RESET) {
+            channel.eventLoop().execute(() -> {
+                channel.pipeline().fireExceptionCaught(new IOException("Connection reset by peer"));
+                channel.unsafe().close(channel.unsafe().voidPromise());
+            });
+        }
+    }
+
     @Override
     public ChannelConfig config() {
         return config;
@@ -199,6 +258,11 @@ public class WgSocketChannel extends AbstractChannel {
                     bytesRead = Native.tcpRead(handle, buffer);
                     LOGGER.debug("Read {} bytes from handle {}", bytesRead, handle);
                 } catch (Exception e) {
+                    if (isReconnecting(handle)) {
+                        // The tunnel woke from sleep; the connection may still be alive
+                        LOGGER.debug("Read on handle {} interrupted by reconnect, retrying", handle);
+                        continue;
+                    }
                     if (!inputShutdown.get()) {
                         LOGGER.error("Read error: {}", e.getMessage());
                         final Exception exc = e;
@@ -246,6 +310,18 @@ public class WgSocketChannel extends AbstractChannel {
         }
     }
 
+    /**
+     * Whether the last read on a handle was cut short by a tunnel reconnect.
+     */
+    private static boolean isReconnecting(long handle) {
+        try {
+            return Native.tcpLastError(handle) == Native.TCP_ERROR_RECONNECTING;
+        } catch (Exception e) {
+            // Closed in the meantime
+            return false;
+        }
+    }
+
     /**
      * Custom Unsafe implementation for WgSocketChannel.
      */
@@ -304,6 +380,13 @@ public class WgSocketChannel extends AbstractChannel {
                         }
 
                         nativeHandle.set(handle);
+                        cleanable = ConnectionCleaner.register(WgSocketChannel.this, handle);
+                        // Entries of channels reaped without closing are dropped here
+                        CHANNELS.values().removeIf(ref -> ref.get() == null);
+                        CHANNELS.put(handle, new WeakReference<>(WgSocketChannel.this));
+                        if (config.tcpNoDelay) {
+                            Native.tcpSetNoDelay(handle, true);
+                        }
                         active = true;
 
                         eventLoop().execute(() -> {
@@ -327,11 +410,37 @@ public class WgSocketChannel extends AbstractChannel {
      * Channel configuration for WgSocketChannel.
      */
     private static class WgChannelConfig extends DefaultChannelConfig {
-        WgChannelConfig(Channel channel) {
+        private final WgSocketChannel channel;
+        private volatile boolean tcpNoDelay = false;
+
+        WgChannelConfig(WgSocketChannel channel) {
             super(channel);
+            this.channel = channel;
             // Set reasonable defaults
             setConnectTimeoutMillis((int) CONNECT_TIMEOUT_MS);
         }
+
+        @Override
+        @SuppressWarnings("unchecked")
+        public <T> T getOption(ChannelOption<T> option) {
+            if (option == ChannelOption.TCP_NODELAY) {
+                return (T) Boolean.valueOf(tcpNoDelay);
+            }
+            return super.getOption(option);
+        }
+
+        @Override
+        public <T> boolean setOption(ChannelOption<T> option, T value) {
+            if (option == ChannelOption.TCP_NODELAY) {
+                tcpNoDelay = (Boolean) value;
+                long handle = channel.nativeHandle.get();
+                if (handle > 0) {
+                    Native.tcpSetNoDelay(handle, tcpNoDelay);
+                }
+                return true;
+            }
+            return super.setOption(option, value);
+        }
     }
 
     /**
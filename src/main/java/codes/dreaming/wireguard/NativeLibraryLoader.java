@@ -18,6 +18,7 @@ import java.util.Locale;
  * - x86_64-linux, aarch64-linux
  * - x86_64-windows
  * - x86_64-macos, aarch64-macos
+ * - aarch64-android (Android launchers such as PojavLauncher)
  */
 public class NativeLibraryLoader {
 
@@ -63,11 +64,14 @@ public class NativeLibraryLoader {
     /**
      * Get the target directory name for the current platform.
      *
-     * @return e.g., "x86_64-linux", "aarch64-macos", "x86_64-windows"
+     * @return e.g., "x86_64-linux", "aarch64-macos", "x86_64-windows", "aarch64-android"
      */
     private static String getTargetDir() {
         String arch = normalizeArch(System.getProperty("os.arch", "").toLowerCase(Locale.ROOT));
-        String os = normalizeOs(System.getProperty("os.name", "").toLowerCase(Locale.ROOT));
+        // Android reports itself as Linux, but needs a library linked against Bionic
+        String os = Platform.isAndroid()
+                ? "android"
+                : normalizeOs(System.getProperty("os.name", "").toLowerCase(Locale.ROOT));
         return arch + "-" + os;
     }
 
@@ -0,0 +1,63 @@
+package codes.dreaming.wireguard;
+
+import java.nio.file.Files;
+import java.nio.file.Path;
+import java.nio.file.Paths;
+
+/**
+ * Platform checks the standard system properties cannot answer.
+ * <p>
+ * Android launchers such as PojavLauncher run a regular OpenJDK whose
+ * {@code os.name} is "Linux", so Android is recognised by the environment
+ * every app process inherits instead.
+ */
+public final class Platform {
+
+    private static final boolean ANDROID =
+            System.getenv("ANDROID_ROOT") != null && System.getenv("ANDROID_DATA") != null;
+
+    private Platform() {
+        // Prevent instantiation
+    }
+
+    /**
+     * Check if running on Android.
+     *
+     * @return true inside an Android app process
+     */
+    public static boolean isAndroid() {
+        return ANDROID;
+    }
+
+    /**
+     * Get the app's private cache directory on Android.
+     * <p>
+     * Launchers point {@code java.io.tmpdir} at it, e.g.
+     * {@code /data/user/0/<package>/cache}. The system may clear it when
+     * storage runs low.
+     *
+     * @return the directory, or null when not on Android or it was not found
+     */
+    public static Path androidCacheDir() {
+        if (!ANDROID) {
+            return null;
+        }
+        Path tmp = Paths.get(System.getProperty("java.io.tmpdir", "")).toAbsolutePath().normalize();
+        if (!tmp.startsWith("/data/") || tmp.getFileName() == null
+                || !tmp.getFileName().toString().equals("cache") || !Files.isWritable(tmp)) {
+            return null;
+        }
+        return tmp;
+    }
+
+    /**
+     * Get the app's private files directory on Android, next to its cache directory.
+     *
+     * @return the directory, e.g. {@code /data/user/0/<package>/files}, or null
+     *         when not on Android or it was not found
+     */
+    public static Path androidFilesDir() {
+        Path cache = androidCacheDir();
+        return cache == null ? null : cache.resolveSibling("files");
+    }
+}
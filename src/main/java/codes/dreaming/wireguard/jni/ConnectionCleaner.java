@@ -0,0 +1,48 @@
+package codes.dreaming.wireguard.jni;
+
+import java.lang.ref.Cleaner;
+
+/**
+ * Closes connections whose Java owner was garbage collected without
+ * calling {@link Native#tcpClose}.
+ * <p>
+ * Register the object wrapping a handle right after connecting. Once it has
+ * been closed normally, call {@link Cleaner.Cleanable#clean()} to deregister
+ * it; reaping a handle that is already closed does nothing.
+ */
+public final class ConnectionCleaner {
+    private static final Cleaner CLEANER = Cleaner.create();
+
+    private ConnectionCleaner() {
+    }
+
+    /**
+     * Close {@code handle} once {@code owner} becomes unreachable.
+     * <p>
+     * The owner must not be reachable from the handle's other callbacks, such
+     * as a registered {@link ConnectionListener}, or it is never collected.
+     *
+     * @param owner  object whose lifetime bounds the connection
+     * @param handle connection handle from {@link Native#tcpConnect}
+     * @return cleanable to deregister the handle after closing it
+     */
+    public static Cleaner.Cleanable register(Object owner, long handle) {
+        return CLEANER.register(owner, new Reaper(handle));
+    }
+
+    /**
+     * Cleanup action; must not refer to the owner.
+     */
+    private static final class Reaper implements Runnable {
+        private final long handle;
+
+        Reaper(long handle) {
+            this.handle = handle;
+        }
+
+        @Override
+        public void run() {
+            Native.tcpReap(handle);
+        }
+    }
+}
@@ -0,0 +1,22 @@
+package codes.dreaming.wireguard.jni;
+
+/**
+ * Notified when the peer closes a connection.
+ * <p>
+ * Register with {@link Native#registerConnectionListener(ConnectionListener)}.
+ */
+public interface ConnectionListener {
+
+    /**
+     * Handle a connection closed by the peer.
+     * <p>
+     * Called once per connection from a native worker thread, usually before
+     * a read on the handle returns EOF. Must return quickly. Tunnel
+     * connections are reported as soon as the FIN or RST arrives; direct
+     * connections only once a read sees it.
+     *
+     * @param handle connection handle from {@link Native#tcpConnect}
+     * @param reason one of CLOSE_REASON_* constants
+     */
+    void onPeerClosed(long handle, int reason);
+}
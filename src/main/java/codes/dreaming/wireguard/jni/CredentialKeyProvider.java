@@ -0,0 +1,20 @@
+package codes.dreaming.wireguard.jni;
+
+/**
+ * Supplies the key used to encrypt the persisted WARP credentials.
+ * <p>
+ * Implementations typically fetch or create the key in an OS keystore.
+ * Register with {@link Native#setCredentialKeyProvider(CredentialKeyProvider)}.
+ */
+public interface CredentialKeyProvider {
+
+    /**
+     * Get the credential encryption key.
+     * <p>
+     * Called from the thread starting the tunnel. Must return the same key
+     * every time, or previously saved credentials can no longer be read.
+     *
+     * @return a 32-byte key
+     */
+    byte[] getCredentialKey();
+}
@@ -0,0 +1,60 @@
+package codes.dreaming.wireguard.jni;
+
+/**
+ * A response returned by {@link Native#httpRequest}.
+ */
+public class HttpResponse {
+
+    private final int status;
+    private final String[] headers;
+    private final byte[] body;
+
+    public HttpResponse(int status, String[] headers, byte[] body) {
+        this.status = status;
+        this.headers = headers;
+        this.body = body;
+    }
+
+    /**
+     * Get the HTTP status code.
+     *
+     * @return status code, e.g. 200
+     */
+    public int getStatus() {
+        return status;
+    }
+
+    /**
+     * Get the response headers in the order received.
+     *
+     * @return headers as "Name: value"
+     */
+    public String[] getHeaders() {
+        return headers;
+    }
+
+    /**
+     * Get the first value of a header.
+     *
+     * @param name header name, matched case-insensitively
+     * @return the value, or null if the header is absent
+     */
+    public String getHeader(String name) {
+        for (String header : headers) {
+            int colon = header.indexOf(':');
+            if (header.substring(0, colon).equalsIgnoreCase(name)) {
+                return header.substring(colon + 1).trim();
+            }
+        }
+        return null;
+    }
+
+    /**
+     * Get the response body, with any chunked encoding removed.
+     *
+     * @return body bytes, empty if there is none
+     */
+    public byte[] getBody() {
+        return body;
+    }
+}
@@ -0,0 +1,26 @@
+package codes.dreaming.wireguard.jni;
+
+/**
+ * Thrown when a WireGuard config is invalid.
+ * <p>
+ * Every error found is listed in {@link #getErrorsJson()}, in the format
+ * returned by {@link Native#validateConfig(String)}.
+ */
+public class InvalidConfigException extends IllegalArgumentException {
+
+    private final String errorsJson;
+
+    public InvalidConfigException(String message, String errorsJson) {
+        super(message);
+        this.errorsJson = errorsJson;
+    }
+
+    /**
+     * Get the problems found, one per bad or missing field.
+     *
+     * @return JSON array of errors
+     */
+    public String getErrorsJson() {
+        return errorsJson;
+    }
+}
@@ -0,0 +1,30 @@
+package codes.dreaming.wireguard.jni;
+
+/**
+ * Completion callback for {@link Native#tcpReadAsync} and {@link Native#tcpWriteAsync}.
+ * <p>
+ * Exactly one method is called per operation, from a native blocking thread
+ * rather than one of the runtime's workers, so implementations may call other
+ * {@link Native} methods, including blocking ones. Each callback holds a
+ * thread of the blocking pool while it runs, so slow work is still better
+ * handed off, e.g. by completing a {@link java.util.concurrent.CompletableFuture}.
+ */
+public interface IoCallback {
+
+    /**
+     * Handle a completed read or write.
+     *
+     * @param handle connection handle the operation was started on
+     * @param result number of bytes transferred; 0 means EOF for reads
+     */
+    void onComplete(long handle, int result);
+
+    /**
+     * Handle a failed read or write.
+     *
+     * @param handle    connection handle the operation was started on
+     * @param errorCode one of the TCP_ERROR_* constants
+     * @param message   description of the failure
+     */
+    void onError(long handle, int errorCode, String message);
+}
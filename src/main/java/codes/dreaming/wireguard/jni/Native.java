@@ -1,10 +1,21 @@
 package codes.dreaming.wireguard.jni;
 
+import java.nio.ByteBuffer;
+
 /**
  * JNI bridge to the native WireGuard tunnel library.
  * <p>
  * Before calling any methods, ensure the native library is loaded via
  * {@link codes.dreaming.wireguard.NativeLibraryLoader#loadLibrary(String)}.
+ * <p>
+ * A panic in native code surfaces as a {@link NativePanicException} from the
+ * method that triggered it instead of crashing the JVM.
+ * <p>
+ * Methods taking a connection handle throw {@link StaleHandleException} when
+ * the handle was already closed.
+ * <p>
+ * The native library registers its methods when it loads and checks them
+ * against this class; see {@link #ABI_VERSION}.
  */
 public final class Native {
 
@@ -12,6 +23,17 @@ public final class Native {
         // Prevent instantiation
     }
 
+    /**
+     * Version of the contract between this class and the native library.
+     * <p>
+     * Checked when the library loads, which fails with an
+     * {@link UnsatisfiedLinkError} on a mismatch. Bump it together with the
+     * native {@code ABI_VERSION} when a native method changes signature or
+     * meaning or is removed; adding methods needs no bump, see
+     * {@link #hasFeature(long)}.
+     */
+    public static final int ABI_VERSION = 1;
+
     // ========================================================================
     // Tunnel state constants
     // ========================================================================
@@ -24,11 +46,315 @@ public final class Native {
     public static final int TUNNEL_STATE_READY = 2;
     /** Tunnel failed to start or encountered an error */
     public static final int TUNNEL_STATE_FAILED = 3;
+    /** Tunnel is paused; connection handles are kept but no traffic flows */
+    public static final int TUNNEL_STATE_PAUSED = 4;
+
+    // ========================================================================
+    // WARP account type constants
+    // ========================================================================
+
+    /** Account type could not be determined, or no tunnel is running */
+    public static final int WARP_ACCOUNT_UNKNOWN = -1;
+    /** Free WARP account */
+    public static final int WARP_ACCOUNT_FREE = 0;
+    /** WARP+ account */
+    public static final int WARP_ACCOUNT_PLUS = 1;
+    /** Cloudflare Zero Trust (Teams) account */
+    public static final int WARP_ACCOUNT_TEAM = 2;
+
+    // ========================================================================
+    // Connect policy constants
+    // ========================================================================
+
+    /** Use the policy configured with {@link #setConnectPolicy(int)} */
+    public static final int CONNECT_POLICY_DEFAULT = -1;
+    /** Fail connections while the tunnel is down */
+    public static final int CONNECT_POLICY_KILL_SWITCH = 0;
+    /** Connect directly, outside the tunnel, while the tunnel is down */
+    public static final int CONNECT_POLICY_FALLBACK_DIRECT = 1;
+
+    // ========================================================================
+    // DSCP constants
+    // ========================================================================
+
+    /** Expedited Forwarding, for latency-sensitive traffic */
+    public static final int DSCP_EF = 46;
+    /** Class Selector 4, commonly used for real-time interactive traffic such as games */
+    public static final int DSCP_CS4 = 32;
+    /** Assured Forwarding class 4, low drop probability */
+    public static final int DSCP_AF41 = 34;
+
+    // ========================================================================
+    // Route constants
+    // ========================================================================
+
+    /** Connect through the tunnel */
+    public static final int ROUTE_TUNNEL = 0;
+    /** Connect directly, bypassing the tunnel */
+    public static final int ROUTE_DIRECT = 1;
+
+    // ========================================================================
+    // DNS record type constants
+    // ========================================================================
+
+    /** IPv4 address record */
+    public static final int DNS_TYPE_A = 1;
+    /** Canonical name record */
+    public static final int DNS_TYPE_CNAME = 5;
+    /** Text record */
+    public static final int DNS_TYPE_TXT = 16;
+    /** IPv6 address record */
+    public static final int DNS_TYPE_AAAA = 28;
+    /** Service record */
+    public static final int DNS_TYPE_SRV = 33;
+
+    // ========================================================================
+    // Reload result constants
+    // ========================================================================
+
+    /** Nothing the tunnel uses changed */
+    public static final int RELOAD_UNCHANGED = 0;
+    /** The tunnel was rebuilt, closing tunneled connections */
+    public static final int RELOAD_RECONNECTED = 1;
+    /** Only the DNS servers changed, so nothing was reconnected */
+    public static final int RELOAD_DNS_UPDATED = 2;
+    /** Only the endpoint changed, and the tunnel moved there keeping its connections */
+    public static final int RELOAD_MOVED = 3;
+
+    // ========================================================================
+    // Metrics format constants
+    // ========================================================================
+
+    /** Prometheus text exposition format */
+    public static final int METRICS_FORMAT_PROMETHEUS = 0;
+    /** JSON object */
+    public static final int METRICS_FORMAT_JSON = 1;
+
+    // ========================================================================
+    // Native feature bits, see nativeFeatures()
+    // ========================================================================
+
+    /** tcpReadAsync and tcpWriteAsync */
+    public static final long FEATURE_ASYNC_IO = 1L << 0;
+    /** Non-blocking connections, tcpReadyOps and tcpSelect */
+    public static final long FEATURE_SELECT = 1L << 1;
+    /** tcpAttachRings */
+    public static final long FEATURE_SHARED_RINGS = 1L << 2;
+    /** tlsConnect */
+    public static final long FEATURE_TLS = 1L << 3;
+    /** socks5:// outer transport */
+    public static final long FEATURE_SOCKS5_TRANSPORT = 1L << 4;
+    /** ws:// and wss:// outer transports */
+    public static final long FEATURE_WEBSOCKET_TRANSPORT = 1L << 5;
+    /** AmneziaWG-style obfuscation, setObfuscation */
+    public static final long FEATURE_OBFUSCATION = 1L << 6;
+    /** importWireGuardProfile and reloadTunnelConfig */
+    public static final long FEATURE_WIREGUARD_PROFILES = 1L << 7;
+    /** startWarpTeamsTunnel */
+    public static final long FEATURE_ZERO_TRUST = 1L << 8;
+    /** httpRequest */
+    public static final long FEATURE_HTTP = 1L << 9;
+    /** wsConnect and the other ws* methods */
+    public static final long FEATURE_WEBSOCKET = 1L << 10;
+    /** startPacketCapture */
+    public static final long FEATURE_PACKET_CAPTURE = 1L << 11;
+    /** addProxyProtocolRule */
+    public static final long FEATURE_PROXY_PROTOCOL = 1L << 12;
+    /** exportDiagnostics */
+    public static final long FEATURE_DIAGNOSTICS = 1L << 13;
+    /** Interface binding and firewall marks in setOuterSocketOptions (Linux and Android) */
+    public static final long FEATURE_SOCKET_MARK = 1L << 14;
+    /** Kernel RTT and congestion statistics in tcpStats (Linux) */
+    public static final long FEATURE_KERNEL_TCP_INFO = 1L << 15;
+    /** setOuterDscp on IPv6 endpoints (Linux, Android and macOS) */
+    public static final long FEATURE_DSCP_IPV6 = 1L << 16;
+    /**
+     * {@code wgt_ffm_*} functions for {@code java.lang.foreign} downcalls on the
+     * read/write path, declared in {@code rust/include/wireguard_tunnel.h}
+     */
+    public static final long FEATURE_FFM = 1L << 17;
+    /** setWriteBehind */
+    public static final long FEATURE_WRITE_BEHIND = 1L << 18;
+    /** tcpSetPartialWrites and tcpAwaitWritable */
+    public static final long FEATURE_PARTIAL_WRITES = 1L << 19;
+    /** tcpSetTrace */
+    public static final long FEATURE_IO_TRACE = 1L << 20;
+    /** setOuterBatching (Linux) */
+    public static final long FEATURE_UDP_BATCHING = 1L << 21;
+    /** This CPU supports AVX2 (x86, detected at runtime); says nothing of the AEAD in use */
+    public static final long FEATURE_CPU_AVX2 = 1L << 22;
+    /** This CPU supports NEON (aarch64); says nothing of the AEAD in use */
+    public static final long FEATURE_CPU_NEON = 1L << 23;
+    /** setPreserveConnections and RELOAD_MOVED */
+    public static final long FEATURE_PRESERVE_CONNECTIONS = 1L << 24;
+    /** icmpPing */
+    public static final long FEATURE_ICMP_PING = 1L << 25;
+    /** udpForwardStart and udpForwardStop */
+    public static final long FEATURE_UDP_FORWARD = 1L << 26;
+
+    // ========================================================================
+    // Socket state constants (TCP states, as in RFC 793)
+    // ========================================================================
+
+    /** Connection is closed, or the handle is no longer valid */
+    public static final int SOCKET_STATE_CLOSED = 0;
+    public static final int SOCKET_STATE_LISTEN = 1;
+    /** Connecting; SYN sent */
+    public static final int SOCKET_STATE_SYN_SENT = 2;
+    public static final int SOCKET_STATE_SYN_RECEIVED = 3;
+    /** Connected; data flows in both directions */
+    public static final int SOCKET_STATE_ESTABLISHED = 4;
+    /** Output shut down; waiting for the peer to acknowledge */
+    public static final int SOCKET_STATE_FIN_WAIT_1 = 5;
+    /** Output shut down; the peer may still send */
+    public static final int SOCKET_STATE_FIN_WAIT_2 = 6;
+    /** Peer shut down its output; we may still send */
+    public static final int SOCKET_STATE_CLOSE_WAIT = 7;
+    public static final int SOCKET_STATE_CLOSING = 8;
+    public static final int SOCKET_STATE_LAST_ACK = 9;
+    public static final int SOCKET_STATE_TIME_WAIT = 10;
+
+    // ========================================================================
+    // TCP error constants
+    // ========================================================================
+
+    /** No read or write has failed */
+    public static final int TCP_ERROR_NONE = 0;
+    /** The peer closed the connection in an orderly way */
+    public static final int TCP_ERROR_EOF = 1;
+    /** The peer reset the connection */
+    public static final int TCP_ERROR_RESET = 2;
+    /** The operation timed out */
+    public static final int TCP_ERROR_TIMEOUT = 3;
+    /** The tunnel the connection runs through is stopped or paused */
+    public static final int TCP_ERROR_TUNNEL_DOWN = 4;
+    /** The send buffer stayed full until the write timed out */
+    public static final int TCP_ERROR_BUFFER_FULL = 5;
+    /** Any other failure; see the exception message */
+    public static final int TCP_ERROR_OTHER = 6;
+    /** A read was cut short because the tunnel is re-handshaking, e.g. after sleep; retry it */
+    public static final int TCP_ERROR_RECONNECTING = 7;
+
+    // ========================================================================
+    // Tunnel error constants
+    // ========================================================================
+
+    /** The tunnel is not running */
+    public static final int TUNNEL_ERROR_NOT_INITIALIZED = 1;
+    /** A tunnel is already running */
+    public static final int TUNNEL_ERROR_ALREADY_RUNNING = 2;
+    /** The tunnel is paused or not connected yet */
+    public static final int TUNNEL_ERROR_NOT_READY = 3;
+    /** Registering a WARP device failed */
+    public static final int TUNNEL_ERROR_WARP_REGISTRATION = 4;
+    /** Another WARP API request failed */
+    public static final int TUNNEL_ERROR_WARP_API = 5;
+    /** Credentials could not be read or written */
+    public static final int TUNNEL_ERROR_CREDENTIAL_PERSISTENCE = 6;
+    /** Credentials are encrypted and no usable key was configured */
+    public static final int TUNNEL_ERROR_CREDENTIALS_LOCKED = 7;
+    /** An endpoint hostname did not resolve */
+    public static final int TUNNEL_ERROR_DNS = 8;
+    /** The handshake or the outer transport failed */
+    public static final int TUNNEL_ERROR_CONNECTION_FAILED = 9;
+    public static final int TUNNEL_ERROR_INVALID_HANDLE = 10;
+    public static final int TUNNEL_ERROR_STALE_HANDLE = 11;
+    /** An OS-level I/O error, e.g. binding the UDP socket */
+    public static final int TUNNEL_ERROR_IO = 12;
+    public static final int TUNNEL_ERROR_TIMEOUT = 13;
+    public static final int TUNNEL_ERROR_WOULD_BLOCK = 14;
+    public static final int TUNNEL_ERROR_TOO_MANY_CONNECTIONS = 15;
+    /** The tunnel is re-handshaking; retry */
+    public static final int TUNNEL_ERROR_RECONNECTING = 16;
+    /** The setting cannot change without restarting the tunnel */
+    public static final int TUNNEL_ERROR_RESTART_REQUIRED = 17;
+    /** The connection policy refused the destination */
+    public static final int TUNNEL_ERROR_POLICY_DENIED = 18;
+
+    // ========================================================================
+    // Peer close reason constants
+    // ========================================================================
+
+    /** The peer closed the connection in an orderly way (FIN) */
+    public static final int CLOSE_REASON_FIN = 0;
+    /** The peer reset the connection (RST) */
+    public static final int CLOSE_REASON_RESET = 1;
+
+    // ========================================================================
+    // Handshake event constants
+    // ========================================================================
+
+    /** A handshake initiation was sent */
+    public static final int HANDSHAKE_EVENT_ATTEMPT = 0;
+    /** The first handshake of a tunnel completed */
+    public static final int HANDSHAKE_EVENT_SUCCESS = 1;
+    /** A tunnel gave up waiting for its first handshake */
+    public static final int HANDSHAKE_EVENT_FAILURE = 2;
+    /** A later handshake completed, replacing the session */
+    public static final int HANDSHAKE_EVENT_REKEY = 3;
+
+    // ========================================================================
+    // Non-blocking I/O constants
+    // ========================================================================
+
+    /** {@link #tcpRead} result when a non-blocking connection has no data */
+    public static final int READ_WOULD_BLOCK = -2;
+    /** Readable, same value as {@link java.nio.channels.SelectionKey#OP_READ} */
+    public static final int READY_READ = 1;
+    /** Writable, same value as {@link java.nio.channels.SelectionKey#OP_WRITE} */
+    public static final int READY_WRITE = 4;
+
+    // ========================================================================
+    // Shared-memory ring constants
+    // ========================================================================
+
+    /** Bytes before the data area of a ring buffer */
+    public static final int RING_HEADER_SIZE = 64;
+    /** Offset of the producer's byte count (native-order long) */
+    public static final int RING_HEAD_OFFSET = 0;
+    /** Offset of the consumer's byte count (native-order long) */
+    public static final int RING_TAIL_OFFSET = 8;
+    /** Offset of the ring state (native-order int), one of RING_STATE_* */
+    public static final int RING_STATE_OFFSET = 16;
+    /** Offset of the TCP_ERROR_* code (native-order int) once the ring is closed */
+    public static final int RING_ERROR_OFFSET = 20;
+
+    /** The ring is open */
+    public static final int RING_STATE_OPEN = 0;
+    /** The peer closed the connection; no more data will be added */
+    public static final int RING_STATE_EOF = 1;
+    /** Reading or writing the connection failed */
+    public static final int RING_STATE_ERROR = 2;
+
+    // ========================================================================
+    // Log level constants
+    // ========================================================================
+
+    public static final int LOG_LEVEL_ERROR = 1;
+    public static final int LOG_LEVEL_WARN = 2;
+    public static final int LOG_LEVEL_INFO = 3;
+    public static final int LOG_LEVEL_DEBUG = 4;
+    public static final int LOG_LEVEL_TRACE = 5;
 
     // ========================================================================
     // Initialization
     // ========================================================================
 
+    /**
+     * Configure the native async runtime.
+     * <p>
+     * Must be called before {@link #initJNI()}, which builds the runtime.
+     * By default 4 worker threads are used. With 0 worker threads a
+     * single-threaded runtime is used instead, trading throughput for a
+     * smaller memory footprint.
+     *
+     * @param workerThreads   worker threads, or 0 for the single-threaded runtime
+     * @param blockingThreads maximum blocking pool threads, or 0 for the default (512)
+     * @param stackSizeKb     stack size of runtime threads in KiB, or 0 for the default (2048)
+     * @throws RuntimeException if the runtime is already running or a value is negative
+     */
+    public static native void configureRuntime(int workerThreads, int blockingThreads, int stackSizeKb);
+
     /**
      * Initialize the JNI layer.
      * <p>
@@ -37,6 +363,38 @@ public final class Native {
      */
     public static native void initJNI();
 
+    /**
+     * Release all native resources.
+     * <p>
+     * Shuts down the tunnel, closes every connection, stops the native
+     * runtime and drops all native state. Afterwards {@link #initJNI()} may
+     * be called again, e.g. after a hot reload in a development environment.
+     * Must not be called while other native calls are in progress.
+     */
+    public static native void shutdownNative();
+
+    /**
+     * Forward native log records to a Java logger.
+     * <p>
+     * Until a logger is registered, native logs are written to stderr.
+     * Records are still filtered by the RUST_LOG environment variable
+     * (default "info").
+     *
+     * @param logger logger to forward to, or null to log to stderr again
+     */
+    public static native void registerLogger(NativeLogger logger);
+
+    /**
+     * Change the native log filter at runtime.
+     * <p>
+     * Uses RUST_LOG syntax: a default level and/or per-target levels,
+     * e.g. "info,wireguard_netstack=trace". Invalid directives are ignored.
+     *
+     * @param targetFilter filter directives, or null/empty to restore the
+     *                     filter from RUST_LOG (default "info")
+     */
+    public static native void setLogLevel(String targetFilter);
+
     /**
      * Simple ping to verify the native library is loaded and working.
      *
@@ -51,129 +409,1731 @@ public final class Native {
      */
     public static native String version();
 
+    /**
+     * Get the capabilities of the loaded native library.
+     * <p>
+     * Bits keep their meaning across versions, so a mod can check for what it
+     * needs and degrade gracefully when paired with an older or newer
+     * library. Use {@link #hasFeature(long)}, which also copes with libraries
+     * that predate this method.
+     *
+     * @return bitmask of FEATURE_* constants
+     */
+    public static native long nativeFeatures();
+
     // ========================================================================
-    // Tunnel Lifecycle
+    // Credential Storage
     // ========================================================================
 
     /**
-     * Start the WARP tunnel.
+     * Encrypt the persisted WARP credentials with a passphrase.
      * <p>
-     * This will load or generate WARP credentials and establish the tunnel.
-     * The credentials are persisted to the specified path for reuse.
+     * Takes effect on the next tunnel start. Existing plaintext credentials
+     * are encrypted when they are next loaded. Replaces any key provider.
      *
-     * @param credPath path to store/load WARP credentials JSON file
-     * @return tunnel state after starting (TUNNEL_STATE_READY on success)
-     * @throws RuntimeException if tunnel fails to start
+     * @param passphrase the passphrase, or null to store credentials unencrypted
      */
-    public static native int startWarpTunnel(String credPath);
+    public static native void setCredentialPassphrase(String passphrase);
 
     /**
-     * Get the current tunnel state.
+     * Encrypt the persisted WARP credentials with a key supplied by Java.
+     * <p>
+     * Takes effect on the next tunnel start. Replaces any passphrase.
      *
-     * @return one of TUNNEL_STATE_* constants
+     * @param provider the key provider, or null to store credentials unencrypted
      */
-    public static native int tunnelState();
+    public static native void setCredentialKeyProvider(CredentialKeyProvider provider);
 
     /**
-     * Shutdown the tunnel.
+     * Keep the cached WARP config in its own directory instead of next to
+     * the credentials file.
      * <p>
-     * This closes all active connections and stops the tunnel.
+     * Meant for Android, where the app's private cache directory suits it
+     * better than shared storage.
+     *
+     * @param dir the directory, created if missing, or null to keep the cache next to the credentials
      */
-    public static native void shutdownTunnel();
+    public static native void setCacheDirectory(String dir);
 
     // ========================================================================
-    // TCP Operations
+    // Tunnel Options
     // ========================================================================
 
     /**
-     * Connect to a remote host via the tunnel.
+     * Set the MTU of the tunnel interface.
      * <p>
-     * This performs DNS resolution through the tunnel and establishes
-     * a TCP connection to the resolved address.
+     * Takes effect on the next tunnel start, unless the start call passes
+     * its own MTU. Some ISPs and PPPoE links need 1380 or lower.
      *
-     * @param host      hostname or IP address to connect to
-     * @param port      port number (1-65535)
-     * @param timeoutMs connection timeout in milliseconds (0 for no timeout)
-     * @return connection handle (positive value) on success
-     * @throws RuntimeException if connection fails or tunnel not ready
+     * @param mtu MTU in bytes (576-1500), or 0 for the default of 1420
+     * @throws RuntimeException if the MTU is out of range
      */
-    public static native long tcpConnect(String host, int port, long timeoutMs);
+    public static native void setMtu(int mtu);
 
     /**
-     * Read data from a TCP connection.
+     * Set the WireGuard persistent keepalive interval.
      * <p>
-     * This is a blocking call that waits for data to be available.
+     * Takes effect on the next tunnel start. Defaults to 25 seconds, which
+     * keeps NAT mappings alive on mobile hotspots. Disable it to save battery
+     * on handheld devices.
      *
-     * @param handle connection handle from {@link #tcpConnect}
-     * @param buffer byte array to read data into
-     * @return number of bytes read, 0 on EOF
-     * @throws RuntimeException on read error or invalid handle
+     * @param seconds interval in seconds (1-65535), or 0 to disable
+     * @throws RuntimeException if the interval is out of range
      */
-    public static native int tcpRead(long handle, byte[] buffer);
+    public static native void setPersistentKeepalive(int seconds);
 
     /**
-     * Write data to a TCP connection.
+     * Pin the WARP endpoint instead of using the one from the WARP config.
+     * <p>
+     * Takes effect on the next tunnel start. Some ISPs route certain
+     * Cloudflare anycast addresses badly. A pinned endpoint is the only one
+     * tried; hostnames are resolved outside the tunnel when it starts.
      *
-     * @param handle connection handle from {@link #tcpConnect}
-     * @param data   byte array containing data to write
-     * @param offset offset in the array to start writing from
-     * @param length number of bytes to write
-     * @return number of bytes written
-     * @throws RuntimeException on write error or invalid handle
+     * @param endpoint "ip", "ip:port", "host" or "host:port" (the port defaults
+     *                 to the config's), or null to use the WARP config's endpoint
+     * @throws RuntimeException if the endpoint is malformed
      */
-    public static native int tcpWrite(long handle, byte[] data, int offset, int length);
+    public static native void setEndpointOverride(String endpoint);
 
     /**
-     * Close a TCP connection.
+     * Set the preshared key mixed into WireGuard handshakes.
      * <p>
-     * After calling this, the handle is no longer valid.
+     * Takes effect on the next tunnel start. For self-hosted servers that
+     * require one; WARP does not use a preshared key, so leave it unset there.
+     * An imported profile's own PresharedKey takes precedence.
      *
-     * @param handle connection handle from {@link #tcpConnect}
+     * @param key base64 key, as printed by {@code wg genpsk}, or null for none
+     * @throws RuntimeException if the key is not 32 bytes of base64
      */
-    public static native void tcpClose(long handle);
+    public static native void setPresharedKey(String key);
 
     /**
-     * Flush a TCP connection.
+     * Choose the DNS servers used for hostname lookups through the tunnel.
      * <p>
-     * Ensures all buffered data is sent.
+     * Takes effect on the next tunnel start; each tunnel keeps its own servers
+     * and cache. Queries never leave the tunnel. Without servers, WARP tunnels
+     * use Cloudflare's DoH endpoint and imported profiles their own DNS
+     * servers, falling back to Cloudflare's.
      *
-     * @param handle connection handle from {@link #tcpConnect}
-     * @return 0 on success
-     * @throws RuntimeException on flush error or invalid handle
+     * @param servers comma-separated list of "ip" for DNS over TCP or
+     *                "ip#hostname" for DoH, or null for the default
+     * @throws RuntimeException if a server is malformed
      */
-    public static native int tcpFlush(long handle);
+    public static native void setDnsServers(String servers);
+
+    /**
+     * Choose how encrypted WireGuard packets reach the endpoint.
+     * <p>
+     * Takes effect on the next tunnel start. For networks that block UDP to
+     * Cloudflare, packets can go through a SOCKS5 proxy's UDP relay, or over a
+     * WebSocket to a bridge that forwards each message as a datagram to the
+     * WireGuard server.
+     *
+     * @param spec "udp" (the default), "socks5://[user:pass@]host:port",
+     *             "ws://host[:port]/path" or "wss://host[:port]/path", or null for UDP
+     * @throws RuntimeException if the spec is malformed
+     */
+    public static native void setOuterTransport(String spec);
+
+    /**
+     * Set AmneziaWG-style obfuscation parameters for the WireGuard packets.
+     * <p>
+     * Takes effect on the next tunnel start. Junk packets (Jc, Jmin, Jmax) are
+     * ignored by standard WireGuard servers, WARP included, and already defeat
+     * some DPI. Padding (S1, S2) and custom message types (H1-H4) need an
+     * AmneziaWG server with the same values.
+     *
+     * @param params AmneziaWG keys, e.g. "Jc=4, Jmin=40, Jmax=70", separated by
+     *               commas, semicolons or newlines; null or empty disables obfuscation
+     * @throws RuntimeException if a parameter is unknown or out of range
+     */
+    public static native void setObfuscation(String params);
+
+    /**
+     * Control where the outer WireGuard sockets send from.
+     * <p>
+     * Takes effect on the next tunnel start. Binding to the physical interface
+     * or marking packets for a policy route keeps the tunnel's own traffic out
+     * of a system-level VPN, avoiding routing loops, and lets host firewalls
+     * match it. Applies to every socket the outer transport opens.
+     *
+     * @param bindAddress local IP address to bind to, or null for any
+     * @param interfaceName network interface to send through, or null for any (Linux and Android)
+     * @param mark firewall mark (SO_MARK), or 0 for none (Linux and Android, needs CAP_NET_ADMIN)
+     * @throws RuntimeException if the bind address is malformed
+     */
+    public static native void setOuterSocketOptions(String bindAddress, String interfaceName, int mark);
+
+    /**
+     * Set the DSCP codepoint on outgoing WireGuard packets.
+     * <p>
+     * Routers running SQM (cake, fq_codel) can prioritize marked packets, e.g.
+     * {@link #DSCP_EF} or {@link #DSCP_CS4} for game traffic. Applies to the next
+     * tunnel start and to the running tunnel: in place when its packets already
+     * go through the relay over UDP, otherwise by reconnecting, which closes
+     * tunneled connections. Marks may be cleared by the ISP.
+     *
+     * @param dscp codepoint from 1 to 63, or 0 for none
+     * @throws RuntimeException if the codepoint is out of range, or reconnecting fails
+     */
+    public static native void setOuterDscp(int dscp);
+
+    /**
+     * Batch outer UDP sends and receives (Linux).
+     * <p>
+     * Takes effect on the next tunnel start. Datagrams leave in sendmmsg
+     * batches, with runs of equal size handed to the kernel as one segmented
+     * send, and arrive through recvmmsg with receive offload where the kernel
+     * supports it, cutting per-packet syscalls on bulk transfers. Plain UDP
+     * then goes through the loopback relay like other outer transports, which
+     * adds a hop the tunnel still crosses one datagram per call. That costs
+     * more than batching saves unless the network side is the bottleneck, so
+     * measure with benches/outer_path.rs before turning it on. Off by default.
+     *
+     * @param enabled whether to batch
+     * @throws RuntimeException if enabling it off Linux
+     */
+    public static native void setOuterBatching(boolean enabled);
+
+    /**
+     * Keep tunneled connections open when the tunnel changes endpoint.
+     * <p>
+     * Takes effect on the next tunnel start. Endpoint failover, and reloads
+     * that only change the endpoint, then move the running tunnel's WireGuard
+     * session to the new endpoint instead of rebuilding the tunnel, so
+     * connections stall for the handshake rather than closing and the player
+     * stays on the server through a brief outage. Plain UDP goes through the
+     * loopback relay to make this possible. Key, address, MTU and keepalive
+     * changes still rebuild the tunnel. Off by default.
+     *
+     * @param enabled whether to preserve connections
+     */
+    public static native void setPreserveConnections(boolean enabled);
+
+    /**
+     * Set what {@link #tcpConnect} does while the tunnel is down.
+     * <p>
+     * Takes effect immediately. Defaults to CONNECT_POLICY_KILL_SWITCH.
+     *
+     * @param policy CONNECT_POLICY_KILL_SWITCH or CONNECT_POLICY_FALLBACK_DIRECT
+     * @throws RuntimeException if the policy is invalid
+     */
+    public static native void setConnectPolicy(int policy);
+
+    /**
+     * Limit how many connections may be open at once.
+     * <p>
+     * Protects the netstack's socket set from callers that leak handles.
+     * Connects beyond the limit fail; open connections are not affected.
+     * Takes effect immediately.
+     *
+     * @param max most open connections, or 0 for no limit
+     */
+    public static native void setMaxConnections(int max);
+
+    /**
+     * Close connections that go unused for too long.
+     * <p>
+     * A connection is idle while no read or write on it completes; a read
+     * waiting for data that never comes does not count as use. Using a closed
+     * handle afterwards throws {@link StaleHandleException}. Takes effect immediately.
+     *
+     * @param seconds idle time before a connection is closed, or 0 to never close idle connections
+     */
+    public static native void setIdleTimeout(int seconds);
 
     // ========================================================================
-    // Helper methods
+    // Tunnel Lifecycle
     // ========================================================================
 
     /**
-     * Check if the tunnel is ready for connections.
+     * Start the WARP tunnel.
+     * <p>
+     * This will load or generate WARP credentials and establish the tunnel.
+     * The credentials are persisted to the specified path for reuse.
+     * <p>
+     * If a WARP+ license key is given it is applied to the device before
+     * connecting. A rejected key is logged and the tunnel starts on the
+     * existing account.
      *
-     * @return true if tunnel state is TUNNEL_STATE_READY
+     * @param credPath   path to store/load WARP credentials JSON file
+     * @param licenseKey WARP+ license key, or null to keep the current account
+     * @param mtu        tunnel MTU in bytes (576-1500), or 0 for the one set with {@link #setMtu(int)}
+     * @return tunnel state after starting (TUNNEL_STATE_READY on success)
+     * @throws RuntimeException if the MTU is out of range or the tunnel fails to start
      */
-    public static boolean isTunnelReady() {
-        return tunnelState() == TUNNEL_STATE_READY;
-    }
+    public static native int startWarpTunnel(String credPath, String licenseKey, int mtu);
 
     /**
-     * Get a human-readable description of the tunnel state.
+     * Start a WARP tunnel enrolled in a Cloudflare Zero Trust organization.
+     * <p>
+     * The auth token is the JWT shown after signing in at
+     * {@code https://<orgName>.cloudflareaccess.com/warp}. It is short-lived and
+     * only needed the first time; later starts reuse the persisted credentials.
      *
-     * @param state tunnel state value
-     * @return description string
+     * @param orgName   Zero Trust team name
+     * @param authToken enrollment JWT from the Access portal
+     * @param credPath  path to store/load WARP credentials JSON file
+     * @param mtu       tunnel MTU in bytes (576-1500), or 0 for the one set with {@link #setMtu(int)}
+     * @return tunnel state after starting (TUNNEL_STATE_READY on success)
+     * @throws RuntimeException if the MTU is out of range, or enrollment or tunnel start fails
      */
-    public static String tunnelStateToString(int state) {
-        switch (state) {
-            case TUNNEL_STATE_STOPPED:
-                return "STOPPED";
-            case TUNNEL_STATE_STARTING:
-                return "STARTING";
-            case TUNNEL_STATE_READY:
-                return "READY";
-            case TUNNEL_STATE_FAILED:
-                return "FAILED";
-            default:
-                return "UNKNOWN(" + state + ")";
+    public static native int startWarpTeamsTunnel(String orgName, String authToken, String credPath, int mtu);
+
+    /**
+     * Start a tunnel from a WireGuard profile in wg-quick {@code .conf} format.
+     * <p>
+     * Accepts profiles exported by providers such as Mullvad and Proton, and
+     * those generated by wgcf. MTU and PersistentKeepalive fall back to the
+     * tunnel options when the profile leaves them out. Hostnames resolve
+     * through the profile's IPv4 DNS servers, over TCP, unless
+     * {@link #setDnsServers(String)} chose others.
+     *
+     * @param text profile contents
+     * @param mtu  tunnel MTU in bytes (576-1500), overriding the profile's, or 0 to keep it
+     * @return tunnel state after starting (TUNNEL_STATE_READY on success)
+     * @throws InvalidConfigException if the profile is invalid, listing every problem found
+     * @throws RuntimeException if the MTU is out of range or the tunnel fails to start
+     */
+    public static native int importWireGuardProfile(String text, int mtu);
+
+    /**
+     * Check a WireGuard profile in wg-quick {@code .conf} format without starting anything.
+     * <p>
+     * Cheap enough to call as the user types. Each problem is an object with
+     * {@code severity} ("error" or "warning"), {@code line} (1-based, or 0
+     * for a missing field), {@code section}, {@code field} and
+     * {@code message}. The profile can be imported if there are no errors.
+     *
+     * @param text profile contents
+     * @return JSON array of problems, empty if there are none
+     */
+    public static native String validateConfig(String text);
+
+    /**
+     * Apply a changed WireGuard profile to the running tunnel.
+     * <p>
+     * The endpoint, MTU, PersistentKeepalive and DNS servers can change.
+     * Changing any of the first three rebuilds the tunnel and closes tunneled
+     * connections, except that with {@link #setPreserveConnections} on, an
+     * endpoint change alone moves the running tunnel. DNS changes alone leave
+     * everything open. The keys and the IPv4 address cannot change without
+     * restarting the tunnel. An MTU passed to
+     * {@link #importWireGuardProfile(String, int)} still overrides the profile's.
+     *
+     * @param text profile contents
+     * @return RELOAD_UNCHANGED, RELOAD_RECONNECTED, RELOAD_DNS_UPDATED or RELOAD_MOVED
+     * @throws InvalidConfigException if the profile is invalid
+     * @throws RuntimeException if no tunnel is running, an identity field changed or reconnecting fails
+     */
+    public static native int reloadTunnelConfig(String text);
+
+    /**
+     * Generate a new key pair for the running tunnel.
+     * <p>
+     * The tunnel keeps using its current key until {@link #confirmKeyRotation()}
+     * is called, so the new public key can first be added to the server.
+     * Calling this again replaces the pending key. Only tunnels started from
+     * a profile can rotate; WARP keys are registered with Cloudflare.
+     *
+     * @return base64 public key to add to the server
+     * @throws RuntimeException if no tunnel is running or it is a WARP tunnel
+     */
+    public static native String rotateKeys();
+
+    /**
+     * Switch the running tunnel to the key from {@link #rotateKeys()}.
+     * <p>
+     * Reconnects the tunnel, closing tunneled connections. If the handshake
+     * with the new key fails the old tunnel keeps running and the new key
+     * stays pending.
+     *
+     * @throws RuntimeException if no rotation is pending or the handshake fails
+     */
+    public static native void confirmKeyRotation();
+
+    /**
+     * Get the current tunnel state.
+     *
+     * @return one of TUNNEL_STATE_* constants
+     */
+    public static native int tunnelState();
+
+    /**
+     * Get the most recent failure to start, reconnect or reconfigure the tunnel.
+     * <p>
+     * Covers start, reload, resume, re-handshake, DSCP and key rotation calls
+     * that threw, and endpoint failover and sleep recovery in the background,
+     * so callers can branch on the code instead of parsing exception messages.
+     * Kept until the next failure, even across restarts.
+     *
+     * @return JSON object with {@code code} (one of the TUNNEL_ERROR_* constants),
+     *         {@code message} and {@code age_seconds}, or null if nothing failed yet
+     */
+    public static native String lastTunnelError();
+
+    /**
+     * Pause the tunnel.
+     * <p>
+     * Stops the WireGuard timers and packet processing without destroying the
+     * network stack or dropping connection handles, e.g. while the player is in
+     * a single-player world. New connections are refused and I/O on existing
+     * ones stalls until {@link #resumeTunnel()}.
+     *
+     * @return TUNNEL_STATE_PAUSED, or TUNNEL_STATE_STOPPED if no tunnel is running
+     */
+    public static native int pauseTunnel();
+
+    /**
+     * Resume a paused tunnel.
+     * <p>
+     * Restarts packet processing and initiates a fresh handshake without
+     * waiting for it to complete.
+     *
+     * @return TUNNEL_STATE_READY, or TUNNEL_STATE_STOPPED if no tunnel is running
+     * @throws RuntimeException if the tunnel fails to resume
+     */
+    public static native int resumeTunnel();
+
+    /**
+     * Tell the tunnel that the local network changed, e.g. a switch between
+     * Wi-Fi, Ethernet or a VPN.
+     * <p>
+     * Starts a new handshake immediately so the peer learns the new address.
+     * A tunnel sending through the loopback relay, as any outer option off its
+     * default makes it, first reopens its outer sockets on the new network. Open connections are
+     * kept and resume once the handshake completes.
+     *
+     * @return true if a handshake was started, false if no tunnel is running or it is paused
+     * @throws RuntimeException if the handshake cannot be sent
+     */
+    public static native boolean notifyNetworkChanged();
+
+    /**
+     * Tell the tunnel that the machine woke from sleep.
+     * <p>
+     * Sleep is also detected natively from clock jumps within a few seconds;
+     * this skips that delay. Starts a new handshake and fails reads pending on
+     * tunneled connections with {@link #TCP_ERROR_RECONNECTING}, so they can be
+     * retried instead of hanging until they time out.
+     *
+     * @return true if a handshake was started, false if no tunnel is running or it is paused
+     * @throws RuntimeException if the handshake cannot be sent
+     */
+    public static native boolean notifyResumed();
+
+    /**
+     * Delete the WARP device registration and its credentials file.
+     * <p>
+     * Use this to reset the WARP identity or free a slot on an account with a
+     * device limit. The tunnel must be shut down first. The next start
+     * registers a new device.
+     *
+     * @param credPath path of the WARP credentials JSON file
+     * @return 0 on success
+     * @throws RuntimeException if the tunnel is running or deletion fails
+     */
+    public static native int deleteWarpDevice(String credPath);
+
+    /**
+     * Get the account type of the running WARP tunnel.
+     *
+     * @return one of WARP_ACCOUNT_* constants
+     */
+    public static native int warpAccountType();
+
+    /**
+     * Get the interface addresses WARP assigned to the running tunnel.
+     * <p>
+     * These are addresses inside the tunnel; servers see connections come
+     * from Cloudflare's egress addresses instead.
+     *
+     * @return addresses, IPv4 first, or null if no tunnel is running
+     */
+    public static native String[] tunnelAddresses();
+
+    /**
+     * Get the WireGuard public key registered for the running tunnel's device.
+     *
+     * @return base64 public key, or null if no tunnel is running or WARP did not report it
+     */
+    public static native String tunnelPublicKey();
+
+    /**
+     * Export the running tunnel's config as a wg-quick profile.
+     * <p>
+     * Lets the WARP identity be used from other WireGuard clients, e.g. to
+     * check whether a problem is specific to this tunnel. The endpoint is the
+     * one currently in use; the outer transport and obfuscation are not
+     * included.
+     *
+     * @param includePrivateKey whether to include the private and preshared keys
+     * @return profile text, or null if no tunnel is running
+     */
+    public static native String exportConfig(boolean includePrivateKey);
+
+    /**
+     * Get the WARP device ID of the running tunnel.
+     *
+     * @return device ID, or null if no tunnel is running or it was started from a profile
+     */
+    public static native String warpDeviceId();
+
+    /**
+     * Shutdown the tunnel.
+     * <p>
+     * This closes all active connections and stops the tunnel.
+     */
+    public static native void shutdownTunnel();
+
+    // ========================================================================
+    // TCP Operations
+    // ========================================================================
+
+    /**
+     * Connect to a remote host via the tunnel.
+     * <p>
+     * This performs DNS resolution through the tunnel and establishes
+     * a TCP connection to the resolved address. When the host has several
+     * addresses, attempts start 250 ms apart (Happy Eyeballs) and the first
+     * to connect wins. While the tunnel is down, the connect policy decides
+     * whether this fails or connects directly.
+     *
+     * @param host      hostname or IP address to connect to
+     * @param port      port number (1-65535)
+     * @param timeoutMs connection timeout in milliseconds (0 for no timeout)
+     * @return connection handle (positive value) on success
+     * @throws RuntimeException if connection fails or tunnel not ready
+     */
+    public static native long tcpConnect(String host, int port, long timeoutMs);
+
+    /**
+     * Connect to a remote host via the tunnel, overriding the connect policy.
+     *
+     * @param host      hostname or IP address to connect to
+     * @param port      port number (1-65535)
+     * @param timeoutMs connection timeout in milliseconds (0 for no timeout)
+     * @param policy    one of CONNECT_POLICY_* constants
+     * @return connection handle (positive value) on success
+     * @throws RuntimeException if connection fails, or the tunnel is down under the kill switch
+     */
+    public static native long tcpConnectWithPolicy(String host, int port, long timeoutMs, int policy);
+
+    /**
+     * Connect to a Minecraft server via the tunnel, honouring its SRV record.
+     * <p>
+     * Looks up {@code _minecraft._tcp.<host>} through the tunnel like the
+     * vanilla client and connects to the record's target. Falls back to
+     * {@code host:port} when there is no record. Routing rules and the
+     * connect policy then apply as for {@link #tcpConnect}.
+     *
+     * @param host      hostname or IP address to connect to
+     * @param port      port number used when there is no SRV record
+     * @param timeoutMs connection timeout in milliseconds (0 for no timeout)
+     * @return connection handle (positive value) on success
+     * @throws RuntimeException if connection fails or tunnel not ready
+     */
+    public static native long tcpConnectSrv(String host, int port, long timeoutMs);
+
+    /**
+     * Open a TLS connection through the tunnel.
+     * <p>
+     * The handle works with the {@code tcp*} functions, which then read and
+     * write plaintext, so HTTPS APIs can be called without a Java TLS layer
+     * over a custom socket. Certificates are verified against the Mozilla
+     * root store. The connection policy applies, but routing rules do not:
+     * the connection always goes through the tunnel. Connecting and the
+     * handshake together time out after 30 seconds.
+     *
+     * @param host hostname or IP address to connect to
+     * @param port port number
+     * @param sni  server name to send and verify the certificate against, or null for {@code host}
+     * @param alpn ALPN protocols to offer in order of preference (e.g. "h2", "http/1.1"), or null
+     * @return connection handle (positive value) on success
+     * @throws RuntimeException if the connection or handshake fails, or the tunnel is not ready
+     */
+    public static native long tlsConnect(String host, int port, String sni, String[] alpn);
+
+    /**
+     * Get the ALPN protocol the server picked for a TLS connection.
+     *
+     * @param handle connection handle from {@link #tlsConnect}
+     * @return the protocol, or null if none was negotiated or the handle is not TLS
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native String tlsAlpnProtocol(long handle);
+
+    /**
+     * Read data from a TCP connection.
+     * <p>
+     * This is a blocking call that waits for data to be available, unless
+     * the connection was made non-blocking with {@link #tcpSetNonBlocking}.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @param buffer byte array to read data into
+     * @return number of bytes read, 0 on EOF, or {@link #READ_WOULD_BLOCK} if non-blocking and no data is available
+     * @throws RuntimeException on read error or invalid handle
+     */
+    public static native int tcpRead(long handle, byte[] buffer);
+
+    /**
+     * Write data to a TCP connection.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @param data   byte array containing data to write
+     * @param offset offset in the array to start writing from
+     * @param length number of bytes to write
+     * @return number of bytes written; non-blocking connections and those in
+     *         {@link #tcpSetPartialWrites partial-write mode} may accept fewer, or 0 if the send buffer is full
+     * @throws RuntimeException on write error or invalid handle
+     */
+    public static native int tcpWrite(long handle, byte[] data, int offset, int length);
+
+    /**
+     * Write several buffers to a TCP connection in one call.
+     * <p>
+     * The remaining bytes of each buffer are sent in order, as one write, so
+     * a length prefix and its payload need only one native call. Buffer
+     * positions are not changed.
+     *
+     * @param handle  connection handle from {@link #tcpConnect}
+     * @param buffers direct or array-backed buffers to write
+     * @return total number of bytes written; non-blocking connections may accept fewer, or 0 if the send buffer is full
+     * @throws RuntimeException on write error, invalid handle, or a read-only heap buffer
+     */
+    public static native long tcpWritev(long handle, ByteBuffer[] buffers);
+
+    /**
+     * Start reading from a TCP connection and return at once.
+     * <p>
+     * The callback is told the number of bytes read, 0 on EOF, once they have
+     * been copied into {@code buffer}. Do not touch the buffer until then.
+     *
+     * @param handle   connection handle from {@link #tcpConnect}
+     * @param buffer   byte array to read data into
+     * @param callback called once the read completes or fails
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native void tcpReadAsync(long handle, byte[] buffer, IoCallback callback);
+
+    /**
+     * Start writing to a TCP connection and return at once.
+     * <p>
+     * The data is copied before this returns, so the array may be reused
+     * immediately.
+     *
+     * @param handle   connection handle from {@link #tcpConnect}
+     * @param data     byte array containing data to write
+     * @param offset   offset in the array to start writing from
+     * @param length   number of bytes to write
+     * @param callback called once the write completes or fails
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native void tcpWriteAsync(long handle, byte[] data, int offset, int length, IoCallback callback);
+
+    /**
+     * Close a TCP connection.
+     * <p>
+     * After calling this, the handle is no longer valid. Blocks for up to the
+     * {@link #setCloseLinger} timeout while data already written is delivered.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @throws StaleHandleException if the handle was already closed
+     * @throws RuntimeException if writes queued by {@link #setWriteBehind} could
+     *                          not be sent; the connection is closed either way
+     */
+    public static native void tcpClose(long handle);
+
+    /**
+     * Close a connection whose owner was garbage collected, if it is still open.
+     * <p>
+     * Logs a warning for each connection it closes, and never throws or waits
+     * for the {@link #setCloseLinger} timeout. Used by {@link ConnectionCleaner}.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return true if the connection was still open
+     */
+    public static native boolean tcpReap(long handle);
+
+    /**
+     * Set how long {@link #tcpClose} waits for written data to be acknowledged
+     * by the peer, like {@link java.net.Socket#setSoLinger(boolean, int)}.
+     * <p>
+     * With 0, {@code tcpClose} returns at once and remaining data is sent in
+     * the background until the tunnel is shut down.
+     *
+     * @param timeoutMs longest wait in milliseconds, or 0 to not wait
+     */
+    public static native void setCloseLinger(int timeoutMs);
+
+    /**
+     * Get the local address of a TCP connection.
+     * <p>
+     * Tunnel connections report the tunnel IP with port 0, since the native
+     * network stack does not expose the port it picked.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return the address as "ip:port", or null if the tunnel the connection used is gone
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native String tcpLocalAddress(long handle);
+
+    /**
+     * Get the remote address of a TCP connection.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return the resolved address the connection was opened to, as "ip:port"
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native String tcpRemoteAddress(long handle);
+
+    /**
+     * Shut down the sending side of a TCP connection, like {@link java.net.Socket#shutdownOutput()}.
+     * <p>
+     * Sends FIN once buffered data is out, while reads continue until the
+     * peer closes. Further writes fail. Waits for up to the
+     * {@link #setCloseLinger} timeout for writes queued by
+     * {@link #setWriteBehind}. The handle must still be closed with
+     * {@link #tcpClose}.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @throws RuntimeException if the handle is invalid or queued writes could not be sent
+     */
+    public static native void tcpShutdownOutput(long handle);
+
+    /**
+     * Enable or disable TCP keepalive on a connection, like {@link java.net.Socket#setKeepAlive(boolean)}.
+     * <p>
+     * Only direct connections (outside the tunnel) support it. Tunnel
+     * connections are left unchanged; the WireGuard persistent keepalive
+     * already keeps their path through NATs and firewalls open.
+     *
+     * @param handle       connection handle from {@link #tcpConnect}
+     * @param idleSecs     idle time before the first probe, or 0 to disable keepalive
+     * @param intervalSecs time between probes
+     * @return true if applied, false if the connection does not support it
+     * @throws RuntimeException if the handle is invalid or the option could not be set
+     */
+    public static native boolean tcpSetKeepAlive(long handle, int idleSecs, int intervalSecs);
+
+    /**
+     * Log every read and write on a connection, at debug level.
+     * <p>
+     * Off by default, so reads and writes skip the native log filter entirely
+     * even when it lets debug records through. The filter (see
+     * {@link #setLogLevel}) must still allow debug records for anything to be
+     * logged.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @param trace  true to log this connection's I/O
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native void tcpSetTrace(long handle, boolean trace);
+
+    /**
+     * Get why the last read or write on a connection failed or hit EOF.
+     * <p>
+     * Call after {@link #tcpRead} returns 0 or a read, write or flush throws,
+     * instead of parsing the exception message.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return one of the TCP_ERROR_* constants
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native int tcpLastError(long handle);
+
+    /**
+     * Get why the last read or write on a connection failed, with the message
+     * it was reported with.
+     * <p>
+     * The message is null when the code was recorded without an exception or
+     * callback error, e.g. EOF from a read that returned 0.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return JSON object with {@code code} (one of the TCP_ERROR_* constants) and {@code message}
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native String tcpLastErrorInfo(long handle);
+
+    /**
+     * Register a listener for connections closed by the peer.
+     * <p>
+     * Replaces any previous listener. Connections closed with {@link #tcpClose}
+     * are not reported.
+     *
+     * @param listener the listener, or null to remove it
+     */
+    public static native void registerConnectionListener(ConnectionListener listener);
+
+    /**
+     * Register a listener for WireGuard handshake events.
+     * <p>
+     * Replaces any previous listener. Events are counted whether or not a
+     * listener is registered; see the "handshakes" object of {@link #metricsSnapshot(int)}.
+     *
+     * @param listener the listener, or null to remove it
+     */
+    public static native void registerTunnelListener(TunnelListener listener);
+
+    /**
+     * Check whether a TCP connection is established and not yet closed by both sides.
+     * <p>
+     * Direct connections (outside the tunnel) count as connected until closed.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return true if data may still flow in at least one direction
+     */
+    public static native boolean tcpIsConnected(long handle);
+
+    /**
+     * Check whether a TCP connection is closed.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return true if the handle was closed with {@link #tcpClose} or the connection has fully shut down
+     */
+    public static native boolean tcpIsClosed(long handle);
+
+    /**
+     * Get the TCP state of a connection.
+     * <p>
+     * Direct connections (outside the tunnel) report {@link #SOCKET_STATE_ESTABLISHED}
+     * until closed.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return one of the SOCKET_STATE_* constants
+     */
+    public static native int tcpSocketState(long handle);
+
+    /**
+     * Flush a TCP connection.
+     * <p>
+     * Ensures all buffered data is sent, including writes held back by
+     * {@link #setWriteCoalescing}.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return 0 on success
+     * @throws RuntimeException on flush error or invalid handle
+     */
+    public static native int tcpFlush(long handle);
+
+    /**
+     * Enable or disable reuse of pre-warmed connections.
+     * <p>
+     * While enabled, {@link #tcpConnect} hands out a spare connection opened
+     * by {@link #prewarmConnection} when one to the same address is ready.
+     * Disabled by default. Disabling drops all spares.
+     *
+     * @param enabled true to enable the pool
+     */
+    public static native void setConnectionPoolEnabled(boolean enabled);
+
+    /**
+     * Keep one pre-warmed connection to a server.
+     * <p>
+     * A spare is opened in the background and replaced every 20 seconds so
+     * the server does not time it out. Only the most recent target is kept
+     * warm. With port 25565 the server's SRV record is followed, as the
+     * vanilla client does. Does nothing while the pool is disabled.
+     *
+     * @param host server hostname or IP address
+     * @param port server port
+     */
+    public static native void prewarmConnection(String host, int port);
+
+    /**
+     * Limit the bandwidth of a connection.
+     * <p>
+     * Reads and writes are limited separately with a token bucket that
+     * allows bursts of up to one second of traffic. Applies on top of the
+     * global limit for tunneled connections.
+     *
+     * @param handle      connection handle from {@link #tcpConnect}
+     * @param bytesPerSec limit per direction in bytes per second, or 0 for no limit
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native void setRateLimit(long handle, long bytesPerSec);
+
+    /**
+     * Limit the combined bandwidth of all tunneled connections.
+     * <p>
+     * Applies to existing and new connections through the tunnel; direct
+     * connections are not limited.
+     *
+     * @param bytesPerSec limit per direction in bytes per second, or 0 for no limit
+     */
+    public static native void setGlobalRateLimit(long bytesPerSec);
+
+    /**
+     * Hold back small writes and send them together.
+     * <p>
+     * Useful for the many tiny packets Minecraft sends. Buffered writes go out
+     * after at most {@code flushIntervalMs}, on {@link #tcpFlush}, or once
+     * enough has accumulated to fill a packet. If sending them fails, the next
+     * {@link #tcpWrite}, {@link #tcpFlush} or {@link #tcpClose} on the
+     * connection throws. Applies to all connections.
+     *
+     * @param flushIntervalMs longest a write is held back, or 0 to disable coalescing
+     */
+    public static native void setWriteCoalescing(int flushIntervalMs);
+
+    /**
+     * Send every write on a connection immediately, like {@link java.net.Socket#setTcpNoDelay(boolean)}.
+     * <p>
+     * Opts the connection out of {@link #setWriteCoalescing}, for latency-sensitive
+     * traffic. Writes already held back are sent right away.
+     *
+     * @param handle  connection handle from {@link #tcpConnect}
+     * @param noDelay true to bypass coalescing, false to follow the global setting
+     * @throws RuntimeException if the handle is invalid or sending buffered writes fails
+     */
+    public static native void tcpSetNoDelay(long handle, boolean noDelay);
+
+    /**
+     * Read ahead of Java on new connections.
+     * <p>
+     * A background task keeps up to {@code bufferKb} of received data ready,
+     * so most {@link #tcpRead} calls return without waiting on the tunnel.
+     * Enabled with 64 KiB by default. Connections that are already open keep
+     * their current setting.
+     *
+     * @param bufferKb read-ahead buffer per connection in KiB, or 0 to disable
+     */
+    public static native void setReadAhead(int bufferKb);
+
+    /**
+     * Send writes from a background task on new connections.
+     * <p>
+     * {@link #tcpWrite} returns once its data is queued, waiting only while
+     * {@code bufferKb} is already queued; {@link #tcpFlush} waits for the queue
+     * to be sent, and {@link #tcpClose} for up to the {@link #setCloseLinger}
+     * timeout. A failed send is thrown by the next write, flush or close.
+     * Disabled by default. Connections that are already open keep their
+     * current setting.
+     *
+     * @param bufferKb write-behind queue per connection in KiB, or 0 to disable
+     */
+    public static native void setWriteBehind(int bufferKb);
+
+    // ========================================================================
+    // Non-blocking I/O
+    // ========================================================================
+
+    /**
+     * Make reads and writes on a connection return at once instead of waiting.
+     * <p>
+     * Together with {@link #tcpSelect} and {@link #wakeupSelect} this is enough to
+     * back a {@link java.nio.channels.SocketChannel} and
+     * {@link java.nio.channels.Selector} pair over the tunnel. Writes held back by
+     * {@link #setWriteCoalescing} are still sent in full before a non-blocking write.
+     *
+     * @param handle      connection handle from {@link #tcpConnect}
+     * @param nonBlocking true for non-blocking mode
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native void tcpSetNonBlocking(long handle, boolean nonBlocking);
+
+    /**
+     * Make writes on a connection accept only what fits, while reads keep waiting.
+     * <p>
+     * {@link #tcpWrite} and {@link #tcpWritev} then return the bytes accepted,
+     * or 0 if none fit, as in non-blocking mode, so a stalled receiver cannot
+     * hold up the writing thread. Wait for room with {@link #tcpAwaitWritable}
+     * before writing the rest.
+     *
+     * @param handle  connection handle from {@link #tcpConnect}
+     * @param partial true to return from writes at once
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native void tcpSetPartialWrites(long handle, boolean partial);
+
+    /**
+     * Wait until a write on a connection would accept some data.
+     * <p>
+     * Wakes as soon as the write-behind queue (see {@link #setWriteBehind}) has
+     * room; without one, writability is rechecked every few milliseconds. A
+     * closed connection counts as writable, so the next write reports the error.
+     *
+     * @param handle    connection handle from {@link #tcpConnect}
+     * @param timeoutMs longest wait in milliseconds, or 0 to wait indefinitely
+     * @return true if writable, false on timeout
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native boolean tcpAwaitWritable(long handle, long timeoutMs);
+
+    /**
+     * Check which operations on a connection would not block right now.
+     * <p>
+     * EOF and errors count as ready, so the next call reports them.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return bitmask of {@link #READY_READ} and {@link #READY_WRITE}
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native int tcpReadyOps(long handle);
+
+    /**
+     * Wait until at least one connection is ready for the operations of interest.
+     * <p>
+     * Closed or unknown handles count as ready for everything, so the next read
+     * or write reports the error. The netstack has no readiness callbacks, so
+     * readiness is rechecked every few milliseconds.
+     *
+     * @param handles     connection handles to watch
+     * @param interestOps {@link #READY_READ}/{@link #READY_WRITE} bitmask for each handle
+     * @param readyOps    receives the ready bits for each handle; same length as {@code handles}
+     * @param timeoutMs   longest wait in milliseconds, 0 to wait indefinitely, or negative to not wait
+     * @return number of ready handles; 0 on timeout or {@link #wakeupSelect}
+     */
+    public static native int tcpSelect(long[] handles, int[] interestOps, int[] readyOps, long timeoutMs);
+
+    /**
+     * Move a connection's data through shared-memory ring buffers instead of
+     * {@link #tcpRead} and {@link #tcpWrite}.
+     * <p>
+     * Both buffers must be direct, 8-byte aligned and larger than
+     * {@link #RING_HEADER_SIZE}, with a zeroed header. Each starts with a header
+     * (see the RING_* offsets) followed by the data area. Head and tail are byte
+     * counts that only grow; the position in the data area is the count modulo
+     * its size. Java must read the other side's index with acquire semantics and
+     * publish its own with release semantics, e.g. through
+     * {@link java.lang.invoke.MethodHandles#byteBufferViewVarHandle}.
+     * <p>
+     * The native side produces {@code rx} and consumes {@code tx}. Call
+     * {@link #tcpRingNotify} after adding to {@code tx} or taking from {@code rx},
+     * and {@link #tcpRingWait} to block. The connection is switched to blocking
+     * mode, and {@link #tcpRead}, {@link #tcpWrite}, {@link #tcpWritev} and the
+     * async variants throw for it afterwards. If Java leaves a ring's head and
+     * tail more than its data area apart, that ring goes to {@link #RING_STATE_ERROR}.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @param rx     buffer for received data
+     * @param tx     buffer for data to send
+     * @throws RuntimeException if the handle is invalid, a buffer is unsuitable, or rings are already attached
+     */
+    public static native void tcpAttachRings(long handle, ByteBuffer rx, ByteBuffer tx);
+
+    /**
+     * Wake the native side after adding data to the send ring or taking data
+     * from the receive ring.
+     *
+     * @param handle connection handle with rings attached
+     * @throws RuntimeException if the handle is invalid or has no rings
+     */
+    public static native void tcpRingNotify(long handle);
+
+    /**
+     * Wait until the receive ring has data or the send ring has space.
+     * <p>
+     * A ring that is no longer {@link #RING_STATE_OPEN} counts as ready.
+     *
+     * @param handle      connection handle with rings attached
+     * @param interestOps {@link #READY_READ} and/or {@link #READY_WRITE}
+     * @param timeoutMs   longest wait in milliseconds, or 0 to wait indefinitely
+     * @return the ready ops, or 0 on timeout
+     * @throws RuntimeException if the handle is invalid or has no rings
+     */
+    public static native int tcpRingWait(long handle, int interestOps, long timeoutMs);
+
+    /**
+     * Make a {@link #tcpSelect} in progress return at once, or the next one if
+     * none is in progress, like {@link java.nio.channels.Selector#wakeup()}.
+     */
+    public static native void wakeupSelect();
+
+    // ========================================================================
+    // Metrics
+    // ========================================================================
+
+    /**
+     * Get a snapshot of tunnel, connection and runtime metrics.
+     * <p>
+     * Includes packets and bytes received through the tunnel, time since the
+     * last packet from the peer, handshake counts and age, open connections and payload totals per
+     * route, per-connection throughput, and Tokio task counts. Outbound
+     * tunnel traffic is reported as payload bytes written by tunneled
+     * connections.
+     *
+     * @param format one of METRICS_FORMAT_* constants
+     * @return metrics as Prometheus text or a JSON object
+     * @throws RuntimeException if the format is invalid
+     */
+    public static native String metricsSnapshot(int format);
+
+    /**
+     * Get payload totals per destination address.
+     * <p>
+     * Covers every connection opened through {@link #tcpConnect} and the like
+     * since {@link #initJNI}, open or closed, so traffic to hosts other than
+     * the game server stands out. Native HTTP and WebSocket requests are not
+     * included.
+     *
+     * @return JSON array of objects with "address", "route" ("tunnel" or
+     *         "direct"), "open_connections", "connections", "rx_bytes" and
+     *         "tx_bytes", busiest first
+     */
+    public static native String trafficByDestination();
+
+    /**
+     * Get the tunnel's throughput over the last {@code seconds} seconds, for live bandwidth graphs.
+     * <p>
+     * One sample per second of payload bytes on tunneled connections, as in the
+     * per-route metrics. Up to ten minutes are kept while the tunnel runs;
+     * starting a tunnel clears them.
+     *
+     * @param seconds how many of the latest samples to return
+     * @return two arrays, bytes received and bytes sent per second, oldest first;
+     *         shorter than {@code seconds} if the history is
+     */
+    public static native long[][] throughputHistory(int seconds);
+
+    /**
+     * List the handles of all open connections.
+     *
+     * @return connection handles, in no particular order
+     */
+    public static native long[] listConnections();
+
+    /**
+     * Describe an open connection, e.g. for a debug screen.
+     * <p>
+     * Returns a JSON object with {@code handle}, {@code route} ("tunnel" or
+     * "direct"), {@code remote}, {@code local} (null for tunnel connections),
+     * {@code state} (TCP state name), {@code age_seconds}, {@code rx_bytes},
+     * {@code tx_bytes} and {@code last_error} (one of the TCP_ERROR_* constants).
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return JSON description of the connection
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native String connectionInfo(long handle);
+
+    /**
+     * Get round-trip and retransmission statistics of a connection, to tell
+     * whether high ping comes from the WARP path or the server.
+     * <p>
+     * Returns a JSON object with {@code handle}, {@code route}, {@code state},
+     * {@code handshake_rtt_ms} (SYN to SYN-ACK at connect), {@code rtt_ms},
+     * {@code rtt_var_ms}, {@code retransmits}, {@code total_retransmits},
+     * {@code cwnd_segments} and {@code mss}. Tunnel connections only report the
+     * handshake RTT, which covers the whole path through WARP to the server;
+     * the other fields come from the kernel for direct connections on Linux,
+     * and are null otherwise.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return JSON statistics
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native String tcpStats(long handle);
+
+    /**
+     * Describe how a connection was opened, to find which phase of a slow
+     * connect took the time.
+     * <p>
+     * Returns a JSON object with {@code dns_start_ms}, {@code dns_end_ms},
+     * {@code syn_sent_ms}, {@code established_ms} (SYN-ACK received) and
+     * {@code first_byte_ms}, each in milliseconds since the connect call began
+     * or null if the phase did not happen, and {@code pooled}, set when a
+     * pre-warmed connection was used.
+     *
+     * @param handle connection handle from {@link #tcpConnect}
+     * @return JSON timeline, or null if the connection was not opened by a connect call
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native String tcpConnectTrace(long handle);
+
+    // ========================================================================
+    // Packet Capture
+    // ========================================================================
+
+    /**
+     * Start writing decrypted tunnel packets to a pcapng file.
+     * <p>
+     * Packets are raw IP and can be opened in Wireshark. Only packets
+     * received from the tunnel are captured; outbound packets are not
+     * available yet. Replaces any capture in progress, and keeps running
+     * across tunnel restarts until stopped or the size limit is reached.
+     *
+     * @param path     file to write, overwritten if it exists
+     * @param maxBytes stop once the file reaches this size, or 0 for no limit
+     * @throws RuntimeException if the file cannot be created
+     */
+    public static native void startPacketCapture(String path, long maxBytes);
+
+    /**
+     * Stop the packet capture and flush the file.
+     *
+     * @return true if a capture was running
+     */
+    public static native boolean stopPacketCapture();
+
+    // ========================================================================
+    // Routing
+    // ========================================================================
+
+    /**
+     * Route a destination range around the tunnel (split tunneling).
+     * <p>
+     * Connections to matching addresses are made directly. When a destination
+     * matches both a bypass and a tunnel route, the more specific one wins.
+     *
+     * @param cidr network in CIDR notation (e.g. "192.168.0.0/16") or a single address
+     * @throws RuntimeException if the CIDR is invalid
+     */
+    public static native void addBypassRoute(String cidr);
+
+    /**
+     * Route a destination range through the tunnel.
+     * <p>
+     * Once any tunnel route is added, destinations matching no route are
+     * connected directly instead of through the tunnel.
+     *
+     * @param cidr network in CIDR notation (e.g. "104.16.0.0/12") or a single address
+     * @throws RuntimeException if the CIDR is invalid
+     */
+    public static native void addTunnelRoute(String cidr);
+
+    /**
+     * Route connections to matching hostnames through the tunnel or directly.
+     * <p>
+     * Rules are matched against the hostname passed to {@link #tcpConnect}.
+     * The most specific pattern wins, and domain rules take precedence over
+     * CIDR routes. For example, {@code addDomainRule("*.hypixel.net", ROUTE_TUNNEL)}
+     * with {@code addDomainRule("*", ROUTE_DIRECT)} tunnels only Hypixel.
+     * Hostnames routed directly are also resolved directly, with the system
+     * resolver. Adding a rule for an existing pattern replaces it.
+     *
+     * @param pattern "example.com", "*.example.com" (subdomains only) or "*" (any hostname)
+     * @param route   one of ROUTE_* constants
+     * @throws RuntimeException if the pattern or route is invalid
+     */
+    public static native void addDomainRule(String pattern, int route);
+
+    /**
+     * Send a HAProxy PROXY protocol v2 header on connections to matching destinations.
+     * <p>
+     * The header goes out right after connecting, before any data, so a server
+     * behind a PROXY-aware proxy (Velocity, BungeeCord, HAProxy) sees
+     * {@code sourceAddress} as the client, e.g. for IP bans. Only add rules for
+     * servers that expect the header; others reject the connection. Domain
+     * rules take precedence over CIDRs and the most specific rule wins, as in
+     * {@link #addDomainRule}. Adding a rule for an existing target replaces it.
+     *
+     * @param target        "example.com", "*.example.com", "*", or a CIDR or single address
+     * @param sourceAddress address to advertise, "ip" or "ip:port", or null for the
+     *                      connection's own address (the tunnel IP when tunneled)
+     * @throws RuntimeException if the target or address is invalid
+     */
+    public static native void addProxyProtocolRule(String target, String sourceAddress);
+
+    /**
+     * Remove all routes, domain rules and PROXY protocol rules, sending
+     * everything through the tunnel.
+     */
+    public static native void clearRoutes();
+
+    /**
+     * Restrict which destinations new connections may go to.
+     * <p>
+     * Rules are checked in order and the first match decides; destinations no
+     * rule matches get the default action. A rule matches when every criterion
+     * it sets does: {@code cidrs} against the destination IP, {@code ports}
+     * ("25565" or "25500-25600") against its port and {@code domains}
+     * (patterns as in {@link #addDomainRule}) against the hostname connected
+     * to, which is the SRV target after an SRV lookup. For example, this
+     * allows only Minecraft's default port:
+     * <pre>{@code
+     * {"default": "deny", "rules": [{"action": "allow", "ports": ["25565"]}]}
+     * }</pre>
+     * Applies to connections opened afterwards, tunneled or direct.
+     *
+     * @param jsonRules {@code {"default": "allow"|"deny", "rules": [{"action": "allow"|"deny",
+     *                  "cidrs": [...], "ports": [...], "domains": [...]}]}}, or null to allow everything
+     * @throws RuntimeException if the rules are malformed
+     */
+    public static native void setConnectionPolicy(String jsonRules);
+
+    // ========================================================================
+    // DNS
+    // ========================================================================
+
+    /**
+     * Resolve DNS records through the tunnel.
+     * <p>
+     * Queries go to the tunnel's DNS servers inside the tunnel, by default
+     * DNS-over-HTTPS to 1.1.1.1, so they never leak to the local network.
+     * Answers are cached for their TTL, and missing names or record types
+     * for the negative TTL the server gives.
+     * <p>
+     * Records are returned in presentation format: addresses for A/AAAA, the
+     * target name for CNAME, the text for TXT and
+     * {@code "priority weight port target"} for SRV.
+     *
+     * @param hostname   name to look up
+     * @param recordType one of DNS_TYPE_* constants
+     * @return the matching records, empty if the name has none
+     * @throws RuntimeException if the lookup fails or tunnel not ready
+     */
+    public static native String[] resolve(String hostname, int recordType);
+
+    /**
+     * Resolve the Minecraft SRV record of a domain through the tunnel.
+     *
+     * @param domain domain name, without the {@code _minecraft._tcp.} prefix
+     * @return {@code "target:port"} of the preferred record, or null if there is none
+     * @throws RuntimeException if the lookup fails or tunnel not ready
+     */
+    public static native String resolveSrv(String domain);
+
+    /**
+     * Drop the running tunnel's cached DNS answers.
+     * <p>
+     * Answers are otherwise kept for their TTL, and missing names for the
+     * negative TTL the server gives, so repeated lookups stay off the tunnel.
+     *
+     * @return number of answers dropped, 0 if no tunnel is running
+     */
+    public static native int flushDnsCache();
+
+    // ========================================================================
+    // HTTP
+    // ========================================================================
+
+    /**
+     * Send an HTTP request through the tunnel and wait for the response.
+     * <p>
+     * Connects to the URL's host over the tunnel, with TLS for https URLs,
+     * sends the request with {@code Connection: close} and reads the whole
+     * response. Redirects are not followed. Routing rules do not apply, so
+     * the request always egresses through the tunnel; the connection policy
+     * does. Responses over 16 MiB are rejected.
+     *
+     * @param method    HTTP method, e.g. "GET" or "POST"
+     * @param url       http or https URL
+     * @param headers   request headers as "Name: value", or null; {@code Host} defaults to the
+     *                  URL's, and {@code Connection} and body framing are set automatically
+     * @param body      request body, or null for none
+     * @param timeoutMs timeout for the whole request in milliseconds (0 for no timeout)
+     * @return the response
+     * @throws RuntimeException if the request is malformed, fails or times out, or the tunnel is not ready
+     */
+    public static native HttpResponse httpRequest(String method, String url, String[] headers, byte[] body,
+                                                  long timeoutMs);
+
+    // ========================================================================
+    // WebSocket
+    // ========================================================================
+
+    /**
+     * Open a WebSocket connection through the tunnel.
+     * <p>
+     * Routing rules do not apply, so the connection always goes through the
+     * tunnel; the connection policy does. Handles are separate from
+     * {@link #tcpConnect} handles and only work with the {@code ws*} functions.
+     *
+     * @param url       ws or wss URL
+     * @param protocols subprotocols to offer, or null
+     * @param headers   extra handshake headers as "Name: value", or null
+     * @param timeoutMs timeout for connecting and the handshake in milliseconds (0 for no timeout)
+     * @return WebSocket handle (positive value) on success
+     * @throws RuntimeException if the URL is invalid, the connection or upgrade fails, or the tunnel is not ready
+     */
+    public static native long wsConnect(String url, String[] protocols, String[] headers, long timeoutMs);
+
+    /**
+     * Get the subprotocol the server picked for a WebSocket.
+     *
+     * @param handle WebSocket handle from {@link #wsConnect}
+     * @return the subprotocol, or null if none was negotiated
+     * @throws RuntimeException if the handle is invalid
+     */
+    public static native String wsProtocol(long handle);
+
+    /**
+     * Send a text message on a WebSocket.
+     *
+     * @param handle WebSocket handle from {@link #wsConnect}
+     * @param text   message text
+     * @throws RuntimeException if the handle is invalid or sending fails
+     */
+    public static native void wsSendText(long handle, String text);
+
+    /**
+     * Send a binary message on a WebSocket.
+     *
+     * @param handle WebSocket handle from {@link #wsConnect}
+     * @param data   message bytes
+     * @throws RuntimeException if the handle is invalid or sending fails
+     */
+    public static native void wsSendBinary(long handle, byte[] data);
+
+    /**
+     * Wait for the next message on a WebSocket.
+     * <p>
+     * Blocks until a whole message arrives; pings are answered while waiting.
+     * When the peer closes the connection a {@link WebSocketMessage#CLOSE}
+     * message is returned and the handle is released.
+     *
+     * @param handle WebSocket handle from {@link #wsConnect}
+     * @return the message
+     * @throws RuntimeException if the handle is invalid, receiving fails, or {@link #wsClose} was called
+     */
+    public static native WebSocketMessage wsReceive(long handle);
+
+    /**
+     * Close a WebSocket and release its handle.
+     * <p>
+     * Sends a close frame without waiting for the peer's reply. A
+     * {@link #wsReceive} in progress fails. Closing an unknown handle does nothing.
+     *
+     * @param handle WebSocket handle from {@link #wsConnect}
+     * @param code   close code, e.g. 1000 for a normal closure
+     * @param reason close reason, or null
+     */
+    public static native void wsClose(long handle, int code, String reason);
+
+    // ========================================================================
+    // Minecraft
+    // ========================================================================
+
+    /**
+     * Query a Minecraft server's status through the tunnel.
+     * <p>
+     * Performs the Server List Ping handshake and status request natively and
+     * returns the server's JSON status (version, players, MOTD, favicon).
+     *
+     * @param host      hostname or IP address, also sent in the handshake
+     * @param port      port number (1-65535)
+     * @param timeoutMs timeout for the whole exchange in milliseconds (0 for no timeout)
+     * @return the JSON status payload
+     * @throws RuntimeException if the query fails or tunnel not ready
+     */
+    public static native String serverStatus(String host, int port, long timeoutMs);
+
+    /**
+     * Forward a local UDP port to a Bedrock server through the tunnel.
+     * <p>
+     * Point Geyser or a Bedrock client at {@code bindAddress} to play over
+     * WARP. Each client address gets its own UDP port in the tunnel, so the
+     * server tells clients apart, and its session ends after 10 seconds
+     * without traffic, as RakNet's own does. The server address is resolved
+     * once, through the tunnel; only IPv4 servers can be reached. The forward
+     * keeps running across tunnel restarts until {@link #udpForwardStop},
+     * dropping datagrams while no tunnel is up.
+     *
+     * @param bindAddress local "ip:port" to receive datagrams on, e.g. "127.0.0.1:19132"
+     * @param host        server hostname or IPv4 address
+     * @param port        server port, 19132 for Bedrock by default
+     * @return a handle for {@link #udpForwardStop}
+     * @throws RuntimeException if the address cannot be bound, the host cannot be resolved, or tunnel not ready
+     */
+    public static native long udpForwardStart(String bindAddress, String host, int port);
+
+    /**
+     * Stop a UDP forward and drop its sessions.
+     *
+     * @param handle handle from {@link #udpForwardStart}; unknown handles are ignored
+     */
+    public static native void udpForwardStop(long handle);
+
+    // ========================================================================
+    // Diagnostics
+    // ========================================================================
+
+    /**
+     * Fetch Cloudflare's {@code /cdn-cgi/trace} through the tunnel.
+     * <p>
+     * Confirms traffic exits via WARP: the {@code warp} field is {@code "on"}
+     * or {@code "plus"} when it does, alongside the egress {@code ip} and the
+     * data center {@code colo}.
+     *
+     * @return JSON object of the trace's fields
+     * @throws RuntimeException if the request fails or tunnel not ready
+     */
+    public static native String warpTrace();
+
+    /**
+     * Write a zip of diagnostics to attach to bug reports.
+     * <p>
+     * Contains the last 2000 native log lines, environment info (library
+     * version, OS, architecture, tunnel state), the running tunnel's config
+     * and the tunnel options, a metrics snapshot, per-destination traffic,
+     * the throughput history, and the open connections with their connect
+     * traces. Private keys, preshared keys and proxy passwords are left out.
+     * Nothing is uploaded.
+     *
+     * @param path file to write, overwritten if it exists
+     * @throws RuntimeException if the file cannot be written
+     */
+    public static native void exportDiagnostics(String path);
+
+    /**
+     * Ping a host through the tunnel with ICMP echo.
+     * <p>
+     * Shows the in-tunnel latency to a server before joining it. Hostnames
+     * are resolved through the tunnel. Only IPv4 destinations can be reached,
+     * like for {@link #tcpConnect}.
+     *
+     * @param host      hostname or IPv4 address
+     * @param timeoutMs longest wait for the reply in milliseconds
+     * @return the round-trip time in milliseconds
+     * @throws RuntimeException if there is no reply in time, the host cannot be resolved, or tunnel not ready
+     */
+    public static native long icmpPing(String host, long timeoutMs);
+
+    /**
+     * Detect the NAT in front of this machine with STUN.
+     * <p>
+     * Outside the tunnel, one UDP socket sends binding requests to two public
+     * STUN servers: matching public addresses mean an endpoint-independent
+     * (cone) NAT, where peers can reach the address a server saw, and
+     * different ones an address-dependent (symmetric) NAT, which usually
+     * needs a relay. If a tunnel is running, a binding request over TCP
+     * through it also reports the tunnel's public address. The direct test
+     * sends traffic outside the tunnel.
+     *
+     * @return JSON report: {@code direct} with {@code nat_type} ("open", "endpoint-independent",
+     *         "address-dependent", "unknown" or "udp-blocked"), {@code local_address},
+     *         {@code mapped_addresses} and {@code errors}; {@code tunnel} with
+     *         {@code public_address} or {@code error}, or null without a tunnel
+     */
+    public static native String detectNat();
+
+    /**
+     * Measure the tunnel's handshake time, round-trip time and throughput.
+     * <p>
+     * The endpoint must speak Cloudflare's speed test protocol over HTTPS,
+     * like {@code speed.cloudflare.com}. The JSON report has {@code dns_ms},
+     * {@code handshake_ms}, {@code rtt_ms}, {@code download_mbps} and
+     * {@code upload_mbps}.
+     *
+     * @param host      endpoint hostname
+     * @param bytes     bytes to transfer in each direction (0 for the default of 10 MiB)
+     * @param timeoutMs timeout for the whole test in milliseconds (0 for no timeout)
+     * @return the JSON report
+     * @throws RuntimeException if the test fails or tunnel not ready
+     */
+    public static native String runSelfTest(String host, int bytes, long timeoutMs);
+
+    // ========================================================================
+    // Helper methods
+    // ========================================================================
+
+    /**
+     * Check whether the loaded native library supports all of the given features.
+     * <p>
+     * Libraries older than {@link #nativeFeatures()} report no features
+     * instead of throwing {@link UnsatisfiedLinkError}.
+     *
+     * @param features one or more FEATURE_* constants, or'ed together
+     * @return true if every requested feature is supported
+     */
+    public static boolean hasFeature(long features) {
+        try {
+            return (nativeFeatures() & features) == features;
+        } catch (UnsatisfiedLinkError e) {
+            return false;
+        }
+    }
+
+    /**
+     * Check if the tunnel is ready for connections.
+     *
+     * @return true if tunnel state is TUNNEL_STATE_READY
+     */
+    public static boolean isTunnelReady() {
+        return tunnelState() == TUNNEL_STATE_READY;
+    }
+
+    /**
+     * Get a human-readable description of the tunnel state.
+     *
+     * @param state tunnel state value
+     * @return description string
+     */
+    public static String tunnelStateToString(int state) {
+        switch (state) {
+            case TUNNEL_STATE_STOPPED:
+                return "STOPPED";
+            case TUNNEL_STATE_STARTING:
+                return "STARTING";
+            case TUNNEL_STATE_READY:
+                return "READY";
+            case TUNNEL_STATE_FAILED:
+                return "FAILED";
+            case TUNNEL_STATE_PAUSED:
+                return "PAUSED";
+            default:
+                return "UNKNOWN(" + state + ")";
+        }
+    }
+
+    /**
+     * Get a human-readable description of a TCP error.
+     *
+     * @param error TCP error value
+     * @return description string
+     */
+    public static String tcpErrorToString(int error) {
+        switch (error) {
+            case TCP_ERROR_NONE:
+                return "NONE";
+            case TCP_ERROR_EOF:
+                return "EOF";
+            case TCP_ERROR_RESET:
+                return "RESET";
+            case TCP_ERROR_TIMEOUT:
+                return "TIMEOUT";
+            case TCP_ERROR_TUNNEL_DOWN:
+                return "TUNNEL_DOWN";
+            case TCP_ERROR_BUFFER_FULL:
+                return "BUFFER_FULL";
+            case TCP_ERROR_OTHER:
+                return "OTHER";
+            case TCP_ERROR_RECONNECTING:
+                return "RECONNECTING";
+            default:
+                return "UNKNOWN(" + error + ")";
+        }
+    }
+
+    /**
+     * Get a human-readable description of a tunnel error code.
+     *
+     * @param error tunnel error value
+     * @return description string
+     */
+    public static String tunnelErrorToString(int error) {
+        switch (error) {
+            case TUNNEL_ERROR_NOT_INITIALIZED:
+                return "NOT_INITIALIZED";
+            case TUNNEL_ERROR_ALREADY_RUNNING:
+                return "ALREADY_RUNNING";
+            case TUNNEL_ERROR_NOT_READY:
+                return "NOT_READY";
+            case TUNNEL_ERROR_WARP_REGISTRATION:
+                return "WARP_REGISTRATION";
+            case TUNNEL_ERROR_WARP_API:
+                return "WARP_API";
+            case TUNNEL_ERROR_CREDENTIAL_PERSISTENCE:
+                return "CREDENTIAL_PERSISTENCE";
+            case TUNNEL_ERROR_CREDENTIALS_LOCKED:
+                return "CREDENTIALS_LOCKED";
+            case TUNNEL_ERROR_DNS:
+                return "DNS";
+            case TUNNEL_ERROR_CONNECTION_FAILED:
+                return "CONNECTION_FAILED";
+            case TUNNEL_ERROR_INVALID_HANDLE:
+                return "INVALID_HANDLE";
+            case TUNNEL_ERROR_STALE_HANDLE:
+                return "STALE_HANDLE";
+            case TUNNEL_ERROR_IO:
+                return "IO";
+            case TUNNEL_ERROR_TIMEOUT:
+                return "TIMEOUT";
+            case TUNNEL_ERROR_WOULD_BLOCK:
+                return "WOULD_BLOCK";
+            case TUNNEL_ERROR_TOO_MANY_CONNECTIONS:
+                return "TOO_MANY_CONNECTIONS";
+            case TUNNEL_ERROR_RECONNECTING:
+                return "RECONNECTING";
+            case TUNNEL_ERROR_RESTART_REQUIRED:
+                return "RESTART_REQUIRED";
+            case TUNNEL_ERROR_POLICY_DENIED:
+                return "POLICY_DENIED";
+            default:
+                return "UNKNOWN(" + error + ")";
+        }
+    }
+
+    /**
+     * Get a human-readable description of a peer close reason.
+     *
+     * @param reason close reason value
+     * @return description string
+     */
+    public static String closeReasonToString(int reason) {
+        switch (reason) {
+            case CLOSE_REASON_FIN:
+                return "FIN";
+            case CLOSE_REASON_RESET:
+                return "RESET";
+            default:
+                return "UNKNOWN(" + reason + ")";
+        }
+    }
+
+    /**
+     * Get a human-readable description of a handshake event.
+     *
+     * @param event handshake event value
+     * @return description string
+     */
+    public static String handshakeEventToString(int event) {
+        switch (event) {
+            case HANDSHAKE_EVENT_ATTEMPT:
+                return "ATTEMPT";
+            case HANDSHAKE_EVENT_SUCCESS:
+                return "SUCCESS";
+            case HANDSHAKE_EVENT_FAILURE:
+                return "FAILURE";
+            case HANDSHAKE_EVENT_REKEY:
+                return "REKEY";
+            default:
+                return "UNKNOWN(" + event + ")";
+        }
+    }
+
+    /**
+     * Get a human-readable description of a socket state.
+     *
+     * @param state socket state value
+     * @return description string
+     */
+    public static String socketStateToString(int state) {
+        switch (state) {
+            case SOCKET_STATE_CLOSED:
+                return "CLOSED";
+            case SOCKET_STATE_LISTEN:
+                return "LISTEN";
+            case SOCKET_STATE_SYN_SENT:
+                return "SYN_SENT";
+            case SOCKET_STATE_SYN_RECEIVED:
+                return "SYN_RECEIVED";
+            case SOCKET_STATE_ESTABLISHED:
+                return "ESTABLISHED";
+            case SOCKET_STATE_FIN_WAIT_1:
+                return "FIN_WAIT_1";
+            case SOCKET_STATE_FIN_WAIT_2:
+                return "FIN_WAIT_2";
+            case SOCKET_STATE_CLOSE_WAIT:
+                return "CLOSE_WAIT";
+            case SOCKET_STATE_CLOSING:
+                return "CLOSING";
+            case SOCKET_STATE_LAST_ACK:
+                return "LAST_ACK";
+            case SOCKET_STATE_TIME_WAIT:
+                return "TIME_WAIT";
+            default:
+                return "UNKNOWN(" + state + ")";
+        }
+    }
+
+    /**
+     * Get a human-readable description of a WARP account type.
+     *
+     * @param accountType account type value
+     * @return description string
+     */
+    public static String warpAccountTypeToString(int accountType) {
+        switch (accountType) {
+            case WARP_ACCOUNT_FREE:
+                return "FREE";
+            case WARP_ACCOUNT_PLUS:
+                return "PLUS";
+            case WARP_ACCOUNT_TEAM:
+                return "TEAM";
+            default:
+                return "UNKNOWN";
         }
     }
 }
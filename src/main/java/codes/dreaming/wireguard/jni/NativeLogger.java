@@ -0,0 +1,21 @@
+package codes.dreaming.wireguard.jni;
+
+/**
+ * Receives log records from the native library.
+ * <p>
+ * Register with {@link Native#registerLogger(NativeLogger)}.
+ */
+public interface NativeLogger {
+
+    /**
+     * Handle a log record.
+     * <p>
+     * Called from native worker threads, possibly concurrently. Must not call
+     * back into {@link Native}.
+     *
+     * @param level   one of LOG_LEVEL_* constants
+     * @param target  Rust module path the record came from (e.g. "wireguard_netstack::tcp")
+     * @param message formatted log message
+     */
+    void log(int level, String target, String message);
+}
@@ -0,0 +1,14 @@
+package codes.dreaming.wireguard.jni;
+
+/**
+ * Thrown when native code panics during a {@link Native} call.
+ * <p>
+ * The message contains the panic message and the Rust backtrace. Global
+ * native state may be left inconsistent, so the tunnel should be restarted.
+ */
+public class NativePanicException extends RuntimeException {
+
+    public NativePanicException(String message) {
+        super(message);
+    }
+}
@@ -0,0 +1,14 @@
+package codes.dreaming.wireguard.jni;
+
+/**
+ * Thrown when a connection handle is used after it was closed.
+ * <p>
+ * Handles are never reused for a different connection, so this always points
+ * to a double close or a late read or write on the Java side.
+ */
+public class StaleHandleException extends IllegalStateException {
+
+    public StaleHandleException(String message) {
+        super(message);
+    }
+}
@@ -0,0 +1,22 @@
+package codes.dreaming.wireguard.jni;
+
+/**
+ * Notified of WireGuard handshake events.
+ * <p>
+ * Register with {@link Native#registerTunnelListener(TunnelListener)}.
+ */
+public interface TunnelListener {
+
+    /**
+     * Handle a handshake event.
+     * <p>
+     * Called from a native worker thread, in order. Must return quickly.
+     * Counters and the current handshake age are in {@link Native#metricsSnapshot(int)}.
+     * Tunnels sending directly over UDP only report the handshakes the library
+     * starts, and no rekeys; tunnels using another outer transport, obfuscation
+     * or socket options report every handshake on the wire.
+     *
+     * @param event one of HANDSHAKE_EVENT_* constants
+     */
+    void onHandshakeEvent(int event);
+}
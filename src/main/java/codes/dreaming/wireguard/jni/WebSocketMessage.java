@@ -0,0 +1,60 @@
+package codes.dreaming.wireguard.jni;
+
+import java.nio.charset.StandardCharsets;
+
+/**
+ * A message returned by {@link Native#wsReceive}.
+ */
+public class WebSocketMessage {
+
+    public static final int TEXT = 0;
+    public static final int BINARY = 1;
+    /** The peer closed the connection; the data is the close reason. */
+    public static final int CLOSE = 2;
+
+    private final int type;
+    private final byte[] data;
+    private final int closeCode;
+
+    public WebSocketMessage(int type, byte[] data, int closeCode) {
+        this.type = type;
+        this.data = data;
+        this.closeCode = closeCode;
+    }
+
+    /**
+     * Get the message type.
+     *
+     * @return {@link #TEXT}, {@link #BINARY} or {@link #CLOSE}
+     */
+    public int getType() {
+        return type;
+    }
+
+    /**
+     * Get the message payload; UTF-8 for text messages and close reasons.
+     *
+     * @return payload bytes
+     */
+    public byte[] getData() {
+        return data;
+    }
+
+    /**
+     * Get the payload as text.
+     *
+     * @return the text, or the reason of a close message
+     */
+    public String getText() {
+        return new String(data, StandardCharsets.UTF_8);
+    }
+
+    /**
+     * Get the close code of a close message.
+     *
+     * @return the code the peer sent (1005 if it sent none), or 0 for other messages
+     */
+    public int getCloseCode() {
+        return closeCode;
+    }
+}